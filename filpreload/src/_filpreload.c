@@ -40,6 +40,7 @@ static int (*underlying_real_pthread_create)(pthread_t *thread,
                                              void *(*start_routine)(void *),
                                              void *arg) = 0;
 static pid_t (*underlying_real_fork)(void) = 0;
+static void (*underlying_real__exit)(int status) __attribute__((noreturn)) = 0;
 
 // Used on Linux to implement these APIs:
 extern void *_rjem_malloc(size_t length);
@@ -49,6 +50,7 @@ extern void _rjem_free(void *addr);
 extern void *_rjem_aligned_alloc(size_t alignment, size_t size);
 extern size_t _rjem_malloc_usable_size(void *ptr);
 extern int _rjem_posix_memalign(void **memptr, size_t alignment, size_t size);
+extern void *_rjem_memalign(size_t alignment, size_t size);
 
 // Note whether we've been initialized yet or not:
 static int initialized = 0;
@@ -58,16 +60,32 @@ static int initialized = 0;
 // this on from start until finish.
 static _Atomic int tracking_allocations = ATOMIC_VAR_INIT(0);
 
+// Set when the memory limit is exceeded in "raise" mode; the tracer then
+// calls memory_limit_callback, or raises MemoryError, from Python code.
+static _Atomic int memory_limit_exceeded = ATOMIC_VAR_INIT(0);
+static PyObject *memory_limit_callback = NULL;
+
+// Set when the soft memory limit is exceeded; the tracer then calls
+// soft_memory_limit_callback, if any, from Python code.
+static _Atomic int soft_memory_limit_exceeded = ATOMIC_VAR_INIT(0);
+static PyObject *soft_memory_limit_callback = NULL;
+
+// Whether only threads registered with fil_set_thread_tracking() have their
+// allocations tracked.
+static _Atomic int only_registered_threads = ATOMIC_VAR_INIT(0);
+
 // ID of Python code object extra data:
 static Py_ssize_t extra_code_index = -1;
 
 #ifdef __APPLE__
 #include "interpose.h"
 static pthread_key_t will_i_be_reentrant;
+static pthread_key_t thread_tracking;
 static pthread_once_t will_i_be_reentrant_once = PTHREAD_ONCE_INIT;
 
 static void make_pthread_key() {
   pthread_key_create(&will_i_be_reentrant, (void *)0);
+  pthread_key_create(&thread_tracking, (void *)0);
 }
 
 // 0 means not reentrant, other values means it is.
@@ -86,6 +104,17 @@ static inline void decrement_reentrancy() {
   pthread_setspecific(will_i_be_reentrant, (void *)(current - 1));
 }
 
+// 1 if the current thread was registered, -1 if it was excluded, 0 if neither.
+static inline int get_thread_tracking() {
+  (void)pthread_once(&will_i_be_reentrant_once, make_pthread_key);
+  return (int)(intptr_t)pthread_getspecific(thread_tracking);
+}
+
+static inline void set_thread_tracking(int mode) {
+  (void)pthread_once(&will_i_be_reentrant_once, make_pthread_key);
+  pthread_setspecific(thread_tracking, (void *)(intptr_t)mode);
+}
+
 #elif __linux__
 #include <sys/syscall.h>
 static _Thread_local uint64_t will_i_be_reentrant = 0;
@@ -95,6 +124,12 @@ static inline uint64_t am_i_reentrant() { return will_i_be_reentrant; }
 
 static inline void increment_reentrancy() { will_i_be_reentrant += 1; }
 static inline void decrement_reentrancy() { will_i_be_reentrant -= 1; }
+
+// 1 if the current thread was registered, -1 if it was excluded, 0 if neither.
+static _Thread_local int thread_tracking = 0;
+
+static inline int get_thread_tracking() { return thread_tracking; }
+static inline void set_thread_tracking(int mode) { thread_tracking = mode; }
 #endif
 
 // Versions for calling from Rust
@@ -118,6 +153,20 @@ static inline int should_track_memory() {
   return (likely(initialized) && atomic_load_explicit(&tracking_allocations, memory_order_acquire) && !am_i_reentrant());
 }
 
+// Return whether to track a new allocation: as should_track_memory(), and the
+// current thread wasn't excluded (or, if only registered threads are tracked,
+// was registered). Frees are always tracked, since memory allocated by a
+// tracked thread may be freed by an excluded one.
+static inline int should_track_allocation() {
+  if (!should_track_memory()) {
+    return 0;
+  }
+  int mode = get_thread_tracking();
+  return mode > 0 ||
+         (mode == 0 && !atomic_load_explicit(&only_registered_threads,
+                                             memory_order_relaxed));
+}
+
 // Current thread's Python state:
 static _Thread_local PyFrameObject *current_frame = NULL;
 
@@ -131,25 +180,66 @@ struct FunctionLocation {
 
 // Implemented in the Rust library:
 extern uint64_t pymemprofile_add_function_location(const char* filename, size_t filename_length, const char* function_name,
-                                                   size_t function_length);
-extern void pymemprofile_start_call(uint16_t parent_line_number,
+                                                   size_t function_length, const char* module_name,
+                                                   size_t module_length);
+extern void pymemprofile_start_call(uint32_t parent_line_number,
                                     uint64_t function_id,
-                                    uint16_t line_number);
+                                    uint32_t line_number,
+                                    uint64_t interpreter_id);
 extern void pymemprofile_finish_call();
-extern void pymemprofile_new_line_number(uint16_t line_number);
+extern void pymemprofile_new_line_number(uint32_t line_number);
 extern void pymemprofile_reset(const char *path);
 extern void pymemprofile_start_tracking();
 extern void pymemprofile_stop_tracking();
-extern void pymemprofile_dump_peak_to_flamegraph(const char *path);
+extern char *pymemprofile_dump_peak_to_flamegraph(const char *path);
+extern char *pymemprofile_take_last_error();
+extern void pymemprofile_dump_current_to_flamegraph(const char *path);
+extern void pymemprofile_dump_on_exception(const char *path,
+                                           const char *exception_type);
+extern void pymemprofile_run_post_dump_hooks(const char *path);
+extern void pymemprofile_add_timeline_marker(const char *label);
+extern int pymemprofile_set_label(const char *key, const char *value);
+extern void pymemprofile_register_child_output_path(const char *path);
+extern void pymemprofile_add_watchpoint(const char *location,
+                                        const char *function,
+                                        uint32_t line_number);
+extern char *pymemprofile_top_callstacks(size_t count, const char *metric);
+extern char *pymemprofile_snapshot_json();
+extern char *pymemprofile_tracker_stats_json();
+extern void pymemprofile_free_string(char *string);
+extern int pymemprofile_checkpoint(const char *path);
+extern int pymemprofile_restore(const char *path, int live_allocations);
 extern void pymemprofile_add_allocation(size_t address, size_t length,
-                                        uint16_t line_number);
+                                        uint32_t line_number, size_t caller);
+extern void pymemprofile_set_memory_limit(size_t limit, int raise);
+extern void pymemprofile_set_soft_memory_limit(size_t limit);
+extern void pymemprofile_add_aligned_allocation(size_t address, size_t size,
+                                               size_t alignment,
+                                               uint32_t line_number,
+                                               size_t caller);
+extern void pymemprofile_add_calloc(size_t address, size_t count, size_t size,
+                                    uint32_t line_number, size_t caller);
 extern void pymemprofile_free_allocation(size_t address);
+extern uint64_t pymemprofile_next_event_sequence(void);
+extern void pymemprofile_add_allocations(const size_t *addresses,
+                                         const size_t *sizes,
+                                         const uint64_t *sequences,
+                                         size_t count, uint32_t line_number);
+extern int pymemprofile_add_suballocation(size_t arena, size_t address,
+                                          size_t size, uint32_t line_number);
+extern void pymemprofile_free_suballocation(size_t address);
+extern void pymemprofile_free_allocations(const size_t *addresses,
+                                          const uint64_t *sequences,
+                                          size_t count);
 extern void pymemprofile_add_anon_mmap(size_t address, size_t length,
-                                       uint16_t line_number);
+                                       uint32_t line_number, size_t caller);
 extern void pymemprofile_free_anon_mmap(size_t address, size_t length);
+extern void pymemprofile_add_file_mmap(size_t address, size_t length, int fd,
+                                       uint32_t line_number);
 extern void *pymemprofile_get_current_callstack();
 extern void pymemprofile_set_current_callstack(void *callstack);
 extern void pymemprofile_clear_current_callstack();
+extern void pymemprofile_exit_dump();
 
 static void __attribute__((constructor)) constructor() {
   if (initialized) {
@@ -174,6 +264,7 @@ static void __attribute__((constructor)) constructor() {
   underlying_real_mmap = REAL_IMPL(mmap);
   underlying_real_pthread_create = REAL_IMPL(pthread_create);
   underlying_real_fork = REAL_IMPL(fork);
+  underlying_real__exit = REAL_IMPL(_exit);
 #else
   underlying_real_mmap = dlsym(RTLD_NEXT, "mmap");
   if (!underlying_real_mmap) {
@@ -190,6 +281,11 @@ static void __attribute__((constructor)) constructor() {
     fprintf(stderr, "Couldn't load fork(): %s\n", dlerror());
     exit(1);
   }
+  underlying_real__exit = dlsym(RTLD_NEXT, "_exit");
+  if (!underlying_real__exit) {
+    fprintf(stderr, "Couldn't load _exit(): %s\n", dlerror());
+    exit(1);
+  }
 #endif
   // Initialize Rust static state before we start doing any calls via malloc(),
   // to ensure we don't get unpleasant reentrancy issues.
@@ -207,15 +303,25 @@ static void __attribute__((constructor)) constructor() {
   initialized = 1;
 }
 
-static void start_call(uint64_t function_id, uint16_t line_number) {
+static void start_call(uint64_t function_id, uint32_t line_number) {
   if (should_track_memory()) {
     increment_reentrancy();
-    uint16_t parent_line_number = 0;
+    uint32_t parent_line_number = 0;
     if (current_frame != NULL && current_frame->f_back != NULL) {
       PyFrameObject *f = current_frame->f_back;
       parent_line_number = PyFrame_GetLineNumber(f);
     }
-    pymemprofile_start_call(parent_line_number, function_id, line_number);
+    // Subinterpreters get their own report sections; the main interpreter's
+    // ID is 0.
+#if PY_VERSION_HEX >= 0x03090000
+    uint64_t interpreter_id = (uint64_t)PyInterpreterState_GetID(
+        PyThreadState_GetInterpreter(PyThreadState_Get()));
+#else
+    uint64_t interpreter_id =
+        (uint64_t)PyInterpreterState_GetID(PyThreadState_Get()->interp);
+#endif
+    pymemprofile_start_call(parent_line_number, function_id, line_number,
+                            interpreter_id);
     decrement_reentrancy();
   }
 }
@@ -228,9 +334,77 @@ static void finish_call() {
   }
 }
 
+/// Get a name as UTF-8. Names that aren't valid Unicode, e.g. filenames
+/// decoded with surrogateescape from a non-UTF-8 filesystem, are encoded back
+/// into their original bytes, which the Rust code converts lossily. *owner
+/// must be Py_XDECREF()ed once the result is no longer needed.
+static const char *name_as_utf8(PyObject *name, Py_ssize_t *length,
+                                PyObject **owner) {
+  *owner = NULL;
+  const char *result = PyUnicode_AsUTF8AndSize(name, length);
+  if (result != NULL) {
+    return result;
+  }
+  PyErr_Clear();
+  *owner = PyUnicode_AsEncodedString(name, "utf-8", "surrogateescape");
+  if (*owner == NULL) {
+    // Lone surrogates that didn't come from bytes:
+    PyErr_Clear();
+    *owner = PyUnicode_AsEncodedString(name, "utf-8", "backslashreplace");
+  }
+  char *bytes;
+  if (*owner != NULL && PyBytes_AsStringAndSize(*owner, &bytes, length) == 0) {
+    return bytes;
+  }
+  PyErr_Clear();
+  *length = 0;
+  return "";
+}
+
+/// Report that the memory limit was exceeded, from Python code. Returns -1 if
+/// an exception was raised.
+static int raise_memory_limit_exceeded() {
+  if (memory_limit_callback == NULL) {
+    PyErr_SetString(PyExc_MemoryError,
+                    "Fil's memory limit was exceeded; see the memory-limit "
+                    "report in the output directory");
+    return -1;
+  }
+  PyObject *result = PyObject_CallObject(memory_limit_callback, NULL);
+  if (result == NULL) {
+    return -1;
+  }
+  Py_DECREF(result);
+  return 0;
+}
+
 /// Callback functions for the Python tracing API (PyEval_SetProfile).
 __attribute__((visibility("hidden"))) int
 fil_tracer(PyObject *obj, PyFrameObject *frame, int what, PyObject *arg) {
+  // Raising on a call, before it's added to the callstack, means the frame is
+  // exited without a matching return event, so the callstack stays in sync.
+  // The load first avoids a write on every call.
+  if (unlikely(what == PyTrace_CALL &&
+               atomic_load_explicit(&memory_limit_exceeded,
+                                    memory_order_relaxed) &&
+               atomic_exchange_explicit(&memory_limit_exceeded, 0,
+                                        memory_order_acq_rel))) {
+    if (raise_memory_limit_exceeded() != 0) {
+      return -1;
+    }
+  }
+  if (unlikely(what == PyTrace_CALL &&
+               atomic_load_explicit(&soft_memory_limit_exceeded,
+                                    memory_order_relaxed) &&
+               atomic_exchange_explicit(&soft_memory_limit_exceeded, 0,
+                                        memory_order_acq_rel) &&
+               soft_memory_limit_callback != NULL)) {
+    PyObject *result = PyObject_CallObject(soft_memory_limit_callback, NULL);
+    if (result == NULL) {
+      return -1;
+    }
+    Py_DECREF(result);
+  }
   switch (what) {
   case PyTrace_CALL:
     // Store the current frame, so malloc() can look up line number:
@@ -248,13 +422,33 @@ fil_tracer(PyObject *obj, PyFrameObject *frame, int what, PyObject *arg) {
                      (void **)&function_id);
     if (function_id == 0) {
       Py_ssize_t filename_length, function_length;
-      const char* filename = PyUnicode_AsUTF8AndSize(frame->f_code->co_filename,
-                                                     &filename_length);
-      const char* function_name = PyUnicode_AsUTF8AndSize(frame->f_code->co_name,
-                                                          &function_length);
+      PyObject *filename_owner, *function_name_owner, *module_owner = NULL;
+      const char* filename = name_as_utf8(frame->f_code->co_filename,
+                                          &filename_length, &filename_owner);
+      // Qualified names (e.g. "Class.method") only exist on code objects in
+      // Python 3.11 and later; on older versions we make do with the name.
+#if PY_VERSION_HEX >= 0x030B0000
+      PyObject *function_name_object = frame->f_code->co_qualname;
+#else
+      PyObject *function_name_object = frame->f_code->co_name;
+#endif
+      const char* function_name = name_as_utf8(
+          function_name_object, &function_length, &function_name_owner);
+      // The dotted module name, if there is one; NULL means unknown:
+      Py_ssize_t module_length = 0;
+      const char* module_name = NULL;
+      if (frame->f_globals != NULL && PyDict_Check(frame->f_globals)) {
+        PyObject *module = PyDict_GetItemString(frame->f_globals, "__name__");
+        if (module != NULL && PyUnicode_Check(module)) {
+          module_name = name_as_utf8(module, &module_length, &module_owner);
+        }
+      }
       increment_reentrancy();
-      function_id = pymemprofile_add_function_location(filename, (uint64_t)filename_length, function_name, (uint64_t)function_length);
+      function_id = pymemprofile_add_function_location(filename, (uint64_t)filename_length, function_name, (uint64_t)function_length, module_name, (uint64_t)module_length);
       decrement_reentrancy();
+      Py_XDECREF(filename_owner);
+      Py_XDECREF(function_name_owner);
+      Py_XDECREF(module_owner);
       _PyCode_SetExtra((PyObject *)frame->f_code, extra_code_index,
                        (void *)function_id + 1);
     } else {
@@ -295,6 +489,71 @@ fil_reset(const char *default_path) {
   decrement_reentrancy();
 }
 
+/// Record where a child process will write its report.
+__attribute__((visibility("default"))) void
+fil_register_child_output_path(const char *path) {
+  increment_reentrancy();
+  pymemprofile_register_child_output_path(path);
+  decrement_reentrancy();
+}
+
+/// Log all allocations from a callsite. A line number of 0 matches any line.
+__attribute__((visibility("default"))) void
+fil_add_watchpoint(const char *location, const char *function,
+                   uint32_t line_number) {
+  increment_reentrancy();
+  pymemprofile_add_watchpoint(location, function, line_number);
+  decrement_reentrancy();
+}
+
+/// Include (mode 1) or exclude (mode -1) the current thread's allocations from
+/// tracking, or go back to the default (mode 0).
+__attribute__((visibility("default"))) void fil_set_thread_tracking(int mode) {
+  set_thread_tracking(mode);
+}
+
+/// Only track allocations from threads registered with
+/// fil_set_thread_tracking(1), or go back to tracking all threads that weren't
+/// excluded.
+__attribute__((visibility("default"))) void
+fil_set_only_registered_threads(int enabled) {
+  atomic_store_explicit(&only_registered_threads, enabled,
+                        memory_order_relaxed);
+}
+
+/// Set a limit on tracked memory usage, in bytes; 0 removes it. Once it's
+/// exceeded a report is written, and then either the process aborts, or if
+/// raise is set the next Python function call raises: the callback is called,
+/// and any exception it raises propagates, or if there's no callback (NULL or
+/// None) MemoryError is raised. Needs the GIL.
+__attribute__((visibility("default"))) void
+fil_set_memory_limit(size_t limit, int raise, PyObject *callback) {
+  if (callback == Py_None) {
+    callback = NULL;
+  }
+  Py_XINCREF(callback);
+  Py_XDECREF(memory_limit_callback);
+  memory_limit_callback = callback;
+  increment_reentrancy();
+  pymemprofile_set_memory_limit(limit, raise);
+  decrement_reentrancy();
+}
+
+/// Set a soft memory limit, 0 to remove it. Crossing it writes a report and
+/// calls the callback, if not None, from the next Python function call.
+__attribute__((visibility("default"))) void
+fil_set_soft_memory_limit(size_t limit, PyObject *callback) {
+  if (callback == Py_None) {
+    callback = NULL;
+  }
+  Py_XINCREF(callback);
+  Py_XDECREF(soft_memory_limit_callback);
+  soft_memory_limit_callback = callback;
+  increment_reentrancy();
+  pymemprofile_set_soft_memory_limit(limit);
+  decrement_reentrancy();
+}
+
 /// End memory tracing.
 __attribute__((visibility("default"))) void fil_stop_tracking() {
   atomic_store_explicit(&tracking_allocations, 0, memory_order_release);
@@ -310,33 +569,264 @@ __attribute__((visibility("default"))) void register_fil_tracer() {
   PyEval_SetProfile(fil_tracer, PyLong_FromLong(123));
 }
 
-/// Dump the current peak memory usage to disk.
-__attribute__((visibility("default"))) void
+/// Dump the current peak memory usage to disk. Returns the directory the
+/// report was written to, which may be a fallback if path couldn't be
+/// created, or NULL if it couldn't be written anywhere; free the result with
+/// fil_free_string().
+__attribute__((visibility("default"))) char *
 fil_dump_peak_to_flamegraph(const char *path) {
   // We want to prevent reentrant malloc() calls, but we want to run regardless
   // of whether this particular call is reentrant.
   increment_reentrancy();
-  pymemprofile_dump_peak_to_flamegraph(path);
+  char *result = pymemprofile_dump_peak_to_flamegraph(path);
   decrement_reentrancy();
+  return result;
+}
+
+/// The most recent error from writing reports and the like, or NULL if there
+/// wasn't one since the last call; free the result with fil_free_string().
+__attribute__((visibility("default"))) char *fil_take_last_error() {
+  increment_reentrancy();
+  char *result = pymemprofile_take_last_error();
+  decrement_reentrancy();
+  return result;
+}
+
+/// Dump the current memory usage to disk, while tracking continues.
+__attribute__((visibility("default"))) void
+fil_dump_current_to_flamegraph(const char *path) {
+  increment_reentrancy();
+  pymemprofile_dump_current_to_flamegraph(path);
+  decrement_reentrancy();
+}
+
+/// Write a report of current allocations because an exception of the given
+/// type is propagating, to the given directory, or if NULL the output
+/// directory.
+__attribute__((visibility("default"))) void
+fil_dump_on_exception(const char *path, const char *exception_type) {
+  increment_reentrancy();
+  pymemprofile_dump_on_exception(path, exception_type);
+  decrement_reentrancy();
+}
+
+/// Run the post-dump hooks for a report written to the given directory.
+__attribute__((visibility("default"))) void
+fil_run_post_dump_hooks(const char *path) {
+  increment_reentrancy();
+  pymemprofile_run_post_dump_hooks(path);
+  decrement_reentrancy();
+}
+
+/// Mark the current moment on the memory timeline chart.
+__attribute__((visibility("default"))) void
+fil_add_timeline_marker(const char *label) {
+  increment_reentrancy();
+  pymemprofile_add_timeline_marker(label);
+  decrement_reentrancy();
+}
+
+/// Label whatever the current thread allocates from now on; a NULL value
+/// removes the label. Returns 0 on success, or -1 if the thread already has
+/// too many labels.
+__attribute__((visibility("default"))) int fil_set_label(const char *key,
+                                                         const char *value) {
+  increment_reentrancy();
+  int result = pymemprofile_set_label(key, value);
+  decrement_reentrancy();
+  return result;
+}
+
+/// The top callstacks by a metric, e.g. "current_bytes", one per line as
+/// "<value>\t<callstack>". Returns NULL for unknown metrics; otherwise free
+/// the result with fil_free_string().
+__attribute__((visibility("default"))) char *
+fil_top_callstacks(size_t count, const char *metric) {
+  increment_reentrancy();
+  char *result = pymemprofile_top_callstacks(count, metric);
+  decrement_reentrancy();
+  return result;
+}
+
+/// The current profile contents as JSON; free the result with
+/// fil_free_string().
+__attribute__((visibility("default"))) char *fil_snapshot_json() {
+  increment_reentrancy();
+  char *result = pymemprofile_snapshot_json();
+  decrement_reentrancy();
+  return result;
+}
+
+/// Statistics about the tracker itself as JSON; free the result with
+/// fil_free_string().
+__attribute__((visibility("default"))) char *fil_tracker_stats_json() {
+  increment_reentrancy();
+  char *result = pymemprofile_tracker_stats_json();
+  decrement_reentrancy();
+  return result;
+}
+
+__attribute__((visibility("default"))) void fil_free_string(char *string) {
+  increment_reentrancy();
+  pymemprofile_free_string(string);
+  decrement_reentrancy();
+}
+
+/// Write the tracker state to a checkpoint file. Returns 0 on success.
+__attribute__((visibility("default"))) int fil_checkpoint(const char *path) {
+  increment_reentrancy();
+  int result = pymemprofile_checkpoint(path);
+  decrement_reentrancy();
+  return result;
+}
+
+/// Replace the tracker state with a checkpoint file. Live allocations are only
+/// restored if live_allocations is non-zero. Returns 0 on success.
+__attribute__((visibility("default"))) int fil_restore(const char *path,
+                                                       int live_allocations) {
+  increment_reentrancy();
+  int result = pymemprofile_restore(path, live_allocations);
+  decrement_reentrancy();
+  return result;
+}
+
+/// A sequence number for an allocation or free happening now. Hook layers
+/// that buffer events across threads should take one when the event happens
+/// and pass it to the _sequenced() batch functions, so events that arrive out
+/// of order, e.g. a free of an address that another thread has already
+/// reused, can be reconciled; see memapi/src/sequence.rs.
+__attribute__((visibility("default"))) uint64_t fil_next_event_sequence() {
+  return pymemprofile_next_event_sequence();
+}
+
+/// Record a batch of allocations made by the current Python callstack, for
+/// hook layers that already buffer events, with a sequence number for each
+/// from fil_next_event_sequence(), or NULL.
+__attribute__((visibility("default"))) void
+fil_add_allocations_sequenced(const size_t *addresses, const size_t *sizes,
+                              const uint64_t *sequences, size_t count) {
+  if (should_track_allocation()) {
+    increment_reentrancy();
+    uint32_t line_number = 0;
+    PyFrameObject *f = current_frame;
+    if (f != NULL) {
+      line_number = PyFrame_GetLineNumber(f);
+    }
+    pymemprofile_add_allocations(addresses, sizes, sequences, count,
+                                 line_number);
+    decrement_reentrancy();
+  }
+}
+
+/// Record a batch of allocations made by the current Python callstack, for
+/// hook layers that already buffer events. Only needed for memory that Fil
+/// doesn't see through malloc() and friends.
+__attribute__((visibility("default"))) void
+fil_add_allocations(const size_t *addresses, const size_t *sizes,
+                    size_t count) {
+  fil_add_allocations_sequenced(addresses, sizes, NULL, count);
+}
+
+/// Record that a batch of allocations were freed, with a sequence number for
+/// each from fil_next_event_sequence(), or NULL.
+__attribute__((visibility("default"))) void
+fil_free_allocations_sequenced(const size_t *addresses,
+                               const uint64_t *sequences, size_t count) {
+  if (should_track_memory()) {
+    increment_reentrancy();
+    pymemprofile_free_allocations(addresses, sequences, count);
+    decrement_reentrancy();
+  }
+}
+
+/// Record that a batch of allocations were freed.
+__attribute__((visibility("default"))) void
+fil_free_allocations(const size_t *addresses, size_t count) {
+  fil_free_allocations_sequenced(addresses, NULL, count);
+}
+
+/// Record that a library handed out part of an arena it allocated earlier,
+/// starting at arena, to the current Python callstack, so the flamegraph
+/// blames that callstack rather than whoever created the arena. Returns 1 on
+/// success, or 0 if the arena isn't tracked or the part doesn't fit in it.
+__attribute__((visibility("default"))) int
+fil_add_suballocation(size_t arena, size_t address, size_t size) {
+  int result = 0;
+  if (should_track_allocation()) {
+    increment_reentrancy();
+    uint32_t line_number = 0;
+    PyFrameObject *f = current_frame;
+    if (f != NULL) {
+      line_number = PyFrame_GetLineNumber(f);
+    }
+    result = pymemprofile_add_suballocation(arena, address, size, line_number);
+    decrement_reentrancy();
+  }
+  return result;
+}
+
+/// Record that a part of an arena handed out by fil_add_suballocation() went
+/// back to the arena.
+__attribute__((visibility("default"))) void
+fil_free_suballocation(size_t address) {
+  if (should_track_memory()) {
+    increment_reentrancy();
+    pymemprofile_free_suballocation(address);
+    decrement_reentrancy();
+  }
 }
 
 // *** End APIs called by Python ***
-static void add_allocation(size_t address, size_t size) {
-  uint16_t line_number = 0;
+
+// The caller arguments are the return address of the allocation function,
+// used to find the library responsible for allocations without a Python
+// stack; see memapi/src/native.rs.
+static void add_allocation(size_t address, size_t size, void *caller) {
+  uint32_t line_number = 0;
   PyFrameObject *f = current_frame;
   if (f != NULL) {
     line_number = PyFrame_GetLineNumber(f);
   }
-  pymemprofile_add_allocation(address, size, line_number);
+  pymemprofile_add_allocation(address, size, line_number, (size_t)caller);
 }
 
-static void add_anon_mmap(size_t address, size_t size) {
-  uint16_t line_number = 0;
+static void add_aligned_allocation(size_t address, size_t size,
+                                   size_t alignment, void *caller) {
+  uint32_t line_number = 0;
   PyFrameObject *f = current_frame;
   if (f != NULL) {
     line_number = PyFrame_GetLineNumber(f);
   }
-  pymemprofile_add_anon_mmap(address, size, line_number);
+  pymemprofile_add_aligned_allocation(address, size, alignment, line_number,
+                                     (size_t)caller);
+}
+
+static void add_calloc(size_t address, size_t count, size_t size,
+                       void *caller) {
+  uint32_t line_number = 0;
+  PyFrameObject *f = current_frame;
+  if (f != NULL) {
+    line_number = PyFrame_GetLineNumber(f);
+  }
+  pymemprofile_add_calloc(address, count, size, line_number, (size_t)caller);
+}
+
+static void add_anon_mmap(size_t address, size_t size, void *caller) {
+  uint32_t line_number = 0;
+  PyFrameObject *f = current_frame;
+  if (f != NULL) {
+    line_number = PyFrame_GetLineNumber(f);
+  }
+  pymemprofile_add_anon_mmap(address, size, line_number, (size_t)caller);
+}
+
+static void add_file_mmap(size_t address, size_t size, int fd) {
+  uint32_t line_number = 0;
+  PyFrameObject *f = current_frame;
+  if (f != NULL) {
+    line_number = PyFrame_GetLineNumber(f);
+  }
+  pymemprofile_add_file_mmap(address, size, fd, line_number);
 }
 
 // Disable memory tracking after fork() in the child.
@@ -364,15 +854,26 @@ __attribute__((visibility("default"))) pid_t SYMBOL_PREFIX(fork)(void) {
   return result;
 }
 
+// os._exit() calls _exit(), which skips atexit() handlers, so try to dump a
+// report here if we're still tracking.
+__attribute__((visibility("default"))) void SYMBOL_PREFIX(_exit)(int status) {
+  if (atomic_load_explicit(&tracking_allocations, memory_order_acquire)) {
+    increment_reentrancy();
+    pymemprofile_exit_dump();
+    decrement_reentrancy();
+  }
+  underlying_real__exit(status);
+}
+
 // Override memory-allocation functions:
 __attribute__((visibility("default"))) void *
 SYMBOL_PREFIX(malloc)(size_t size) {
   increment_reentrancy();
   void *result = REAL_IMPL(malloc)(size);
   decrement_reentrancy();
-  if (should_track_memory()) {
+  if (should_track_allocation()) {
     increment_reentrancy();
-    add_allocation((size_t)result, size);
+    add_allocation((size_t)result, size, __builtin_return_address(0));
     decrement_reentrancy();
   }
   return result;
@@ -383,10 +884,10 @@ SYMBOL_PREFIX(calloc)(size_t nmemb, size_t size) {
   increment_reentrancy();
   void *result = REAL_IMPL(calloc)(nmemb, size);
   decrement_reentrancy();
-  size_t allocated = nmemb * size;
-  if (should_track_memory()) {
+  if (should_track_allocation()) {
     increment_reentrancy();
-    add_allocation((size_t)result, allocated);
+    // The multiplication may overflow, so leave it to the checked version:
+    add_calloc((size_t)result, nmemb, size, __builtin_return_address(0));
     decrement_reentrancy();
   }
   return result;
@@ -412,9 +913,9 @@ SYMBOL_PREFIX(realloc)(void *addr, size_t size) {
   increment_reentrancy();
   void *result = REAL_IMPL(realloc)(addr, size);
   decrement_reentrancy();
-  if (should_track_memory()) {
+  if (should_track_allocation()) {
     increment_reentrancy();
-    add_allocation((size_t)result, size);
+    add_allocation((size_t)result, size, __builtin_return_address(0));
     decrement_reentrancy();
   }
   return result;
@@ -425,14 +926,33 @@ SYMBOL_PREFIX(posix_memalign)(void **memptr, size_t alignment, size_t size) {
   increment_reentrancy();
   int result = REAL_IMPL(posix_memalign)(memptr, alignment, size);
   decrement_reentrancy();
-  if (!result && should_track_memory()) {
+  if (!result && should_track_allocation()) {
     increment_reentrancy();
-    add_allocation((size_t)*memptr, size);
+    add_aligned_allocation((size_t)*memptr, size, alignment,
+                           __builtin_return_address(0));
     decrement_reentrancy();
   }
   return result;
 }
 
+#ifdef __linux__
+// Obsolete, but still used by some libraries for aligned buffers. macOS
+// doesn't have it.
+__attribute__((visibility("default"))) void *
+SYMBOL_PREFIX(memalign)(size_t alignment, size_t size) {
+  increment_reentrancy();
+  void *result = REAL_IMPL(memalign)(alignment, size);
+  decrement_reentrancy();
+  if (should_track_allocation()) {
+    increment_reentrancy();
+    add_aligned_allocation((size_t)result, size, alignment,
+                           __builtin_return_address(0));
+    decrement_reentrancy();
+  }
+  return result;
+}
+#endif
+
 __attribute__((visibility("default"))) void SYMBOL_PREFIX(free)(void *addr) {
   // We do bookkeeping first. Otherwise, as soon as the free() happens another
   // thread may allocate the same address, leading to a race condition in the
@@ -462,12 +982,17 @@ fil_mmap_impl(void *addr, size_t length, int prot, int flags, int fd,
 
   void *result = underlying_real_mmap(addr, length, prot, flags, fd, offset);
 
-  // For now we only track anonymous mmap()s:
-  if (result != MAP_FAILED && (flags & MAP_ANONYMOUS) &&
-      should_track_memory()) {
-    increment_reentrancy();
-    add_anon_mmap((size_t)result, length);
-    decrement_reentrancy();
+  if (result != MAP_FAILED && should_track_allocation()) {
+    if (flags & MAP_ANONYMOUS) {
+      increment_reentrancy();
+      add_anon_mmap((size_t)result, length, __builtin_return_address(0));
+      decrement_reentrancy();
+    } else if (fd >= 0) {
+      // Only tracked if enabled, which the Rust code checks:
+      increment_reentrancy();
+      add_file_mmap((size_t)result, length, fd);
+      decrement_reentrancy();
+    }
   }
   return result;
 }
@@ -497,9 +1022,10 @@ reimplemented_aligned_alloc(size_t alignment, size_t size) {
   void *result = REAL_IMPL(aligned_alloc)(alignment, size);
   decrement_reentrancy();
 
-  if (should_track_memory()) {
+  if (should_track_allocation()) {
     increment_reentrancy();
-    add_allocation((size_t)result, size);
+    add_aligned_allocation((size_t)result, size, alignment,
+                           __builtin_return_address(0));
     decrement_reentrancy();
   }
   return result;
@@ -556,16 +1082,25 @@ SYMBOL_PREFIX(pthread_create)(pthread_t *thread, const pthread_attr_t *attr,
 
 #ifdef __APPLE__
 extern int reimplemented_munmap(void *addr, size_t length);
+extern int reimplemented_mlock(const void *addr, size_t len);
+extern int reimplemented_munlock(const void *addr, size_t len);
+extern int reimplemented_mlockall(int flags);
+extern int reimplemented_munlockall(void);
 DYLD_INTERPOSE(SYMBOL_PREFIX(malloc), malloc)
 DYLD_INTERPOSE(SYMBOL_PREFIX(calloc), calloc)
 DYLD_INTERPOSE(SYMBOL_PREFIX(realloc), realloc)
 DYLD_INTERPOSE(SYMBOL_PREFIX(free), free)
 DYLD_INTERPOSE(SYMBOL_PREFIX(mmap), mmap)
 DYLD_INTERPOSE(SYMBOL_PREFIX(munmap), munmap)
+DYLD_INTERPOSE(SYMBOL_PREFIX(mlock), mlock)
+DYLD_INTERPOSE(SYMBOL_PREFIX(munlock), munlock)
+DYLD_INTERPOSE(SYMBOL_PREFIX(mlockall), mlockall)
+DYLD_INTERPOSE(SYMBOL_PREFIX(munlockall), munlockall)
 DYLD_INTERPOSE(SYMBOL_PREFIX(aligned_alloc), aligned_alloc)
 DYLD_INTERPOSE(SYMBOL_PREFIX(posix_memalign), posix_memalign)
 DYLD_INTERPOSE(SYMBOL_PREFIX(pthread_create), pthread_create)
 DYLD_INTERPOSE(SYMBOL_PREFIX(fork), fork)
+DYLD_INTERPOSE(SYMBOL_PREFIX(_exit), _exit)
 #endif
 
 
@@ -584,3 +1119,24 @@ void call_if_tracking(void (*f)(void *), void *user_data) {
 int is_initialized() {
   return initialized;
 }
+
+// Called from Rust, possibly without the GIL, when the memory limit is
+// exceeded in "raise" mode.
+void fil_memory_limit_exceeded() {
+  atomic_store_explicit(&memory_limit_exceeded, 1, memory_order_release);
+}
+
+// Called from Rust, possibly without the GIL, when the soft memory limit is
+// exceeded.
+void fil_soft_memory_limit_exceeded() {
+  atomic_store_explicit(&soft_memory_limit_exceeded, 1, memory_order_release);
+}
+
+// The same thread ID threading.get_native_id() returns, where supported.
+uint64_t fil_current_thread_id() {
+#ifdef PY_HAVE_THREAD_NATIVE_ID
+  return (uint64_t)PyThread_get_thread_native_id();
+#else
+  return (uint64_t)PyThread_get_thread_ident();
+#endif
+}
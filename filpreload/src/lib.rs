@@ -1,12 +1,47 @@
 #![deny(unsafe_op_in_unsafe_fn)]
-use parking_lot::Mutex;
+use pymemprofile_api::ages::AllocationAges;
+use pymemprofile_api::backfill;
+use pymemprofile_api::callcounts::CallCounts;
+use pymemprofile_api::capacity::Capacity;
+use pymemprofile_api::control::{self, Command};
+use pymemprofile_api::doublefree::RecentFrees;
+use pymemprofile_api::errors::{record_error, take_last_error};
+use pymemprofile_api::exithooks;
+use pymemprofile_api::interpreters::{
+    root_function_name, InterpreterId, INTERPRETER_FILENAME, MAIN_INTERPRETER,
+};
+use pymemprofile_api::labels::Labels;
+use pymemprofile_api::largeallocs::LargeAllocations;
+use pymemprofile_api::lifetimepairs::LifetimePairs;
+use pymemprofile_api::limit::{LimitAction, MemoryLimit};
+use pymemprofile_api::locked::LockEvent;
 use pymemprofile_api::memorytracking::{
-    AllocationTracker, CallSiteId, Callstack, FunctionId, VecFunctionLocations, PARENT_PROCESS,
+    AllocationTracker, CallSiteId, Callstack, CallstackId, FunctionId, Generation, LineNumber,
+    TopMetric, VecFunctionLocations, PARENT_PROCESS,
 };
+use pymemprofile_api::mmap;
+use pymemprofile_api::native::{self, LibraryCache, NativeCaller};
+use pymemprofile_api::numa::NumaStats;
 use pymemprofile_api::oom::{InfiniteMemory, OutOfMemoryEstimator, RealMemoryInfo};
+use pymemprofile_api::provider::{CallstackProvider, PushedCallstacks};
+use pymemprofile_api::python::with_gil_if_running;
+use pymemprofile_api::report::ReportOptions;
+use pymemprofile_api::sequence::{next_sequence, UNKNOWN_SEQUENCE};
+use pymemprofile_api::streaming::EventStream;
+use pymemprofile_api::sync::{OwnedRwLock, OwnedWriteGuard};
+use pymemprofile_api::threads::{self, THREAD_FILENAME};
+use pymemprofile_api::trackedaddresses::TrackedAddresses;
+use pymemprofile_api::util::name_from_bytes;
+use pymemprofile_api::watchpoints::Watchpoint;
+use pymemprofile_api::window::WindowedPeak;
 use std::cell::RefCell;
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
 use std::os::raw::{c_char, c_int, c_void};
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Once};
+use std::time::Duration;
 
 #[macro_use]
 extern crate lazy_static;
@@ -20,14 +55,70 @@ static GLOBAL: Jemalloc = Jemalloc;
 
 thread_local!(static THREAD_CALLSTACK: RefCell<Callstack> = RefCell::new(Callstack::new()));
 
+/// The current thread's labels; see memapi/src/labels.rs.
+#[derive(Default)]
+struct ThreadLabels {
+    labels: Labels,
+    // The last callstack that was labelled, since most allocations come from
+    // the same callstack as the one before: (generation, unlabelled,
+    // labelled).
+    last: Option<(Generation, CallstackId, CallstackId)>,
+}
+
+thread_local!(static THREAD_LABELS: RefCell<ThreadLabels> = RefCell::new(ThreadLabels::default()));
+
+/// Python frames are pushed and popped by the tracing hook in C.
+static CALLSTACKS: PushedCallstacks = PushedCallstacks(&THREAD_CALLSTACK);
+
+/// Whether callstacks start with a frame naming the thread; set from the
+/// report options on reset(), and read on every function call.
+static PER_THREAD: AtomicBool = AtomicBool::new(false);
+
+/// Whether file-backed mmap()s are tracked, attributed to their file; set
+/// from FIL_TRACK_FILE_MMAPS on reset(), and read on every mmap().
+static FILE_MMAPS: AtomicBool = AtomicBool::new(false);
+
+/// Whether allocations without a Python stack are broken down by thread,
+/// library and size; set from FIL_NATIVE_BREAKDOWN on reset(), and read on
+/// every allocation.
+static NATIVE_BREAKDOWN: AtomicBool = AtomicBool::new(false);
+
+/// Whether allocations get a frame naming the library that called malloc();
+/// set from FIL_CALLER_LIBRARY on reset(), and read on every allocation.
+static CALLER_LIBRARY: AtomicBool = AtomicBool::new(false);
+
+/// Whether frees of addresses that definitely aren't tracked can skip the
+/// lock; not if double free detection or lifetime pairs need to see every
+/// free. Set on reset().
+static SKIP_UNTRACKED_FREES: AtomicBool = AtomicBool::new(false);
+
+/// True if freeing the address is known to be a no-op for the tracker.
+#[inline]
+fn is_untracked(address: usize) -> bool {
+    SKIP_UNTRACKED_FREES.load(Ordering::Relaxed) && !TRACKED_ADDRESSES.may_contain(address)
+}
+
 struct TrackerState {
     oom: OutOfMemoryEstimator,
     allocations: AllocationTracker<VecFunctionLocations>,
 }
 
 lazy_static! {
-    static ref TRACKER_STATE: Mutex<TrackerState> = Mutex::new(TrackerState {
-        allocations: AllocationTracker::new("/tmp".to_string(), VecFunctionLocations::new()),
+    // Looked up before taking the lock; see memapi/src/native.rs:
+    static ref LIBRARIES: LibraryCache = LibraryCache::default();
+    // Updated on every function call, so it's kept outside the lock:
+    static ref CALL_COUNTS: Arc<CallCounts> = Arc::new(CallCounts::default());
+    // Checked on every free, so it's kept outside the lock; see
+    // memapi/src/trackedaddresses.rs:
+    static ref TRACKED_ADDRESSES: Arc<TrackedAddresses> = Arc::new(TrackedAddresses::default());
+    static ref TRACKER_STATE: OwnedRwLock<TrackerState> = OwnedRwLock::new(TrackerState {
+        allocations: {
+            let mut allocations =
+                AllocationTracker::new("/tmp".to_string(), VecFunctionLocations::new());
+            allocations.call_counts = CALL_COUNTS.clone();
+            allocations.tracked_addresses = TRACKED_ADDRESSES.clone();
+            allocations
+        },
         oom: OutOfMemoryEstimator::new(
             if std::env::var("__FIL_DISABLE_OOM_DETECTION") == Ok("1".to_string()) {
                 Box::new(InfiniteMemory {})
@@ -38,27 +129,104 @@ lazy_static! {
     });
 }
 
+/// Lock the tracker from an allocation hook. If this thread already holds
+/// the lock, the allocation was made by the profiler itself (e.g. a map
+/// rehash or a report buffer), so it shouldn't be tracked, and waiting for the
+/// lock would deadlock.
+fn lock_for_hook() -> Option<OwnedWriteGuard<'static, TrackerState>> {
+    if BROKEN.load(Ordering::Relaxed) {
+        return None;
+    }
+    TRACKER_STATE.lock_unless_reentrant()
+}
+
+/// Set once tracker code called from a hook has panicked. The tracker's state
+/// may be inconsistent at that point, so the hooks leave it alone from then
+/// on.
+static BROKEN: AtomicBool = AtomicBool::new(false);
+
+/// Run tracker code called from C. A panic unwinding into C would abort the
+/// process, and one that left the tracker half-updated could cause more, so
+/// instead panics are caught and tracking stops, letting the program carry on
+/// without the profiler. Reports can still be written, they just won't cover
+/// anything after the panic.
+fn catch_panics<R>(default: R, f: impl FnOnce() -> R) -> R {
+    match std::panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => result,
+        Err(_) => {
+            if !BROKEN.swap(true, Ordering::SeqCst) {
+                eprintln!("=fil-profile= WARNING: Fil hit an internal error, so memory is no longer being tracked, and reports will only cover usage up to this point. Please file a bug report at https://github.com/pythonspeed/filprofiler/issues/new");
+            }
+            default
+        }
+    }
+}
+
+/// While alive, allocations on this thread aren't tracked, even if a panic
+/// unwinds through the code holding it.
+struct ReentrancyGuard;
+
+impl ReentrancyGuard {
+    fn new() -> Self {
+        unsafe { fil_increment_reentrancy() };
+        Self
+    }
+}
+
+impl Drop for ReentrancyGuard {
+    fn drop(&mut self) {
+        unsafe { fil_decrement_reentrancy() };
+    }
+}
+
 /// Register a new function/filename location.
-fn add_function(filename: String, function_name: String) -> FunctionId {
-    let tracker_state = TRACKER_STATE.try_lock();
+fn add_function(filename: String, function_name: String, module: Option<String>) -> FunctionId {
+    let tracker_state = TRACKER_STATE.lock_unless_reentrant();
     if let Some(mut tracker_state) = tracker_state {
-        tracker_state
-            .allocations
-            .functions
-            .add_function(filename, function_name)
+        let functions = &mut tracker_state.allocations.functions;
+        match module {
+            Some(module) => functions.add_function_with_module(filename, function_name, module),
+            None => functions.add_function(filename, function_name),
+        }
     } else {
         // This will help in SIGUSR2 handler: dumping calls into Python, we
-        // can't really acquire lock since it's in the middle of dumping. So
-        // just give up.
+        // can't really acquire lock since this thread is in the middle of
+        // dumping. So just give up.
         FunctionId::UNKNOWN
     }
 }
 
 /// Add to per-thread function stack:
-fn start_call(call_site: FunctionId, parent_line_number: u16, line_number: u16) {
+fn start_call(
+    call_site: FunctionId,
+    parent_line_number: LineNumber,
+    line_number: LineNumber,
+    interpreter: InterpreterId,
+) {
+    CALL_COUNTS.record(call_site);
     THREAD_CALLSTACK.with(|cs| {
-        cs.borrow_mut()
-            .start_call(parent_line_number, CallSiteId::new(call_site, line_number));
+        let mut cs = cs.borrow_mut();
+        // Subinterpreter callstacks start with a frame naming the
+        // interpreter; see memapi/src/interpreters.rs.
+        if interpreter != MAIN_INTERPRETER && cs.calls().is_empty() {
+            let root = add_function(
+                INTERPRETER_FILENAME.to_string(),
+                root_function_name(interpreter),
+                None,
+            );
+            cs.start_call(0, CallSiteId::new(root, 0));
+        }
+        // Likewise for threads, if enabled; see memapi/src/threads.rs.
+        if PER_THREAD.load(Ordering::Relaxed) && cs.calls().is_empty() {
+            let thread = unsafe { fil_current_thread_id() };
+            let root = add_function(
+                THREAD_FILENAME.to_string(),
+                threads::root_function_name(thread),
+                None,
+            );
+            cs.start_call(0, CallSiteId::new(root, 0));
+        }
+        cs.start_call(parent_line_number, CallSiteId::new(call_site, line_number));
     });
 }
 
@@ -87,6 +255,34 @@ extern "C" {
     fn free(address: *mut c_void);
 }
 
+/// Which function an allocation came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum AllocationKind {
+    Malloc,
+    /// aligned_alloc(), posix_memalign() or memalign(), with the alignment.
+    Aligned(usize),
+    AnonMmap,
+}
+
+/// If the breakdown of allocations without a Python stack is enabled and this
+/// thread has no Python stack, where the allocation came from; `caller` is the
+/// return address of the allocation function.
+fn native_caller(caller: usize) -> Option<NativeCaller> {
+    if !NATIVE_BREAKDOWN.load(Ordering::Relaxed) {
+        return None;
+    }
+    let no_python_stack = THREAD_CALLSTACK
+        .try_with(|cs| cs.try_borrow().is_ok_and(|cs| cs.calls().is_empty()))
+        .unwrap_or(false);
+    if !no_python_stack {
+        return None;
+    }
+    Some(NativeCaller {
+        thread: unsafe { fil_current_thread_id() },
+        library: LIBRARIES.library_for_address(caller),
+    })
+}
+
 /// Add a new allocation based off the current callstack.
 ///
 /// This can fail if the thread local with the Python stack is not available.
@@ -95,10 +291,21 @@ extern "C" {
 fn add_allocation(
     address: usize,
     size: usize,
-    line_number: u16,
-    is_mmap: bool,
-) -> Result<(), std::thread::AccessError> {
-    let mut tracker_state = TRACKER_STATE.lock();
+    line_number: LineNumber,
+    kind: AllocationKind,
+    caller: usize,
+) -> Option<()> {
+    // Resolved before taking the lock; see memapi/src/native.rs:
+    let native_caller = native_caller(caller);
+    let caller_library = if native_caller.is_none() && CALLER_LIBRARY.load(Ordering::Relaxed) {
+        Some(LIBRARIES.library_for_address(caller))
+    } else {
+        None
+    };
+    let mut tracker_state = match lock_for_hook() {
+        Some(tracker_state) => tracker_state,
+        None => return Some(()),
+    };
     let current_allocated_bytes = tracker_state.allocations.get_current_allocated_bytes();
 
     // Check if we're out of memory:
@@ -114,13 +321,13 @@ fn add_allocation(
     if oom {
         if address == 0 {
             eprintln!(
-                "=fil-profile= WARNING: Allocation of size {} failed (mmap()? {})",
-                size, is_mmap
+                "=fil-profile= WARNING: Allocation of size {} failed ({:?})",
+                size, kind
             );
         } else {
             unsafe {
                 let address = address as *mut c_void;
-                if is_mmap {
+                if kind == AllocationKind::AnonMmap {
                     (pymemprofile_api::ffi::LIBC.munmap)(address, size);
                 } else {
                     free(address);
@@ -133,76 +340,657 @@ fn add_allocation(
     }
 
     let allocations = &mut tracker_state.allocations;
-    // Will fail during thread shutdown, but not much we can do at that point.
-    let callstack_id = THREAD_CALLSTACK.try_with(|tcs| {
-        let mut callstack = tcs.borrow_mut();
-        callstack.id_for_new_allocation(line_number, |callstack| {
-            allocations.get_callstack_id(callstack)
-        })
-    })?;
+    let callstack_id = match native_caller {
+        Some(native_caller) => native_caller.callstack_id(allocations, size),
+        // Will fail during thread shutdown, but not much we can do at that
+        // point.
+        None => CALLSTACKS.callstack_id(allocations, line_number)?,
+    };
+    let callstack_id = match caller_library {
+        Some(library) => native::with_library_frame(allocations, callstack_id, library.as_deref()),
+        None => callstack_id,
+    };
+    let callstack_id = with_labels(allocations, callstack_id);
 
-    if is_mmap {
-        allocations.add_anon_mmap(PARENT_PROCESS, address, size, callstack_id);
-    } else {
-        allocations.add_allocation(PARENT_PROCESS, address, size, callstack_id);
+    match kind {
+        AllocationKind::Malloc => {
+            allocations.add_allocation(PARENT_PROCESS, address, size, callstack_id)
+        }
+        AllocationKind::Aligned(alignment) => allocations.add_aligned_allocation(
+            PARENT_PROCESS,
+            address,
+            size,
+            alignment,
+            callstack_id,
+        ),
+        AllocationKind::AnonMmap => {
+            allocations.add_anon_mmap(PARENT_PROCESS, address, size, callstack_id)
+        }
     }
 
     if oom {
         // Uh-oh, we're out of memory.
         allocations.oom_dump();
     };
-    Ok(())
+    enforce_memory_limit(tracker_state);
+    Some(())
+}
+
+/// Add a file-backed mmap(), if those are being tracked.
+fn add_file_mmap(address: usize, size: usize, fd: c_int, line_number: LineNumber) -> Option<()> {
+    if !FILE_MMAPS.load(Ordering::Relaxed) {
+        return Some(());
+    }
+    // Resolved before taking the lock, since it does I/O:
+    let path = mmap::fd_path(fd)?;
+    let mut tracker_state = match lock_for_hook() {
+        Some(tracker_state) => tracker_state,
+        None => return Some(()),
+    };
+    let allocations = &mut tracker_state.allocations;
+    let callstack_id = CALLSTACKS.callstack_id(allocations, line_number)?;
+    let callstack_id = with_labels(allocations, callstack_id);
+    allocations.add_file_mmap(PARENT_PROCESS, address, size, callstack_id, &path);
+    enforce_memory_limit(tracker_state);
+    Some(())
+}
+
+/// Add a batch of (address, size, sequence number) allocations from the
+/// current callstack, taking the lock only once.
+fn add_allocations(batch: &[(usize, usize, u64)], line_number: LineNumber) -> Option<()> {
+    let mut tracker_state = match lock_for_hook() {
+        Some(tracker_state) => tracker_state,
+        None => return Some(()),
+    };
+    let total_size: usize = batch.iter().map(|(_, size, _)| size).sum();
+    let current_allocated_bytes = tracker_state.allocations.get_current_allocated_bytes();
+    if batch.iter().any(|(address, _, _)| *address == 0)
+        || tracker_state
+            .oom
+            .too_big_allocation(total_size, current_allocated_bytes)
+    {
+        // Rare, so just let the one-at-a-time path deal with out-of-memory:
+        drop(tracker_state);
+        for (address, size, _) in batch {
+            add_allocation(*address, *size, line_number, AllocationKind::Malloc, 0)?;
+        }
+        return Some(());
+    }
+
+    let allocations = &mut tracker_state.allocations;
+    let callstack_id = CALLSTACKS.callstack_id(allocations, line_number)?;
+    let callstack_id = with_labels(allocations, callstack_id);
+    allocations.add_allocations(PARENT_PROCESS, batch, callstack_id);
+    enforce_memory_limit(tracker_state);
+    Some(())
+}
+
+/// A library handed out part of an arena it allocated; see
+/// memapi/src/arenas.rs.
+fn add_suballocation(
+    arena: usize,
+    address: usize,
+    size: usize,
+    line_number: LineNumber,
+) -> Option<bool> {
+    let mut tracker_state = lock_for_hook()?;
+    let allocations = &mut tracker_state.allocations;
+    let callstack_id = CALLSTACKS.callstack_id(allocations, line_number)?;
+    let callstack_id = with_labels(allocations, callstack_id);
+    Some(allocations.add_suballocation(arena, address, size, callstack_id))
+}
+
+/// Add the current thread's labels, if any, to a callstack.
+fn with_labels(
+    allocations: &mut AllocationTracker<VecFunctionLocations>,
+    callstack_id: CallstackId,
+) -> CallstackId {
+    THREAD_LABELS
+        .try_with(|thread_labels| {
+            let mut thread_labels = match thread_labels.try_borrow_mut() {
+                Ok(thread_labels) => thread_labels,
+                Err(_) => return callstack_id,
+            };
+            if thread_labels.labels.is_empty() {
+                return callstack_id;
+            }
+            let generation = allocations.generation();
+            if let Some((last_generation, unlabelled, labelled)) = thread_labels.last {
+                if last_generation == generation && unlabelled == callstack_id {
+                    return labelled;
+                }
+            }
+            let labelled = thread_labels.labels.apply(allocations, callstack_id);
+            thread_labels.last = Some((generation, callstack_id, labelled));
+            labelled
+        })
+        .unwrap_or(callstack_id)
+}
+
+/// Set or, if value is None, remove one of the current thread's labels.
+/// Returns false if the thread already has too many labels.
+fn set_label(key: &str, value: Option<&str>) -> bool {
+    THREAD_LABELS.with(|thread_labels| {
+        let mut thread_labels = thread_labels.borrow_mut();
+        thread_labels.last = None;
+        match value {
+            Some(value) => thread_labels.labels.set(key, value),
+            None => {
+                thread_labels.labels.remove(key);
+                true
+            }
+        }
+    })
+}
+
+/// Called by hooks once they're done updating the tracker. If usage just
+/// crossed the memory limit, write a report, then abort or have the C tracer
+/// raise an exception in Python code; see memapi/src/limit.rs.
+///
+/// Writing the report needs the GIL, which has to be taken before the tracker
+/// lock, so the lock is released first and then taken again.
+fn enforce_memory_limit(mut tracker_state: OwnedWriteGuard<'static, TrackerState>) {
+    let action = tracker_state.allocations.check_memory_limit();
+    drop(tracker_state);
+    let action = match action {
+        Some(action) => action,
+        None => return,
+    };
+    with_gil_if_running(|| {
+        if let Some(mut tracker_state) = lock_for_hook() {
+            tracker_state.allocations.memory_limit_dump(action);
+        }
+    });
+    match action {
+        LimitAction::Abort => {
+            // The report is already written, so the exit hooks shouldn't try
+            // to write another:
+            exithooks::disarm();
+            eprintln!("=fil-profile= Aborting.");
+            std::process::abort();
+        }
+        LimitAction::Raise => unsafe { fil_memory_limit_exceeded() },
+        LimitAction::Notify => unsafe { fil_soft_memory_limit_exceeded() },
+    }
+}
+
+/// Free a batch of (address, sequence number) allocations, taking the lock
+/// only once.
+fn free_allocations(addresses: &[(usize, u64)]) {
+    if addresses.iter().all(|(address, _)| is_untracked(*address)) {
+        return;
+    }
+    if let Some(mut tracker_state) = lock_for_hook() {
+        tracker_state
+            .allocations
+            .free_allocations(PARENT_PROCESS, addresses);
+    }
 }
 
 /// Free an existing allocation.
 fn free_allocation(address: usize) {
-    let mut tracker_state = TRACKER_STATE.lock();
+    if is_untracked(address) {
+        return;
+    }
+    let mut tracker_state = match lock_for_hook() {
+        Some(tracker_state) => tracker_state,
+        None => return,
+    };
 
     let allocations = &mut tracker_state.allocations;
+    if allocations.wants_free_callstacks() {
+        // Double free detection and lifetime pairs need to know who freed the
+        // memory:
+        if let Some(callstack_id) = CALLSTACKS.callstack_id(allocations, 0) {
+            allocations.free_allocation_from(PARENT_PROCESS, address, callstack_id);
+            return;
+        }
+    }
     allocations.free_allocation(PARENT_PROCESS, address);
 }
 
 /// Get the size of an allocation, or 0 if it's not tracked.
 fn get_allocation_size(address: usize) -> usize {
-    let tracker_state = TRACKER_STATE.lock();
-    let allocations = &tracker_state.allocations;
-    allocations.get_allocation_size(PARENT_PROCESS, address)
+    match lock_for_hook() {
+        Some(tracker_state) => tracker_state
+            .allocations
+            .get_allocation_size(PARENT_PROCESS, address),
+        None => 0,
+    }
 }
 
 /// Reset internal state.
 fn reset(default_path: String) {
     // Make sure we initialize this static, to prevent deadlocks:
     pymemprofile_api::ffi::initialize();
+    // Read before taking the lock, since it does I/O; see
+    // memapi/src/backfill.rs:
+    let existing_mappings = if backfill::enabled_from_env() {
+        backfill::existing_mappings()
+    } else {
+        vec![]
+    };
     let mut tracker_state = TRACKER_STATE.lock();
     tracker_state.allocations.reset(default_path);
+    tracker_state.allocations.report_options = ReportOptions::from_env();
+    PER_THREAD.store(
+        tracker_state.allocations.report_options.per_thread,
+        Ordering::Relaxed,
+    );
+    // See memapi/src/mmap.rs:
+    FILE_MMAPS.store(
+        std::env::var("FIL_TRACK_FILE_MMAPS") == Ok("1".to_string()),
+        Ordering::Relaxed,
+    );
+    NATIVE_BREAKDOWN.store(native::enabled_from_env(), Ordering::Relaxed);
+    CALLER_LIBRARY.store(native::caller_library_from_env(), Ordering::Relaxed);
+    tracker_state.allocations.numa = NumaStats::from_env();
+    tracker_state.allocations.recent_frees = RecentFrees::from_env();
+    tracker_state.allocations.lifetime_pairs = LifetimePairs::from_env();
+    tracker_state.allocations.allocation_ages = AllocationAges::from_env();
+    tracker_state.allocations.windowed_peak = WindowedPeak::from_env();
+    tracker_state.allocations.large_allocations = LargeAllocations::from_env();
+    tracker_state.allocations.memory_limit = MemoryLimit::from_env();
+    tracker_state.allocations.soft_memory_limit = MemoryLimit::soft_from_env();
+    tracker_state.allocations.post_dump.command_from_env();
+    tracker_state.allocations.reserve(Capacity::from_env());
+    SKIP_UNTRACKED_FREES.store(
+        !tracker_state.allocations.wants_free_callstacks(),
+        Ordering::Relaxed,
+    );
+    backfill::record(&mut tracker_state.allocations, &existing_mappings);
+    drop(tracker_state);
+    install_exit_hooks();
+    start_streaming();
+    start_control();
+    start_dashboard();
+}
+
+/// Serve the live dashboard if FIL_DASHBOARD_PORT is set.
+#[cfg(feature = "dashboard")]
+fn start_dashboard() {
+    use pymemprofile_api::dashboard::{self, Snapshot, TOP_CALLSTACKS};
+
+    // Only reads, so several pollers don't wait for each other, but the
+    // allocation hooks still wait for each poll:
+    fn snapshot(top: bool) -> Snapshot {
+        let tracker_state = TRACKER_STATE.read();
+        let allocations = &tracker_state.allocations;
+        let current_bytes = allocations.get_current_allocated_bytes();
+        Snapshot {
+            current_bytes,
+            peak_bytes: allocations.get_peak_allocated_bytes().max(current_bytes),
+            top_callstacks: if top {
+                allocations.top_callstacks(TOP_CALLSTACKS, TopMetric::CurrentBytes)
+            } else {
+                vec![]
+            },
+        }
+    }
+
+    static STARTED: Once = Once::new();
+    STARTED.call_once(|| {
+        dashboard::start_from_env(snapshot, || {
+            // Don't track the dashboard threads' own allocations:
+            unsafe { fil_increment_reentrancy() };
+        });
+    });
+}
+
+#[cfg(not(feature = "dashboard"))]
+fn start_dashboard() {
+    if std::env::var_os("FIL_DASHBOARD_PORT").is_some() {
+        static WARNED: Once = Once::new();
+        WARNED.call_once(|| {
+            eprintln!(
+                "=fil-profile= WARNING: This build of Fil doesn't include the live dashboard."
+            );
+        });
+    }
+}
+
+/// Listen for commands if FIL_CONTROL_SOCKET is set.
+fn start_control() {
+    static STARTED: Once = Once::new();
+    STARTED.call_once(|| {
+        control::start_from_env(handle_control_command, || {
+            // Don't track the control thread's own allocations:
+            unsafe { fil_increment_reentrancy() };
+        });
+    });
+}
+
+/// Run a command from the control socket. The GIL is already held.
+fn handle_control_command(command: Command) -> Result<String, String> {
+    let mut tracker_state = TRACKER_STATE.lock();
+    let allocations = &mut tracker_state.allocations;
+    match command {
+        Command::Status => {
+            let mut status = format!(
+                "current_bytes={} peak_bytes={} generation={}",
+                allocations.get_current_allocated_bytes(),
+                allocations
+                    .get_peak_allocated_bytes()
+                    .max(allocations.get_current_allocated_bytes()),
+                allocations.generation().as_u64(),
+            );
+            if let Some(window_peak) = allocations.get_window_peak_allocated_bytes() {
+                status.push_str(&format!(" window_peak_bytes={}", window_peak));
+            }
+            let address_space = allocations.get_address_space();
+            status.push_str(&format!(
+                " address_space_bytes={} peak_address_space_bytes={}",
+                address_space.current_bytes, address_space.peak_bytes
+            ));
+            Ok(status)
+        }
+        Command::Dump(path) => {
+            let path = path.unwrap_or_else(|| remote_dump_path(allocations.default_path()));
+            allocations.dump_peak_svgs(&path);
+            Ok(path)
+        }
+        Command::DumpCurrent(path) => {
+            let path = path.unwrap_or_else(|| remote_dump_path(allocations.default_path()));
+            allocations.dump_current_svgs(&path);
+            Ok(path)
+        }
+        Command::Reset => {
+            // Same as a reset from Python, so settings get reread too:
+            let default_path = allocations.default_path().to_string();
+            drop(tracker_state);
+            reset(default_path);
+            Ok("reset".to_string())
+        }
+        Command::SetSamplingRate(_) => {
+            Err("Fil tracks every allocation, sampling isn't supported".to_string())
+        }
+    }
+}
+
+/// Where remote dumps go if no directory was given. The counter keeps dumps
+/// made within the same second from overwriting each other.
+fn remote_dump_path(default_path: &str) -> String {
+    static DUMPS: AtomicUsize = AtomicUsize::new(0);
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    format!(
+        "{}/remote-dump-{}-{}",
+        default_path,
+        timestamp,
+        DUMPS.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Stream live events if FIL_STREAM_SOCKET is set. Only done once, since
+/// reset() is called multiple times per process.
+fn start_streaming() {
+    static STARTED: Once = Once::new();
+    STARTED.call_once(|| {
+        // Connecting and starting the writer thread happen without the lock
+        // held, in case they allocate:
+        let stream = EventStream::from_env(|| {
+            // Don't track the writer thread's own allocations:
+            unsafe { fil_increment_reentrancy() };
+        });
+        if stream.is_some() {
+            TRACKER_STATE.lock().allocations.set_event_stream(stream);
+        }
+    });
+}
+
+/// Make sure we try to dump a report if the process crashes, or optionally if
+/// it gets killed.
+fn install_exit_hooks() {
+    static INSTALLED: Once = Once::new();
+    INSTALLED.call_once(|| {
+        exithooks::set_dump_callback(emergency_dump);
+        exithooks::install_panic_hook();
+        exithooks::install_fatal_signal_handlers();
+        exithooks::install_exit_handler();
+        // Set by the --dump-on-terminate command-line option:
+        if std::env::var("FIL_DUMP_ON_TERMINATE") == Ok("1".to_string()) {
+            exithooks::install_termination_signal_handlers(|| {
+                // Don't track the dumping thread's own allocations:
+                unsafe { fil_increment_reentrancy() };
+            });
+        }
+    });
+}
+
+/// Dump peak memory usage when the process is about to die unexpectedly.
+fn emergency_dump(reason: &str) {
+    // Same check as the Python code does for normal reports:
+    if std::env::var_os("FIL_NO_REPORT").is_some_and(|v| !v.is_empty()) {
+        return;
+    }
+    // If we died while holding the lock, e.g. due to a panic in the tracker,
+    // waiting forever would deadlock, so give up after a bit:
+    if let Some(mut tracker_state) = TRACKER_STATE.try_lock_for(Duration::from_secs(1)) {
+        let _guard = ReentrancyGuard::new();
+        tracker_state.allocations.emergency_dump(reason);
+    } else {
+        eprintln!(
+            "=fil-profile= {}, but the profiler was busy so no report could be written.",
+            reason
+        );
+    }
 }
 
 /// Dump all callstacks in peak memory usage to format used by flamegraph.
-fn dump_peak_to_flamegraph(path: &str) {
+fn dump_peak_to_flamegraph(path: &str) -> Option<PathBuf> {
     let mut tracker_state = TRACKER_STATE.lock();
     let allocations = &mut tracker_state.allocations;
-    allocations.dump_peak_to_flamegraph(path);
+    let report = allocations
+        .report(path)
+        .with_standard_artifacts()
+        .for_html();
+    allocations.dump(&report)
+}
+
+/// Dump all callstacks in current memory usage to format used by flamegraph.
+fn dump_current_to_flamegraph(path: &str) {
+    TRACKER_STATE.lock().allocations.dump_current_svgs(path);
+}
+
+/// Write a report of current allocations tagged with an exception type.
+fn dump_on_exception(path: Option<&str>, exception_type: &str) {
+    TRACKER_STATE
+        .lock()
+        .allocations
+        .exception_dump(path, exception_type);
+}
+
+/// Run the post-dump hooks, once the Python code has finished writing the
+/// HTML report.
+fn run_post_dump_hooks(path: &str) {
+    TRACKER_STATE
+        .lock()
+        .allocations
+        .post_dump
+        .run(std::path::Path::new(path));
+}
+
+/// Mark the current moment on the timeline chart.
+fn add_timeline_marker(label: &str) {
+    TRACKER_STATE.lock().allocations.add_timeline_marker(label);
+}
+
+#[no_mangle]
+extern "C" fn pymemprofile_add_allocation(
+    address: usize,
+    size: usize,
+    line_number: LineNumber,
+    caller: usize,
+) {
+    catch_panics((), || {
+        add_allocation(address, size, line_number, AllocationKind::Malloc, caller).unwrap_or(())
+    });
 }
 
 #[no_mangle]
-extern "C" fn pymemprofile_add_allocation(address: usize, size: usize, line_number: u16) {
-    add_allocation(address, size, line_number, false).unwrap_or(());
+extern "C" fn pymemprofile_add_aligned_allocation(
+    address: usize,
+    size: usize,
+    alignment: usize,
+    line_number: LineNumber,
+    caller: usize,
+) {
+    catch_panics((), || {
+        add_allocation(
+            address,
+            size,
+            line_number,
+            AllocationKind::Aligned(alignment),
+            caller,
+        )
+        .unwrap_or(())
+    });
+}
+
+/// calloc() takes a count and an element size. On overflow calloc() fails,
+/// so there's nothing to track, and likewise if it failed for other reasons.
+#[no_mangle]
+extern "C" fn pymemprofile_add_calloc(
+    address: usize,
+    count: usize,
+    size: usize,
+    line_number: LineNumber,
+    caller: usize,
+) {
+    if address == 0 {
+        return;
+    }
+    if let Some(total) = count.checked_mul(size) {
+        catch_panics((), || {
+            add_allocation(address, total, line_number, AllocationKind::Malloc, caller)
+                .unwrap_or(())
+        });
+    }
 }
 
 #[no_mangle]
 extern "C" fn pymemprofile_free_allocation(address: usize) {
-    free_allocation(address);
+    catch_panics((), || free_allocation(address));
+}
+
+#[no_mangle]
+extern "C" fn pymemprofile_next_event_sequence() -> u64 {
+    next_sequence()
+}
+
+/// Sequence numbers for a batch, or UNKNOWN_SEQUENCE for all of them if
+/// `sequences` is NULL.
+///
+/// # Safety
+/// `sequences` must be NULL or point to `count` values.
+unsafe fn batch_sequences(sequences: *const u64, count: usize) -> Vec<u64> {
+    if sequences.is_null() {
+        vec![UNKNOWN_SEQUENCE; count]
+    } else {
+        unsafe { std::slice::from_raw_parts(sequences, count) }.to_vec()
+    }
+}
+
+/// # Safety
+/// `addresses` and `sizes` must both point to `count` values, and
+/// `sequences` must be NULL or point to `count` values.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_add_allocations(
+    addresses: *const usize,
+    sizes: *const usize,
+    sequences: *const u64,
+    count: usize,
+    line_number: LineNumber,
+) {
+    if count == 0 {
+        return;
+    }
+    let addresses = unsafe { std::slice::from_raw_parts(addresses, count) };
+    let sizes = unsafe { std::slice::from_raw_parts(sizes, count) };
+    let sequences = unsafe { batch_sequences(sequences, count) };
+    let batch: Vec<(usize, usize, u64)> = addresses
+        .iter()
+        .zip(sizes.iter())
+        .zip(sequences.iter())
+        .map(|((address, size), sequence)| (*address, *size, *sequence))
+        .collect();
+    catch_panics((), || add_allocations(&batch, line_number).unwrap_or(()));
+}
+
+/// # Safety
+/// `addresses` must point to `count` values, and `sequences` must be NULL or
+/// point to `count` values.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_free_allocations(
+    addresses: *const usize,
+    sequences: *const u64,
+    count: usize,
+) {
+    if count == 0 {
+        return;
+    }
+    let addresses = unsafe { std::slice::from_raw_parts(addresses, count) };
+    let sequences = unsafe { batch_sequences(sequences, count) };
+    let batch: Vec<(usize, u64)> = addresses
+        .iter()
+        .copied()
+        .zip(sequences.iter().copied())
+        .collect();
+    catch_panics((), || free_allocations(&batch));
+}
+
+/// Returns 1 if the sub-allocation was recorded, 0 otherwise.
+#[no_mangle]
+extern "C" fn pymemprofile_add_suballocation(
+    arena: usize,
+    address: usize,
+    size: usize,
+    line_number: LineNumber,
+) -> c_int {
+    catch_panics(0, || {
+        add_suballocation(arena, address, size, line_number).unwrap_or(false) as c_int
+    })
+}
+
+#[no_mangle]
+extern "C" fn pymemprofile_free_suballocation(address: usize) {
+    catch_panics((), || {
+        if let Some(mut tracker_state) = lock_for_hook() {
+            tracker_state.allocations.free_suballocation(address);
+        }
+    });
 }
 
 /// Returns allocation size, or 0 if not stored. Useful for tests, mostly.
 #[no_mangle]
 extern "C" fn pymemprofile_get_allocation_size(address: usize) -> usize {
-    get_allocation_size(address)
+    catch_panics(0, || get_allocation_size(address))
 }
 
 #[no_mangle]
-extern "C" fn pymemprofile_add_anon_mmap(address: usize, size: usize, line_number: u16) {
-    add_allocation(address, size, line_number, true).unwrap_or(());
+extern "C" fn pymemprofile_add_anon_mmap(
+    address: usize,
+    size: usize,
+    line_number: LineNumber,
+    caller: usize,
+) {
+    catch_panics((), || {
+        add_allocation(address, size, line_number, AllocationKind::AnonMmap, caller).unwrap_or(())
+    });
+}
+
+#[no_mangle]
+extern "C" fn pymemprofile_add_file_mmap(
+    address: usize,
+    size: usize,
+    fd: c_int,
+    line_number: LineNumber,
+) {
+    catch_panics((), || {
+        add_file_mmap(address, size, fd, line_number).unwrap_or(())
+    });
 }
 
 #[no_mangle]
@@ -211,21 +999,29 @@ unsafe extern "C" fn pymemprofile_add_function_location(
     filename_length: u64,
     function_name: *const c_char,
     function_length: u64,
+    module_name: *const c_char,
+    module_length: u64,
 ) -> u64 {
-    let filename = unsafe {
-        std::str::from_utf8_unchecked(std::slice::from_raw_parts(
-            filename as *const u8,
-            filename_length as usize,
-        ))
+    // Names aren't necessarily valid UTF-8, e.g. filenames on odd
+    // filesystems, so they're converted lossily:
+    let name = |pointer: *const c_char, length: u64| {
+        if pointer.is_null() {
+            return String::new();
+        }
+        name_from_bytes(unsafe {
+            std::slice::from_raw_parts(pointer as *const u8, length as usize)
+        })
     };
-    let function_name = unsafe {
-        std::str::from_utf8_unchecked(std::slice::from_raw_parts(
-            function_name as *const u8,
-            function_length as usize,
-        ))
+    let module_name = if module_name.is_null() {
+        None
+    } else {
+        Some(name(module_name, module_length))
     };
-
-    let function_id = add_function(filename.to_string(), function_name.to_string());
+    let function_id = add_function(
+        name(filename, filename_length),
+        name(function_name, function_length),
+        module_name,
+    );
     function_id.as_u64()
 }
 
@@ -233,17 +1029,26 @@ unsafe extern "C" fn pymemprofile_add_function_location(
 /// Intended for use from C APIs, what can I say.
 #[no_mangle]
 unsafe extern "C" fn pymemprofile_start_call(
-    parent_line_number: u16,
+    parent_line_number: LineNumber,
     function_id: u64,
-    line_number: u16,
+    line_number: LineNumber,
+    interpreter_id: u64,
 ) {
     let function_id = FunctionId::new(function_id);
-    start_call(function_id, parent_line_number, line_number);
+    catch_panics((), || {
+        start_call(function_id, parent_line_number, line_number, interpreter_id)
+    });
+}
+
+/// Called from _exit(), which skips atexit() handlers.
+#[no_mangle]
+extern "C" fn pymemprofile_exit_dump() {
+    exithooks::emergency_dump(exithooks::EXIT_REASON);
 }
 
 #[no_mangle]
 extern "C" fn pymemprofile_finish_call() {
-    finish_call();
+    catch_panics((), finish_call);
 }
 
 /// # Safety
@@ -260,12 +1065,284 @@ unsafe extern "C" fn pymemprofile_reset(default_path: *const c_char) {
 /// # Safety
 /// Intended for use from C.
 #[no_mangle]
-unsafe extern "C" fn pymemprofile_dump_peak_to_flamegraph(path: *const c_char) {
+unsafe extern "C" fn pymemprofile_register_child_output_path(path: *const c_char) {
     let path = unsafe { CStr::from_ptr(path) }
         .to_str()
         .expect("Path wasn't UTF-8")
         .to_string();
-    dump_peak_to_flamegraph(&path);
+    let mut tracker_state = TRACKER_STATE.lock();
+    tracker_state.allocations.register_child_output_path(path);
+}
+
+/// Set the memory limit at runtime; 0 removes it. If `raise` is false, the
+/// process aborts once the limit is exceeded.
+#[no_mangle]
+extern "C" fn pymemprofile_set_memory_limit(limit: usize, raise: c_int) {
+    let action = if raise != 0 {
+        LimitAction::Raise
+    } else {
+        LimitAction::Abort
+    };
+    let mut tracker_state = TRACKER_STATE.lock();
+    tracker_state.allocations.memory_limit = if limit == 0 {
+        None
+    } else {
+        Some(MemoryLimit::new(limit, action))
+    };
+}
+
+/// Set the soft memory limit at runtime; 0 removes it.
+#[no_mangle]
+extern "C" fn pymemprofile_set_soft_memory_limit(limit: usize) {
+    let mut tracker_state = TRACKER_STATE.lock();
+    tracker_state.allocations.soft_memory_limit = if limit == 0 {
+        None
+    } else {
+        Some(MemoryLimit::new(limit, LimitAction::Notify))
+    };
+}
+
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_add_watchpoint(
+    location: *const c_char,
+    function: *const c_char,
+    line_number: LineNumber,
+) {
+    // Converted the same way as pymemprofile_add_function_location(), so
+    // they match:
+    let location = name_from_bytes(unsafe { CStr::from_ptr(location) }.to_bytes());
+    let function = name_from_bytes(unsafe { CStr::from_ptr(function) }.to_bytes());
+    let mut tracker_state = TRACKER_STATE.lock();
+    tracker_state.allocations.add_watchpoint(Watchpoint {
+        location,
+        function,
+        // 0 means any line:
+        line_number: if line_number == 0 {
+            None
+        } else {
+            Some(line_number)
+        },
+    });
+}
+
+/// Get the top callstacks by a metric (see TopMetric::parse()), one per
+/// line as "<value>\t<callstack>". Returns NULL if the metric is unknown;
+/// otherwise the result must be freed with pymemprofile_free_string().
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_top_callstacks(
+    count: usize,
+    metric: *const c_char,
+) -> *mut c_char {
+    let metric = match unsafe { CStr::from_ptr(metric) }
+        .to_str()
+        .ok()
+        .and_then(TopMetric::parse)
+    {
+        Some(metric) => metric,
+        None => return std::ptr::null_mut(),
+    };
+    let top = TRACKER_STATE
+        .read()
+        .allocations
+        .top_callstacks(count, metric);
+    let mut result = String::new();
+    for (callstack, value) in top {
+        result.push_str(&format!("{}\t{}\n", value, callstack));
+    }
+    // Filenames can't contain NUL in practice, but just in case:
+    CString::new(result.replace('\0', ""))
+        .expect("NULs were removed")
+        .into_raw()
+}
+
+/// The current snapshot as JSON (see ProfileData::to_json()), so callers can
+/// inspect profile contents without writing a report. The result must be
+/// freed with pymemprofile_free_string().
+#[no_mangle]
+extern "C" fn pymemprofile_snapshot_json() -> *mut c_char {
+    let json = TRACKER_STATE.lock().allocations.snapshot().to_json();
+    // Filenames can't contain NUL in practice, but just in case:
+    CString::new(json.to_string().replace('\0', ""))
+        .expect("NULs were removed")
+        .into_raw()
+}
+
+/// Statistics about the tracker itself, as JSON; see
+/// memapi/src/trackerstats.rs. The result must be freed with
+/// pymemprofile_free_string().
+#[no_mangle]
+extern "C" fn pymemprofile_tracker_stats_json() -> *mut c_char {
+    let mut stats = TRACKER_STATE.read().allocations.tracker_stats();
+    stats.lock = TRACKER_STATE.contention();
+    CString::new(stats.to_json().to_string())
+        .expect("JSON numbers don't contain NULs")
+        .into_raw()
+}
+
+/// # Safety
+/// Must be a string returned by pymemprofile_top_callstacks(),
+/// pymemprofile_snapshot_json(), pymemprofile_tracker_stats_json(),
+/// pymemprofile_dump_peak_to_flamegraph() or pymemprofile_take_last_error().
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_free_string(string: *mut c_char) {
+    if !string.is_null() {
+        drop(unsafe { CString::from_raw(string) });
+    }
+}
+
+/// Write the tracker state to a checkpoint file. Returns 0 on success, -1 on
+/// failure.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_checkpoint(path: *const c_char) -> c_int {
+    let path = unsafe { CStr::from_ptr(path) }
+        .to_str()
+        .expect("Path wasn't UTF-8");
+    let result = TRACKER_STATE
+        .lock()
+        .allocations
+        .write_checkpoint(Path::new(path));
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("=fil-profile= Failed to write checkpoint {}: {}", path, e);
+            -1
+        }
+    }
+}
+
+/// Replace the tracker state with a checkpoint file. Returns 0 on success, -1
+/// on failure.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_restore(path: *const c_char, live_allocations: c_int) -> c_int {
+    let path = unsafe { CStr::from_ptr(path) }
+        .to_str()
+        .expect("Path wasn't UTF-8");
+    let result = TRACKER_STATE
+        .lock()
+        .allocations
+        .restore_from_file(Path::new(path), live_allocations != 0);
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("=fil-profile= Failed to restore checkpoint {}: {}", path, e);
+            -1
+        }
+    }
+}
+
+/// Returns the directory the report was written to, which may be a fallback
+/// if the given one couldn't be created, or NULL if it couldn't be written
+/// anywhere; see pymemprofile_take_last_error(). The result must be freed
+/// with pymemprofile_free_string().
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_dump_peak_to_flamegraph(path: *const c_char) -> *mut c_char {
+    let path = match unsafe { CStr::from_ptr(path) }.to_str() {
+        Ok(path) => path.to_string(),
+        Err(_) => {
+            record_error("Output path wasn't UTF-8");
+            return std::ptr::null_mut();
+        }
+    };
+    catch_panics(std::ptr::null_mut(), || {
+        match dump_peak_to_flamegraph(&path)
+            .and_then(|directory| CString::new(directory.to_string_lossy().as_bytes()).ok())
+        {
+            Some(directory) => directory.into_raw(),
+            None => std::ptr::null_mut(),
+        }
+    })
+}
+
+/// The most recent error from writing reports and the like, or NULL if
+/// there wasn't one since the last call. The result must be freed with
+/// pymemprofile_free_string().
+#[no_mangle]
+extern "C" fn pymemprofile_take_last_error() -> *mut c_char {
+    match take_last_error().and_then(|error| CString::new(error.replace('\0', "")).ok()) {
+        Some(error) => error.into_raw(),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_dump_current_to_flamegraph(path: *const c_char) {
+    let path = unsafe { CStr::from_ptr(path) }
+        .to_str()
+        .expect("Path wasn't UTF-8")
+        .to_string();
+    dump_current_to_flamegraph(&path);
+}
+
+/// # Safety
+/// Intended for use from C; `path` may be NULL for the default output
+/// directory.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_dump_on_exception(
+    path: *const c_char,
+    exception_type: *const c_char,
+) {
+    let path = if path.is_null() {
+        None
+    } else {
+        Some(name_from_bytes(unsafe { CStr::from_ptr(path) }.to_bytes()))
+    };
+    let exception_type = name_from_bytes(unsafe { CStr::from_ptr(exception_type) }.to_bytes());
+    catch_panics((), || dump_on_exception(path.as_deref(), &exception_type));
+}
+
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_run_post_dump_hooks(path: *const c_char) {
+    let path = unsafe { CStr::from_ptr(path) }
+        .to_str()
+        .expect("Path wasn't UTF-8")
+        .to_string();
+    run_post_dump_hooks(&path);
+}
+
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_add_timeline_marker(label: *const c_char) {
+    let label = unsafe { CStr::from_ptr(label) }.to_string_lossy();
+    add_timeline_marker(&label);
+}
+
+/// Returns 0 on success, or -1 if the thread already has too many labels. A
+/// NULL value removes the label.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+unsafe extern "C" fn pymemprofile_set_label(key: *const c_char, value: *const c_char) -> c_int {
+    let key = unsafe { CStr::from_ptr(key) }.to_string_lossy();
+    let value = if value.is_null() {
+        None
+    } else {
+        Some(unsafe { CStr::from_ptr(value) }.to_string_lossy())
+    };
+    if set_label(&key, value.as_deref()) {
+        0
+    } else {
+        -1
+    }
 }
 
 /// # Safety
@@ -314,6 +1391,15 @@ extern "C" {
     // Return whether C code has initialized.
     fn is_initialized() -> c_int;
 
+    // Have the tracer raise an exception from Python code, because the
+    // memory limit was exceeded.
+    fn fil_memory_limit_exceeded();
+    fn fil_soft_memory_limit_exceeded();
+
+    // The current thread's native ID, or Python's thread identifier where
+    // native IDs aren't supported.
+    fn fil_current_thread_id() -> u64;
+
     // Increment/decrement reentrancy counter.
     fn fil_increment_reentrancy();
     fn fil_decrement_reentrancy();
@@ -327,15 +1413,25 @@ impl pymemprofile_api::mmap::MmapAPI for FilMmapAPI {
     }
 
     fn remove_mmap(&self, address: usize, length: usize) {
-        let mut tracker_state = TRACKER_STATE.lock();
-
-        let allocations = &mut tracker_state.allocations;
-        allocations.free_anon_mmap(PARENT_PROCESS, address, length);
+        catch_panics((), || {
+            if let Some(mut tracker_state) = lock_for_hook() {
+                let allocations = &mut tracker_state.allocations;
+                allocations.free_anon_mmap(PARENT_PROCESS, address, length);
+            }
+        });
     }
 
     fn is_initialized(&self) -> bool {
         return unsafe { is_initialized() == 1 };
     }
+
+    fn record_lock_event(&self, event: LockEvent) {
+        catch_panics((), || {
+            if let Some(mut tracker_state) = lock_for_hook() {
+                tracker_state.allocations.record_lock_event(event);
+            }
+        });
+    }
 }
 
 /// On macOS we're using reimplemented_* prefix.
@@ -351,3 +1447,72 @@ pub extern "C" fn reimplemented_munmap(addr: *mut c_void, len: usize) -> c_int {
 pub extern "C" fn munmap(addr: *mut c_void, len: usize) -> c_int {
     return unsafe { pymemprofile_api::mmap::munmap_wrapper(addr, len, &FilMmapAPI {}) };
 }
+
+// mlock() and friends are thin wrappers around system calls, so on Linux the
+// system calls are made directly, which also works before initialization. On
+// macOS they're interposed, so calling them from here gets the real ones.
+
+#[cfg_attr(target_os = "linux", export_name = "mlock")]
+#[cfg_attr(target_os = "macos", export_name = "reimplemented_mlock")]
+extern "C" fn fil_mlock(addr: *const c_void, len: usize) -> c_int {
+    let event = LockEvent::Lock {
+        address: addr as usize,
+        length: len,
+    };
+    mmap::lock_wrapper(Some(event), &FilMmapAPI {}, || {
+        #[cfg(target_os = "linux")]
+        return unsafe { libc::syscall(libc::SYS_mlock, addr, len) } as c_int;
+        #[cfg(target_os = "macos")]
+        return unsafe { libc::mlock(addr, len) };
+    })
+}
+
+#[cfg_attr(target_os = "linux", export_name = "munlock")]
+#[cfg_attr(target_os = "macos", export_name = "reimplemented_munlock")]
+extern "C" fn fil_munlock(addr: *const c_void, len: usize) -> c_int {
+    let event = LockEvent::Unlock {
+        address: addr as usize,
+        length: len,
+    };
+    mmap::lock_wrapper(Some(event), &FilMmapAPI {}, || {
+        #[cfg(target_os = "linux")]
+        return unsafe { libc::syscall(libc::SYS_munlock, addr, len) } as c_int;
+        #[cfg(target_os = "macos")]
+        return unsafe { libc::munlock(addr, len) };
+    })
+}
+
+#[cfg_attr(target_os = "linux", export_name = "mlockall")]
+#[cfg_attr(target_os = "macos", export_name = "reimplemented_mlockall")]
+extern "C" fn fil_mlockall(flags: c_int) -> c_int {
+    let event = LockEvent::from_mlockall(flags);
+    mmap::lock_wrapper(Some(event), &FilMmapAPI {}, || {
+        #[cfg(target_os = "linux")]
+        return unsafe { libc::syscall(libc::SYS_mlockall, flags) } as c_int;
+        #[cfg(target_os = "macos")]
+        return unsafe { libc::mlockall(flags) };
+    })
+}
+
+#[cfg_attr(target_os = "linux", export_name = "munlockall")]
+#[cfg_attr(target_os = "macos", export_name = "reimplemented_munlockall")]
+extern "C" fn fil_munlockall() -> c_int {
+    mmap::lock_wrapper(Some(LockEvent::UnlockAll), &FilMmapAPI {}, || {
+        #[cfg(target_os = "linux")]
+        return unsafe { libc::syscall(libc::SYS_munlockall) } as c_int;
+        #[cfg(target_os = "macos")]
+        return unsafe { libc::munlockall() };
+    })
+}
+
+/// Only MADV_HUGEPAGE and MADV_NOHUGEPAGE are recorded, which macOS doesn't
+/// have, so this is Linux only. Allocators call madvise() a lot, so the other
+/// kinds of advice go straight through.
+#[cfg(target_os = "linux")]
+#[no_mangle]
+extern "C" fn madvise(addr: *mut c_void, len: usize, advice: c_int) -> c_int {
+    let event = LockEvent::from_madvise(addr as usize, len, advice);
+    mmap::lock_wrapper(event, &FilMmapAPI {}, || unsafe {
+        libc::syscall(libc::SYS_madvise, addr, len, advice) as c_int
+    })
+}
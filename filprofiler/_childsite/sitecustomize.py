@@ -0,0 +1,49 @@
+"""
+Start profiling in a child process launched with the environment from
+filprofiler.api.child_environment().
+
+This directory is prepended to the child's PYTHONPATH so Python imports this
+module at startup.
+"""
+
+import os
+import sys
+
+
+def _start_child_profiling():
+    # Pop it, so that grandchildren aren't profiled unless asked for:
+    output_path = os.environ.pop("__FIL_CHILD_OUTPUT_PATH", None)
+    if output_path is None:
+        return
+
+    import atexit
+    from filprofiler._tracer import start_tracing, stop_tracing
+
+    def shutdown():
+        index_path = stop_tracing(output_path)
+        print("=fil-profile= Wrote HTML report to " + index_path, file=sys.stderr)
+
+    # Use atexit rather than try/finally so threads that live beyond main
+    # thread also get profiled:
+    atexit.register(shutdown)
+    start_tracing(output_path)
+
+
+def _import_original_sitecustomize():
+    """We're shadowing any existing sitecustomize, so import it too."""
+    this_directory = os.path.dirname(os.path.abspath(__file__))
+    original_path = sys.path[:]
+    sys.path[:] = [
+        p for p in sys.path if os.path.abspath(p or os.curdir) != this_directory
+    ]
+    del sys.modules["sitecustomize"]
+    try:
+        import sitecustomize  # noqa: F401
+    except ImportError:
+        pass
+    finally:
+        sys.path[:] = original_path
+
+
+_start_child_profiling()
+_import_original_sitecustomize()
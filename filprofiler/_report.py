@@ -21,6 +21,24 @@ Python: {sys.version}
 )
 
 
+# Only written if the heap peaked separately; see memapi/src/reporting.rs:
+HEAP_PEAK = """<h2>Peak heap memory, without mmap()</h2>
+<p>Only <code>malloc()</code>-style allocations, at the moment they peaked, which may differ from when the total including <code>mmap()</code> peaked.</p>
+<div><p><input type="button" onclick="fullScreen('#peak-heap');" value="Full screen"> · <a href="peak-memory-heap.svg" target="_blank"><button>Open in new window</button></a></p>
+            <iframe id="peak-heap" src="peak-memory-heap.svg" width="100%" height="400" scrolling="auto" frameborder="0"></iframe><br>
+</div>
+<br>
+"""
+
+
+def wants_html() -> bool:
+    """Whether the HTML report was chosen, see memapi/src/formats.rs."""
+    formats = os.environ.get("FIL_OUTPUT_FORMATS")
+    if formats is None:
+        return True
+    return "html" in [f.strip() for f in formats.split(",")]
+
+
 def render_report(output_path: str, now: datetime) -> str:
     """Write out the HTML index and improve the SVGs."""
     index_path = os.path.join(output_path, "index.html")
@@ -85,6 +103,36 @@ def render_report(output_path: str, now: datetime) -> str:
             <iframe id="peak-reversed" src="peak-memory-reversed.svg" width="100%" height="400" scrolling="auto" frameborder="0"></iframe><br>
 </div>
 <br>
+{heap_peak}<h2>Was peak memory freed later?</h2>
+<p>The same peak memory, split into memory that was freed by the time the program finished, and memory that was still allocated at the end: the latter may indicate a leak.</p>
+<div><p><input type="button" onclick="fullScreen('#peak-lifetime');" value="Full screen"> · <a href="peak-memory-lifetime.svg" target="_blank"><button>Open in new window</button></a></p>
+            <iframe id="peak-lifetime" src="peak-memory-lifetime.svg" width="100%" height="400" scrolling="auto" frameborder="0"></iframe><br>
+</div>
+<br>
+<h2>Number of allocations at peak memory</h2>
+<p>The same moment as the peak memory flamegraph, but weighted by the number of live allocations rather than bytes: an explosion of tiny objects looks deceptively small when weighted by bytes.</p>
+<div><p><input type="button" onclick="fullScreen('#peak-allocation-count-at-peak');" value="Full screen"> · <a href="peak-memory-allocation-count-at-peak.svg" target="_blank"><button>Open in new window</button></a></p>
+            <iframe id="peak-allocation-count-at-peak" src="peak-memory-allocation-count-at-peak.svg" width="100%" height="400" scrolling="auto" frameborder="0"></iframe><br>
+</div>
+<br>
+<h2>Peak number of allocations</h2>
+<p>Where allocations came from at the point when the most allocations were alive at once. Lots of small allocations can be a problem even when they don't add up to much memory.</p>
+<div><p><input type="button" onclick="fullScreen('#peak-allocation-count');" value="Full screen"> · <a href="peak-memory-allocation-count.svg" target="_blank"><button>Open in new window</button></a></p>
+            <iframe id="peak-allocation-count" src="peak-memory-allocation-count.svg" width="100%" height="400" scrolling="auto" frameborder="0"></iframe><br>
+</div>
+<br>
+<h2>Memory over time</h2>
+<p>Total tracked memory over the run, with the peak and any markers added with <code>filprofiler.api.add_timeline_marker()</code>.</p>
+<div><p><input type="button" onclick="fullScreen('#peak-timeline');" value="Full screen"> · <a href="peak-memory-timeline.svg" target="_blank"><button>Open in new window</button></a></p>
+            <iframe id="peak-timeline" src="peak-memory-timeline.svg" width="100%" height="300" scrolling="auto" frameborder="0"></iframe><br>
+</div>
+<br>
+<h2>Peak memory by package</h2>
+<p>Memory is attributed to the package of the function that did the allocation; see the <a href="peak-memory-packages.txt">table</a> for exact numbers.</p>
+<div><p><input type="button" onclick="fullScreen('#peak-packages');" value="Full screen"> · <a href="peak-memory-packages.svg" target="_blank"><button>Open in new window</button></a></p>
+            <iframe id="peak-packages" src="peak-memory-packages.svg" width="100%" height="200" scrolling="auto" frameborder="0"></iframe><br>
+</div>
+<br>
 <blockquote><strong>Need help, or does something look wrong?</strong>
 <a href="https://pythonspeed.com/fil/docs/">Read the documentation</a>,
 and if that doesn't help please
@@ -138,8 +186,14 @@ if you're using threads.</li>
 </html>
 """.format(
                 now=now.ctime(),
-                argv=" ".join(map(shlex.quote, sys.argv)),
+                # The command line can be revealing; see memapi/src/redact.rs:
+                argv="(redacted)"
+                if os.environ.get("FIL_REDACT") == "1"
+                else " ".join(map(shlex.quote, sys.argv)),
                 bugreport=DEBUGGING_INFO,
+                heap_peak=HEAP_PEAK
+                if os.path.exists(os.path.join(output_path, "peak-memory-heap.svg"))
+                else "",
             )
         )
     return index_path
@@ -82,7 +82,7 @@ PARSER.add_argument(
     dest="output_path",
     action="store",
     default="fil-result",
-    help="Directory where the profiling results written",
+    help="Directory where the profiling results written; ~ and environment variables are expanded, and missing parent directories created",
 )
 PARSER.add_argument(
     "--disable-oom-detection",
@@ -90,6 +90,249 @@ PARSER.add_argument(
     default=False,
     help="Disable the heuristic that tries to catch out-of-memory situations before they occur",
 )
+PARSER.add_argument(
+    "--group-by-file",
+    action="store_true",
+    default=False,
+    help="Also write flamegraphs where frames are source files rather than functions",
+)
+PARSER.add_argument(
+    "--frame-display",
+    choices=["absolute", "relative", "module"],
+    default=None,
+    help="Show source files as absolute paths (the default), paths relative to the current directory, or dotted module names",
+)
+PARSER.add_argument(
+    "--output-formats",
+    default=None,
+    metavar="FORMATS",
+    help="Comma-separated formats to write, from svg, html, json, pprof, speedscope and csv, instead of the usual report; html implies svg",
+)
+PARSER.add_argument(
+    "--units",
+    default=None,
+    choices=["bytes", "KiB", "MiB", "GiB", "auto"],
+    help="Units for report titles, summaries and flamegraph tooltips; 'auto' picks KiB, MiB or GiB to suit each number (default: MiB, with exact bytes in tooltips)",
+)
+PARSER.add_argument(
+    "--output-fallback",
+    default=None,
+    metavar="FALLBACKS",
+    help="Where to write the report if the output directory can't be created: a comma-separated list from temp and stderr, or 'none' (default: temp,stderr)",
+)
+PARSER.add_argument(
+    "--strip-prefixes",
+    default=None,
+    metavar="PATTERNS",
+    help="Comma-separated regexes for path prefixes to remove from filenames, e.g. virtualenv directories; 'auto' covers site-packages, the standard library and Bazel sandboxes",
+)
+PARSER.add_argument(
+    "--collapse-frames",
+    default=None,
+    metavar="PATTERNS",
+    help="Comma-separated function names to remove from callstacks, e.g. decorator wrappers; * matches anything, e.g. '*.<locals>.wrapper'",
+)
+PARSER.add_argument(
+    "--merge-lines",
+    action="store_true",
+    default=False,
+    help="Ignore line numbers, merging frames of the same function, for a function-level view",
+)
+PARSER.add_argument(
+    "--include-stacks",
+    default=None,
+    metavar="REGEX",
+    help="Also write flamegraphs of only the callstacks with a frame matching this regex",
+)
+PARSER.add_argument(
+    "--exclude-frames",
+    default=None,
+    metavar="REGEX",
+    help="Also write flamegraphs without the frames matching this regex, e.g. 'importlib'",
+)
+PARSER.add_argument(
+    "--min-frame-percent",
+    type=float,
+    default=None,
+    metavar="PERCENT",
+    help="Leave frames smaller than this percentage of the total out of flamegraphs, e.g. 0.1, to keep them small",
+)
+PARSER.add_argument(
+    "--min-frame-bytes",
+    type=int,
+    default=None,
+    metavar="BYTES",
+    help="Leave frames smaller than this many bytes out of flamegraphs",
+)
+PARSER.add_argument(
+    "--max-lines",
+    type=int,
+    default=None,
+    metavar="N",
+    help="Merge the smallest callstacks into their callers until each flamegraph and .prof file has at most N, to keep reports small enough to share",
+)
+PARSER.add_argument(
+    "--expected-allocations",
+    default=None,
+    metavar="N",
+    help="Size Fil's table of live allocations for about N up front, e.g. 10M, to avoid pauses while it grows",
+)
+PARSER.add_argument(
+    "--expected-callstacks",
+    default=None,
+    metavar="N",
+    help="Size Fil's table of callstacks for about N distinct callstacks up front",
+)
+PARSER.add_argument(
+    "--expected-functions",
+    default=None,
+    metavar="N",
+    help="Size Fil's table of functions for about N distinct functions up front",
+)
+PARSER.add_argument(
+    "--hasher",
+    choices=["ahash", "fast"],
+    default=None,
+    help="Hash function for Fil's tables: ahash (the default) or fast, which is quicker but not randomized",
+)
+PARSER.add_argument(
+    "--per-thread",
+    action="store_true",
+    default=False,
+    help="Split callstacks by thread, and also write flamegraphs for each thread",
+)
+PARSER.add_argument(
+    "--compress",
+    action="store_true",
+    default=False,
+    help="Gzip the raw .prof, JSON and log outputs, which can be very large for big programs",
+)
+PARSER.add_argument(
+    "--post-dump-command",
+    default=None,
+    metavar="COMMAND",
+    help="Shell command to run after each report is written, with the report's directory as $1, e.g. to upload it",
+)
+PARSER.add_argument(
+    "--redact",
+    action="store_true",
+    default=False,
+    help="Replace function, file and module names in the report with opaque identifiers, so it can be shared",
+)
+PARSER.add_argument(
+    "--redaction-mapping",
+    default=None,
+    help="With --redact, write a JSON file mapping the opaque identifiers back to real names to this path; keep it private",
+)
+PARSER.add_argument(
+    "--dump-on-terminate",
+    action="store_true",
+    default=False,
+    help="Write out peak memory flamegraphs if the process is killed with SIGTERM, e.g. by a batch scheduler",
+)
+PARSER.add_argument(
+    "--detect-double-free",
+    action="store_true",
+    default=False,
+    help="Warn, with callstacks, when memory is freed twice, e.g. by a buggy native extension",
+)
+PARSER.add_argument(
+    "--large-allocation-bytes",
+    type=int,
+    default=None,
+    metavar="BYTES",
+    help="Print the callstack of every allocation of at least this many bytes as it happens, and log them to large-allocations.jsonl in the output directory",
+)
+PARSER.add_argument(
+    "--memory-limit-bytes",
+    type=int,
+    default=None,
+    metavar="BYTES",
+    help="Once tracked memory usage exceeds this, write a report of current allocations and then abort, or raise MemoryError with --memory-limit-action=raise",
+)
+PARSER.add_argument(
+    "--soft-memory-limit-bytes",
+    type=int,
+    default=None,
+    metavar="BYTES",
+    help="Once tracked memory usage exceeds this, write a report of current allocations and carry on; typically lower than --memory-limit-bytes",
+)
+PARSER.add_argument(
+    "--memory-limit-action",
+    choices=["abort", "raise"],
+    default="abort",
+    help="What to do after writing the report when --memory-limit-bytes is exceeded",
+)
+PARSER.add_argument(
+    "--dump-on-exception",
+    action="store_true",
+    default=False,
+    help="If the program exits with an exception, write a report of the memory allocated at that point, as is always done for MemoryError",
+)
+PARSER.add_argument(
+    "--lifetime-pairs",
+    action="store_true",
+    default=False,
+    help="Record which code frees memory allocated by which other code, and how long it lived",
+)
+PARSER.add_argument(
+    "--allocation-ages",
+    action="store_true",
+    default=False,
+    help="Record how old memory at peak is, e.g. to tell fresh working data from stale caches",
+)
+PARSER.add_argument(
+    "--track-file-mmaps",
+    action="store_true",
+    default=False,
+    help="Also track memory-mapped files, attributed to the file that was mapped",
+)
+PARSER.add_argument(
+    "--native-breakdown",
+    action="store_true",
+    default=False,
+    help="Break down allocations with no Python stack by thread, native library and size",
+)
+PARSER.add_argument(
+    "--caller-library",
+    action="store_true",
+    default=False,
+    help="Add a final frame to every callstack naming the native library that allocated the memory",
+)
+PARSER.add_argument(
+    "--startup-backfill",
+    action="store_true",
+    default=False,
+    help="Include memory allocated before profiling started, e.g. by interpreter startup (Linux only)",
+)
+PARSER.add_argument(
+    "--peak-window-seconds",
+    type=int,
+    default=None,
+    help="Also report the peak over the last this many seconds, e.g. to see steady-state peaks after a startup spike",
+)
+PARSER.add_argument(
+    "--numa-threshold-mb",
+    type=int,
+    default=None,
+    help="Report which NUMA nodes back allocations of at least this many MiB",
+)
+PARSER.add_argument(
+    "--stream-socket",
+    default=None,
+    help="Stream live allocation events to the Unix domain socket at this path",
+)
+PARSER.add_argument(
+    "--control-socket",
+    default=None,
+    help="Accept commands like 'dump' and 'status' on a Unix domain socket at this path",
+)
+PARSER.add_argument(
+    "--dashboard-port",
+    type=int,
+    default=None,
+    help="Serve a live memory dashboard on this localhost port (needs a Fil build with the dashboard feature)",
+)
 PARSER.add_argument(
     "--no-browser",
     action="store_true",
@@ -167,6 +410,113 @@ def stage_1():
     if arguments.disable_oom_detection:
         # See filpreload/src/lib.rs:
         environ["__FIL_DISABLE_OOM_DETECTION"] = "1"
+    if arguments.group_by_file:
+        # See memapi/src/report.rs:
+        environ["FIL_GROUP_BY_FILE"] = "1"
+    if arguments.frame_display is not None:
+        environ["FIL_FRAME_DISPLAY"] = arguments.frame_display
+    if arguments.output_formats is not None:
+        # See memapi/src/formats.rs:
+        environ["FIL_OUTPUT_FORMATS"] = arguments.output_formats
+    if arguments.units is not None:
+        # See memapi/src/units.rs:
+        environ["FIL_UNITS"] = arguments.units
+    if arguments.output_fallback is not None:
+        # See memapi/src/report.rs:
+        environ["FIL_OUTPUT_FALLBACK"] = arguments.output_fallback
+    if arguments.strip_prefixes is not None:
+        environ["FIL_STRIP_PREFIXES"] = arguments.strip_prefixes
+    if arguments.collapse_frames is not None:
+        environ["FIL_COLLAPSE_FRAMES"] = arguments.collapse_frames
+    if arguments.merge_lines:
+        # See memapi/src/filters.rs:
+        environ["FIL_MERGE_LINES"] = "1"
+    if arguments.include_stacks is not None:
+        environ["FIL_INCLUDE_STACKS"] = arguments.include_stacks
+    if arguments.exclude_frames is not None:
+        environ["FIL_EXCLUDE_FRAMES"] = arguments.exclude_frames
+    if arguments.min_frame_percent is not None:
+        environ["FIL_MIN_FRAME_PERCENT"] = str(arguments.min_frame_percent)
+    if arguments.min_frame_bytes is not None:
+        environ["FIL_MIN_FRAME_BYTES"] = str(arguments.min_frame_bytes)
+    if arguments.max_lines is not None:
+        # See memapi/src/downsample.rs:
+        environ["FIL_MAX_LINES"] = str(arguments.max_lines)
+    # See memapi/src/capacity.rs:
+    for name, value in [
+        ("FIL_EXPECTED_ALLOCATIONS", arguments.expected_allocations),
+        ("FIL_EXPECTED_CALLSTACKS", arguments.expected_callstacks),
+        ("FIL_EXPECTED_FUNCTIONS", arguments.expected_functions),
+    ]:
+        if value is not None:
+            environ[name] = value
+    if arguments.hasher is not None:
+        # See memapi/src/hashing.rs:
+        environ["FIL_HASHER"] = arguments.hasher
+    if arguments.per_thread:
+        environ["FIL_PER_THREAD"] = "1"
+    if arguments.compress:
+        # See memapi/src/compression.rs:
+        environ["FIL_COMPRESS"] = "1"
+    if arguments.post_dump_command is not None:
+        # See memapi/src/postdump.rs:
+        environ["FIL_POST_DUMP_COMMAND"] = arguments.post_dump_command
+    if arguments.redact:
+        environ["FIL_REDACT"] = "1"
+    if arguments.redaction_mapping is not None:
+        environ["FIL_REDACT_MAPPING"] = abspath(arguments.redaction_mapping)
+    if arguments.detect_double_free:
+        # See memapi/src/doublefree.rs:
+        environ["FIL_DETECT_DOUBLE_FREE"] = "1"
+    if arguments.large_allocation_bytes is not None:
+        # See memapi/src/largeallocs.rs:
+        environ["FIL_LARGE_ALLOCATION_BYTES"] = str(arguments.large_allocation_bytes)
+    if arguments.memory_limit_bytes is not None:
+        # See memapi/src/limit.rs:
+        environ["FIL_MEMORY_LIMIT_BYTES"] = str(arguments.memory_limit_bytes)
+        environ["FIL_MEMORY_LIMIT_ACTION"] = arguments.memory_limit_action
+    if arguments.soft_memory_limit_bytes is not None:
+        # See memapi/src/limit.rs:
+        environ["FIL_SOFT_MEMORY_LIMIT_BYTES"] = str(arguments.soft_memory_limit_bytes)
+    if arguments.dump_on_exception:
+        # See filprofiler/_tracer.py:
+        environ["FIL_DUMP_ON_EXCEPTION"] = "1"
+    if arguments.lifetime_pairs:
+        # See memapi/src/lifetimepairs.rs:
+        environ["FIL_LIFETIME_PAIRS"] = "1"
+    if arguments.allocation_ages:
+        # See memapi/src/ages.rs:
+        environ["FIL_ALLOCATION_AGES"] = "1"
+    if arguments.track_file_mmaps:
+        # See memapi/src/mmap.rs:
+        environ["FIL_TRACK_FILE_MMAPS"] = "1"
+    if arguments.native_breakdown:
+        # See memapi/src/native.rs:
+        environ["FIL_NATIVE_BREAKDOWN"] = "1"
+    if arguments.caller_library:
+        # See memapi/src/native.rs:
+        environ["FIL_CALLER_LIBRARY"] = "1"
+    if arguments.startup_backfill:
+        # See memapi/src/backfill.rs:
+        environ["FIL_STARTUP_BACKFILL"] = "1"
+    if arguments.peak_window_seconds is not None:
+        # See memapi/src/window.rs:
+        environ["FIL_PEAK_WINDOW_SECONDS"] = str(arguments.peak_window_seconds)
+    if arguments.numa_threshold_mb is not None:
+        # See memapi/src/numa.rs:
+        environ["FIL_NUMA_THRESHOLD_MB"] = str(arguments.numa_threshold_mb)
+    if arguments.stream_socket is not None:
+        # See memapi/src/streaming.rs:
+        environ["FIL_STREAM_SOCKET"] = arguments.stream_socket
+    if arguments.control_socket is not None:
+        # See memapi/src/control.rs:
+        environ["FIL_CONTROL_SOCKET"] = arguments.control_socket
+    if arguments.dashboard_port is not None:
+        # See memapi/src/dashboard.rs:
+        environ["FIL_DASHBOARD_PORT"] = str(arguments.dashboard_port)
+    if arguments.dump_on_terminate:
+        # See filpreload/src/lib.rs:
+        environ["FIL_DUMP_ON_TERMINATE"] = "1"
 
     # Initial status:
     environ["__FIL_STATUS"] = "launcher"
@@ -225,6 +575,12 @@ def stage_2():
                 print(line, end="")
         sys.exit(0)
 
+    # The path may not have gone through a shell, e.g. if it came from a
+    # config file; memapi/src/paths.rs does the same for paths it's given.
+    arguments.output_path = os.path.expandvars(
+        os.path.expanduser(arguments.output_path)
+    )
+
     if arguments.rest[0] == "-m":
         # Not quite the same as what python -m does, but pretty close:
         if len(arguments.rest) == 1:
@@ -5,11 +5,28 @@ from glob import glob
 from pathlib import Path
 
 
+# Written alongside every report, with the same prefix as the .prof file:
+COMMON_SUFFIXES = [
+    "-packages.txt",
+    "-packages.svg",
+    "-metadata.json",
+    "-bytes-per-call.txt",
+    "-bytes-per-call.csv",
+    "-allocation-sizes.csv",
+    "-callstacks.csv",
+    "-timeline.svg",
+    "-frames.json",
+]
+
+
 def get_allocations(
     output_directory: Path,
     expected_files=[
         "peak-memory.svg",
         "peak-memory-reversed.svg",
+        "peak-memory-allocation-count.svg",
+        "peak-memory-allocation-count-at-peak.svg",
+        "peak-memory-lifetime.svg",
         "index.html",
         "peak-memory.prof",
     ],
@@ -21,6 +38,10 @@ def get_allocations(
         prof_path = str(output_directory)
     else:
         subdir = glob(str(output_directory / "*"))[0]
+        prefix = prof_file[: -len(".prof")]
+        expected_files = expected_files + [
+            prefix + suffix for suffix in COMMON_SUFFIXES
+        ]
         assert sorted(os.listdir(subdir)) == sorted(expected_files)
         for expected_file in expected_files:
             assert (Path(subdir) / expected_file).stat().st_size > 0
@@ -28,6 +49,9 @@ def get_allocations(
     result = {}
     with open(prof_path) as f:
         for line in f:
+            if line.startswith("#"):
+                # Header with the format version etc.
+                continue
             *calls, size_kb = line.split(" ")
             calls = " ".join(calls)
             size_kb = int(int(size_kb) / 1024)
@@ -1,7 +1,7 @@
 """Trace code, so that libpymemprofile_api know's where we are."""
 
 import atexit
-from ctypes import PyDLL
+from ctypes import PyDLL, c_void_p, string_at
 from datetime import datetime
 import os
 import sys
@@ -9,11 +9,11 @@ import threading
 import webbrowser
 from contextlib import contextmanager
 from pathlib import Path
-from typing import Union
+from typing import Callable, List, Optional, Union
 import traceback
 
 from ._utils import timestamp_now, library_path
-from ._report import render_report
+from ._report import render_report, wants_html
 
 
 def check_if_fil_preloaded():
@@ -83,10 +83,11 @@ def _start_thread_trace(frame, event, arg):
     return _start_thread_trace
 
 
-def stop_tracing(output_path: str) -> str:
+def stop_tracing(output_path: str) -> Optional[str]:
     """Finish tracing allocations, and dump to disk.
 
-    Returns path to the index HTML page of the report.
+    Returns path to the index HTML page of the report, or None if it couldn't
+    be written.
     """
     sys.setprofile(None)
     threading.setprofile(None)
@@ -98,10 +99,59 @@ def stop_tracing(output_path: str) -> str:
     return result
 
 
-def create_report(output_path: Union[str, Path]) -> str:
-    preload.fil_dump_peak_to_flamegraph(str(output_path).encode("utf-8"))
-    now = datetime.now()
-    return render_report(output_path, now)
+# Called with the output directory after each report; see
+# api.register_post_dump_callback().
+POST_DUMP_CALLBACKS: List[Callable[[str], None]] = []
+
+
+def run_post_dump_callbacks(output_path: Union[str, Path]):
+    """Run the Python post-dump callbacks; errors are printed, not raised."""
+    for callback in POST_DUMP_CALLBACKS:
+        try:
+            callback(str(output_path))
+        except Exception:
+            print(
+                "=fil-profile= Post-dump callback failed:\n" + traceback.format_exc(),
+                file=sys.stderr,
+            )
+
+
+def take_last_error() -> Optional[str]:
+    """The most recent error Fil couldn't raise, if any; see api.last_error()."""
+    preload.fil_take_last_error.restype = c_void_p
+    error = preload.fil_take_last_error()
+    if not error:
+        return None
+    try:
+        return string_at(error).decode("utf-8", errors="replace")
+    finally:
+        preload.fil_free_string(c_void_p(error))
+
+
+def create_report(output_path: Union[str, Path]) -> Optional[str]:
+    """
+    Write the report, returning the path of its index. If the output directory
+    can't be created the report may end up in a fallback directory instead, or
+    nowhere, in which case None is returned.
+    """
+    preload.fil_dump_peak_to_flamegraph.restype = c_void_p
+    written = preload.fil_dump_peak_to_flamegraph(str(output_path).encode("utf-8"))
+    if not written:
+        # The reason was already printed, and is kept for api.last_error().
+        return None
+    try:
+        output_path = string_at(written).decode("utf-8")
+    finally:
+        preload.fil_free_string(c_void_p(written))
+    if wants_html():
+        index_path = render_report(output_path, datetime.now())
+    else:
+        index_path = str(output_path)
+    # The Rust side leaves the hooks to us, so they run after the HTML is
+    # written:
+    preload.fil_run_post_dump_hooks(str(output_path).encode("utf-8"))
+    run_post_dump_callbacks(output_path)
+    return index_path
 
 
 def trace_until_exit(function, args, kwargs, output_path: str, open_browser: bool):
@@ -117,8 +167,11 @@ def trace_until_exit(function, args, kwargs, output_path: str, open_browser: boo
             )
             return
         index_path = stop_tracing(os.path.join(output_path, timestamp_now()))
-        print("=fil-profile= Wrote HTML report to " + index_path, file=sys.stderr)
-        if open_browser:
+        if index_path is None:
+            return
+        kind = "HTML report" if wants_html() else "report"
+        print(f"=fil-profile= Wrote {kind} to " + index_path, file=sys.stderr)
+        if open_browser and wants_html():
             try:
                 print(
                     "=fil-profile= Trying to open the report in a browser.",
@@ -138,7 +191,25 @@ def trace_until_exit(function, args, kwargs, output_path: str, open_browser: boo
     atexit.register(shutdown)
     with disable_thread_pools():
         start_tracing(os.path.join(output_path, timestamp_now()))
-        function(*args, **kwargs)
+        try:
+            function(*args, **kwargs)
+        except BaseException as e:
+            if should_dump_on_exception(e):
+                preload.fil_dump_on_exception(None, type(e).__name__.encode("utf-8"))
+            raise
+
+
+def should_dump_on_exception(exception: BaseException) -> bool:
+    """
+    Whether an exception escaping the program gets a report of the memory at
+    that point: always for MemoryError, and for any other error if
+    FIL_DUMP_ON_EXCEPTION=1.
+    """
+    if isinstance(exception, MemoryError):
+        return True
+    return os.environ.get("FIL_DUMP_ON_EXCEPTION") == "1" and isinstance(
+        exception, Exception
+    )
 
 
 @contextmanager
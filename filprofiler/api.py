@@ -14,11 +14,21 @@ instead of:
 # if Fil won't work. As such, all imports of ._tracer should not happen at
 # module level.
 
-from typing import Union, Callable, TypeVar
+from typing import Any, Dict, Iterator, List, Optional, Tuple, Union, Callable, TypeVar
+from contextlib import contextmanager
+from dataclasses import dataclass
 from pathlib import Path
+import os
+import re
+import sys
+import threading
 
 _T = TypeVar("_T")
 
+# Each thread's labels, mirroring what the profiler has, so labels() can
+# restore previous values:
+_THREAD_LABELS = threading.local()
+
 
 def profile(code_to_profile: Callable[[], _T], path: Union[str, Path]) -> _T:
     """
@@ -45,4 +55,729 @@ def profile(code_to_profile: Callable[[], _T], path: Union[str, Path]) -> _T:
             stop_tracing(path)
 
 
-__all__ = ["profile"]
+# The recording window in progress, if any: (output path, thread pool
+# context manager).
+_recording = None
+
+
+def start_recording(label: str, path: Union[str, Path] = "fil-result") -> None:
+    """
+    Start a recording window. Memory usage is profiled from now until
+    ``stop_recording()``, with its own peak, and the report is written to a
+    subdirectory of ``path`` named after ``label``.
+
+    Use this to profile only some phases of a long pipeline::
+
+        start_recording("load")
+        data = load()
+        stop_recording()
+        ...
+        start_recording("train")
+        train(data)
+        stop_recording()
+
+    Only one window can be recording at a time.
+    """
+    from ._tracer import start_tracing, disable_thread_pools, check_if_fil_preloaded
+
+    global _recording
+    check_if_fil_preloaded()
+    if _recording is not None:
+        raise RuntimeError(
+            "A recording is already in progress; call stop_recording() first."
+        )
+    base = os.path.join(str(path), label.replace(os.sep, "_"))
+    output_path = base
+    suffix = 1
+    while os.path.exists(output_path):
+        # Same label used more than once:
+        suffix += 1
+        output_path = f"{base}-{suffix}"
+    thread_pools = disable_thread_pools()
+    thread_pools.__enter__()
+    _recording = (output_path, thread_pools)
+    start_tracing(output_path)
+
+
+def stop_recording() -> Optional[str]:
+    """
+    Stop the current recording window and write out its report.
+
+    Returns the path of the report's HTML index, or ``None`` if the report
+    couldn't be written; see ``last_error()``.
+    """
+    from ._tracer import stop_tracing
+
+    global _recording
+    if _recording is None:
+        raise RuntimeError("No recording in progress; call start_recording() first.")
+    output_path, thread_pools = _recording
+    _recording = None
+    try:
+        return stop_tracing(output_path)
+    finally:
+        thread_pools.__exit__(None, None, None)
+
+
+def child_environment(output_path: Union[str, Path]) -> Dict[str, str]:
+    """
+    Return environment variables for a Python subprocess, such that it is
+    profiled by Fil too, writing its report to the given path.
+
+    The path is also recorded in this process's report metadata, so the
+    reports can be found and merged later.  For example::
+
+        subprocess.run(
+            [sys.executable, "child.py"],
+            env=child_environment("/tmp/fil-result/child-1"),
+        )
+    """
+    from ._tracer import check_if_fil_preloaded, preload
+    from ._utils import library_path
+
+    check_if_fil_preloaded()
+    output_path = os.path.abspath(str(output_path))
+    env = dict(os.environ)
+    to_preload = library_path("_filpreload")
+    # Fil goes first, but keep anything else that's already preloaded. On
+    # Linux libraries can be separated by spaces or colons, on macOS only by
+    # colons:
+    if sys.platform == "linux":
+        variable, separators = "LD_PRELOAD", "[: ]"
+    else:
+        variable, separators = "DYLD_INSERT_LIBRARIES", ":"
+    already_preloaded = [
+        path
+        for path in re.split(separators, env.get(variable, ""))
+        if path and path != to_preload
+    ]
+    env[variable] = ":".join([to_preload] + already_preloaded)
+    # Same settings the fil-profile launcher uses:
+    env["PYTHONMALLOC"] = "malloc"
+    env["__FIL_STATUS"] = "program"
+    # Picked up by _childsite/sitecustomize.py:
+    env["__FIL_CHILD_OUTPUT_PATH"] = output_path
+    child_site = os.path.join(os.path.dirname(__file__), "_childsite")
+    env["PYTHONPATH"] = os.pathsep.join(
+        p for p in [child_site, env.get("PYTHONPATH")] if p
+    )
+    preload.fil_register_child_output_path(output_path.encode("utf-8"))
+    return env
+
+
+def watch(location: str, function: str, line: Optional[int] = None) -> None:
+    """
+    Log every allocation made from a specific callsite, with its size,
+    timestamp and full stack, to ``watchpoints.jsonl`` in the output directory.
+
+    ``location`` is a dotted module name, a filename, or the end of a
+    filename, e.g. ``"mypackage.data"`` or ``"mypackage/data.py"``.
+    ``function`` is the function's qualified name, e.g. ``"Loader.load"``.
+    If ``line`` is given, only allocations on that line are logged.
+    """
+    from ._tracer import check_if_fil_preloaded, preload
+
+    check_if_fil_preloaded()
+    preload.fil_add_watchpoint(
+        location.encode("utf-8"), function.encode("utf-8"), line or 0
+    )
+
+
+def set_memory_limit(
+    limit: int, action: Union[str, Callable[[], None]] = "raise"
+) -> None:
+    """
+    Enforce a limit on tracked memory usage, in bytes, e.g. to reproduce
+    out-of-memory problems deterministically in CI. Once usage crosses the
+    limit, a report of current allocations is written to the output directory
+    as ``memory-limit.svg`` and friends, and then:
+
+    * If ``action`` is ``"raise"``, the next Python function call raises
+      ``MemoryError``.
+    * If ``action`` is a callable, it's called from the next Python function
+      call, and any exception it raises propagates from there.
+    * If ``action`` is ``"abort"``, the process aborts.
+
+    The limit triggers again if usage drops below it and then crosses it
+    again. A limit of 0 removes it.
+    """
+    from ._tracer import check_if_fil_preloaded, preload
+    from ctypes import c_int, c_size_t, py_object
+
+    check_if_fil_preloaded()
+    if action not in ("raise", "abort") and not callable(action):
+        raise ValueError(f"Unknown action: {action!r}")
+    preload.fil_set_memory_limit.argtypes = [c_size_t, c_int, py_object]
+    preload.fil_set_memory_limit(
+        limit, int(action != "abort"), action if callable(action) else None
+    )
+
+
+def set_soft_memory_limit(
+    limit: int, callback: Optional[Callable[[], None]] = None
+) -> None:
+    """
+    A softer limit on tracked memory usage, in bytes, typically set below the
+    one from ``set_memory_limit()`` for a staged response. Once usage crosses
+    it, a report of current allocations is written to the output directory as
+    ``soft-memory-limit.svg`` and friends, ``callback`` (if given) is called
+    from the next Python function call, and the program carries on.
+
+    The limit triggers again if usage drops below it and then crosses it
+    again. A limit of 0 removes it.
+    """
+    from ._tracer import check_if_fil_preloaded, preload
+    from ctypes import c_size_t, py_object
+
+    check_if_fil_preloaded()
+    if callback is not None and not callable(callback):
+        raise ValueError(f"Callback isn't callable: {callback!r}")
+    preload.fil_set_soft_memory_limit.argtypes = [c_size_t, py_object]
+    preload.fil_set_soft_memory_limit(limit, callback)
+
+
+def dump_current_to_flamegraph(path: Union[str, Path]) -> str:
+    """
+    Write flamegraphs of the memory allocated right now, rather than at peak,
+    to the given directory as ``current-memory.svg`` and friends. Tracking
+    carries on as before. Returns the path of the main flamegraph.
+    """
+    from ._tracer import check_if_fil_preloaded, preload, run_post_dump_callbacks
+
+    check_if_fil_preloaded()
+    path = Path(path)
+    preload.fil_dump_current_to_flamegraph(str(path).encode("utf-8"))
+    run_post_dump_callbacks(path)
+    return str(path / "current-memory.svg")
+
+
+def dump_on_exception(
+    exception: BaseException, path: Optional[Union[str, Path]] = None
+) -> None:
+    """
+    Write a report of the memory allocated right now, because ``exception``
+    is propagating, e.g. from an ``except`` block or ``sys.excepthook``. The
+    report goes to the given directory, or by default the output directory,
+    as ``exception.svg``, ``exception.json`` and friends, titled with the
+    exception's type, which is also marked on the timeline. Tracking carries
+    on as before.
+    """
+    from ._tracer import check_if_fil_preloaded, preload
+
+    check_if_fil_preloaded()
+    preload.fil_dump_on_exception(
+        None if path is None else str(path).encode("utf-8"),
+        type(exception).__name__.encode("utf-8"),
+    )
+
+
+def add_timeline_marker(label: str) -> None:
+    """
+    Mark the current moment on the report's memory timeline chart, e.g. the
+    end of a phase of the program.
+    """
+    from ._tracer import check_if_fil_preloaded, preload
+
+    check_if_fil_preloaded()
+    preload.fil_add_timeline_marker(label.encode("utf-8"))
+
+
+def set_label(key: str, value: Optional[str]) -> None:
+    """
+    Label everything the current thread allocates from now on, e.g.
+    ``set_label("endpoint", "/upload")``, so memory can be sliced by request
+    type; a value of ``None`` removes the label. Each thread can have at most
+    8 labels at a time.
+    """
+    from ._tracer import check_if_fil_preloaded, preload
+
+    check_if_fil_preloaded()
+    encoded = None if value is None else value.encode("utf-8")
+    if preload.fil_set_label(key.encode("utf-8"), encoded) != 0:
+        raise ValueError("This thread already has the maximum number of labels")
+    current = _THREAD_LABELS.__dict__.setdefault("labels", {})
+    if value is None:
+        current.pop(key, None)
+    else:
+        current[key] = value
+
+
+@contextmanager
+def labels(**labels: str) -> Iterator[None]:
+    """
+    Label everything the current thread allocates inside the ``with`` block,
+    e.g. ``with labels(endpoint="/upload", tenant="acme"):``; previous values
+    of the same labels are restored afterwards.
+    """
+    previous = dict(_THREAD_LABELS.__dict__.get("labels", {}))
+    for key, value in labels.items():
+        set_label(key, value)
+    try:
+        yield
+    finally:
+        for key in labels:
+            set_label(key, previous.get(key))
+
+
+def register_post_dump_callback(callback: Callable[[str], None]) -> None:
+    """
+    Call ``callback`` with the output directory every time a report is
+    written from Python, e.g. to upload it somewhere. It runs after the
+    ``FIL_POST_DUMP_COMMAND`` shell command, if that's set. Exceptions are
+    printed rather than raised.
+    """
+    from ._tracer import check_if_fil_preloaded, POST_DUMP_CALLBACKS
+
+    check_if_fil_preloaded()
+    POST_DUMP_CALLBACKS.append(callback)
+
+
+def exclude_current_thread() -> None:
+    """
+    Stop tracking allocations made by the current thread, e.g. a logging
+    thread or a telemetry agent. Memory it frees is still accounted for.
+    """
+    from ._tracer import check_if_fil_preloaded, preload
+
+    check_if_fil_preloaded()
+    preload.fil_set_thread_tracking(-1)
+
+
+def register_current_thread() -> None:
+    """
+    Track allocations made by the current thread, even if it was excluded, or
+    only registered threads are being tracked.
+    """
+    from ._tracer import check_if_fil_preloaded, preload
+
+    check_if_fil_preloaded()
+    preload.fil_set_thread_tracking(1)
+
+
+def track_only_registered_threads(enabled: bool = True) -> None:
+    """
+    Only track allocations made by threads that called
+    ``register_current_thread()``; or, if ``enabled`` is false, go back to
+    tracking all threads that weren't excluded.
+    """
+    from ._tracer import check_if_fil_preloaded, preload
+
+    check_if_fil_preloaded()
+    preload.fil_set_only_registered_threads(int(enabled))
+
+
+def add_suballocation(arena: int, address: int, size: int) -> bool:
+    """
+    For libraries that allocate a big arena and hand out pieces of it
+    themselves: record that ``size`` bytes at ``address`` were handed out
+    from the arena starting at ``arena``, for the current Python callstack.
+    The flamegraph then blames those bytes on the current callstack rather
+    than on whoever created the arena, until ``free_suballocation()`` is
+    called or the arena is freed.
+
+    Returns ``False`` if the arena isn't memory Fil is tracking, or the piece
+    doesn't fit in what's left of it.
+    """
+    from ._tracer import check_if_fil_preloaded, preload
+    from ctypes import c_size_t
+
+    check_if_fil_preloaded()
+    preload.fil_add_suballocation.argtypes = [c_size_t, c_size_t, c_size_t]
+    return bool(preload.fil_add_suballocation(arena, address, size))
+
+
+def free_suballocation(address: int) -> None:
+    """
+    Record that the piece handed out at ``address`` by ``add_suballocation()``
+    went back to its arena.
+    """
+    from ._tracer import check_if_fil_preloaded, preload
+    from ctypes import c_size_t
+
+    check_if_fil_preloaded()
+    preload.fil_free_suballocation.argtypes = [c_size_t]
+    preload.fil_free_suballocation(address)
+
+
+def last_error() -> Optional[str]:
+    """
+    The most recent error Fil couldn't raise as an exception, e.g. failing to
+    create a report's output directory while the program was exiting, or
+    ``None`` if there wasn't one. Each error is only returned once.
+    """
+    from ._tracer import check_if_fil_preloaded, take_last_error
+
+    check_if_fil_preloaded()
+    return take_last_error()
+
+
+def top_callstacks(count: int = 10, metric: str = "current_bytes") -> List[Tuple[str, int]]:
+    """
+    Return the ``count`` callstacks with the highest ``metric``, largest first,
+    as ``(callstack, value)`` pairs. This is cheap enough to call regularly,
+    e.g. from monitoring code, since no files are written.
+
+    ``metric`` is one of ``"current_bytes"``, ``"peak_bytes"``,
+    ``"current_allocations"`` or ``"peak_allocations"``.
+    """
+    from ._tracer import check_if_fil_preloaded, preload
+    from ctypes import c_void_p, c_size_t, c_char_p, string_at
+
+    check_if_fil_preloaded()
+    preload.fil_top_callstacks.restype = c_void_p
+    preload.fil_top_callstacks.argtypes = [c_size_t, c_char_p]
+    preload.fil_free_string.argtypes = [c_void_p]
+    result = preload.fil_top_callstacks(count, metric.encode("utf-8"))
+    if not result:
+        raise ValueError(f"Unknown metric: {metric!r}")
+    try:
+        text = string_at(result).decode("utf-8")
+    finally:
+        preload.fil_free_string(result)
+    top = []
+    for line in text.splitlines():
+        value, callstack = line.split("\t", 1)
+        top.append((callstack, int(value)))
+    return top
+
+
+@dataclass
+class Frame:
+    """A frame in a callstack."""
+
+    filename: str
+    line_number: int
+    function: str
+    module: Optional[str]
+    # The same in every run, derived from the module, function and line:
+    id: str = ""
+
+
+@dataclass
+class CallstackUsage:
+    """Memory used by a single callstack, outermost frame first."""
+
+    frames: List[Frame]
+    peak_bytes: int
+    current_bytes: int
+    peak_allocations: int
+    allocations_at_peak: int
+    # The same in every run, derived from the frames:
+    id: str = ""
+    # Like id, but ignoring line numbers, so it survives edits that move code:
+    fingerprint: str = ""
+    # malloc()-style bytes, without mmap(), when the heap alone peaked:
+    heap_peak_bytes: int = 0
+
+    def functions(self) -> List[str]:
+        """The function names, outermost first."""
+        return [frame.function for frame in self.frames]
+
+
+@dataclass
+class CallTreeNode:
+    """
+    A callsite in the call tree, with the memory allocated by it or anything
+    it called (``bytes``) and by it directly (``self_bytes``). The root has no
+    frame, and its ``bytes`` are the total. Children are largest first.
+    """
+
+    frame: Optional[Frame]
+    bytes: int
+    self_bytes: int
+    children: List["CallTreeNode"]
+
+    def _calls(self, function: str) -> Iterator[Tuple["CallTreeNode", "CallTreeNode"]]:
+        # (parent, call) for the outermost calls to the function; recursive
+        # calls inside those would count the same memory twice.
+        for child in self.children:
+            if child.frame is not None and child.frame.function == function:
+                yield (self, child)
+            else:
+                yield from child._calls(function)
+
+    def callers(self, function: str) -> List[Tuple[Optional[Frame], int]]:
+        """
+        Who calls ``function``, and the bytes allocated through those calls,
+        largest first. Calls from the top level have ``None`` as the caller.
+        """
+        totals: Dict[Any, Tuple[Optional[Frame], int]] = {}
+        for parent, call in self._calls(function):
+            key = _frame_key(parent.frame)
+            caller, total = totals.get(key, (parent.frame, 0))
+            totals[key] = (caller, total + call.bytes)
+        return sorted(totals.values(), key=lambda item: -item[1])
+
+    def callees(self, function: str) -> List[Tuple[Optional[Frame], int]]:
+        """
+        What ``function`` calls, and the bytes each allocated, largest first.
+        Memory ``function`` allocated directly is under ``None``.
+        """
+        totals: Dict[Any, Tuple[Optional[Frame], int]] = {}
+        for _, call in self._calls(function):
+            parts = [(None, call.self_bytes)] if call.self_bytes else []
+            parts += [(callee.frame, callee.bytes) for callee in call.children]
+            for frame, size in parts:
+                key = _frame_key(frame)
+                callee, total = totals.get(key, (frame, 0))
+                totals[key] = (callee, total + size)
+        return sorted(totals.values(), key=lambda item: -item[1])
+
+
+def _frame_key(frame: Optional[Frame]) -> Any:
+    if frame is None:
+        return None
+    return (frame.filename, frame.line_number, frame.function, frame.module)
+
+
+@dataclass
+class ProfileSnapshot:
+    """
+    The profile contents at a point in time: every callstack that uses memory,
+    and the totals. ``raw`` is everything Fil knows, as parsed JSON.
+    """
+
+    callstacks: List[CallstackUsage]
+    peak_bytes: int
+    current_bytes: int
+    peak_allocations: int
+    raw: Dict[str, Any]
+
+    def callstacks_with(self, function: str) -> List[CallstackUsage]:
+        """The callstacks that include a call to the given function."""
+        return [
+            usage for usage in self.callstacks if function in usage.functions()
+        ]
+
+    def call_tree(self, peak: bool = False) -> CallTreeNode:
+        """
+        The callstacks merged into a tree of callsites, weighted by current
+        bytes, or with ``peak=True`` by bytes at peak. JSON reports include the
+        same tree as ``call_tree``.
+        """
+        root = CallTreeNode(frame=None, bytes=0, self_bytes=0, children=[])
+        # Children by frame, for each node:
+        index: Dict[int, Dict[Any, CallTreeNode]] = {}
+        for usage in self.callstacks:
+            size = usage.peak_bytes if peak else usage.current_bytes
+            if size == 0:
+                continue
+            node = root
+            node.bytes += size
+            for frame in usage.frames:
+                children = index.setdefault(id(node), {})
+                key = _frame_key(frame)
+                if key not in children:
+                    child = CallTreeNode(
+                        frame=frame, bytes=0, self_bytes=0, children=[]
+                    )
+                    children[key] = child
+                    node.children.append(child)
+                node = children[key]
+                node.bytes += size
+            node.self_bytes += size
+        nodes = [root]
+        while nodes:
+            node = nodes.pop()
+            node.children.sort(key=lambda child: -child.bytes)
+            nodes.extend(node.children)
+        return root
+
+
+def snapshot() -> ProfileSnapshot:
+    """
+    Return the current profile contents, without writing any files, e.g. so
+    tests can assert on where memory was allocated.
+    """
+    from ._tracer import check_if_fil_preloaded, preload
+    from ctypes import c_void_p, string_at
+    import json
+
+    check_if_fil_preloaded()
+    preload.fil_snapshot_json.restype = c_void_p
+    preload.fil_snapshot_json.argtypes = []
+    preload.fil_free_string.argtypes = [c_void_p]
+    result = preload.fil_snapshot_json()
+    try:
+        raw = json.loads(string_at(result).decode("utf-8"))
+    finally:
+        preload.fil_free_string(result)
+    callstacks = [
+        CallstackUsage(
+            frames=[
+                Frame(
+                    filename=frame["filename"],
+                    line_number=frame["line_number"],
+                    function=frame["function"],
+                    module=frame["module"],
+                    id=frame["id"],
+                )
+                for frame in usage["frames"]
+            ],
+            peak_bytes=usage["peak_bytes"],
+            current_bytes=usage["current_bytes"],
+            peak_allocations=usage["peak_allocations"],
+            allocations_at_peak=usage["allocations_at_peak"],
+            id=usage["id"],
+            fingerprint=usage["fingerprint"],
+            heap_peak_bytes=usage["heap_peak_bytes"],
+        )
+        for usage in raw["callstacks"]
+    ]
+    return ProfileSnapshot(
+        callstacks=callstacks,
+        peak_bytes=raw["peak_bytes"],
+        current_bytes=raw["current_bytes"],
+        peak_allocations=raw["peak_allocations"],
+        raw=raw,
+    )
+
+
+def to_tracemalloc(snapshot: ProfileSnapshot, peak: bool = False):
+    """
+    Convert a Fil snapshot into a ``tracemalloc.Snapshot``, so tooling built
+    on ``tracemalloc`` can be used on Fil's data, or the two compared with
+    ``Snapshot.compare_to()``. Fil doesn't keep individual allocations, so
+    each callstack becomes a single trace of its current bytes, or its bytes
+    at peak if ``peak`` is true. Callstacks using no memory are left out.
+    """
+    import tracemalloc
+
+    traces = []
+    for usage in snapshot.callstacks:
+        size = usage.peak_bytes if peak else usage.current_bytes
+        if size == 0:
+            continue
+        # tracemalloc stores frames most recent first:
+        frames = tuple(
+            (frame.filename, frame.line_number) for frame in reversed(usage.frames)
+        )
+        if sys.version_info >= (3, 9):
+            traces.append((0, size, frames, len(frames)))
+        else:
+            traces.append((0, size, frames))
+    traceback_limit = max(
+        (len(usage.frames) for usage in snapshot.callstacks), default=1
+    )
+    return tracemalloc.Snapshot(tuple(traces), traceback_limit)
+
+
+def from_tracemalloc(tracemalloc_snapshot) -> ProfileSnapshot:
+    """
+    Convert a ``tracemalloc.Snapshot`` into a Fil snapshot, e.g. to compare
+    it with ``snapshot()``. Traces with the same traceback are combined into
+    one callstack. ``tracemalloc`` doesn't record function names, so frames'
+    functions are ``"?"``, and since it only knows about live memory, peak and
+    current numbers are the same.
+    """
+    combined: Dict[Tuple[Tuple[str, int], ...], List[int]] = {}
+    for trace in tracemalloc_snapshot.traces:
+        key = tuple((frame.filename, frame.lineno) for frame in trace.traceback)
+        totals = combined.setdefault(key, [0, 0])
+        totals[0] += trace.size
+        totals[1] += 1
+    callstacks = [
+        CallstackUsage(
+            frames=[
+                Frame(filename=filename, line_number=line, function="?", module=None)
+                for (filename, line) in key
+            ],
+            peak_bytes=size,
+            current_bytes=size,
+            peak_allocations=count,
+            allocations_at_peak=count,
+        )
+        for key, (size, count) in combined.items()
+    ]
+    total_bytes = sum(usage.current_bytes for usage in callstacks)
+    total_allocations = sum(usage.peak_allocations for usage in callstacks)
+    return ProfileSnapshot(
+        callstacks=callstacks,
+        peak_bytes=total_bytes,
+        current_bytes=total_bytes,
+        peak_allocations=total_allocations,
+        raw={"source": "tracemalloc"},
+    )
+
+
+def tracker_stats() -> Dict[str, int]:
+    """
+    Return numbers about Fil itself rather than your program, for figuring out
+    why profiling is slow or uses a lot of memory: live tracked allocations
+    and mmap()s, how many functions and callstacks Fil has seen, the size of
+    the copy kept of peak usage, Fil's own memory use, and how often its lock
+    was taken and how often that meant waiting for another thread.
+    """
+    from ._tracer import check_if_fil_preloaded, preload
+    from ctypes import c_void_p, string_at
+    import json
+
+    check_if_fil_preloaded()
+    preload.fil_tracker_stats_json.restype = c_void_p
+    preload.fil_tracker_stats_json.argtypes = []
+    preload.fil_free_string.argtypes = [c_void_p]
+    result = preload.fil_tracker_stats_json()
+    try:
+        return json.loads(string_at(result).decode("utf-8"))
+    finally:
+        preload.fil_free_string(result)
+
+
+def checkpoint(path: Union[str, Path]) -> None:
+    """
+    Save the profiling state (callstacks, peak usage and live allocations) to
+    a file, so a restarted process can pick up where this one left off with
+    ``restore()``.
+    """
+    from ._tracer import check_if_fil_preloaded, preload
+
+    check_if_fil_preloaded()
+    if preload.fil_checkpoint(str(path).encode("utf-8")) != 0:
+        raise RuntimeError(f"Failed to write checkpoint to {path}")
+
+
+def restore(path: Union[str, Path], live_allocations: bool = False) -> None:
+    """
+    Replace the profiling state with a checkpoint written by ``checkpoint()``.
+    Later reports then include the peak from before the checkpoint.
+
+    Live allocations are only meaningful if the process memory survived too,
+    e.g. when the whole process was checkpointed and restored, so by default
+    only peak usage is restored.
+    """
+    from ._tracer import check_if_fil_preloaded, preload
+
+    check_if_fil_preloaded()
+    if preload.fil_restore(str(path).encode("utf-8"), int(live_allocations)) != 0:
+        raise RuntimeError(f"Failed to restore checkpoint from {path}")
+
+
+__all__ = [
+    "profile",
+    "start_recording",
+    "stop_recording",
+    "child_environment",
+    "watch",
+    "set_memory_limit",
+    "set_soft_memory_limit",
+    "dump_current_to_flamegraph",
+    "dump_on_exception",
+    "register_post_dump_callback",
+    "add_timeline_marker",
+    "set_label",
+    "labels",
+    "exclude_current_thread",
+    "register_current_thread",
+    "track_only_registered_threads",
+    "add_suballocation",
+    "last_error",
+    "free_suballocation",
+    "top_callstacks",
+    "snapshot",
+    "to_tracemalloc",
+    "from_tracemalloc",
+    "ProfileSnapshot",
+    "tracker_stats",
+    "checkpoint",
+    "restore",
+]
@@ -0,0 +1,75 @@
+"""Tests for converting between Fil and tracemalloc snapshots."""
+
+import tracemalloc
+
+from ..api import (
+    CallstackUsage,
+    Frame,
+    ProfileSnapshot,
+    from_tracemalloc,
+    to_tracemalloc,
+)
+
+
+def make_snapshot() -> ProfileSnapshot:
+    def usage(lines, current_bytes, peak_bytes):
+        return CallstackUsage(
+            frames=[
+                Frame(filename="a.py", line_number=line, function="f", module=None)
+                for line in lines
+            ],
+            peak_bytes=peak_bytes,
+            current_bytes=current_bytes,
+            peak_allocations=1,
+            allocations_at_peak=1,
+        )
+
+    return ProfileSnapshot(
+        callstacks=[usage([1, 2], 100, 300), usage([1, 5], 0, 50)],
+        peak_bytes=350,
+        current_bytes=100,
+        peak_allocations=2,
+        raw={},
+    )
+
+
+def test_to_tracemalloc():
+    """Each callstack becomes a trace, outermost frame first."""
+    snapshot = make_snapshot()
+    current = to_tracemalloc(snapshot)
+    assert [trace.size for trace in current.traces] == [100]
+    traceback = current.traces[0].traceback
+    assert [(frame.filename, frame.lineno) for frame in traceback] == [
+        ("a.py", 1),
+        ("a.py", 2),
+    ]
+    stats = to_tracemalloc(snapshot, peak=True).statistics("lineno")
+    assert sorted(stat.size for stat in stats) == [50, 300]
+
+
+def test_round_trip():
+    """Converting to tracemalloc and back keeps frames and sizes."""
+    snapshot = from_tracemalloc(to_tracemalloc(make_snapshot()))
+    assert snapshot.current_bytes == 100
+    assert [
+        (frame.filename, frame.line_number) for frame in snapshot.callstacks[0].frames
+    ] == [("a.py", 1), ("a.py", 2)]
+
+
+def test_from_real_tracemalloc():
+    """A real tracemalloc snapshot converts, combining identical tracebacks."""
+    tracemalloc.start(5)
+    try:
+        data = [bytearray(1000) for _ in range(3)]
+        snapshot = tracemalloc.take_snapshot()
+    finally:
+        tracemalloc.stop()
+    converted = from_tracemalloc(snapshot)
+    assert converted.current_bytes == sum(trace.size for trace in snapshot.traces)
+    ours = [
+        usage
+        for usage in converted.callstacks
+        if usage.frames[-1].filename == __file__ and usage.current_bytes >= 3000
+    ]
+    assert ours and ours[0].peak_allocations >= 3
+    del data
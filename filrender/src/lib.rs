@@ -0,0 +1,508 @@
+//! Render Fil's raw `.prof` files (folded callstacks, one `stack count` per
+//! line) into flamegraph SVGs.
+//!
+//! Since version 1 of the format, `.prof` files start with a header line like
+//! `# fil-prof version=3 units=bytes sampling_rate=1 timestamp=1700000000`.
+//! Files without a header are version 0; readers should refuse versions newer
+//! than they know, rather than misinterpret them. Since version 2, names in
+//! frames are escaped with escape_frame_text(). Since version 3, frames may
+//! be `file (function)` without a line number, if lines were merged.
+//!
+//! This has no dependencies on Python or the operating system, so it can be
+//! compiled to WebAssembly, letting a browser re-render a profile with
+//! different options without re-running the program:
+//!
+//! ```console
+//! $ cargo build --release --target wasm32-unknown-unknown -p filrender
+//! ```
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use inferno::flamegraph::{self, color::Palette};
+
+/// The `.prof` format version written by this version of Fil.
+pub const PROF_VERSION: u32 = 3;
+
+/// Characters with special meaning in the folded format, and their escapes.
+/// `%` comes first so that it's escaped before the others add more.
+const ESCAPES: &[(char, &str)] = &[('%', "%25"), (';', "%3B"), ('\n', "%0A"), ('\r', "%0D")];
+
+/// Escape text going into a frame: `;` separates frames and newlines
+/// separate callstacks, so they'd corrupt the output. They're
+/// percent-encoded, as is `%` itself, so the text can be recovered with
+/// unescape_frame_text().
+pub fn escape_frame_text(text: &str) -> Cow<'_, str> {
+    if !text.contains(|c| ESCAPES.iter().any(|(special, _)| c == *special)) {
+        return Cow::Borrowed(text);
+    }
+    let mut result = String::with_capacity(text.len() + 8);
+    for c in text.chars() {
+        match ESCAPES.iter().find(|(special, _)| c == *special) {
+            Some((_, escaped)) => result.push_str(escaped),
+            None => result.push(c),
+        }
+    }
+    Cow::Owned(result)
+}
+
+/// Undo escape_frame_text(). Other `%` sequences are left alone.
+pub fn unescape_frame_text(text: &str) -> Cow<'_, str> {
+    if !text.contains('%') {
+        return Cow::Borrowed(text);
+    }
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(index) = rest.find('%') {
+        result.push_str(&rest[..index]);
+        rest = &rest[index..];
+        match ESCAPES
+            .iter()
+            .find(|(_, escaped)| rest.starts_with(escaped))
+        {
+            Some((special, escaped)) => {
+                result.push(*special);
+                rest = &rest[escaped.len()..];
+            }
+            None => {
+                result.push('%');
+                rest = &rest[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    Cow::Owned(result)
+}
+
+const HEADER_PREFIX: &str = "# fil-prof";
+
+/// The header line of a `.prof` file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProfHeader {
+    pub version: u32,
+    /// What the counts are, e.g. "bytes" or "allocations".
+    pub units: String,
+    /// The fraction of allocations that were tracked. Fil tracks all of
+    /// them, so this is 1.
+    pub sampling_rate: f64,
+    /// When the file was written, in seconds since the UNIX epoch.
+    pub timestamp: Option<u64>,
+}
+
+impl ProfHeader {
+    /// A header for a file written now by this version of Fil.
+    pub fn new(units: &str, timestamp: u64) -> Self {
+        Self {
+            version: PROF_VERSION,
+            units: units.to_string(),
+            sampling_rate: 1.0,
+            timestamp: Some(timestamp),
+        }
+    }
+
+    /// Files from before the format was versioned.
+    fn unversioned() -> Self {
+        Self {
+            version: 0,
+            units: "bytes".to_string(),
+            sampling_rate: 1.0,
+            timestamp: None,
+        }
+    }
+
+    pub fn to_line(&self) -> String {
+        let mut line = format!(
+            "{} version={} units={} sampling_rate={}",
+            HEADER_PREFIX, self.version, self.units, self.sampling_rate
+        );
+        if let Some(timestamp) = self.timestamp {
+            line.push_str(&format!(" timestamp={}", timestamp));
+        }
+        line
+    }
+
+    /// Parse a header line. Unknown keys are ignored, so later versions can
+    /// add information that older readers don't need.
+    fn parse(line: &str) -> Result<Self, String> {
+        let mut header = Self::unversioned();
+        let mut version = None;
+        for field in line[HEADER_PREFIX.len()..].split_whitespace() {
+            let (key, value) = field
+                .split_once('=')
+                .ok_or_else(|| format!("invalid .prof header field: {}", field))?;
+            let invalid = || format!("invalid .prof header {}: {}", key, value);
+            match key {
+                "version" => version = Some(value.parse().map_err(|_| invalid())?),
+                "units" => header.units = value.to_string(),
+                "sampling_rate" => header.sampling_rate = value.parse().map_err(|_| invalid())?,
+                "timestamp" => header.timestamp = Some(value.parse().map_err(|_| invalid())?),
+                _ => {}
+            }
+        }
+        header.version = version.ok_or(".prof header is missing the version")?;
+        if header.version > PROF_VERSION {
+            return Err(format!(
+                ".prof format version {} is newer than the newest supported version, {}",
+                header.version, PROF_VERSION
+            ));
+        }
+        Ok(header)
+    }
+}
+
+/// Split the contents of a `.prof` file into its header and the folded
+/// lines.
+pub fn parse_prof(prof: &str) -> Result<(ProfHeader, impl Iterator<Item = &str>), String> {
+    let mut lines = prof.lines().peekable();
+    let header = match lines.peek() {
+        Some(line) if line.starts_with(HEADER_PREFIX) => {
+            let header = ProfHeader::parse(line)?;
+            lines.next();
+            header
+        }
+        _ => ProfHeader::unversioned(),
+    };
+    Ok((header, lines))
+}
+
+/// How to render a flamegraph.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RenderOptions {
+    pub title: String,
+    /// Shown under the title; may contain HTML.
+    pub subtitle: Option<String>,
+    /// What the counts are, e.g. "bytes".
+    pub count_name: String,
+    /// Put the innermost frames at the top.
+    pub reversed: bool,
+    /// Only keep callstacks containing this text, if set.
+    pub include: Option<String>,
+    /// Drop callstacks containing this text, if set.
+    pub exclude: Option<String>,
+    /// An inferno color palette name, e.g. "mem" or "blue". The default is
+    /// the usual flamegraph colors.
+    pub palette: Option<String>,
+    /// Frames narrower than this percentage of the total width are left
+    /// out. The default is inferno's, 0.01%.
+    pub min_width: Option<f64>,
+    /// Ignore line numbers, merging frames of the same function, for a
+    /// function-level rather than line-level view.
+    pub merge_lines: bool,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            title: "Peak Tracked Memory Usage".to_string(),
+            subtitle: None,
+            count_name: "bytes".to_string(),
+            reversed: false,
+            include: None,
+            exclude: None,
+            palette: None,
+            min_width: None,
+            merge_lines: false,
+        }
+    }
+}
+
+/// The inferno options Fil uses for all its flamegraphs.
+pub fn flamegraph_options(options: &RenderOptions) -> Result<flamegraph::Options<'static>, String> {
+    let mut result = flamegraph::Options::default();
+    result.title = format!(
+        "{}{}",
+        options.title,
+        if options.reversed { ", Reversed" } else { "" }
+    );
+    result.subtitle = options.subtitle.clone();
+    result.count_name = options.count_name.clone();
+    result.font_size = 16;
+    result.font_type = "monospace".to_string();
+    result.frame_height = 22;
+    result.reverse_stack_order = options.reversed;
+    result.color_diffusion = true;
+    result.direction = flamegraph::Direction::Inverted;
+    // Maybe disable this some day; but for now it makes debugging much
+    // easier:
+    result.pretty_xml = true;
+    if let Some(palette) = &options.palette {
+        result.colors = Palette::from_str(palette)?;
+    }
+    if let Some(min_width) = options.min_width {
+        result.min_width = min_width;
+    }
+    Ok(result)
+}
+
+/// Whether a line of folded input passes the include/exclude filters.
+fn is_included(line: &str, options: &RenderOptions) -> bool {
+    // The count is at the end, after the last space:
+    let stack = line.rsplit_once(' ').map_or(line, |(stack, _)| stack);
+    if let Some(include) = &options.include {
+        if !stack.contains(include.as_str()) {
+            return false;
+        }
+    }
+    if let Some(exclude) = &options.exclude {
+        if stack.contains(exclude.as_str()) {
+            return false;
+        }
+    }
+    true
+}
+
+/// A frame without its line number: `file:12 (function)` becomes
+/// `file (function)`. Frames without one are returned as they are.
+fn without_line_number(frame: &str) -> Cow<'_, str> {
+    for (index, _) in frame.match_indices(" (") {
+        if let Some((filename, line_number)) = frame[..index].rsplit_once(':') {
+            if !line_number.is_empty() && line_number.bytes().all(|b| b.is_ascii_digit()) {
+                return Cow::Owned(format!("{}{}", filename, &frame[index..]));
+            }
+        }
+    }
+    Cow::Borrowed(frame)
+}
+
+/// Drop the line numbers from folded lines, adding up the counts of
+/// callstacks that become the same. Lines without a count are kept as they
+/// are.
+fn merge_line_numbers<'a>(lines: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    let mut counts: BTreeMap<String, u64> = BTreeMap::new();
+    let mut unparsed = vec![];
+    for line in lines {
+        match line
+            .rsplit_once(' ')
+            .and_then(|(stack, count)| Some((stack, count.parse::<u64>().ok()?)))
+        {
+            Some((stack, count)) => {
+                let stack = stack
+                    .split(';')
+                    .map(without_line_number)
+                    .collect::<Vec<_>>()
+                    .join(";");
+                *counts.entry(stack).or_insert(0) += count;
+            }
+            None => unparsed.push(line.to_string()),
+        }
+    }
+    counts
+        .into_iter()
+        .map(|(stack, count)| format!("{} {}", stack, count))
+        .chain(unparsed)
+        .collect()
+}
+
+/// Render folded lines into an SVG.
+pub fn render_lines<'a, I: IntoIterator<Item = &'a str>>(
+    lines: I,
+    options: &RenderOptions,
+) -> Result<Vec<u8>, String> {
+    let mut inferno_options = flamegraph_options(options)?;
+    let lines: Vec<&str> = lines
+        .into_iter()
+        .filter(|line| !line.trim().is_empty() && is_included(line, options))
+        .collect();
+    let mut output = vec![];
+    let result = if options.merge_lines {
+        let merged = merge_line_numbers(lines);
+        flamegraph::from_lines(
+            &mut inferno_options,
+            merged.iter().map(String::as_str),
+            &mut output,
+        )
+    } else {
+        flamegraph::from_lines(&mut inferno_options, lines, &mut output)
+    };
+    result.map_err(|e| format!("{}", e))?;
+    Ok(output)
+}
+
+/// Render the contents of a `.prof` file into an SVG.
+pub fn render_prof(prof: &str, options: &RenderOptions) -> Result<String, String> {
+    let (_, lines) = parse_prof(prof)?;
+    let svg = render_lines(lines, options)?;
+    String::from_utf8(svg).map_err(|e| format!("{}", e))
+}
+
+#[cfg(target_arch = "wasm32")]
+mod wasm {
+    use super::{render_prof, RenderOptions};
+    use wasm_bindgen::prelude::*;
+
+    fn non_empty(value: String) -> Option<String> {
+        if value.is_empty() {
+            None
+        } else {
+            Some(value)
+        }
+    }
+
+    /// Render a `.prof` file's contents into an SVG string. Empty strings
+    /// mean "not set" for the optional arguments.
+    #[wasm_bindgen(js_name = renderProf)]
+    pub fn render_prof_js(
+        prof: &str,
+        title: String,
+        count_name: String,
+        reversed: bool,
+        include: String,
+        exclude: String,
+        palette: String,
+        merge_lines: bool,
+    ) -> Result<String, JsValue> {
+        let options = RenderOptions {
+            title,
+            subtitle: None,
+            count_name,
+            reversed,
+            include: non_empty(include),
+            exclude: non_empty(exclude),
+            palette: non_empty(palette),
+            min_width: None,
+            merge_lines,
+        };
+        render_prof(prof, &options).map_err(|e| JsValue::from_str(&e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        escape_frame_text, is_included, merge_line_numbers, parse_prof, render_prof,
+        unescape_frame_text, ProfHeader, RenderOptions, PROF_VERSION,
+    };
+
+    const PROF: &str = "a.py:1 (main);b.py:2 (load) 1000\na.py:1 (main);c.py:3 (parse) 500\n";
+
+    #[test]
+    fn filters_match_stacks_not_counts() {
+        let options = RenderOptions {
+            include: Some("load".to_string()),
+            ..RenderOptions::default()
+        };
+        assert!(is_included("a.py:1 (main);b.py:2 (load) 1000", &options));
+        assert!(!is_included("a.py:1 (main);c.py:3 (parse) 500", &options));
+        let options = RenderOptions {
+            exclude: Some("100".to_string()),
+            ..RenderOptions::default()
+        };
+        assert!(is_included("a.py:1 (main);b.py:2 (load) 1000", &options));
+    }
+
+    #[test]
+    fn renders_svg() {
+        let svg = render_prof(PROF, &RenderOptions::default()).unwrap();
+        assert!(svg.contains("<svg"));
+        assert!(svg.contains("load"));
+        assert!(svg.contains("parse"));
+
+        let svg = render_prof(
+            PROF,
+            &RenderOptions {
+                exclude: Some("parse".to_string()),
+                reversed: true,
+                palette: Some("blue".to_string()),
+                ..RenderOptions::default()
+            },
+        )
+        .unwrap();
+        assert!(svg.contains("Reversed"));
+        assert!(svg.contains("load"));
+        assert!(!svg.contains("c.py"));
+
+        // parse() is a third of the width:
+        let svg = render_prof(
+            PROF,
+            &RenderOptions {
+                min_width: Some(50.0),
+                ..RenderOptions::default()
+            },
+        )
+        .unwrap();
+        assert!(svg.contains("b.py"));
+        assert!(!svg.contains("c.py"));
+    }
+
+    #[test]
+    fn line_numbers_merge() {
+        let merged = merge_line_numbers(vec![
+            "a.py:1 (main);b.py:2 (load) 1000",
+            "a.py:9 (main);b.py:5 (load) 24",
+            "a.py:1 (main);My Files (x):3.py:3 (f (y)) 1",
+            "a.py (main) 2",
+            "garbage",
+        ]);
+        assert_eq!(
+            merged,
+            vec![
+                "a.py (main) 2",
+                "a.py (main);My Files (x):3.py (f (y)) 1",
+                "a.py (main);b.py (load) 1024",
+                "garbage",
+            ]
+        );
+        let svg = render_prof(
+            PROF,
+            &RenderOptions {
+                merge_lines: true,
+                ..RenderOptions::default()
+            },
+        )
+        .unwrap();
+        assert!(svg.contains("b.py (load)"));
+        assert!(!svg.contains("b.py:2"));
+    }
+
+    #[test]
+    fn headers_are_versioned() {
+        let header = ProfHeader::new("allocations", 1700000000);
+        let prof = format!("{}\n{}", header.to_line(), PROF);
+        let (parsed, lines) = parse_prof(&prof).unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(lines.count(), 2);
+        assert!(render_prof(&prof, &RenderOptions::default())
+            .unwrap()
+            .contains("load"));
+
+        // No header means version 0:
+        let (parsed, lines) = parse_prof(PROF).unwrap();
+        assert_eq!(parsed.version, 0);
+        assert_eq!(parsed.units, "bytes");
+        assert_eq!(lines.count(), 2);
+
+        // Newer versions are refused, unknown fields aren't:
+        let newer = format!("# fil-prof version={}\n{}", PROF_VERSION + 1, PROF);
+        assert!(parse_prof(&newer).is_err());
+        let (parsed, _) = parse_prof("# fil-prof version=1 units=bytes color=red\n").unwrap();
+        assert_eq!(parsed.version, 1);
+        assert!(parse_prof("# fil-prof units=bytes\n").is_err());
+    }
+
+    #[test]
+    fn escaping_roundtrips() {
+        for text in [
+            "<listcomp>",
+            "a;b",
+            "100%3B",
+            "line\nbreak\r",
+            "%",
+            "caf\u{e9};%",
+        ] {
+            let escaped = escape_frame_text(text);
+            assert!(!escaped.contains(';') && !escaped.contains('\n'));
+            assert_eq!(unescape_frame_text(&escaped), text);
+        }
+        assert_eq!(escape_frame_text("a;b"), "a%3Bb");
+        assert_eq!(unescape_frame_text("50%off"), "50%off");
+    }
+
+    #[test]
+    fn bad_palette_is_an_error() {
+        let options = RenderOptions {
+            palette: Some("plaid".to_string()),
+            ..RenderOptions::default()
+        };
+        assert!(render_prof(PROF, &options).is_err());
+    }
+}
@@ -0,0 +1,83 @@
+// Compare data structures for the live-allocation map, under the churn a
+// tracker sees: lots of inserts and removes of recent addresses, with an
+// occasional copy of the whole map (what a per-address peak would need).
+//
+// Run with: cargo run --release --example allocation_map_bench
+use ahash::RandomState as ARandomState;
+use std::collections::HashMap;
+use std::time::Instant;
+
+const LIVE: usize = 1_000_000;
+const CHURN: usize = 5_000_000;
+const SNAPSHOT_EVERY: usize = 500_000;
+
+trait AllocationMap: Clone {
+    fn insert(&mut self, address: usize, callstack_id: u32);
+    fn remove(&mut self, address: usize) -> Option<u32>;
+    fn len(&self) -> usize;
+}
+
+impl AllocationMap for HashMap<usize, u32, ARandomState> {
+    fn insert(&mut self, address: usize, callstack_id: u32) {
+        HashMap::insert(self, address, callstack_id);
+    }
+
+    fn remove(&mut self, address: usize) -> Option<u32> {
+        HashMap::remove(self, &address)
+    }
+
+    fn len(&self) -> usize {
+        HashMap::len(self)
+    }
+}
+
+impl AllocationMap for im::HashMap<usize, u32, ARandomState> {
+    fn insert(&mut self, address: usize, callstack_id: u32) {
+        im::HashMap::insert(self, address, callstack_id);
+    }
+
+    fn remove(&mut self, address: usize) -> Option<u32> {
+        im::HashMap::remove(self, &address)
+    }
+
+    fn len(&self) -> usize {
+        im::HashMap::len(self)
+    }
+}
+
+/// Returns (seconds for the churn, seconds for the snapshots).
+fn run<M: AllocationMap>(mut map: M) -> (f64, f64) {
+    // Addresses are 16-byte aligned, like malloc()'s:
+    for i in 0..LIVE {
+        map.insert(i * 16, (i % 1000) as u32);
+    }
+    let mut snapshots = vec![];
+    let mut snapshot_time = 0.0;
+    let start = Instant::now();
+    for i in 0..CHURN {
+        // Free an old allocation, allocate a new one:
+        map.remove(i * 16);
+        map.insert((LIVE + i) * 16, (i % 1000) as u32);
+        if i % SNAPSHOT_EVERY == 0 {
+            let snapshot_start = Instant::now();
+            snapshots.push(map.clone());
+            snapshot_time += snapshot_start.elapsed().as_secs_f64();
+        }
+    }
+    let total = start.elapsed().as_secs_f64();
+    assert_eq!(map.len(), LIVE);
+    (total - snapshot_time, snapshot_time)
+}
+
+fn main() {
+    let (churn, snapshots) = run(HashMap::<usize, u32, ARandomState>::default());
+    println!(
+        "std HashMap: {:.2}s churn, {:.2}s snapshots",
+        churn, snapshots
+    );
+    let (churn, snapshots) = run(im::HashMap::<usize, u32, ARandomState>::default());
+    println!(
+        "im::HashMap: {:.2}s churn, {:.2}s snapshots",
+        churn, snapshots
+    );
+}
@@ -1,14 +1,19 @@
-/*
-use pymemprofile_api::oom::get_cgroup_available_memory;
+use pymemprofile_api::cgroup::CgroupMemory;
 use std::time::Instant;
-*/
+
 fn main() {
-    /*
+    let cgroup = match CgroupMemory::find() {
+        Some(cgroup) => cgroup,
+        None => {
+            println!("No memory cgroup found.");
+            return;
+        }
+    };
+    println!("{:?}: {:?}", cgroup.version(), cgroup.usages());
     let now = Instant::now();
     for _ in 1..1_000 {
-        get_cgroup_available_memory();
+        cgroup.available();
     }
     let elapsed_secs = (now.elapsed().as_millis() as f64) / 1000.0;
     println!("Calls/sec: {}", 1000.0 * (1.0 / elapsed_secs));
-    */
 }
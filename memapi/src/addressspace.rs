@@ -0,0 +1,196 @@
+// Virtual address space in use, tracked separately from memory usage. Running
+// out of address space is a different failure than running out of memory,
+// e.g. in 32-bit processes, or under `ulimit -v`, where large mmap()s that are
+// barely touched can fail long before physical memory is an issue. The
+// kernel maps whole pages, so mappings count rounded up to page boundaries,
+// plus the bytes of live heap allocations. The peak is tracked on its own,
+// since it needn't happen at the same moment as the memory usage peak.
+use std::collections::BTreeMap;
+
+use serde_json::{json, Value};
+
+use crate::memorytracking::ProcessUid;
+use crate::rangemap::RangeMap;
+use crate::snapshot::get_usize;
+
+lazy_static! {
+    pub(crate) static ref PAGE_SIZE: usize = match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as usize,
+        _ => 4096,
+    };
+}
+
+/// The range of whole pages covering a range, as (start, length).
+pub(crate) fn page_range(address: usize, size: usize, page_size: usize) -> (usize, usize) {
+    let start = address / page_size * page_size;
+    let end = address.saturating_add(size).saturating_add(page_size - 1) / page_size * page_size;
+    (start, end - start)
+}
+
+/// Address space totals as of a snapshot.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AddressSpaceStats {
+    pub current_bytes: usize,
+    pub peak_bytes: usize,
+}
+
+impl AddressSpaceStats {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "current_bytes": self.current_bytes,
+            "peak_bytes": self.peak_bytes,
+        })
+    }
+
+    pub fn from_json(value: &Value) -> Result<Self, String> {
+        Ok(Self {
+            current_bytes: get_usize(value, "current_bytes")?,
+            peak_bytes: get_usize(value, "peak_bytes")?,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AddressSpace {
+    page_size: usize,
+    // Page-aligned mapped ranges, per process:
+    mappings: BTreeMap<ProcessUid, RangeMap<()>>,
+    mapped_bytes: usize,
+    heap_bytes: usize,
+    peak_bytes: usize,
+}
+
+impl Default for AddressSpace {
+    fn default() -> Self {
+        Self::with_page_size(*PAGE_SIZE)
+    }
+}
+
+impl AddressSpace {
+    fn with_page_size(page_size: usize) -> Self {
+        Self {
+            page_size,
+            mappings: BTreeMap::new(),
+            mapped_bytes: 0,
+            heap_bytes: 0,
+            peak_bytes: 0,
+        }
+    }
+
+    pub fn current_bytes(&self) -> usize {
+        self.mapped_bytes + self.heap_bytes
+    }
+
+    pub fn stats(&self) -> AddressSpaceStats {
+        AddressSpaceStats {
+            current_bytes: self.current_bytes(),
+            peak_bytes: self.peak_bytes,
+        }
+    }
+
+    /// Roughly how much memory this uses.
+    pub fn memory_usage(&self) -> usize {
+        self.mappings
+            .values()
+            .map(|mappings| mappings.memory_usage())
+            .sum()
+    }
+
+    fn update_peak(&mut self) {
+        self.peak_bytes = self.peak_bytes.max(self.current_bytes());
+    }
+
+    pub fn add_heap(&mut self, bytes: usize) {
+        self.heap_bytes = self.heap_bytes.saturating_add(bytes);
+        self.update_peak();
+    }
+
+    pub fn remove_heap(&mut self, bytes: usize) {
+        self.heap_bytes = self.heap_bytes.saturating_sub(bytes);
+    }
+
+    /// An mmap(), anonymous or file-backed.
+    pub fn add_mapping(&mut self, process: ProcessUid, address: usize, size: usize) {
+        if size == 0 {
+            return;
+        }
+        let (start, length) = page_range(address, size, self.page_size);
+        // Mapping over an existing mapping replaces it:
+        let mappings = self.mappings.entry(process).or_default();
+        let replaced: usize = mappings
+            .remove(start, length)
+            .iter()
+            .map(|(_, size)| size)
+            .sum();
+        mappings.add(start, length, ());
+        self.mapped_bytes = self.mapped_bytes.saturating_sub(replaced) + length;
+        self.update_peak();
+    }
+
+    /// A munmap(), which like the kernel unmaps every page the range touches.
+    pub fn remove_mapping(&mut self, process: ProcessUid, address: usize, size: usize) {
+        if size == 0 {
+            return;
+        }
+        let (start, length) = page_range(address, size, self.page_size);
+        let removed: usize = self
+            .mappings
+            .entry(process)
+            .or_default()
+            .remove(start, length)
+            .iter()
+            .map(|(_, size)| size)
+            .sum();
+        self.mapped_bytes = self.mapped_bytes.saturating_sub(removed);
+    }
+
+    /// Forget a process' mappings; its heap allocations are removed with
+    /// remove_heap().
+    pub fn drop_process(&mut self, process: ProcessUid) {
+        if let Some(mappings) = self.mappings.remove(&process) {
+            self.mapped_bytes = self.mapped_bytes.saturating_sub(mappings.size());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{page_range, AddressSpace, AddressSpaceStats};
+    use crate::memorytracking::PARENT_PROCESS;
+
+    #[test]
+    fn mappings_are_rounded_to_pages() {
+        assert_eq!(page_range(0, 1, 4096), (0, 4096));
+        assert_eq!(page_range(4096, 4096, 4096), (4096, 4096));
+        assert_eq!(page_range(4000, 200, 4096), (0, 8192));
+        let mut space = AddressSpace::with_page_size(4096);
+        space.add_mapping(PARENT_PROCESS, 0x10000, 100);
+        assert_eq!(space.current_bytes(), 4096);
+        // Mapping over the same pages doesn't count them twice:
+        space.add_mapping(PARENT_PROCESS, 0x10000, 8192);
+        assert_eq!(space.current_bytes(), 8192);
+        // Unmapping part of a page unmaps the whole page:
+        space.remove_mapping(PARENT_PROCESS, 0x11000, 1);
+        assert_eq!(space.current_bytes(), 4096);
+        space.drop_process(PARENT_PROCESS);
+        assert_eq!(space.current_bytes(), 0);
+    }
+
+    #[test]
+    fn peak_is_separate_from_current() {
+        let mut space = AddressSpace::with_page_size(4096);
+        space.add_heap(1000);
+        space.add_mapping(PARENT_PROCESS, 0x10000, 40960);
+        space.remove_mapping(PARENT_PROCESS, 0x10000, 40960);
+        space.add_heap(2000);
+        assert_eq!(
+            space.stats(),
+            AddressSpaceStats {
+                current_bytes: 3000,
+                peak_bytes: 41960,
+            }
+        );
+        space.remove_heap(3000);
+        assert_eq!(space.stats().current_bytes, 0);
+    }
+}
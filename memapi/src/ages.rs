@@ -0,0 +1,163 @@
+// How old the memory at peak is. Memory allocated just before the peak is
+// usually working data; memory that was allocated long before and is still
+// around is more likely a cache or a leak. Live allocations are bucketed by
+// age, per callstack, both at the peak and when the report is written. It
+// costs a timestamp per live allocation, so it's opt-in.
+//
+// Ages at peak are measured relative to when the peak was noticed, i.e. the
+// first free() after it, which is close enough for buckets this coarse.
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::hashing::HashState;
+
+use crate::memorytracking::CallstackId;
+use crate::util::{hashmap_bytes, new_hashmap};
+
+/// Labels of the age buckets, and their upper bounds in seconds.
+pub const AGE_BUCKETS: [(&str, f64); 5] = [
+    ("<1s", 1.0),
+    ("1-10s", 10.0),
+    ("10-60s", 60.0),
+    ("1-10min", 600.0),
+    (">10min", f64::INFINITY),
+];
+
+/// Bytes in each of AGE_BUCKETS.
+pub type AgeHistogram = [usize; AGE_BUCKETS.len()];
+
+/// Which of AGE_BUCKETS an age falls into.
+pub fn bucket(age: Duration) -> usize {
+    let seconds = age.as_secs_f64();
+    AGE_BUCKETS
+        .iter()
+        .position(|(_, upper)| seconds < *upper)
+        .unwrap_or(AGE_BUCKETS.len() - 1)
+}
+
+#[derive(Clone, Debug)]
+pub struct AllocationAges {
+    allocated_at: HashMap<usize, Instant, HashState>,
+    // When the current peak was noticed.
+    peak_time: Option<Instant>,
+    // Allocations that were live at the peak but have since been freed,
+    // bucketed by their age at the peak:
+    freed_since_peak: HashMap<CallstackId, AgeHistogram, HashState>,
+}
+
+impl Default for AllocationAges {
+    fn default() -> Self {
+        Self {
+            allocated_at: new_hashmap(),
+            peak_time: None,
+            freed_since_peak: new_hashmap(),
+        }
+    }
+}
+
+impl AllocationAges {
+    /// Enabled by setting FIL_ALLOCATION_AGES=1.
+    pub fn from_env() -> Option<Self> {
+        if std::env::var("FIL_ALLOCATION_AGES").as_deref() == Ok("1") {
+            Some(Self::default())
+        } else {
+            None
+        }
+    }
+
+    /// Same settings, no data.
+    pub fn cleared(&self) -> Self {
+        Self::default()
+    }
+
+    /// Note when an allocation happened.
+    pub fn allocated(&mut self, address: usize) {
+        self.allocated_at.insert(address, Instant::now());
+    }
+
+    /// A new peak was reached; everything live now is what's live at the
+    /// peak.
+    pub fn new_peak(&mut self) {
+        self.peak_time = Some(Instant::now());
+        self.freed_since_peak.clear();
+    }
+
+    /// Record a free of a tracked allocation.
+    pub fn freed(&mut self, address: usize, size: usize, callstack_id: CallstackId) {
+        let allocated_at = match self.allocated_at.remove(&address) {
+            Some(allocated_at) => allocated_at,
+            None => return,
+        };
+        if let Some(peak_time) = self.peak_time {
+            if allocated_at <= peak_time {
+                let histogram = self.freed_since_peak.entry(callstack_id).or_default();
+                histogram[bucket(peak_time - allocated_at)] += size;
+            }
+        }
+    }
+
+    /// Per-callstack histograms of (at peak, now), given the live
+    /// allocations as (address, size, callstack).
+    pub fn histograms(
+        &self,
+        live: impl Iterator<Item = (usize, usize, CallstackId)>,
+    ) -> HashMap<CallstackId, (AgeHistogram, AgeHistogram), HashState> {
+        let now = Instant::now();
+        let mut result: HashMap<CallstackId, (AgeHistogram, AgeHistogram), HashState> =
+            new_hashmap();
+        for (callstack_id, freed) in self.freed_since_peak.iter() {
+            result.entry(*callstack_id).or_default().0 = *freed;
+        }
+        for (address, size, callstack_id) in live {
+            let allocated_at = match self.allocated_at.get(&address) {
+                Some(allocated_at) => *allocated_at,
+                None => continue,
+            };
+            let (peak, current) = result.entry(callstack_id).or_default();
+            current[bucket(now.saturating_duration_since(allocated_at))] += size;
+            if let Some(peak_time) = self.peak_time {
+                if allocated_at <= peak_time {
+                    peak[bucket(peak_time - allocated_at)] += size;
+                }
+            }
+        }
+        result
+    }
+
+    /// Roughly how much memory this uses.
+    pub fn memory_usage(&self) -> usize {
+        hashmap_bytes(&self.allocated_at) + hashmap_bytes(&self.freed_since_peak)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bucket, AllocationAges};
+    use std::time::Duration;
+
+    #[test]
+    fn buckets() {
+        assert_eq!(bucket(Duration::from_millis(10)), 0);
+        assert_eq!(bucket(Duration::from_secs(1)), 1);
+        assert_eq!(bucket(Duration::from_secs(59)), 2);
+        assert_eq!(bucket(Duration::from_secs(60)), 3);
+        assert_eq!(bucket(Duration::from_secs(100_000)), 4);
+    }
+
+    #[test]
+    fn live_at_peak() {
+        let mut ages = AllocationAges::default();
+        ages.allocated(1);
+        ages.allocated(2);
+        ages.new_peak();
+        // Freed after the peak, so it still counts at the peak:
+        ages.freed(1, 100, 7);
+        // Allocated after the peak, so it doesn't:
+        ages.allocated(3);
+        let histograms = ages.histograms(vec![(2, 20, 7), (3, 5, 8)].into_iter());
+        assert_eq!(histograms[&7].0[0], 120);
+        assert_eq!(histograms[&7].1[0], 20);
+        assert_eq!(histograms[&8].0, [0; 5]);
+        assert_eq!(histograms[&8].1[0], 5);
+    }
+}
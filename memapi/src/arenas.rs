@@ -0,0 +1,191 @@
+// Logical sub-allocations inside arenas.
+//
+// Libraries like pyarrow, or numpy's caching allocators, malloc() or mmap() a
+// big arena and then hand out pieces of it themselves. Fil only sees the
+// arena, so all the memory gets attributed to whichever callstack happened to
+// create it. Such libraries can instead report the pieces they hand out: the
+// piece's bytes are then moved from the arena's callstack to the callstack
+// that asked for the piece, and moved back when the piece is returned, or
+// when the arena itself goes away. Totals don't change, only who gets
+// blamed.
+use std::collections::BTreeMap;
+
+use crate::memorytracking::CallstackId;
+
+/// An arena with at least one piece handed out.
+#[derive(Clone, Debug, PartialEq)]
+struct Arena {
+    end: usize,
+    callstack_id: CallstackId,
+    // Bytes currently handed out, never more than the arena's size:
+    lent: usize,
+}
+
+/// A piece of an arena, as reported by the library that owns the arena.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SubAllocation {
+    pub arena: usize,
+    pub arena_callstack_id: CallstackId,
+    pub callstack_id: CallstackId,
+    pub size: usize,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Arenas {
+    // Arena start address -> arena:
+    arenas: BTreeMap<usize, Arena>,
+    // Sub-allocation address -> sub-allocation:
+    suballocations: BTreeMap<usize, SubAllocation>,
+}
+
+impl Arenas {
+    pub fn is_empty(&self) -> bool {
+        self.suballocations.is_empty()
+    }
+
+    /// Record a piece of the arena at `arena` handed out at `address`.
+    /// Returns false, recording nothing, if the piece doesn't fit in what's
+    /// left of the arena, or that address was already handed out.
+    pub fn lend(
+        &mut self,
+        (arena, arena_size, arena_callstack_id): (usize, usize, CallstackId),
+        address: usize,
+        size: usize,
+        callstack_id: CallstackId,
+    ) -> bool {
+        let arena_end = arena.saturating_add(arena_size);
+        if size == 0
+            || address < arena
+            || address.saturating_add(size) > arena_end
+            || self.suballocations.contains_key(&address)
+        {
+            return false;
+        }
+        let entry = self.arenas.entry(arena).or_insert(Arena {
+            end: arena_end,
+            callstack_id: arena_callstack_id,
+            lent: 0,
+        });
+        if entry.lent + size > arena_size {
+            if entry.lent == 0 {
+                self.arenas.remove(&arena);
+            }
+            return false;
+        }
+        entry.lent += size;
+        self.suballocations.insert(
+            address,
+            SubAllocation {
+                arena,
+                arena_callstack_id,
+                callstack_id,
+                size,
+            },
+        );
+        true
+    }
+
+    /// A piece was handed back to its arena.
+    pub fn give_back(&mut self, address: usize) -> Option<SubAllocation> {
+        let suballocation = self.suballocations.remove(&address)?;
+        if let Some(arena) = self.arenas.get_mut(&suballocation.arena) {
+            arena.lent -= suballocation.size;
+            if arena.lent == 0 {
+                self.arenas.remove(&suballocation.arena);
+            }
+        }
+        Some(suballocation)
+    }
+
+    /// The arena starting at `arena` is going away, so all its pieces go
+    /// back to it.
+    pub fn release_arena(&mut self, arena: usize) -> Vec<SubAllocation> {
+        match self.arenas.remove(&arena) {
+            Some(removed) => self.take_pieces(arena, removed.end),
+            None => vec![],
+        }
+    }
+
+    /// Memory in [start, end) is going away, e.g. munmap(), so the pieces of
+    /// any arena overlapping it go back to their arena.
+    pub fn release_range(&mut self, start: usize, end: usize) -> Vec<SubAllocation> {
+        let overlapping: Vec<(usize, usize)> = self
+            .arenas
+            .range(..end)
+            .filter(|(_, arena)| arena.end > start)
+            .map(|(arena_start, arena)| (*arena_start, arena.end))
+            .collect();
+        let mut released = vec![];
+        for (arena_start, arena_end) in overlapping {
+            self.arenas.remove(&arena_start);
+            released.extend(self.take_pieces(arena_start, arena_end));
+        }
+        released
+    }
+
+    /// Remove everything, returning the pieces that were handed out.
+    pub fn drain(&mut self) -> Vec<SubAllocation> {
+        self.arenas.clear();
+        std::mem::take(&mut self.suballocations)
+            .into_values()
+            .collect()
+    }
+
+    fn take_pieces(&mut self, arena: usize, end: usize) -> Vec<SubAllocation> {
+        let addresses: Vec<usize> = self
+            .suballocations
+            .range(arena..end)
+            .filter(|(_, suballocation)| suballocation.arena == arena)
+            .map(|(address, _)| *address)
+            .collect();
+        addresses
+            .iter()
+            .filter_map(|address| self.suballocations.remove(address))
+            .collect()
+    }
+
+    /// Roughly how much memory this uses.
+    pub fn memory_usage(&self) -> usize {
+        // B-tree nodes are partially empty, hence the 2x:
+        2 * (self.arenas.len() * std::mem::size_of::<(usize, Arena)>()
+            + self.suballocations.len() * std::mem::size_of::<(usize, SubAllocation)>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Arenas;
+
+    #[test]
+    fn pieces_fit_in_their_arena() {
+        let mut arenas = Arenas::default();
+        let arena = (1000, 100, 1);
+        // Outside the arena, or too big:
+        assert!(!arenas.lend(arena, 990, 10, 2));
+        assert!(!arenas.lend(arena, 1050, 60, 2));
+        assert!(arenas.is_empty());
+        assert!(arenas.lend(arena, 1000, 60, 2));
+        // Already handed out:
+        assert!(!arenas.lend(arena, 1000, 10, 3));
+        assert!(arenas.lend(arena, 1060, 40, 3));
+        assert_eq!(arenas.give_back(1000).unwrap().size, 60);
+        assert_eq!(arenas.give_back(1000), None);
+        assert!(arenas.lend(arena, 1000, 20, 4));
+
+        // Another arena right after it isn't affected by releasing the
+        // first:
+        assert!(arenas.lend((1100, 50, 5), 1100, 50, 6));
+        let mut released = arenas.release_arena(1000);
+        released.sort_by_key(|suballocation| suballocation.callstack_id);
+        assert_eq!(
+            released
+                .iter()
+                .map(|suballocation| (suballocation.callstack_id, suballocation.size))
+                .collect::<Vec<_>>(),
+            vec![(3, 40), (4, 20)]
+        );
+        assert!(arenas.release_range(900, 1100).is_empty());
+        assert_eq!(arenas.release_range(1120, 1130).len(), 1);
+        assert!(arenas.is_empty());
+    }
+}
@@ -0,0 +1,212 @@
+// Memory allocated before tracking started: by the interpreter starting up,
+// by imports done before the profiled code ran, or by anything that ran
+// before Fil was loaded. None of it went through the hooks, so normally it's
+// silently missing from reports. When enabled, the anonymous memory mapped at
+// the time tracking starts is recorded as if it were mmap()ed then, under a
+// synthetic "pre-profiling" root, so reports account for it.
+//
+// This is approximate: it's what's resident in anonymous, writable mappings,
+// which includes Fil's own memory, and heap memory that is freed and reused
+// later gets counted again when it's reallocated.
+use crate::memorytracking::{
+    AllocationTracker, CallSiteId, Callstack, VecFunctionLocations, PARENT_PROCESS,
+};
+
+/// Filename of the synthetic frames of pre-existing memory.
+pub const PRE_PROFILING_FILENAME: &str = "<pre-profiling>";
+
+/// Function name of the root frame.
+pub const PRE_PROFILING_ROOT: &str = "pre-profiling";
+
+/// Kernel-provided mappings that aren't the program's memory.
+const SPECIAL_MAPPINGS: [&str; 4] = ["[stack", "[vvar", "[vdso", "[vsyscall"];
+
+/// Enabled by setting FIL_STARTUP_BACKFILL=1.
+pub fn enabled_from_env() -> bool {
+    std::env::var("FIL_STARTUP_BACKFILL")
+        .map(|v| v == "1")
+        .unwrap_or(false)
+}
+
+/// An anonymous mapping that existed before tracking started.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExistingMapping {
+    pub address: usize,
+    /// Resident bytes if known, otherwise the size of the mapping.
+    pub size: usize,
+    /// "[heap]", "[anon:<name>]", or "[anonymous]".
+    pub name: String,
+}
+
+/// Parse the contents of /proc/self/smaps, or of /proc/self/maps, which has
+/// the same header lines but no resident sizes.
+pub fn parse_smaps(contents: &str) -> Vec<ExistingMapping> {
+    let mut result: Vec<ExistingMapping> = vec![];
+    // Whether the last header was for a mapping we're keeping, so its Rss:
+    // line applies to the last item in result:
+    let mut keeping = false;
+    for line in contents.lines() {
+        if let Some(rss) = line.strip_prefix("Rss:") {
+            if let (true, Some(kb)) = (keeping, parse_kb(rss)) {
+                result.last_mut().unwrap().size = kb * 1024;
+            }
+            continue;
+        }
+        let mut fields = line.splitn(6, ' ');
+        let (range, permissions, inode) = match (
+            fields.next(),
+            fields.next(),
+            fields.next(),
+            fields.next(),
+            fields.next(),
+        ) {
+            (Some(range), Some(permissions), Some(_offset), Some(_device), Some(inode)) => {
+                (range, permissions, inode)
+            }
+            _ => continue,
+        };
+        let (start, end) = match range.split_once('-').and_then(|(start, end)| {
+            Some((
+                usize::from_str_radix(start, 16).ok()?,
+                usize::from_str_radix(end, 16).ok()?,
+            ))
+        }) {
+            Some(range) => range,
+            // Not a header line, e.g. "Size:" or "VmFlags:":
+            None => continue,
+        };
+        let path = fields.next().unwrap_or("").trim();
+        keeping = permissions.as_bytes().get(1) == Some(&b'w')
+            && inode == "0"
+            && (path.is_empty() || path.starts_with('['))
+            && !SPECIAL_MAPPINGS
+                .iter()
+                .any(|prefix| path.starts_with(prefix));
+        if keeping {
+            result.push(ExistingMapping {
+                address: start,
+                size: end.saturating_sub(start),
+                name: if path.is_empty() {
+                    "[anonymous]".to_string()
+                } else {
+                    path.to_string()
+                },
+            });
+        }
+    }
+    result.retain(|mapping| mapping.size > 0);
+    result
+}
+
+fn parse_kb(value: &str) -> Option<usize> {
+    value.trim().strip_suffix("kB")?.trim().parse().ok()
+}
+
+/// The current process' anonymous mappings. Always empty on macOS, which
+/// doesn't have /proc.
+pub fn existing_mappings() -> Vec<ExistingMapping> {
+    std::fs::read_to_string("/proc/self/smaps")
+        .or_else(|_| std::fs::read_to_string("/proc/self/maps"))
+        .map(|contents| parse_smaps(&contents))
+        .unwrap_or_default()
+}
+
+/// Record the mappings as anonymous mmap()s, attributed to a callstack of
+/// the pre-profiling root plus a frame with the mapping's name.
+pub fn record(tracker: &mut AllocationTracker<VecFunctionLocations>, mappings: &[ExistingMapping]) {
+    let root = tracker.functions.add_function(
+        PRE_PROFILING_FILENAME.to_string(),
+        PRE_PROFILING_ROOT.to_string(),
+    );
+    for mapping in mappings {
+        let name = tracker
+            .functions
+            .add_function(PRE_PROFILING_FILENAME.to_string(), mapping.name.clone());
+        let mut callstack = Callstack::new();
+        callstack.start_call(0, CallSiteId::new(root, 0));
+        callstack.start_call(0, CallSiteId::new(name, 0));
+        let callstack_id = tracker.get_callstack_id(&callstack);
+        tracker.add_anon_mmap(PARENT_PROCESS, mapping.address, mapping.size, callstack_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{existing_mappings, parse_smaps, record, ExistingMapping, PRE_PROFILING_ROOT};
+    use crate::memorytracking::{AllocationTracker, VecFunctionLocations, PARENT_PROCESS};
+
+    const SMAPS: &str = "\
+5556fbd63000-5556fbd65000 r--p 00000000 fe:00 317783                     /usr/bin/python3
+Size:                  8 kB
+Rss:                   8 kB
+5556fc000000-5556fc100000 rw-p 00000000 00:00 0                          [heap]
+Size:               1024 kB
+Rss:                 600 kB
+VmFlags: rd wr mr mw me ac
+7f0000000000-7f0000200000 rw-p 00000000 00:00 0
+Size:               2048 kB
+Rss:                 100 kB
+7f0000200000-7f0000201000 ---p 00000000 00:00 0
+Rss:                   0 kB
+7f0000300000-7f0000310000 rw-p 00000000 00:00 0                          [anon:arena]
+Rss:                  64 kB
+7f0000400000-7f0000410000 rw-p 00000000 00:00 0                          [stack]
+Rss:                  64 kB
+7f0000500000-7f0000510000 rw-s 00000000 00:05 1234                       /dev/shm/data
+Rss:                  64 kB
+";
+
+    #[test]
+    fn anonymous_writable_mappings_are_kept() {
+        assert_eq!(
+            parse_smaps(SMAPS),
+            vec![
+                ExistingMapping {
+                    address: 0x5556fc000000,
+                    size: 600 * 1024,
+                    name: "[heap]".to_string()
+                },
+                ExistingMapping {
+                    address: 0x7f0000000000,
+                    size: 100 * 1024,
+                    name: "[anonymous]".to_string()
+                },
+                ExistingMapping {
+                    address: 0x7f0000300000,
+                    size: 64 * 1024,
+                    name: "[anon:arena]".to_string()
+                },
+            ]
+        );
+        // Without resident sizes, i.e. /proc/self/maps, the mapping size is
+        // used:
+        let maps: String = SMAPS
+            .lines()
+            .filter(|line| !line.starts_with("Rss:"))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        assert_eq!(parse_smaps(&maps)[0].size, 1024 * 1024);
+    }
+
+    #[test]
+    fn recorded_under_pre_profiling_root() {
+        let mut tracker = AllocationTracker::new(".".to_string(), VecFunctionLocations::new());
+        record(&mut tracker, &parse_smaps(SMAPS));
+        assert_eq!(tracker.get_current_allocated_bytes(), 764 * 1024);
+        let data = tracker.snapshot();
+        assert_eq!(data.callstacks.len(), 3);
+        for usage in &data.callstacks {
+            assert_eq!(usage.frames[0].function, PRE_PROFILING_ROOT);
+        }
+        // Unmapping it later is tracked like any other mmap():
+        tracker.free_anon_mmap(PARENT_PROCESS, 0x5556fc000000, 1024 * 1024);
+        assert_eq!(tracker.get_current_allocated_bytes(), 164 * 1024);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn this_process_has_mappings() {
+        let total: usize = existing_mappings().iter().map(|m| m.size).sum();
+        assert!(total > 0);
+    }
+}
@@ -0,0 +1,169 @@
+// How many times each function was called. This is updated on every Python
+// function call, without holding the tracker lock, so it's a lock-free table
+// of atomic counters indexed by FunctionId, allocated a chunk at a time.
+use crate::memorytracking::FunctionId;
+use crate::sync::{AtomicPtr, AtomicU64, Ordering};
+
+#[cfg(not(loom))]
+const CHUNK_SIZE: usize = 4096;
+// Every atomic is tracked by loom, so keep the model small:
+#[cfg(loom)]
+const CHUNK_SIZE: usize = 2;
+
+/// Functions beyond MAX_CHUNKS * CHUNK_SIZE (4M) aren't counted.
+#[cfg(not(loom))]
+const MAX_CHUNKS: usize = 1024;
+#[cfg(loom)]
+const MAX_CHUNKS: usize = 2;
+
+type Chunk = [AtomicU64; CHUNK_SIZE];
+
+pub struct CallCounts {
+    chunks: Vec<AtomicPtr<Chunk>>,
+}
+
+impl Default for CallCounts {
+    fn default() -> Self {
+        Self {
+            chunks: (0..MAX_CHUNKS)
+                .map(|_| AtomicPtr::new(std::ptr::null_mut()))
+                .collect(),
+        }
+    }
+}
+
+impl CallCounts {
+    fn chunk(&self, index: usize) -> Option<&Chunk> {
+        let chunk = self.chunks[index].load(Ordering::Acquire);
+        // Chunks are never freed while self is alive, see Drop.
+        unsafe { chunk.as_ref() }
+    }
+
+    /// Record a call to a function.
+    pub fn record(&self, function: FunctionId) {
+        let id = function.as_u64() as usize;
+        let (chunk_index, offset) = (id / CHUNK_SIZE, id % CHUNK_SIZE);
+        if chunk_index >= MAX_CHUNKS {
+            return;
+        }
+        let chunk = match self.chunk(chunk_index) {
+            Some(chunk) => chunk,
+            None => {
+                let new_chunk =
+                    Box::into_raw(Box::new([(); CHUNK_SIZE].map(|_| AtomicU64::new(0))));
+                match self.chunks[chunk_index].compare_exchange(
+                    std::ptr::null_mut(),
+                    new_chunk,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                ) {
+                    Ok(_) => unsafe { &*new_chunk },
+                    Err(existing) => {
+                        // Another thread got there first:
+                        drop(unsafe { Box::from_raw(new_chunk) });
+                        unsafe { &*existing }
+                    }
+                }
+            }
+        };
+        chunk[offset].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// How many times a function was called.
+    pub fn get(&self, function: FunctionId) -> u64 {
+        let id = function.as_u64() as usize;
+        if id / CHUNK_SIZE >= MAX_CHUNKS {
+            return 0;
+        }
+        self.chunk(id / CHUNK_SIZE)
+            .map(|chunk| chunk[id % CHUNK_SIZE].load(Ordering::Relaxed))
+            .unwrap_or(0)
+    }
+
+    /// Functions that were called at least once, with their call counts.
+    pub fn non_zero(&self) -> Vec<(FunctionId, u64)> {
+        let mut result = vec![];
+        for chunk_index in 0..MAX_CHUNKS {
+            if let Some(chunk) = self.chunk(chunk_index) {
+                for (offset, count) in chunk.iter().enumerate() {
+                    let count = count.load(Ordering::Relaxed);
+                    if count > 0 {
+                        result.push((
+                            FunctionId::new((chunk_index * CHUNK_SIZE + offset) as u64),
+                            count,
+                        ));
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// Set all counts back to zero.
+    pub fn clear(&self) {
+        for chunk_index in 0..MAX_CHUNKS {
+            if let Some(chunk) = self.chunk(chunk_index) {
+                for count in chunk.iter() {
+                    count.store(0, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for CallCounts {
+    fn drop(&mut self) {
+        for chunk in self.chunks.iter() {
+            let chunk = chunk.load(Ordering::Acquire);
+            if !chunk.is_null() {
+                drop(unsafe { Box::from_raw(chunk) });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CallCounts, CHUNK_SIZE, MAX_CHUNKS};
+    use crate::memorytracking::FunctionId;
+    use std::sync::Arc;
+
+    #[test]
+    fn counts_calls() {
+        let counts = CallCounts::default();
+        let a = FunctionId::new(3);
+        let b = FunctionId::new(CHUNK_SIZE as u64 * 2 + 1);
+        counts.record(a);
+        counts.record(a);
+        counts.record(b);
+        // Too big, ignored:
+        counts.record(FunctionId::new((CHUNK_SIZE * MAX_CHUNKS) as u64));
+        counts.record(FunctionId::UNKNOWN);
+        assert_eq!(counts.get(a), 2);
+        assert_eq!(counts.get(b), 1);
+        assert_eq!(counts.get(FunctionId::new(4)), 0);
+        assert_eq!(counts.get(FunctionId::UNKNOWN), 0);
+        assert_eq!(counts.non_zero(), vec![(a, 2), (b, 1)]);
+        counts.clear();
+        assert_eq!(counts.non_zero(), vec![]);
+    }
+
+    #[test]
+    fn counts_from_multiple_threads() {
+        let counts = Arc::new(CallCounts::default());
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let counts = counts.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        counts.record(FunctionId::new(7));
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(counts.get(FunctionId::new(7)), 4000);
+    }
+}
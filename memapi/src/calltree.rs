@@ -0,0 +1,228 @@
+// Memory attributed to nodes of the call tree, rather than to whole
+// callstacks. Folded stacks and flamegraphs list every callstack separately,
+// which makes questions like "what did this function's callees allocate, in
+// total?" or "who calls this function, and how much does each caller end up
+// allocating through it?" awkward to answer; a tree where each node is a
+// callsite, with inclusive and self bytes, answers them directly and is what
+// tree-view UIs want.
+use std::collections::HashMap;
+
+use serde_json::{json, Value};
+
+use crate::snapshot::{frame_to_json, Frame, ProfileData};
+
+/// A node in the call tree. The root has no frame, and its bytes are the
+/// total.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CallTreeNode {
+    pub frame: Option<Frame>,
+    /// Bytes allocated by this callsite or anything it called.
+    pub bytes: usize,
+    /// Bytes allocated by callstacks ending at this callsite.
+    pub self_bytes: usize,
+    /// Largest first.
+    pub children: Vec<CallTreeNode>,
+}
+
+// Nodes while the tree is being built, indexing into a Vec so children can
+// be looked up by frame:
+struct PendingNode<'a> {
+    frame: Option<&'a Frame>,
+    bytes: usize,
+    self_bytes: usize,
+    children: HashMap<&'a Frame, usize>,
+}
+
+impl<'a> PendingNode<'a> {
+    fn new(frame: Option<&'a Frame>) -> Self {
+        Self {
+            frame,
+            bytes: 0,
+            self_bytes: 0,
+            children: HashMap::new(),
+        }
+    }
+}
+
+fn finish(nodes: &[PendingNode], index: usize) -> CallTreeNode {
+    let node = &nodes[index];
+    let mut children: Vec<_> = node
+        .children
+        .values()
+        .map(|child| finish(nodes, *child))
+        .collect();
+    children.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.frame.cmp(&b.frame)));
+    CallTreeNode {
+        frame: node.frame.cloned(),
+        bytes: node.bytes,
+        self_bytes: node.self_bytes,
+        children,
+    }
+}
+
+/// The call tree of memory at peak, or of current memory. Callstacks that
+/// use no memory are left out.
+pub fn call_tree(data: &ProfileData, peak: bool) -> CallTreeNode {
+    let mut nodes = vec![PendingNode::new(None)];
+    for usage in data.callstacks.iter() {
+        let bytes = if peak {
+            usage.peak_bytes
+        } else {
+            usage.current_bytes
+        };
+        if bytes == 0 {
+            continue;
+        }
+        let mut index = 0;
+        nodes[0].bytes += bytes;
+        for frame in usage.frames.iter() {
+            index = match nodes[index].children.get(frame) {
+                Some(child) => *child,
+                None => {
+                    nodes.push(PendingNode::new(Some(frame)));
+                    let child = nodes.len() - 1;
+                    nodes[index].children.insert(frame, child);
+                    child
+                }
+            };
+            nodes[index].bytes += bytes;
+        }
+        nodes[index].self_bytes += bytes;
+    }
+    finish(&nodes, 0)
+}
+
+impl CallTreeNode {
+    /// The child for a frame, if it allocated anything.
+    pub fn child(&self, frame: &Frame) -> Option<&CallTreeNode> {
+        self.children
+            .iter()
+            .find(|child| child.frame.as_ref() == Some(frame))
+    }
+
+    /// Visit the nodes calling `function`, and their parents, outermost call
+    /// first. Recursive calls inside a call that was already visited are
+    /// skipped, so memory isn't counted twice.
+    fn visit_calls<'a>(
+        &'a self,
+        function: &str,
+        visit: &mut impl FnMut(&'a CallTreeNode, &'a CallTreeNode),
+    ) {
+        for child in self.children.iter() {
+            if matches!(&child.frame, Some(frame) if frame.function == function) {
+                visit(self, child);
+            } else {
+                child.visit_calls(function, visit);
+            }
+        }
+    }
+
+    /// Who calls `function`, and how many bytes were allocated through those
+    /// calls, largest first. Calls from the top level have no caller.
+    pub fn callers(&self, function: &str) -> Vec<(Option<Frame>, usize)> {
+        let mut callers: HashMap<Option<&Frame>, usize> = HashMap::new();
+        self.visit_calls(function, &mut |parent, child| {
+            *callers.entry(parent.frame.as_ref()).or_insert(0) += child.bytes;
+        });
+        sorted(callers)
+    }
+
+    /// What `function` calls, and how many bytes each allocated, largest
+    /// first. Memory allocated directly by `function` is under None.
+    pub fn callees(&self, function: &str) -> Vec<(Option<Frame>, usize)> {
+        let mut callees: HashMap<Option<&Frame>, usize> = HashMap::new();
+        self.visit_calls(function, &mut |_, call| {
+            if call.self_bytes > 0 {
+                *callees.entry(None).or_insert(0) += call.self_bytes;
+            }
+            for callee in call.children.iter() {
+                *callees.entry(callee.frame.as_ref()).or_insert(0) += callee.bytes;
+            }
+        });
+        sorted(callees)
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "frame": self.frame.as_ref().map(frame_to_json),
+            "bytes": self.bytes,
+            "self_bytes": self.self_bytes,
+            "children": self.children.iter().map(|child| child.to_json()).collect::<Vec<_>>(),
+        })
+    }
+}
+
+fn sorted(totals: HashMap<Option<&Frame>, usize>) -> Vec<(Option<Frame>, usize)> {
+    let mut result: Vec<_> = totals
+        .into_iter()
+        .map(|(frame, bytes)| (frame.cloned(), bytes))
+        .collect();
+    result.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::call_tree;
+    use crate::folded::parse_folded_str;
+
+    #[test]
+    fn inclusive_and_self_bytes() {
+        let data = parse_folded_str(
+            "a.py:1 (main);b.py:2 (load) 100\n\
+             a.py:1 (main);b.py:2 (load);c.py:3 (parse) 50\n\
+             a.py:1 (main);b.py:9 (save);c.py:3 (parse) 20\n\
+             a.py:1 (main);b.py:9 (save);c.py:3 (parse);c.py:3 (parse) 5\n\
+             d.py:4 (other) 1\n",
+        )
+        .unwrap();
+        let tree = call_tree(&data, true);
+        assert_eq!(tree.frame, None);
+        assert_eq!((tree.bytes, tree.self_bytes), (176, 0));
+        let main = &tree.children[0];
+        assert_eq!(main.frame.as_ref().unwrap().function, "main");
+        assert_eq!((main.bytes, main.self_bytes), (175, 0));
+        assert_eq!(tree.children[1].bytes, 1);
+        let load = &main.children[0];
+        assert_eq!((load.bytes, load.self_bytes), (150, 100));
+        assert_eq!(load.children[0].self_bytes, 50);
+        let save = &main.children[1];
+        assert_eq!(main.child(save.frame.as_ref().unwrap()), Some(save));
+        assert_eq!((save.bytes, save.self_bytes), (25, 0));
+        assert_eq!(save.children[0].children[0].bytes, 5);
+
+        // The recursive call isn't counted twice:
+        let callers: Vec<_> = tree
+            .callers("parse")
+            .into_iter()
+            .map(|(frame, bytes)| (frame.unwrap().function, bytes))
+            .collect();
+        assert_eq!(
+            callers,
+            vec![("load".to_string(), 50), ("save".to_string(), 25)]
+        );
+        let callees: Vec<_> = tree
+            .callees("main")
+            .into_iter()
+            .map(|(frame, bytes)| (frame.map(|frame| frame.function), bytes))
+            .collect();
+        assert_eq!(
+            callees,
+            vec![
+                (Some("load".to_string()), 150),
+                (Some("save".to_string()), 25)
+            ]
+        );
+        assert_eq!(tree.callees("load")[0], (None, 100));
+        assert_eq!(tree.callers("other"), vec![(None, 1)]);
+
+        let json = tree.to_json();
+        assert_eq!(json["frame"], serde_json::Value::Null);
+        assert_eq!(json["children"][0]["frame"]["function"], "main");
+        assert_eq!(json["children"][0]["children"][0]["self_bytes"], 100);
+
+        // With nothing allocated there's just the root:
+        let empty = parse_folded_str("").unwrap();
+        assert!(call_tree(&empty, false).children.is_empty());
+    }
+}
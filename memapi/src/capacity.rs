@@ -0,0 +1,69 @@
+// Pre-sizing the tracker's tables. The tables grow as needed, but every time
+// a hashmap outgrows its capacity it's rehashed into one twice the size, and
+// with millions of live allocations each rehash stalls the program while
+// everything is copied, right when it's busiest allocating. If you know
+// roughly how big a program's profile gets, the tables can be sized for it
+// up front:
+//
+//     FIL_EXPECTED_ALLOCATIONS=10M fil-profile run yourscript.py
+//
+// Over-estimating costs memory, which counts towards Fil's own overhead.
+
+/// Expected sizes of the tracker's tables; zero means no pre-sizing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Capacity {
+    /// Allocations live at the same time.
+    pub live_allocations: usize,
+    /// Distinct callstacks.
+    pub callstacks: usize,
+    /// Distinct functions.
+    pub functions: usize,
+}
+
+/// Parse a count like "10000", "500k", "10M" or "1G"; the suffixes are
+/// powers of 1000.
+pub fn parse_count(value: &str) -> Option<usize> {
+    let value = value.trim();
+    let (number, multiplier) = match value.char_indices().last()? {
+        (index, 'k') | (index, 'K') => (&value[..index], 1_000),
+        (index, 'M') => (&value[..index], 1_000_000),
+        (index, 'G') => (&value[..index], 1_000_000_000),
+        _ => (value, 1),
+    };
+    number.parse::<usize>().ok()?.checked_mul(multiplier)
+}
+
+impl Capacity {
+    /// From FIL_EXPECTED_ALLOCATIONS, FIL_EXPECTED_CALLSTACKS and
+    /// FIL_EXPECTED_FUNCTIONS.
+    pub fn from_env() -> Self {
+        let get = |name: &str| match std::env::var(name) {
+            Ok(value) => parse_count(&value).unwrap_or_else(|| {
+                eprintln!("=fil-profile= Ignoring invalid {} {:?}", name, value);
+                0
+            }),
+            Err(_) => 0,
+        };
+        Self {
+            live_allocations: get("FIL_EXPECTED_ALLOCATIONS"),
+            callstacks: get("FIL_EXPECTED_CALLSTACKS"),
+            functions: get("FIL_EXPECTED_FUNCTIONS"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_count;
+
+    #[test]
+    fn counts() {
+        assert_eq!(parse_count("1234"), Some(1234));
+        assert_eq!(parse_count("500k"), Some(500_000));
+        assert_eq!(parse_count(" 10M "), Some(10_000_000));
+        assert_eq!(parse_count("2G"), Some(2_000_000_000));
+        assert_eq!(parse_count("M"), None);
+        assert_eq!(parse_count("1.5M"), None);
+        assert_eq!(parse_count(""), None);
+    }
+}
@@ -0,0 +1,271 @@
+// Memory limits of the current cgroup, e.g. a container. A process that hits
+// its cgroup's limit gets killed by the kernel with no chance of writing a
+// report, so the out-of-memory detection needs to know how close we are to
+// the limit, and not just how much memory the host has left.
+//
+// The limit and usage files are read directly, for both cgroups v1 and v2.
+// Limits also apply to nested cgroups, so the whole chain of ancestors is
+// checked, and whichever is closest to its limit wins.
+use std::path::{Path, PathBuf};
+
+/// Where cgroup filesystems are normally mounted.
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Limits at or above this mean "no limit": cgroups v1 reports unlimited as
+/// the largest page-aligned i64.
+const UNLIMITED: u64 = 1 << 62;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CgroupVersion {
+    V1,
+    V2,
+}
+
+impl CgroupVersion {
+    fn limit_filename(self) -> &'static str {
+        match self {
+            CgroupVersion::V1 => "memory.limit_in_bytes",
+            CgroupVersion::V2 => "memory.max",
+        }
+    }
+
+    fn usage_filename(self) -> &'static str {
+        match self {
+            CgroupVersion::V1 => "memory.usage_in_bytes",
+            CgroupVersion::V2 => "memory.current",
+        }
+    }
+
+    fn inactive_file_keys(self) -> &'static [&'static str] {
+        match self {
+            // With hierarchy enabled, the total_ variant includes children:
+            CgroupVersion::V1 => &["total_inactive_file", "inactive_file"],
+            CgroupVersion::V2 => &["inactive_file"],
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CgroupMemory {
+    version: CgroupVersion,
+    // The process's own cgroup first, then its ancestors.
+    directories: Vec<PathBuf>,
+}
+
+/// Memory usage of a single cgroup, in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CgroupUsage {
+    pub limit: Option<usize>,
+    pub usage: usize,
+}
+
+impl CgroupMemory {
+    /// Find the memory cgroup of the current process, if there is one.
+    pub fn find() -> Option<Self> {
+        let contents = match std::fs::read_to_string("/proc/self/cgroup") {
+            Ok(contents) => contents,
+            Err(err) => {
+                eprintln!("=fil-profile= Couldn't read /proc/self/cgroup ({:})", err);
+                return None;
+            }
+        };
+        Self::find_in(&contents, Path::new(CGROUP_ROOT))
+    }
+
+    /// Find the memory cgroup given the contents of /proc/self/cgroup and
+    /// where cgroup filesystems are mounted.
+    pub fn find_in(proc_self_cgroup: &str, root: &Path) -> Option<Self> {
+        let mut v2 = None;
+        for line in proc_self_cgroup.lines() {
+            let mut parts = line.splitn(3, ':');
+            let (hierarchy, controllers, path) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(hierarchy), Some(controllers), Some(path)) => (hierarchy, controllers, path),
+                _ => continue,
+            };
+            if controllers.split(',').any(|c| c == "memory") {
+                // A v1 memory controller takes precedence, since in hybrid
+                // setups it's the one that enforces limits.
+                if let Some(found) = Self::for_path(CgroupVersion::V1, &root.join("memory"), path) {
+                    return Some(found);
+                }
+            } else if hierarchy == "0" && controllers.is_empty() {
+                v2 = Self::for_path(CgroupVersion::V2, root, path);
+            }
+        }
+        v2
+    }
+
+    fn for_path(version: CgroupVersion, mount: &Path, path: &str) -> Option<Self> {
+        let has_limit = |directory: &Path| directory.join(version.limit_filename()).exists();
+        let mut directory = mount.join(path.trim_start_matches('/'));
+        // Inside a container the path is often the host's, which doesn't
+        // exist in the container's view of the filesystem; the container's
+        // own cgroup is then mounted at the root.
+        if !has_limit(&directory) {
+            directory = mount.to_path_buf();
+        }
+        let mut directories = vec![];
+        let mut current = Some(directory.as_path());
+        while let Some(directory) = current {
+            if !directory.starts_with(mount) || !has_limit(directory) {
+                break;
+            }
+            directories.push(directory.to_path_buf());
+            current = directory.parent();
+        }
+        if directories.is_empty() {
+            None
+        } else {
+            Some(Self {
+                version,
+                directories,
+            })
+        }
+    }
+
+    pub fn version(&self) -> CgroupVersion {
+        self.version
+    }
+
+    /// Limit and usage for the cgroup and each of its ancestors. Usage
+    /// excludes inactive page cache, which the kernel can reclaim rather than
+    /// killing the process.
+    pub fn usages(&self) -> Vec<CgroupUsage> {
+        self.directories
+            .iter()
+            .filter_map(|directory| {
+                let read = |filename| std::fs::read_to_string(directory.join(filename)).ok();
+                let limit = parse_limit(&read(self.version.limit_filename())?);
+                let usage: usize = read(self.version.usage_filename())?.trim().parse().ok()?;
+                let inactive_file = read("memory.stat")
+                    .and_then(|stat| parse_stat(&stat, self.version.inactive_file_keys()))
+                    .unwrap_or(0);
+                Some(CgroupUsage {
+                    limit,
+                    usage: usage.saturating_sub(inactive_file),
+                })
+            })
+            .collect()
+    }
+
+    /// The lowest limit of the cgroup and its ancestors, if any is set.
+    pub fn limit(&self) -> Option<usize> {
+        self.usages().iter().filter_map(|usage| usage.limit).min()
+    }
+
+    /// How many more bytes can be used before hitting a limit, if any is set.
+    pub fn available(&self) -> Option<usize> {
+        self.usages()
+            .iter()
+            .filter_map(|usage| Some(usage.limit?.saturating_sub(usage.usage)))
+            .min()
+    }
+}
+
+/// Parse a limit file, where "max" (v2) or a huge number (v1) means no limit.
+fn parse_limit(contents: &str) -> Option<usize> {
+    let contents = contents.trim();
+    if contents == "max" {
+        return None;
+    }
+    let limit: u64 = contents.parse().ok()?;
+    // A limit of 0 is nonsensical. Seen on Docker with cgroups v1 with no
+    // limit set, and the usage was also 0. So just assume there is no limit.
+    if limit == 0 || limit >= UNLIMITED {
+        None
+    } else {
+        Some(limit as usize)
+    }
+}
+
+/// Get the first of the given keys from a memory.stat file.
+fn parse_stat(stat: &str, keys: &[&str]) -> Option<usize> {
+    keys.iter().find_map(|key| {
+        stat.lines().find_map(|line| {
+            let (name, value) = line.split_once(' ')?;
+            if name == *key {
+                value.trim().parse().ok()
+            } else {
+                None
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_limit, CgroupMemory, CgroupVersion};
+    use std::path::Path;
+
+    fn write(directory: &Path, files: &[(&str, &str)]) {
+        std::fs::create_dir_all(directory).unwrap();
+        for (name, contents) in files {
+            std::fs::write(directory.join(name), contents).unwrap();
+        }
+    }
+
+    #[test]
+    fn limits() {
+        assert_eq!(parse_limit("max\n"), None);
+        assert_eq!(parse_limit("9223372036854771712\n"), None);
+        assert_eq!(parse_limit("0\n"), None);
+        assert_eq!(parse_limit("536870912\n"), Some(536870912));
+        assert_eq!(parse_limit("garbage"), None);
+    }
+
+    #[test]
+    fn v2_nested() {
+        let root = std::env::temp_dir().join(format!("fil-cgroup-v2-{}", std::process::id()));
+        let job = root.join("jobs/job1");
+        write(
+            &root,
+            &[("memory.max", "max\n"), ("memory.current", "5000000\n")],
+        );
+        write(
+            &root.join("jobs"),
+            &[("memory.max", "1000000\n"), ("memory.current", "900000\n")],
+        );
+        write(
+            &job,
+            &[
+                ("memory.max", "max\n"),
+                ("memory.current", "800000\n"),
+                ("memory.stat", "anon 700000\ninactive_file 100000\n"),
+            ],
+        );
+        let cgroup = CgroupMemory::find_in("0::/jobs/job1\n", &root).unwrap();
+        assert_eq!(cgroup.version(), CgroupVersion::V2);
+        assert_eq!(cgroup.usages().len(), 3);
+        assert_eq!(cgroup.usages()[0].usage, 700000);
+        // The parent's limit applies:
+        assert_eq!(cgroup.limit(), Some(1000000));
+        assert_eq!(cgroup.available(), Some(100000));
+
+        // Path from outside a container falls back to the mount root:
+        let cgroup = CgroupMemory::find_in("0::/host/path\n", &root).unwrap();
+        assert_eq!(cgroup.limit(), None);
+        assert_eq!(cgroup.available(), None);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn v1() {
+        let root = std::env::temp_dir().join(format!("fil-cgroup-v1-{}", std::process::id()));
+        write(
+            &root.join("memory"),
+            &[
+                ("memory.limit_in_bytes", "2000000\n"),
+                ("memory.usage_in_bytes", "1500000\n"),
+                (
+                    "memory.stat",
+                    "inactive_file 10\ntotal_inactive_file 500000\n",
+                ),
+            ],
+        );
+        let cgroup = CgroupMemory::find_in("5:cpu:/\n4:memory:/docker/abc\n0::/\n", &root).unwrap();
+        assert_eq!(cgroup.version(), CgroupVersion::V1);
+        assert_eq!(cgroup.available(), Some(1000000));
+        assert_eq!(CgroupMemory::find_in("5:cpu:/\n", &root), None);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,188 @@
+// Optional gzip compression of the raw outputs: .prof files, JSON, and the
+// JSON-lines event logs. Folded callstacks from large programs can run to
+// hundreds of MB, and compress very well. SVGs and text are left alone, since
+// people open those directly.
+//
+// Compressed files get a ".gz" suffix, and everything that reads these files
+// back accepts either form.
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::sink::OutputSink;
+
+pub const GZIP_SUFFIX: &str = ".gz";
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Enabled by setting FIL_COMPRESS=1.
+pub fn enabled_from_env() -> bool {
+    std::env::var("FIL_COMPRESS")
+        .map(|v| v == "1")
+        .unwrap_or(false)
+}
+
+/// Whether a file with this name gets compressed.
+pub fn should_compress(name: &str) -> bool {
+    name.ends_with(".prof") || name.ends_with(".json") || name.ends_with(".jsonl")
+}
+
+/// Gzip some data.
+pub fn gzip(contents: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(contents)?;
+    encoder.finish()
+}
+
+/// Decompress data if it's gzipped, otherwise return it as is.
+///
+/// A log that was cut off, e.g. because the process was killed, is missing
+/// the end of its gzip stream; everything before that is still returned.
+pub fn decompress(data: Vec<u8>) -> std::io::Result<Vec<u8>> {
+    if !data.starts_with(&GZIP_MAGIC) {
+        return Ok(data);
+    }
+    let mut result = vec![];
+    match MultiGzDecoder::new(&data[..]).read_to_end(&mut result) {
+        Err(e) if e.kind() != ErrorKind::UnexpectedEof => Err(e),
+        _ => Ok(result),
+    }
+}
+
+/// Read a file that may have been compressed. If the file doesn't exist but
+/// a ".gz" version of it does, that is read instead.
+pub fn read<P: AsRef<Path>>(path: P) -> std::io::Result<Vec<u8>> {
+    let path = path.as_ref();
+    let data = match std::fs::read(path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            std::fs::read(with_gzip_suffix(path)).map_err(|_| e)?
+        }
+        result => result?,
+    };
+    decompress(data)
+}
+
+/// Like read(), for text.
+pub fn read_to_string<P: AsRef<Path>>(path: P) -> std::io::Result<String> {
+    String::from_utf8(read(path)?).map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))
+}
+
+fn with_gzip_suffix(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_os_string();
+    path.push(GZIP_SUFFIX);
+    PathBuf::from(path)
+}
+
+/// Open a JSON-lines log in a directory for appending, compressed if
+/// `compress` is set. Each run appends its own gzip stream, and flushing
+/// makes everything written so far readable, so entries survive the process
+/// dying just like they do in an uncompressed log.
+pub fn open_log(
+    directory: &Path,
+    filename: &str,
+    compress: bool,
+) -> std::io::Result<Box<dyn Write + Send + Sync>> {
+    std::fs::create_dir_all(directory)?;
+    let open = |path: PathBuf| {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+    };
+    if compress {
+        let file = open(with_gzip_suffix(&directory.join(filename)))?;
+        Ok(Box::new(GzEncoder::new(file, Compression::default())))
+    } else {
+        let file: File = open(directory.join(filename))?;
+        Ok(Box::new(std::io::BufWriter::new(file)))
+    }
+}
+
+/// Wraps another sink, gzipping the files that should_compress() picks.
+pub struct CompressingSink<'a> {
+    inner: &'a mut dyn OutputSink,
+}
+
+impl<'a> CompressingSink<'a> {
+    pub fn new(inner: &'a mut dyn OutputSink) -> Self {
+        Self { inner }
+    }
+
+    fn name(name: &str) -> String {
+        if should_compress(name) {
+            format!("{}{}", name, GZIP_SUFFIX)
+        } else {
+            name.to_string()
+        }
+    }
+}
+
+impl<'a> OutputSink for CompressingSink<'a> {
+    fn write_file(&mut self, name: &str, contents: &[u8]) -> std::io::Result<()> {
+        if should_compress(name) {
+            self.inner.write_file(&Self::name(name), &gzip(contents)?)
+        } else {
+            self.inner.write_file(name, contents)
+        }
+    }
+
+    fn describe(&self, name: &str) -> String {
+        self.inner.describe(&Self::name(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decompress, gzip, open_log, read_to_string, CompressingSink};
+    use crate::sink::{MemorySink, OutputSink};
+    use std::io::Write;
+
+    #[test]
+    fn compressed_files_read_back() {
+        let mut memory = MemorySink::default();
+        {
+            let mut sink = CompressingSink::new(&mut memory);
+            sink.write_file("peak-memory.prof", b"a;b 10\n").unwrap();
+            sink.write_file("peak-memory.svg", b"<svg>").unwrap();
+        }
+        assert_eq!(memory.files["peak-memory.svg"], b"<svg>");
+        assert!(!memory.files.contains_key("peak-memory.prof"));
+        let compressed = memory.files["peak-memory.prof.gz"].clone();
+        assert_ne!(compressed, b"a;b 10\n");
+        assert_eq!(decompress(compressed).unwrap(), b"a;b 10\n");
+        // Uncompressed data is passed through:
+        assert_eq!(decompress(b"a;b 10\n".to_vec()).unwrap(), b"a;b 10\n");
+        // Truncated streams give what they can:
+        let long = "x".repeat(100_000);
+        let compressed = gzip(long.as_bytes()).unwrap();
+        let truncated = decompress(compressed[..compressed.len() - 4].to_vec()).unwrap();
+        assert_eq!(truncated, long.as_bytes());
+    }
+
+    #[test]
+    fn compressed_logs_survive_without_closing() {
+        let directory = std::env::temp_dir().join(format!("fil-compress-{}", std::process::id()));
+        let mut log = open_log(&directory, "events.jsonl", true).unwrap();
+        writeln!(log, "one").unwrap();
+        log.flush().unwrap();
+        // Not closed yet, but what was flushed is readable:
+        assert_eq!(
+            read_to_string(directory.join("events.jsonl")).unwrap(),
+            "one\n"
+        );
+        drop(log);
+        // A second run appends another stream:
+        let mut log = open_log(&directory, "events.jsonl", true).unwrap();
+        writeln!(log, "two").unwrap();
+        drop(log);
+        assert_eq!(
+            read_to_string(directory.join("events.jsonl")).unwrap(),
+            "one\ntwo\n"
+        );
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
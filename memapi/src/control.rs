@@ -0,0 +1,168 @@
+// Remote control: a Unix domain socket that accepts simple commands, so
+// operators can get reports out of a long-running service without
+// restarting it.
+//
+// Each command is a line of text, and gets a single line in response,
+// starting with "ok" or "error":
+//
+//     status                Current and peak tracked memory, and the peak
+//                           over the recent window if enabled
+//     dump [directory]      Write peak memory SVGs
+//     dump-current [dir]    Write SVGs of the memory allocated right now
+//     reset                 Throw away everything tracked so far
+//     set-sampling-rate N   Not supported, Fil tracks every allocation
+//
+// Commands are run with the GIL held, since writing reports calls into Python.
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+use crate::python::with_gil_if_running;
+
+/// A parsed command.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Status,
+    Dump(Option<String>),
+    DumpCurrent(Option<String>),
+    Reset,
+    SetSamplingRate(f64),
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let mut parts = line.split_whitespace();
+        let command = parts.next().ok_or_else(|| "empty command".to_string())?;
+        let argument = parts.next();
+        if parts.next().is_some() {
+            return Err("too many arguments".to_string());
+        }
+        match (command, argument) {
+            ("status", None) => Ok(Command::Status),
+            ("dump", path) => Ok(Command::Dump(path.map(|p| p.to_string()))),
+            ("dump-current", path) => Ok(Command::DumpCurrent(path.map(|p| p.to_string()))),
+            ("reset", None) => Ok(Command::Reset),
+            ("set-sampling-rate", Some(rate)) => rate
+                .parse()
+                .map(Command::SetSamplingRate)
+                .map_err(|_| format!("invalid sampling rate: {}", rate)),
+            ("set-sampling-rate", None) => Err("missing sampling rate".to_string()),
+            ("status" | "reset", Some(_)) => Err(format!("{} takes no arguments", command)),
+            _ => Err(format!("unknown command: {}", command)),
+        }
+    }
+}
+
+/// Runs a command, returning the response message.
+pub type CommandHandler = fn(Command) -> Result<String, String>;
+
+/// Start listening on the socket given by FIL_CONTROL_SOCKET, if set.
+/// Commands are handled one at a time on a background thread, which runs
+/// `thread_init` before anything else.
+pub fn start_from_env(handler: CommandHandler, thread_init: fn()) {
+    let path = match std::env::var_os("FIL_CONTROL_SOCKET") {
+        Some(path) => path,
+        None => return,
+    };
+    // Clean up after a previous run:
+    let _ = std::fs::remove_file(&path);
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!(
+                "=fil-profile= Couldn't listen on control socket {:?}: {}",
+                Path::new(&path),
+                e
+            );
+            return;
+        }
+    };
+    std::thread::Builder::new()
+        .name("fil-control".to_string())
+        .spawn(move || {
+            thread_init();
+            for connection in listener.incoming().flatten() {
+                serve(connection, handler);
+            }
+        })
+        .expect("Couldn't start control thread");
+}
+
+fn serve(connection: UnixStream, handler: CommandHandler) {
+    let mut writer = match connection.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+    for line in BufReader::new(connection).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let result = Command::parse(&line).and_then(|command| {
+            with_gil_if_running(|| handler(command))
+                .unwrap_or_else(|| Err("Python isn't running".to_string()))
+        });
+        let response = match result {
+            Ok(message) => format!("ok {}", message),
+            Err(message) => format!("error {}", message),
+        };
+        if writeln!(writer, "{}", response.trim_end()).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{serve, Command};
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn commands_are_parsed() {
+        assert_eq!(Command::parse("status"), Ok(Command::Status));
+        assert_eq!(Command::parse("  reset \n"), Ok(Command::Reset));
+        assert_eq!(Command::parse("dump"), Ok(Command::Dump(None)));
+        assert_eq!(
+            Command::parse("dump /tmp/out"),
+            Ok(Command::Dump(Some("/tmp/out".to_string())))
+        );
+        assert_eq!(
+            Command::parse("set-sampling-rate 0.5"),
+            Ok(Command::SetSamplingRate(0.5))
+        );
+        assert_eq!(
+            Command::parse("dump-current"),
+            Ok(Command::DumpCurrent(None))
+        );
+        assert!(Command::parse("set-sampling-rate lots").is_err());
+        assert!(Command::parse("status now").is_err());
+        assert!(Command::parse("dump a b").is_err());
+        assert!(Command::parse("explode").is_err());
+    }
+
+    fn handler(command: Command) -> Result<String, String> {
+        match command {
+            Command::Status => Ok("all good".to_string()),
+            _ => Err("nope".to_string()),
+        }
+    }
+
+    #[test]
+    fn each_command_gets_a_response() {
+        pyo3::prepare_freethreaded_python();
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let thread = std::thread::spawn(move || serve(server, handler));
+        client.write_all(b"status\n\nreset\nbogus\n").unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let lines: Vec<String> = BufReader::new(client).lines().map(|l| l.unwrap()).collect();
+        assert_eq!(
+            lines,
+            vec!["ok all good", "error nope", "error unknown command: bogus"]
+        );
+        thread.join().unwrap();
+    }
+}
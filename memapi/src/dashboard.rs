@@ -0,0 +1,202 @@
+// A tiny live dashboard, served over HTTP from the profiled process: a chart
+// of memory usage over time, and the callstacks currently using the most
+// memory. Only built with the "dashboard" feature.
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+/// How often memory usage is sampled for the chart.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many samples the chart shows; older ones are dropped.
+const MAX_SAMPLES: usize = 600;
+
+/// How many callstacks to show.
+pub const TOP_CALLSTACKS: usize = 20;
+
+const PAGE: &str = include_str!("dashboard.html");
+
+/// The tracker's state at a point in time.
+pub struct Snapshot {
+    pub current_bytes: usize,
+    pub peak_bytes: usize,
+    /// Largest first.
+    pub top_callstacks: Vec<(String, usize)>,
+}
+
+/// Gets a snapshot; `top` says whether top_callstacks is needed, since it's
+/// more expensive to calculate.
+pub type SnapshotCallback = fn(top: bool) -> Snapshot;
+
+#[derive(Default)]
+struct History {
+    // (seconds since start, bytes):
+    samples: VecDeque<(f64, usize)>,
+}
+
+impl History {
+    fn add(&mut self, seconds: f64, bytes: usize) {
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((seconds, bytes));
+    }
+}
+
+/// Start serving on localhost at the port given by FIL_DASHBOARD_PORT, if
+/// set. The sampling and server threads run `thread_init` before anything
+/// else.
+pub fn start_from_env(snapshot: SnapshotCallback, thread_init: fn()) {
+    let port = match std::env::var("FIL_DASHBOARD_PORT")
+        .ok()
+        .and_then(|p| p.parse::<u16>().ok())
+    {
+        Some(port) => port,
+        None => return,
+    };
+    let listener = match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!(
+                "=fil-profile= Couldn't start dashboard on port {}: {}",
+                port, e
+            );
+            return;
+        }
+    };
+    eprintln!("=fil-profile= Live dashboard at http://127.0.0.1:{}/", port);
+
+    let history = Arc::new(Mutex::new(History::default()));
+    let start = Instant::now();
+    let sampler_history = history.clone();
+    std::thread::Builder::new()
+        .name("fil-dashboard-sampler".to_string())
+        .spawn(move || {
+            thread_init();
+            loop {
+                let current_bytes = snapshot(false).current_bytes;
+                sampler_history
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .add(start.elapsed().as_secs_f64(), current_bytes);
+                std::thread::sleep(SAMPLE_INTERVAL);
+            }
+        })
+        .expect("Couldn't start dashboard thread");
+    std::thread::Builder::new()
+        .name("fil-dashboard".to_string())
+        .spawn(move || {
+            thread_init();
+            for connection in listener.incoming().flatten() {
+                let _ = serve(connection, snapshot, &history);
+            }
+        })
+        .expect("Couldn't start dashboard thread");
+}
+
+fn serve(
+    connection: TcpStream,
+    snapshot: SnapshotCallback,
+    history: &Mutex<History>,
+) -> std::io::Result<()> {
+    let mut reader = BufReader::new(connection.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip the headers:
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && header.trim() != "" {
+        header.clear();
+    }
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+    let (status, content_type, body) = route(path, snapshot, history);
+    let mut connection = connection;
+    write!(
+        connection,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    connection.flush()
+}
+
+fn route(
+    path: &str,
+    snapshot: SnapshotCallback,
+    history: &Mutex<History>,
+) -> (&'static str, &'static str, String) {
+    match path {
+        "/" => ("200 OK", "text/html; charset=utf-8", PAGE.to_string()),
+        "/data.json" => (
+            "200 OK",
+            "application/json",
+            // A panic elsewhere while holding the lock shouldn't break the
+            // dashboard, the history is still fine to read:
+            to_json(
+                &snapshot(true),
+                &history.lock().unwrap_or_else(|e| e.into_inner()),
+            ),
+        ),
+        _ => ("404 Not Found", "text/plain", "Not found".to_string()),
+    }
+}
+
+fn to_json(snapshot: &Snapshot, history: &History) -> String {
+    json!({
+        "current_bytes": snapshot.current_bytes,
+        "peak_bytes": snapshot.peak_bytes,
+        "samples": history.samples,
+        "top_callstacks": snapshot
+            .top_callstacks
+            .iter()
+            .map(|(callstack, bytes)| json!({"callstack": callstack, "bytes": bytes}))
+            .collect::<Vec<_>>(),
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{route, History, Snapshot, MAX_SAMPLES};
+    use std::sync::Mutex;
+
+    fn snapshot(_top: bool) -> Snapshot {
+        Snapshot {
+            current_bytes: 100,
+            peak_bytes: 200,
+            top_callstacks: vec![("a.py:1 (f)".to_string(), 100)],
+        }
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let mut history = History::default();
+        for i in 0..(MAX_SAMPLES + 5) {
+            history.add(i as f64, i);
+        }
+        assert_eq!(history.samples.len(), MAX_SAMPLES);
+        assert_eq!(history.samples.front(), Some(&(5.0, 5)));
+    }
+
+    #[test]
+    fn routes() {
+        let history = Mutex::new(History::default());
+        history.lock().unwrap().add(1.0, 50);
+        let (status, _, body) = route("/", snapshot, &history);
+        assert_eq!(status, "200 OK");
+        assert!(body.contains("<html"));
+        let (status, content_type, body) = route("/data.json", snapshot, &history);
+        assert_eq!((status, content_type), ("200 OK", "application/json"));
+        let data: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(data["current_bytes"], 100);
+        assert_eq!(data["peak_bytes"], 200);
+        assert_eq!(data["samples"][0][1], 50);
+        assert_eq!(data["top_callstacks"][0]["callstack"], "a.py:1 (f)");
+        assert_eq!(route("/nope", snapshot, &history).0, "404 Not Found");
+    }
+}
@@ -0,0 +1,110 @@
+// Double-free detection. A second free() of the same pointer is a bug, often
+// in a native extension, and it also corrupts profiling data. To say where
+// the memory came from and where it was first freed, we remember the most
+// recently freed addresses.
+use std::collections::{HashMap, VecDeque};
+
+use crate::hashing::HashState;
+
+use crate::memorytracking::CallstackId;
+use crate::util::new_hashmap;
+
+/// How many freed addresses to remember.
+const DEFAULT_CAPACITY: usize = 64 * 1024;
+
+/// A previously freed allocation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Freed {
+    pub size: usize,
+    pub allocated_by: CallstackId,
+    pub freed_by: CallstackId,
+}
+
+/// A bounded map of recently freed addresses; the oldest are forgotten
+/// first.
+#[derive(Clone, Debug)]
+pub struct RecentFrees {
+    capacity: usize,
+    order: VecDeque<usize>,
+    freed: HashMap<usize, Freed, HashState>,
+}
+
+impl RecentFrees {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            order: VecDeque::with_capacity(capacity),
+            freed: new_hashmap(),
+        }
+    }
+
+    /// Enabled by setting FIL_DETECT_DOUBLE_FREE=1.
+    pub fn from_env() -> Option<Self> {
+        if std::env::var("FIL_DETECT_DOUBLE_FREE").as_deref() == Ok("1") {
+            Some(Self::new(DEFAULT_CAPACITY))
+        } else {
+            None
+        }
+    }
+
+    /// Same settings, no data.
+    pub fn cleared(&self) -> Self {
+        Self::new(self.capacity)
+    }
+
+    /// Remember a successful free().
+    pub fn record(&mut self, address: usize, freed: Freed) {
+        if self.freed.insert(address, freed).is_none() {
+            self.order.push_back(address);
+        }
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.freed.remove(&oldest);
+            }
+        }
+    }
+
+    /// The address was allocated again, so freeing it is fine once more.
+    /// Its slot in the queue is left behind, and gets reclaimed when it
+    /// reaches the front.
+    pub fn forget(&mut self, address: usize) {
+        self.freed.remove(&address);
+    }
+
+    /// If this address was recently freed, how.
+    pub fn check(&self, address: usize) -> Option<Freed> {
+        self.freed.get(&address).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Freed, RecentFrees};
+
+    fn freed(n: u32) -> Freed {
+        Freed {
+            size: 10,
+            allocated_by: n,
+            freed_by: n + 1,
+        }
+    }
+
+    #[test]
+    fn remembers_recent_frees() {
+        let mut recent = RecentFrees::new(2);
+        recent.record(100, freed(1));
+        recent.record(200, freed(2));
+        assert_eq!(recent.check(100), Some(freed(1)));
+        assert_eq!(recent.check(300), None);
+
+        // Reallocated, so no longer freed:
+        recent.forget(200);
+        assert_eq!(recent.check(200), None);
+
+        // Oldest is forgotten once we go over capacity:
+        recent.record(300, freed(3));
+        recent.record(400, freed(4));
+        assert_eq!(recent.check(100), None);
+        assert_eq!(recent.check(400), Some(freed(4)));
+    }
+}
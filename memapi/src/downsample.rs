@@ -0,0 +1,167 @@
+// Capping the number of callstacks in a report. Profiles of big programs can
+// have hundreds of thousands of distinct callstacks, which makes for .prof
+// files and SVGs too big to attach to an email or a chat message. Rather than
+// dropping the smallest callstacks, which would make the totals wrong, they're
+// merged into their callers: "a;b;c 10" becomes "a;b;[merged] 10". The
+// smallest callstack is merged first, again and again, until few enough
+// remain, so the big callstacks keep all their detail.
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+use itertools::Itertools;
+
+/// The frame that merged callstacks are attributed to, under their caller.
+pub const MERGED_FRAME: &str = "[smaller callstacks, merged]";
+
+// Source code lines in flamegraph input start with this; see
+// reporting::frames_as_string():
+const SOURCE_LINE_MARKER: char = '\u{2800}';
+
+// Queued callstacks: (count, depth, frames), smallest count first; ties go
+// to the deepest, then alphabetically, so the result doesn't depend on hash
+// order.
+type QueueEntry<'a> = Reverse<(usize, Reverse<usize>, Vec<&'a str>)>;
+
+/// The callstack that `frames` gets merged into, or None if it's as merged
+/// as it can get.
+fn merge_target<'a>(frames: &[&'a str]) -> Option<Vec<&'a str>> {
+    let mut target = frames.to_vec();
+    if target.last() == Some(&MERGED_FRAME) {
+        target.pop();
+    }
+    if target.is_empty() {
+        return None;
+    }
+    // Frames are followed by their source code, if any, which goes with
+    // them:
+    if target.pop()?.starts_with(SOURCE_LINE_MARKER) {
+        target.pop();
+    }
+    target.push(MERGED_FRAME);
+    Some(target)
+}
+
+/// Merge the smallest callstacks in flamegraph input lines ("a;b;c 123")
+/// into their callers until there are at most `max_lines`, keeping the
+/// total the same. Lines that can't be parsed are kept as they are.
+pub fn cap_lines(lines: Vec<String>, max_lines: usize) -> Vec<String> {
+    if lines.len() <= max_lines {
+        return lines;
+    }
+    let mut unparsed = vec![];
+    let mut counts: HashMap<Vec<&str>, usize> = HashMap::with_capacity(lines.len());
+    for line in lines.iter() {
+        match line
+            .rsplit_once(' ')
+            .and_then(|(stack, count)| Some((stack, count.parse::<usize>().ok()?)))
+        {
+            Some((stack, count)) => {
+                *counts.entry(stack.split(';').collect()).or_insert(0) += count;
+            }
+            None => unparsed.push(line.clone()),
+        }
+    }
+    let max_stacks = max_lines.saturating_sub(unparsed.len()).max(1);
+    let mut queue: BinaryHeap<QueueEntry> = counts
+        .iter()
+        .map(|(frames, count)| Reverse((*count, Reverse(frames.len()), frames.clone())))
+        .collect();
+    while counts.len() > max_stacks {
+        let Reverse((count, _, frames)) = match queue.pop() {
+            Some(entry) => entry,
+            None => break,
+        };
+        // Entries whose count has changed since they were queued are stale;
+        // the up to date entry is also in the queue.
+        if counts.get(&frames) != Some(&count) {
+            continue;
+        }
+        let target = match merge_target(&frames) {
+            Some(target) => target,
+            // Already merged as far as it goes, but it can still absorb
+            // others:
+            None => continue,
+        };
+        counts.remove(&frames);
+        let merged = counts.entry(target.clone()).or_insert(0);
+        *merged += count;
+        queue.push(Reverse((*merged, Reverse(target.len()), target)));
+    }
+    counts
+        .into_iter()
+        .map(|(frames, count)| format!("{} {}", frames.join(";"), count))
+        .sorted()
+        .chain(unparsed)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cap_lines, MERGED_FRAME};
+    use itertools::Itertools;
+    use proptest::prelude::*;
+
+    fn total(lines: &[String]) -> usize {
+        lines
+            .iter()
+            .map(|line| line.rsplit_once(' ').unwrap().1.parse::<usize>().unwrap())
+            .sum()
+    }
+
+    #[test]
+    fn smallest_callstacks_merge_into_callers() {
+        let lines: Vec<String> = ["a;b;c 1000", "a;b;d 3", "a;b;e 2", "a;f 1"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(cap_lines(lines.clone(), 4), lines);
+        let merged = |prefix: &str, count| format!("{}{} {}", prefix, MERGED_FRAME, count);
+        let sorted = |lines: Vec<String>| lines.into_iter().sorted().collect::<Vec<_>>();
+        assert_eq!(
+            sorted(cap_lines(lines.clone(), 3)),
+            sorted(vec![
+                "a;b;c 1000".to_string(),
+                "a;b;d 3".to_string(),
+                merged("", 3)
+            ])
+        );
+        assert_eq!(
+            sorted(cap_lines(lines.clone(), 2)),
+            sorted(vec!["a;b;c 1000".to_string(), merged("", 6)])
+        );
+        assert_eq!(cap_lines(lines, 1), vec![merged("", 1006)]);
+
+        // Source code lines go with their frame:
+        let lines = vec![
+            "a.py:1 (f);\u{2800}f();b.py:2 (g);\u{2800}g() 10".to_string(),
+            "a.py:1 (f);\u{2800}f();b.py:3 (h);\u{2800}h() 1".to_string(),
+            merged("a.py:1 (f);\u{2800}f();", 5),
+        ];
+        assert_eq!(
+            sorted(cap_lines(lines, 2)),
+            sorted(vec![
+                "a.py:1 (f);\u{2800}f();b.py:2 (g);\u{2800}g() 10".to_string(),
+                merged("a.py:1 (f);\u{2800}f();", 6),
+            ])
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn totals_are_kept(
+            stacks in prop::collection::vec(
+                (prop::collection::vec(0..5u8, 1..6), 1..1000usize), 1..200),
+            max_lines in 1..50usize,
+        ) {
+            let lines: Vec<String> = stacks
+                .iter()
+                .map(|(frames, count)| {
+                    format!("{} {}", frames.iter().map(|f| format!("f{}", f)).join(";"), count)
+                })
+                .collect();
+            let capped = cap_lines(lines.clone(), max_lines);
+            prop_assert!(capped.len() <= max_lines);
+            prop_assert_eq!(total(&capped), total(&lines));
+        }
+    }
+}
@@ -0,0 +1,21 @@
+// Errors that can't be returned to whoever caused them. Reports are often
+// written from an atexit() handler or a signal handler in the profiled
+// program, where there's nobody to return an error to, and panicking would
+// take the host down with us. So failures are printed to stderr as usual, and
+// the most recent one is also kept here, for the Python API (and other
+// embedders) to ask about afterwards.
+use std::sync::Mutex;
+
+static LAST_ERROR: Mutex<Option<String>> = Mutex::new(None);
+
+/// Remember an error, replacing any earlier one.
+pub fn record_error(message: &str) {
+    if let Ok(mut last_error) = LAST_ERROR.lock() {
+        *last_error = Some(message.to_string());
+    }
+}
+
+/// The most recent error, if any; it's forgotten once taken.
+pub fn take_last_error() -> Option<String> {
+    LAST_ERROR.lock().ok()?.take()
+}
@@ -0,0 +1,288 @@
+// Last-chance hooks that try to write out a report before the process dies,
+// so that a crash hours into a long job doesn't lose all the profiling data.
+//
+// Everything here is best-effort: by the time we're running, the process may
+// be in a bad state, so the dump may well fail too.
+//
+// Fatal signals like SIGSEGV don't get a report: the handler can only safely
+// make async-signal-safe calls, and writing a report needs locks and the GIL,
+// which the crashing thread may well be holding.
+use once_cell::sync::OnceCell;
+use std::os::raw::{c_int, c_void};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::Once;
+
+/// Writes out a report, given a short description of why we're doing so.
+pub type DumpCallback = fn(&str);
+
+static DUMP_CALLBACK: OnceCell<DumpCallback> = OnceCell::new();
+
+/// We only want one emergency dump per process, e.g. a panic followed by an
+/// abort() shouldn't dump twice.
+static DUMPED: AtomicBool = AtomicBool::new(false);
+
+/// Signals that mean the process is crashing.
+const FATAL_SIGNALS: &[c_int] = &[
+    libc::SIGSEGV,
+    libc::SIGBUS,
+    libc::SIGABRT,
+    libc::SIGFPE,
+    libc::SIGILL,
+];
+
+/// Printed when a fatal signal arrives, so it's clear why there's no report.
+const FATAL_SIGNAL_MESSAGE: &[u8] =
+    b"=fil-profile= Process crashed with a fatal signal, so no report was written.\n";
+
+/// Signals that politely ask the process to exit, e.g. when a batch scheduler
+/// preempts a job. Not SIGINT: Python turns that into KeyboardInterrupt, which
+/// programs often catch and carry on.
+const TERMINATION_SIGNALS: &[c_int] = &[libc::SIGTERM];
+
+/// Write end of the pipe that wakes up the thread dumping on termination
+/// signals, or -1 if there's no such thread.
+static TERMINATION_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_HANDLER: OnceCell<libc::sigaction> = OnceCell::new();
+
+/// Signal handlers that were installed before ours, indexed by signal number,
+/// so we can hand over to them once we're done.
+static PREVIOUS_HANDLERS: [OnceCell<libc::sigaction>; 32] = [NO_HANDLER; 32];
+
+/// Set the function used to write out emergency reports. Only the first call
+/// has any effect.
+pub fn set_dump_callback(callback: DumpCallback) {
+    let _ = DUMP_CALLBACK.set(callback);
+}
+
+/// Write out a report using the registered callback, unless that already
+/// happened. Returns whether the callback was called.
+pub fn emergency_dump(reason: &str) -> bool {
+    if DUMPED.swap(true, Ordering::SeqCst) {
+        return false;
+    }
+    match DUMP_CALLBACK.get() {
+        Some(callback) => {
+            callback(reason);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Stop any further emergency dumps, e.g. because a report was written some
+/// other way and we're about to exit.
+pub fn disarm() {
+    DUMPED.store(true, Ordering::SeqCst);
+}
+
+/// Dump when the process exits via exit() without a report having been
+/// written, e.g. because a C extension called exit() directly. Normal Python
+/// shutdown writes its own report first, so this won't do anything then.
+pub fn install_exit_handler() {
+    static INSTALLED: Once = Once::new();
+    INSTALLED.call_once(|| {
+        if unsafe { libc::atexit(on_exit) } != 0 {
+            eprintln!("=fil-profile= WARNING: Couldn't register atexit() handler");
+        }
+    });
+}
+
+extern "C" fn on_exit() {
+    emergency_dump(EXIT_REASON);
+}
+
+/// Reason given when dumping because the process is exiting. _exit(), which
+/// skips atexit() handlers, needs to be intercepted by the caller.
+pub const EXIT_REASON: &str = "Process is exiting without having written a report";
+
+/// Dump when a Rust panic happens, after the usual panic message is printed.
+pub fn install_panic_hook() {
+    static INSTALLED: Once = Once::new();
+    INSTALLED.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            previous(info);
+            emergency_dump("Rust code panicked");
+        }));
+    });
+}
+
+/// On fatal signals like SIGSEGV, say that no report is coming, then pass the
+/// signal on to whatever handler was there before (e.g. Python's
+/// faulthandler).
+pub fn install_fatal_signal_handlers() {
+    static INSTALLED: Once = Once::new();
+    INSTALLED.call_once(|| {
+        for signal in FATAL_SIGNALS {
+            unsafe { install_signal_handler(*signal, on_fatal_signal) };
+        }
+    });
+}
+
+/// Dump on SIGTERM, then let the signal do whatever it would have done
+/// otherwise, typically exit.
+///
+/// The signal may well arrive in the middle of malloc(), so writing a report
+/// from the signal handler could deadlock. Instead the handler just writes to
+/// a pipe, waking up a thread that does the dump; `on_thread_start` runs on
+/// that thread first, e.g. to stop its own allocations being tracked.
+pub fn install_termination_signal_handlers<F: FnOnce() + Send + 'static>(on_thread_start: F) {
+    static INSTALLED: Once = Once::new();
+    INSTALLED.call_once(|| {
+        let mut fds: [c_int; 2] = [-1, -1];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            eprintln!("=fil-profile= WARNING: Couldn't create pipe for termination signals");
+            return;
+        }
+        let (read_fd, write_fd) = (fds[0], fds[1]);
+        let spawned = std::thread::Builder::new()
+            .name("fil-terminate".to_string())
+            .spawn(move || {
+                on_thread_start();
+                dump_on_termination(read_fd);
+            });
+        if spawned.is_err() {
+            eprintln!("=fil-profile= WARNING: Couldn't start thread for termination signals");
+            unsafe {
+                libc::close(read_fd);
+                libc::close(write_fd);
+            }
+            return;
+        }
+        TERMINATION_PIPE.store(write_fd, Ordering::SeqCst);
+        for signal in TERMINATION_SIGNALS {
+            unsafe { install_signal_handler(*signal, on_termination_signal) };
+        }
+    });
+}
+
+/// Only async-signal-safe calls here.
+extern "C" fn on_termination_signal(signal: c_int) {
+    let fd = TERMINATION_PIPE.load(Ordering::SeqCst);
+    let byte = signal as u8;
+    if fd < 0 || unsafe { libc::write(fd, &byte as *const u8 as *const c_void, 1) } != 1 {
+        // Can't dump, so behave as if we weren't here:
+        unsafe { forward_signal(signal) };
+    }
+}
+
+/// Wait for a termination signal, then dump and pass the signal on. The dump
+/// needs the GIL, which is taken before the tracker lock; see python.rs.
+fn dump_on_termination(read_fd: c_int) {
+    let mut byte: u8 = 0;
+    loop {
+        match unsafe { libc::read(read_fd, &mut byte as *mut u8 as *mut c_void, 1) } {
+            1 => break,
+            -1 if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted => {
+                continue
+            }
+            _ => return,
+        }
+    }
+    let signal = byte as c_int;
+    let reason = format!("Received {}", signal_name(signal));
+    crate::python::with_gil_if_running(|| emergency_dump(&reason));
+    unsafe { forward_signal(signal) };
+}
+
+/// Only async-signal-safe calls here.
+extern "C" fn on_fatal_signal(signal: c_int) {
+    unsafe {
+        libc::write(
+            2,
+            FATAL_SIGNAL_MESSAGE.as_ptr() as *const c_void,
+            FATAL_SIGNAL_MESSAGE.len(),
+        );
+        forward_signal(signal);
+    }
+}
+
+fn signal_name(signal: c_int) -> String {
+    match signal {
+        libc::SIGSEGV => "SIGSEGV".to_string(),
+        libc::SIGBUS => "SIGBUS".to_string(),
+        libc::SIGABRT => "SIGABRT".to_string(),
+        libc::SIGFPE => "SIGFPE".to_string(),
+        libc::SIGILL => "SIGILL".to_string(),
+        libc::SIGTERM => "SIGTERM".to_string(),
+        _ => format!("signal {}", signal),
+    }
+}
+
+/// Install a handler for a signal, remembering the previous one.
+///
+/// # Safety
+/// The handler must be reasonably safe to run in signal context.
+unsafe fn install_signal_handler(signal: c_int, handler: extern "C" fn(c_int)) {
+    let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+    action.sa_sigaction = handler as libc::sighandler_t;
+    action.sa_flags = libc::SA_ONSTACK;
+    let mut previous: libc::sigaction = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(signal, &action, &mut previous) != 0 {
+            eprintln!(
+                "=fil-profile= WARNING: Couldn't install handler for signal {}",
+                signal
+            );
+            return;
+        }
+    }
+    let _ = PREVIOUS_HANDLERS[signal as usize].set(previous);
+}
+
+/// Restore the handler we replaced for a signal, and re-raise the signal so
+/// it gets handled the way it would have been without us.
+///
+/// # Safety
+/// Only call for a signal whose handler was installed by
+/// install_signal_handler(), from that handler or once it has run.
+unsafe fn forward_signal(signal: c_int) {
+    unsafe {
+        match PREVIOUS_HANDLERS[signal as usize].get() {
+            Some(previous) => {
+                libc::sigaction(signal, previous, std::ptr::null_mut());
+            }
+            None => {
+                libc::signal(signal, libc::SIG_DFL);
+            }
+        }
+        libc::raise(signal);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{emergency_dump, install_panic_hook, set_dump_callback, signal_name};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static DUMPS: AtomicUsize = AtomicUsize::new(0);
+
+    fn count_dump(_reason: &str) {
+        DUMPS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn panic_dumps_only_once() {
+        set_dump_callback(count_dump);
+        install_panic_hook();
+        assert!(std::panic::catch_unwind(|| panic!("oh no")).is_err());
+        assert_eq!(DUMPS.load(Ordering::SeqCst), 1);
+        // Later attempts don't dump again:
+        assert!(!emergency_dump("again"));
+        assert!(std::panic::catch_unwind(|| panic!("oh no")).is_err());
+        assert_eq!(DUMPS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn signal_names() {
+        assert_eq!(signal_name(libc::SIGTERM), "SIGTERM");
+        assert_eq!(signal_name(libc::SIGSEGV), "SIGSEGV");
+        assert_eq!(
+            signal_name(libc::SIGUSR1),
+            format!("signal {}", libc::SIGUSR1)
+        );
+    }
+}
@@ -0,0 +1,300 @@
+// Filtering of callstacks at dump time. Regex filtering focuses on the
+// callstacks going through one library, or drops importlib frames that just
+// add noise; it produces additional outputs, leaving the raw data alone.
+// Collapsing removes decorator wrapper frames from all outputs, and merging
+// lines turns every output into a function-level view.
+use regex::Regex;
+
+use crate::report::ReportOptions;
+use crate::snapshot::{CallstackUsage, Frame, ProfileData};
+
+pub struct StackFilter {
+    include: Option<Regex>,
+    exclude_frames: Option<Regex>,
+}
+
+impl StackFilter {
+    /// The filter configured in the options, if any.
+    pub fn from_options(options: &ReportOptions) -> Result<Option<Self>, String> {
+        let compile = |pattern: &Option<String>| {
+            pattern
+                .as_deref()
+                .map(Regex::new)
+                .transpose()
+                .map_err(|e| format!("invalid regex: {}", e))
+        };
+        let include = compile(&options.include_stacks)?;
+        let exclude_frames = compile(&options.exclude_frames)?;
+        if include.is_none() && exclude_frames.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            include,
+            exclude_frames,
+        }))
+    }
+
+    /// Frames are matched as `filename:line (function)`, with the full
+    /// filename.
+    fn matches(regex: &Regex, frame: &Frame) -> bool {
+        regex.is_match(&format!(
+            "{}:{} ({})",
+            frame.filename, frame.line_number, frame.function
+        ))
+    }
+
+    /// Keep only callstacks with a frame matching the include regex, then
+    /// drop frames matching the exclude regex. Callstacks that end up the
+    /// same are merged.
+    pub fn apply(&self, data: &ProfileData) -> ProfileData {
+        let mut callstacks: Vec<CallstackUsage> = vec![];
+        for usage in &data.callstacks {
+            if let Some(include) = &self.include {
+                if !usage
+                    .frames
+                    .iter()
+                    .any(|frame| Self::matches(include, frame))
+                {
+                    continue;
+                }
+            }
+            let frames: Vec<Frame> = match &self.exclude_frames {
+                Some(exclude) => usage
+                    .frames
+                    .iter()
+                    .filter(|frame| !Self::matches(exclude, frame))
+                    .cloned()
+                    .collect(),
+                None => usage.frames.clone(),
+            };
+            callstacks.push(CallstackUsage {
+                frames,
+                ..usage.clone()
+            });
+        }
+        let callstacks = merge_identical(callstacks);
+        ProfileData {
+            peak_bytes: callstacks.iter().map(|usage| usage.peak_bytes).sum(),
+            current_bytes: callstacks.iter().map(|usage| usage.current_bytes).sum(),
+            peak_allocations: callstacks.iter().map(|usage| usage.peak_allocations).sum(),
+            callstacks,
+            ..data.clone()
+        }
+    }
+}
+
+/// Removes frames whose function matches any of a list of patterns, e.g. the
+/// `wrapper` and `inner` functions added by decorators, so the frames on
+/// either side of them end up adjacent.
+pub struct FrameCollapser {
+    pattern: Regex,
+}
+
+impl FrameCollapser {
+    /// Patterns match the whole qualified function name, and `*` matches
+    /// anything, e.g. `*.<locals>.wrapper`.
+    pub fn new(patterns: &[String]) -> Option<Self> {
+        if patterns.is_empty() {
+            return None;
+        }
+        let pattern = patterns
+            .iter()
+            .map(|pattern| regex::escape(pattern).replace(r"\*", ".*"))
+            .collect::<Vec<_>>()
+            .join("|");
+        // Escaped input is always a valid regex:
+        let pattern = Regex::new(&format!("^(?:{})$", pattern)).ok()?;
+        Some(Self { pattern })
+    }
+
+    /// Collapse matching frames. A callstack where every frame matches is
+    /// left alone, rather than becoming empty. Totals don't change.
+    pub fn apply(&self, data: &ProfileData) -> ProfileData {
+        let callstacks = data
+            .callstacks
+            .iter()
+            .map(|usage| {
+                let frames: Vec<Frame> = usage
+                    .frames
+                    .iter()
+                    .filter(|frame| !self.pattern.is_match(&frame.function))
+                    .cloned()
+                    .collect();
+                CallstackUsage {
+                    frames: if frames.is_empty() {
+                        usage.frames.clone()
+                    } else {
+                        frames
+                    },
+                    ..usage.clone()
+                }
+            })
+            .collect();
+        ProfileData {
+            callstacks: merge_identical(callstacks),
+            ..data.clone()
+        }
+    }
+}
+
+/// Drop line numbers, so calls from different lines of the same function
+/// become the same frame: a function-level view rather than a line-level
+/// one. Frames end up on line 0, which is shown without a line number.
+/// Totals don't change.
+pub fn merge_lines(data: &ProfileData) -> ProfileData {
+    let callstacks = data
+        .callstacks
+        .iter()
+        .map(|usage| CallstackUsage {
+            frames: usage
+                .frames
+                .iter()
+                .map(|frame| Frame {
+                    line_number: 0,
+                    ..frame.clone()
+                })
+                .collect(),
+            ..usage.clone()
+        })
+        .collect();
+    ProfileData {
+        callstacks: merge_identical(callstacks),
+        ..data.clone()
+    }
+}
+
+/// Sort callstacks, and merge those with the same frames.
+fn merge_identical(mut callstacks: Vec<CallstackUsage>) -> Vec<CallstackUsage> {
+    callstacks.sort_by(|a, b| a.frames.cmp(&b.frames));
+    callstacks.dedup_by(|later, earlier| {
+        if later.frames != earlier.frames {
+            return false;
+        }
+        earlier.peak_bytes += later.peak_bytes;
+        earlier.current_bytes += later.current_bytes;
+        earlier.peak_allocations += later.peak_allocations;
+        earlier.allocations_at_peak += later.allocations_at_peak;
+        earlier.heap_peak_bytes += later.heap_peak_bytes;
+        earlier.sizes.merge(&later.sizes);
+        // The rates may come from different windows, so adding them could
+        // overstate things:
+        earlier.peak_allocation_rate = earlier.peak_allocation_rate.max(later.peak_allocation_rate);
+        true
+    });
+    callstacks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{merge_lines, FrameCollapser, StackFilter};
+    use crate::folded::parse_folded_str;
+    use crate::report::ReportOptions;
+
+    #[test]
+    fn include_and_exclude() {
+        let data = parse_folded_str(
+            "a.py:1 (main);<frozen importlib._bootstrap>:5 (_load);np.py:2 (zeros) 100\n\
+             a.py:1 (main);np.py:2 (zeros) 50\n\
+             a.py:1 (main);b.py:3 (other) 10\n",
+        )
+        .unwrap();
+        let options = ReportOptions {
+            include_stacks: Some(r"\(zeros\)".to_string()),
+            exclude_frames: Some("importlib".to_string()),
+            ..ReportOptions::default()
+        };
+        let filtered = StackFilter::from_options(&options)
+            .unwrap()
+            .unwrap()
+            .apply(&data);
+        // The two zeros() callstacks are the same once importlib is gone:
+        assert_eq!(filtered.callstacks.len(), 1);
+        assert_eq!(filtered.callstacks[0].frames.len(), 2);
+        assert_eq!(filtered.peak_bytes, 150);
+        // The original is untouched:
+        assert_eq!(data.callstacks.len(), 3);
+
+        assert!(StackFilter::from_options(&ReportOptions::default())
+            .unwrap()
+            .is_none());
+        let bad = ReportOptions {
+            include_stacks: Some("(".to_string()),
+            ..ReportOptions::default()
+        };
+        assert!(StackFilter::from_options(&bad).is_err());
+    }
+
+    #[test]
+    fn collapse_wrappers() {
+        let data = parse_folded_str(
+            "a.py:1 (main);d.py:5 (logged.<locals>.wrapper);b.py:2 (load) 100\n\
+             a.py:1 (main);d.py:9 (timed.<locals>.wrapper);b.py:2 (load) 50\n\
+             d.py:5 (logged.<locals>.wrapper) 10\n\
+             a.py:1 (main);c.py:3 (wrapper_factory) 1\n",
+        )
+        .unwrap();
+        assert!(FrameCollapser::new(&[]).is_none());
+        let collapsed = FrameCollapser::new(&["*.<locals>.wrapper".to_string()])
+            .unwrap()
+            .apply(&data);
+        let stacks: Vec<_> = collapsed
+            .callstacks
+            .iter()
+            .map(|usage| {
+                (
+                    usage
+                        .frames
+                        .iter()
+                        .map(|frame| frame.function.as_str())
+                        .collect::<Vec<_>>(),
+                    usage.peak_bytes,
+                )
+            })
+            .collect();
+        assert_eq!(
+            stacks,
+            vec![
+                (vec!["logged.<locals>.wrapper"], 10),
+                (vec!["main", "load"], 150),
+                (vec!["main", "wrapper_factory"], 1),
+            ]
+        );
+        assert_eq!(collapsed.peak_bytes, data.peak_bytes);
+    }
+
+    #[test]
+    fn merge_line_numbers() {
+        let data = parse_folded_str(
+            "a.py:1 (main);b.py:2 (load) 100\n\
+             a.py:1 (main);b.py:7 (load) 50\n\
+             a.py:4 (main);b.py:2 (load);b.py:2 (load) 5\n\
+             a.py:1 (main);c.py:3 (parse) 1\n",
+        )
+        .unwrap();
+        let merged = merge_lines(&data);
+        let stacks: Vec<_> = merged
+            .callstacks
+            .iter()
+            .map(|usage| {
+                (
+                    usage
+                        .frames
+                        .iter()
+                        .map(|frame| (frame.function.as_str(), frame.line_number))
+                        .collect::<Vec<_>>(),
+                    usage.peak_bytes,
+                )
+            })
+            .collect();
+        // Recursive calls are still separate frames:
+        assert_eq!(
+            stacks,
+            vec![
+                (vec![("main", 0), ("load", 0)], 150),
+                (vec![("main", 0), ("load", 0), ("load", 0)], 5),
+                (vec![("main", 0), ("parse", 0)], 1),
+            ]
+        );
+        assert_eq!(merged.peak_bytes, data.peak_bytes);
+    }
+}
@@ -1,8 +1,14 @@
-use std::{fs, io::Write, path::Path};
+use std::io::Write;
 
+use filrender::{flamegraph_options, ProfHeader, RenderOptions};
 use inferno::flamegraph;
 use itertools::Itertools;
 
+use crate::downsample::cap_lines;
+use crate::report::{Artifacts, FramePruning};
+use crate::sink::OutputSink;
+use crate::units::{Scale, Units};
+
 /// Filter down to top 99% of samples.
 ///
 /// 1. Empty samples are dropped.
@@ -48,37 +54,43 @@ where
         )
 }
 
-/// Write strings to disk, one line per string.
-pub fn write_lines<I: IntoIterator<Item = String>>(lines: I, path: &Path) -> std::io::Result<()> {
-    let mut file = std::fs::File::create(path)?;
+/// Write strings to a sink, one line per string.
+pub fn write_lines<I: IntoIterator<Item = String>>(
+    lines: I,
+    sink: &mut dyn OutputSink,
+    name: &str,
+) -> std::io::Result<()> {
+    let mut contents = vec![];
     for line in lines {
-        file.write_all(line.as_bytes())?;
-        file.write_all(b"\n")?;
+        contents.write_all(line.as_bytes())?;
+        contents.write_all(b"\n")?;
     }
-    file.flush()?;
-    Ok(())
+    sink.write_file(name, &contents)
 }
 
-/// Write a flamegraph SVG to disk, given lines in summarized format.
+/// Write a flamegraph SVG to a sink, given lines in summarized format.
+#[allow(clippy::too_many_arguments)]
 pub fn write_flamegraph<I: IntoIterator<Item = String>>(
     lines: I,
-    path: &Path,
+    sink: &mut dyn OutputSink,
+    name: &str,
     reversed: bool,
     title: &str,
     subtitle: &str,
-    count_name: &str,
+    units: &Units,
     to_be_post_processed: bool,
+    pruning: &FramePruning,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let flamegraph = get_flamegraph(
         lines,
         reversed,
         title,
         subtitle,
-        count_name,
+        units,
         to_be_post_processed,
+        pruning,
     )?;
-    let mut file = std::fs::File::create(path)?;
-    file.write_all(&flamegraph)?;
+    sink.write_file(name, &flamegraph)?;
     Ok(())
 }
 
@@ -88,28 +100,30 @@ pub fn get_flamegraph<I: IntoIterator<Item = String>>(
     reversed: bool,
     title: &str,
     subtitle: &str,
-    count_name: &str,
+    units: &Units,
     to_be_post_processed: bool,
+    pruning: &FramePruning,
 ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    let title = format!("{}{}", title, if reversed { ", Reversed" } else { "" },);
-    let mut options = flamegraph::Options::default();
-    options.title = title;
-    options.count_name = count_name.to_string();
-    options.font_size = 16;
-    options.font_type = "monospace".to_string();
-    options.frame_height = 22;
-    options.reverse_stack_order = reversed;
-    options.color_diffusion = true;
-    options.direction = flamegraph::Direction::Inverted;
-    // Maybe disable this some day; but for now it makes debugging much
-    // easier:
-    options.pretty_xml = true;
-    if to_be_post_processed {
+    let lines: Vec<String> = lines.into_iter().collect();
+    // The count is at the end, after the last space:
+    let total: usize = lines
+        .iter()
+        .filter_map(|line| line.rsplit_once(' ')?.1.parse::<usize>().ok())
+        .sum();
+    let mut options = flamegraph_options(&RenderOptions {
+        title: title.to_string(),
         // Can't put structured text into subtitle, so have to do a hack.
-        options.subtitle = Some("__FIL-SUBTITLE-HERE__".to_string());
-    }
+        subtitle: if to_be_post_processed {
+            Some("__FIL-SUBTITLE-HERE__".to_string())
+        } else {
+            None
+        },
+        count_name: units.count_name.clone(),
+        reversed,
+        min_width: pruning.min_width(total, &units.count_name),
+        ..RenderOptions::default()
+    })?;
     let mut output = vec![];
-    let lines: Vec<String> = lines.into_iter().collect();
     match flamegraph::from_lines(&mut options, lines.iter().map(|s| s.as_ref()), &mut output) {
         Err(e) => Err(format!("{}", e).into()),
         Ok(_) => {
@@ -125,89 +139,98 @@ pub fn get_flamegraph<I: IntoIterator<Item = String>>(
                 let data = data.replace("\u{2800}", "");
                 output = data.as_bytes().to_vec();
             }
+            if units.scale != Scale::Raw {
+                output = units
+                    .rewrite_tooltips(&String::from_utf8(output)?)
+                    .into_bytes();
+            }
             Ok(output)
         }
     }
 }
 
-/// Write .prof, -source.prof, .svg and -reversed.svg files for given lines.
+/// The timestamp for .prof headers. SOURCE_DATE_EPOCH overrides the current
+/// time, as is usual for reproducible outputs, so identical runs can still
+/// give identical files.
+fn prof_timestamp() -> u64 {
+    std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or(0)
+        })
+}
+
+/// Write whichever of the .prof, .svg and -reversed.svg files are selected,
+/// for given lines.
+#[allow(clippy::too_many_arguments)]
 pub fn write_flamegraphs<I, F>(
-    directory_path: &Path,
+    sink: &mut dyn OutputSink,
+    artifacts: &Artifacts,
     base_filename: &str,
     title: &str,
     subtitle: &str,
-    count_name: &str,
+    units: &Units,
     to_be_post_processed: bool,
+    pruning: &FramePruning,
     get_lines: F,
 ) where
     I: IntoIterator<Item = String>,
     F: Fn(bool) -> I, // (to_be_post_processed) -> lines
 {
-    if !directory_path.exists() {
-        fs::create_dir_all(directory_path)
-            .expect("=fil-profile= Couldn't create the output directory.");
-    } else if !directory_path.is_dir() {
-        panic!("=fil-profile= Output path must be a directory.");
-    }
-
-    let raw_path_without_source_code = directory_path.join(format!("{}.prof", base_filename));
-
-    let raw_path_with_source_code = directory_path.join(format!("{}-source.prof", base_filename));
-
-    // Always write .prof file without source code, for use by tests and
-    // other automated post-processing.
-    if let Err(e) = write_lines(get_lines(false), &raw_path_without_source_code) {
-        eprintln!("=fil-profile= Error writing raw profiling data: {}", e);
-        return;
-    }
-
-    // Optionally write version with source code for SVGs, if we're using
-    // source code.
-    if to_be_post_processed {
-        if let Err(e) = write_lines(get_lines(true), &raw_path_with_source_code) {
+    let get_lines = |to_be_post_processed| {
+        let lines: Vec<String> = get_lines(to_be_post_processed).into_iter().collect();
+        match pruning.max_lines {
+            Some(max_lines) => cap_lines(lines, max_lines),
+            None => lines,
+        }
+    };
+    // The .prof file is without source code, for use by tests and other
+    // automated post-processing. It's sorted, so identical runs give
+    // identical files that can be diffed.
+    if artifacts.prof {
+        // Header fields are separated by spaces:
+        let header = ProfHeader::new(&units.count_name.replace(' ', "_"), prof_timestamp());
+        let lines = std::iter::once(header.to_line()).chain(get_lines(false).into_iter().sorted());
+        if let Err(e) = write_lines(lines, sink, &format!("{}.prof", base_filename)) {
             eprintln!("=fil-profile= Error writing raw profiling data: {}", e);
             return;
         }
     }
 
-    let svg_path = directory_path.join(format!("{}.svg", base_filename));
-    match write_flamegraph(
-        get_lines(to_be_post_processed),
-        &svg_path,
-        false,
-        title,
-        subtitle,
-        count_name,
-        to_be_post_processed,
-    ) {
-        Ok(_) => {
-            eprintln!("=fil-profile= Wrote flamegraph to {:?}", svg_path);
-        }
-        Err(e) => {
-            eprintln!("=fil-profile= Error writing SVG: {}", e);
+    for (suffix, reversed, wanted) in [
+        ("", false, artifacts.flamegraph),
+        ("-reversed", true, artifacts.reversed),
+    ] {
+        if !wanted {
+            continue;
         }
-    }
-    let svg_path = directory_path.join(format!("{}-reversed.svg", base_filename));
-    match write_flamegraph(
-        get_lines(to_be_post_processed),
-        &svg_path,
-        true,
-        title,
-        subtitle,
-        count_name,
-        to_be_post_processed,
-    ) {
-        Ok(_) => {
-            eprintln!("=fil-profile= Wrote flamegraph to {:?}", svg_path);
-        }
-        Err(e) => {
-            eprintln!("=fil-profile= Error writing SVG: {}", e);
+        let svg_name = format!("{}{}.svg", base_filename, suffix);
+        match write_flamegraph(
+            get_lines(to_be_post_processed),
+            sink,
+            &svg_name,
+            reversed,
+            title,
+            subtitle,
+            units,
+            to_be_post_processed,
+            pruning,
+        ) {
+            Ok(_) => {
+                eprintln!(
+                    "=fil-profile= Wrote flamegraph to {}",
+                    sink.describe(&svg_name)
+                );
+            }
+            Err(e) => {
+                eprintln!("=fil-profile= Error writing SVG: {}", e);
+            }
         }
     }
-    if to_be_post_processed {
-        // Don't need this file, and it'll be quite big, so delete it.
-        let _ = std::fs::remove_file(raw_path_with_source_code);
-    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,279 @@
+// Loading previously written .prof files back into a ProfileData, so merging,
+// diffing, filtering and re-rendering can work on old profiles and not just
+// on a live tracker. The .prof format only has what the flamegraphs show, so
+// the result is lossy: filenames are as displayed, there are no modules, and
+// only the one count per callstack that was written.
+use std::path::Path;
+
+use filrender::{parse_prof, unescape_frame_text};
+
+use crate::compression::{self, GZIP_SUFFIX};
+use crate::memorytracking::LineNumber;
+use crate::metadata::ProcessMetadata;
+use crate::metric::MEMORY;
+use crate::reporting::NO_PYTHON_STACK;
+use crate::snapshot::{CallstackUsage, Frame, ProfileData};
+
+/// Load a .prof file, which may be gzipped. If the `-metadata.json` file
+/// written alongside it exists, the process metadata is loaded from there.
+pub fn parse_folded<P: AsRef<Path>>(path: P) -> Result<ProfileData, String> {
+    let path = path.as_ref();
+    let prof = compression::read_to_string(path)
+        .map_err(|e| format!("couldn't read {}: {}", path.display(), e))?;
+    let mut data = parse_folded_str(&prof)?;
+    let base = path.to_str().map(|p| p.trim_end_matches(GZIP_SUFFIX));
+    if let Some(base) = base.and_then(|p| p.strip_suffix(".prof")) {
+        if let Ok(metadata) = compression::read_to_string(format!("{}-metadata.json", base)) {
+            let value = serde_json::from_str(&metadata)
+                .map_err(|e| format!("invalid process metadata: {}", e))?;
+            data.metadata = ProcessMetadata::from_json(&value)?;
+        }
+    }
+    Ok(data)
+}
+
+/// Parse the contents of a .prof file. Counts go into both peak and current
+/// bytes, or into peak allocations if that's what was counted, so the result
+/// renders the same either way.
+pub fn parse_folded_str(prof: &str) -> Result<ProfileData, String> {
+    let (header, lines) = parse_prof(prof)?;
+    let counts_allocations = match header.units.as_str() {
+        "bytes" => false,
+        "allocations" => true,
+        units => return Err(format!("unsupported .prof units: {}", units)),
+    };
+    let mut callstacks = vec![];
+    for line in lines.filter(|line| !line.trim().is_empty()) {
+        let (stack, count) = line
+            .rsplit_once(' ')
+            .ok_or_else(|| format!("missing count: {}", line))?;
+        let count: usize = count
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid count: {}", line))?;
+        let frames = if stack == NO_PYTHON_STACK {
+            vec![]
+        } else {
+            stack
+                .split(';')
+                .map(|frame| parse_frame(frame, header.version))
+                .collect::<Result<_, _>>()?
+        };
+        callstacks.push(if counts_allocations {
+            CallstackUsage {
+                frames,
+                peak_allocations: count,
+                ..CallstackUsage::default()
+            }
+        } else {
+            CallstackUsage {
+                frames,
+                peak_bytes: count,
+                current_bytes: count,
+                // There's no telling mmap() apart:
+                heap_peak_bytes: count,
+                ..CallstackUsage::default()
+            }
+        });
+    }
+    callstacks.sort_by(|a, b| a.frames.cmp(&b.frames));
+    Ok(ProfileData {
+        resource: MEMORY.to_string(),
+        peak_bytes: callstacks.iter().map(|usage| usage.peak_bytes).sum(),
+        current_bytes: callstacks.iter().map(|usage| usage.current_bytes).sum(),
+        peak_allocations: callstacks.iter().map(|usage| usage.peak_allocations).sum(),
+        callstacks,
+        missing_bytes: 0,
+        failed_deallocations: 0,
+        accounting_errors: 0,
+        metadata: ProcessMetadata {
+            argv: vec![],
+            environment: vec![],
+            pid: 0,
+            hostname: String::new(),
+            start_time: header.timestamp.unwrap_or(0),
+            child_output_paths: vec![],
+        },
+        numa: None,
+        function_calls: vec![],
+        lifetime_pairs: vec![],
+        interpreters: vec![],
+        profiler_memory: Default::default(),
+        address_space: Default::default(),
+        locked_memory: Default::default(),
+        allocation_ages: vec![],
+        window_peak: None,
+        timeline: Default::default(),
+        heap_peaked_separately: false,
+    })
+}
+
+/// Parse a `filename:line (function)` frame. Either name may contain
+/// parentheses, so the split is at the first ` (` that follows a line
+/// number.
+fn parse_frame(frame: &str, version: u32) -> Result<Frame, String> {
+    let invalid = || format!("invalid frame: {}", frame);
+    let frame_without_paren = frame.strip_suffix(')').ok_or_else(invalid)?;
+    let (filename, line_number, function) = frame_without_paren
+        .match_indices(" (")
+        .find_map(|(index, _)| {
+            let (filename, line_number) = frame_without_paren[..index].rsplit_once(':')?;
+            let line_number = line_number.parse::<LineNumber>().ok()?;
+            Some((filename, line_number, &frame_without_paren[index + 2..]))
+        })
+        // Reports with line numbers merged away have "file (function)":
+        .or_else(|| {
+            if version < 3 {
+                return None;
+            }
+            let (filename, function) = frame_without_paren.split_once(" (")?;
+            Some((filename, 0, function))
+        })
+        .ok_or_else(invalid)?;
+    let unescape = |text: &str| {
+        if version >= 2 {
+            unescape_frame_text(text).into_owned()
+        } else {
+            text.to_string()
+        }
+    };
+    Ok(Frame {
+        function: unescape(function),
+        filename: unescape(filename),
+        line_number,
+        module: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_folded, parse_folded_str};
+    use crate::memorytracking::{
+        AllocationTracker, CallSiteId, Callstack, VecFunctionLocations, PARENT_PROCESS,
+    };
+    use crate::report::{FilenameDisplay, ReportOptions};
+    use crate::reporting::{to_lines, write_report};
+    use crate::sink::DirectorySink;
+
+    #[test]
+    fn parse_what_was_written() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = AllocationTracker::new(".".to_string(), VecFunctionLocations::new());
+        let main = tracker
+            .functions
+            .add_function("/src/main.py".to_string(), "main".to_string());
+        let load = tracker
+            .functions
+            .add_function("/src/data.py".to_string(), "Loader.load".to_string());
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(main, 3));
+        let mut cs2 = cs1.clone();
+        cs2.start_call(0, CallSiteId::new(load, 70));
+        let cs1_id = tracker.get_callstack_id(&cs1);
+        let cs2_id = tracker.get_callstack_id(&cs2);
+        let cs3_id = tracker.get_callstack_id(&Callstack::new());
+        tracker.add_allocation(PARENT_PROCESS, 1, 1000, cs1_id);
+        tracker.add_allocation(PARENT_PROCESS, 2, 3000, cs2_id);
+        tracker.add_allocation(PARENT_PROCESS, 3, 5, cs3_id);
+        let data = tracker.snapshot();
+
+        let directory = std::env::temp_dir().join(format!("fil-folded-{}", std::process::id()));
+        let mut sink = DirectorySink::new(&directory).unwrap();
+        write_report(
+            &data,
+            &ReportOptions::default(),
+            &mut sink,
+            true,
+            "peak-memory",
+            "Peak",
+            false,
+        );
+        let parsed = parse_folded(directory.join("peak-memory.prof")).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(parsed.callstacks.len(), 3);
+        assert_eq!(parsed.peak_bytes, 4005);
+        assert_eq!(parsed.metadata, data.metadata);
+
+        // Compressed reports read back the same, by either name:
+        let options = ReportOptions {
+            compress: true,
+            ..ReportOptions::default()
+        };
+        let mut sink = DirectorySink::new(&directory).unwrap();
+        write_report(
+            &data,
+            &options,
+            &mut sink,
+            true,
+            "peak-memory",
+            "Peak",
+            false,
+        );
+        assert!(!directory.join("peak-memory.prof").exists());
+        assert!(directory.join("peak-memory-metadata.json.gz").exists());
+        let compressed = parse_folded(directory.join("peak-memory.prof")).unwrap();
+        assert_eq!(
+            compressed,
+            parse_folded(directory.join("peak-memory.prof.gz")).unwrap()
+        );
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(compressed, parsed);
+        // Writing it out again gives the same lines:
+        let display = FilenameDisplay::default();
+        let mut original = to_lines(&data, true, false, &display);
+        let mut reparsed = to_lines(&parsed, true, false, &display);
+        original.sort();
+        reparsed.sort();
+        assert_eq!(original, reparsed);
+    }
+
+    #[test]
+    fn parse_merged_lines() {
+        let parsed =
+            parse_folded_str("# fil-prof version=3\na.py (main);b.py:7 (load) 10\n").unwrap();
+        let frames = &parsed.callstacks[0].frames;
+        assert_eq!(
+            (frames[0].filename.as_str(), frames[0].line_number),
+            ("a.py", 0)
+        );
+        assert_eq!(frames[0].function, "main");
+        assert_eq!(frames[1].line_number, 7);
+        let lines = to_lines(&parsed, true, false, &FilenameDisplay::default());
+        assert_eq!(lines, vec!["a.py (main);b.py:7 (load) 10".to_string()]);
+    }
+
+    #[test]
+    fn delimiters_in_names() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = AllocationTracker::new(".".to_string(), VecFunctionLocations::new());
+        let odd = tracker.functions.add_function(
+            "/My Files (old)/a;b.py".to_string(),
+            "f (x)\n<listcomp>".to_string(),
+        );
+        let mut callstack = Callstack::new();
+        callstack.start_call(0, CallSiteId::new(odd, 3));
+        let callstack_id = tracker.get_callstack_id(&callstack);
+        tracker.add_allocation(PARENT_PROCESS, 1, 1000, callstack_id);
+        let data = tracker.snapshot();
+        let lines = to_lines(&data, true, false, &FilenameDisplay::default());
+        assert_eq!(lines.len(), 1);
+        assert!(!lines[0].contains(';') && !lines[0].contains('\n'));
+        let parsed = parse_folded_str(&format!("# fil-prof version=2\n{}", lines[0])).unwrap();
+        assert_eq!(parsed.callstacks[0].frames, data.callstacks[0].frames);
+        // Older files weren't escaped:
+        let parsed = parse_folded_str("a%3B.py:1 (f) 10").unwrap();
+        assert_eq!(parsed.callstacks[0].frames[0].filename, "a%3B.py");
+    }
+
+    #[test]
+    fn bad_input() {
+        assert!(parse_folded_str("a.py:1 (main)").is_err());
+        assert!(parse_folded_str("a.py (main) 10").is_err());
+        assert!(parse_folded_str("# fil-prof version=2\na.py (main) 10").is_err());
+        assert!(parse_folded_str("# fil-prof version=1 units=seconds\n").is_err());
+        let parsed =
+            parse_folded_str("# fil-prof version=1 units=allocations\na.py:1 (main) 10\n").unwrap();
+        assert_eq!(parsed.peak_allocations, 10);
+        assert_eq!(parsed.peak_bytes, 0);
+    }
+}
@@ -0,0 +1,320 @@
+// Which file formats a dump produces, and encoders for the formats other
+// tools read: speedscope's JSON, and pprof's protobuf. Every format is
+// written from the same snapshot in one go, after collapsing, redaction and
+// so on have happened once, rather than each format re-doing that work.
+use std::collections::{BTreeSet, HashMap};
+
+use serde_json::{json, Value};
+
+use crate::report::Artifacts;
+use crate::snapshot::Frame;
+
+/// A file format a dump can produce.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OutputFormat {
+    /// Flamegraph SVGs.
+    Svg,
+    /// The HTML report, which the Python code builds from the SVGs.
+    Html,
+    /// The whole snapshot as JSON.
+    Json,
+    /// Gzipped pprof protobuf, for `go tool pprof` and friends.
+    Pprof,
+    /// https://www.speedscope.app/ JSON.
+    Speedscope,
+    /// The per-function and per-callsite tables as CSV.
+    Csv,
+}
+
+impl OutputFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "svg" => Some(OutputFormat::Svg),
+            "html" => Some(OutputFormat::Html),
+            "json" => Some(OutputFormat::Json),
+            "pprof" => Some(OutputFormat::Pprof),
+            "speedscope" => Some(OutputFormat::Speedscope),
+            "csv" => Some(OutputFormat::Csv),
+            _ => None,
+        }
+    }
+}
+
+/// The set of formats to write.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OutputFormats(BTreeSet<OutputFormat>);
+
+impl OutputFormats {
+    /// Parse a comma-separated list, e.g. "svg,json,pprof".
+    pub fn parse(value: &str) -> Result<Self, String> {
+        value
+            .split(',')
+            .map(|format| format.trim())
+            .filter(|format| !format.is_empty())
+            .map(|format| {
+                OutputFormat::parse(format).ok_or_else(|| format!("unknown format {:?}", format))
+            })
+            .collect::<Result<BTreeSet<_>, _>>()
+            .map(OutputFormats)
+    }
+
+    /// From FIL_OUTPUT_FORMATS; None means the usual outputs.
+    pub fn from_env() -> Option<Self> {
+        let value = std::env::var("FIL_OUTPUT_FORMATS").ok()?;
+        match Self::parse(&value) {
+            Ok(formats) => Some(formats),
+            Err(e) => {
+                eprintln!("=fil-profile= Ignoring FIL_OUTPUT_FORMATS: {}", e);
+                None
+            }
+        }
+    }
+
+    pub fn contains(&self, format: OutputFormat) -> bool {
+        self.0.contains(&format)
+    }
+
+    /// Adjust the artifacts a report asked for to these formats: formats
+    /// that weren't chosen are left out, and chosen ones are added.
+    pub fn apply(&self, artifacts: Artifacts) -> Artifacts {
+        // HTML is built from the SVGs:
+        let svg = self.contains(OutputFormat::Svg) || self.contains(OutputFormat::Html);
+        Artifacts {
+            flamegraph: artifacts.flamegraph && svg,
+            reversed: artifacts.reversed && svg,
+            json: self.contains(OutputFormat::Json),
+            csv: self.contains(OutputFormat::Csv),
+            pprof: self.contains(OutputFormat::Pprof),
+            speedscope: self.contains(OutputFormat::Speedscope),
+            extras: artifacts.extras && svg,
+            ..artifacts
+        }
+    }
+}
+
+/// A callstack, outermost frame first, and its value, e.g. bytes.
+pub type WeightedStack = (Vec<Frame>, usize);
+
+/// Interns frames, the way both speedscope and pprof want them.
+#[derive(Default)]
+struct FrameTable<'a> {
+    indexes: HashMap<&'a Frame, usize>,
+    frames: Vec<&'a Frame>,
+}
+
+impl<'a> FrameTable<'a> {
+    fn index(&mut self, frame: &'a Frame) -> usize {
+        let frames = &mut self.frames;
+        *self.indexes.entry(frame).or_insert_with(|| {
+            frames.push(frame);
+            frames.len() - 1
+        })
+    }
+}
+
+/// A speedscope "sampled" profile, where each callstack is one sample
+/// weighted by its value.
+pub fn to_speedscope(stacks: &[WeightedStack], title: &str, unit: &str) -> Value {
+    let mut table = FrameTable::default();
+    let samples: Vec<Vec<usize>> = stacks
+        .iter()
+        .map(|(frames, _)| frames.iter().map(|frame| table.index(frame)).collect())
+        .collect();
+    let weights: Vec<usize> = stacks.iter().map(|(_, value)| *value).collect();
+    let frames: Vec<Value> = table
+        .frames
+        .iter()
+        .map(|frame| {
+            json!({
+                "name": frame.function,
+                "file": frame.filename,
+                "line": frame.line_number,
+            })
+        })
+        .collect();
+    json!({
+        "$schema": "https://www.speedscope.app/file-format-schema.json",
+        "name": title,
+        "exporter": "Fil",
+        "activeProfileIndex": 0,
+        "shared": {"frames": frames},
+        "profiles": [{
+            "type": "sampled",
+            "name": title,
+            // speedscope knows "bytes" and "none":
+            "unit": if unit == "bytes" { "bytes" } else { "none" },
+            "startValue": 0,
+            "endValue": weights.iter().sum::<usize>(),
+            "samples": samples,
+            "weights": weights,
+        }],
+    })
+}
+
+// Just enough protobuf encoding for pprof's profile.proto:
+
+fn varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn uint_field(buf: &mut Vec<u8>, field: u64, value: u64) {
+    varint(buf, field << 3);
+    varint(buf, value);
+}
+
+fn bytes_field(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    varint(buf, (field << 3) | 2);
+    varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn packed_field(buf: &mut Vec<u8>, field: u64, values: impl Iterator<Item = u64>) {
+    let mut packed = vec![];
+    for value in values {
+        varint(&mut packed, value);
+    }
+    bytes_field(buf, field, &packed);
+}
+
+#[derive(Default)]
+struct StringTable {
+    indexes: HashMap<String, u64>,
+    strings: Vec<String>,
+}
+
+impl StringTable {
+    fn index(&mut self, string: &str) -> u64 {
+        if let Some(index) = self.indexes.get(string) {
+            return *index;
+        }
+        let index = self.strings.len() as u64;
+        self.indexes.insert(string.to_string(), index);
+        self.strings.push(string.to_string());
+        index
+    }
+}
+
+/// An uncompressed pprof profile. Each frame becomes a location, and
+/// functions are (name, filename) pairs.
+pub fn to_pprof(stacks: &[WeightedStack], unit: &str) -> Vec<u8> {
+    let mut strings = StringTable::default();
+    // pprof requires the first string to be empty:
+    strings.index("");
+    let mut table = FrameTable::default();
+    let mut profile = vec![];
+
+    // sample_type, e.g. space/bytes:
+    let mut value_type = vec![];
+    let kind = if unit == "bytes" { "space" } else { unit };
+    uint_field(&mut value_type, 1, strings.index(kind));
+    uint_field(&mut value_type, 2, strings.index(unit));
+    bytes_field(&mut profile, 1, &value_type);
+
+    for (frames, value) in stacks {
+        let mut sample = vec![];
+        // Location IDs start at 1, innermost frame first:
+        packed_field(
+            &mut sample,
+            1,
+            frames
+                .iter()
+                .rev()
+                .map(|frame| table.index(frame) as u64 + 1),
+        );
+        packed_field(&mut sample, 2, std::iter::once(*value as u64));
+        bytes_field(&mut profile, 2, &sample);
+    }
+
+    let mut function_ids: HashMap<(&str, &str), u64> = HashMap::new();
+    for (index, frame) in table.frames.iter().enumerate() {
+        let key = (frame.function.as_str(), frame.filename.as_str());
+        let next_id = function_ids.len() as u64 + 1;
+        let function_id = *function_ids.entry(key).or_insert_with(|| {
+            let mut function = vec![];
+            uint_field(&mut function, 1, next_id);
+            let name = strings.index(&frame.function);
+            uint_field(&mut function, 2, name);
+            uint_field(&mut function, 3, name);
+            uint_field(&mut function, 4, strings.index(&frame.filename));
+            bytes_field(&mut profile, 5, &function);
+            next_id
+        });
+        let mut line = vec![];
+        uint_field(&mut line, 1, function_id);
+        uint_field(&mut line, 2, frame.line_number as u64);
+        let mut location = vec![];
+        uint_field(&mut location, 1, index as u64 + 1);
+        bytes_field(&mut location, 4, &line);
+        bytes_field(&mut profile, 4, &location);
+    }
+
+    for string in strings.strings.iter() {
+        bytes_field(&mut profile, 6, string.as_bytes());
+    }
+    profile
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{to_pprof, to_speedscope, varint, OutputFormat, OutputFormats};
+    use crate::report::Artifacts;
+    use crate::snapshot::Frame;
+
+    fn frame(function: &str, line_number: u32) -> Frame {
+        Frame {
+            function: function.to_string(),
+            filename: "a.py".to_string(),
+            line_number,
+            module: None,
+        }
+    }
+
+    #[test]
+    fn formats_pick_artifacts() {
+        assert!(OutputFormats::parse("svg,nope").is_err());
+        let formats = OutputFormats::parse("json, pprof,").unwrap();
+        assert!(formats.contains(OutputFormat::Pprof));
+        let artifacts = formats.apply(Artifacts::STANDARD);
+        assert!(!artifacts.flamegraph && !artifacts.extras && !artifacts.csv);
+        assert!(artifacts.json && artifacts.pprof && !artifacts.speedscope);
+        // HTML needs the SVGs:
+        let artifacts = OutputFormats::parse("html")
+            .unwrap()
+            .apply(Artifacts::STANDARD);
+        assert!(artifacts.flamegraph && artifacts.extras && !artifacts.json);
+    }
+
+    #[test]
+    fn speedscope_shares_frames() {
+        let stacks = vec![
+            (vec![frame("main", 1), frame("f", 2)], 100),
+            (vec![frame("main", 1), frame("g", 3)], 50),
+        ];
+        let value = to_speedscope(&stacks, "Peak", "bytes");
+        assert_eq!(value["shared"]["frames"].as_array().unwrap().len(), 3);
+        assert_eq!(
+            value["profiles"][0]["samples"][1],
+            serde_json::json!([0, 2])
+        );
+        assert_eq!(value["profiles"][0]["endValue"], 150);
+    }
+
+    #[test]
+    fn pprof_encoding() {
+        let mut buf = vec![];
+        varint(&mut buf, 300);
+        assert_eq!(buf, vec![0xac, 0x02]);
+        let stacks = vec![(vec![frame("main", 1), frame("f", 2)], 100)];
+        let profile = to_pprof(&stacks, "bytes");
+        // Starts with the sample type, field 1:
+        assert_eq!(profile[0], (1 << 3) | 2);
+        // The sample: locations for f then main, innermost first, and the
+        // value:
+        let sample = [(2 << 3) | 2, 7, (1 << 3) | 2, 2, 1, 2, (2 << 3) | 2, 1, 100];
+        assert!(profile.windows(sample.len()).any(|w| w == sample));
+    }
+}
@@ -0,0 +1,124 @@
+// Independent trackers for library users. The preload hooks feed a single
+// global tracker, but code embedding this crate may want its own, e.g. to
+// profile one subsystem in isolation by feeding it that subsystem's
+// allocations. A TrackerHandle owns a tracker that has nothing to do with the
+// global one; clones share the same tracker, and can be used from multiple
+// threads.
+use std::path::PathBuf;
+
+use crate::memorytracking::{
+    AllocationTracker, LineNumber, TopMetric, VecFunctionLocations, PARENT_PROCESS,
+};
+use crate::report::Report;
+use crate::snapshot::ProfileData;
+use crate::sync::{Arc, OwnedRwLock};
+
+pub type Tracker = AllocationTracker<VecFunctionLocations>;
+
+#[derive(Clone)]
+pub struct TrackerHandle {
+    tracker: Arc<OwnedRwLock<Tracker>>,
+}
+
+impl TrackerHandle {
+    /// A new, empty tracker; default_path is where reports go by default.
+    pub fn new(default_path: &str) -> Self {
+        Self {
+            tracker: Arc::new(OwnedRwLock::new(AllocationTracker::new(
+                default_path.to_string(),
+                VecFunctionLocations::new(),
+            ))),
+        }
+    }
+
+    /// Run a function with exclusive access to the tracker, for anything the
+    /// other methods don't cover.
+    pub fn with_tracker<R>(&self, f: impl FnOnce(&mut Tracker) -> R) -> R {
+        f(&mut self.tracker.lock())
+    }
+
+    /// Run a function with shared, read-only access to the tracker. Other
+    /// readers can run at the same time; only changes to the tracker wait.
+    pub fn read_tracker<R>(&self, f: impl FnOnce(&Tracker) -> R) -> R {
+        f(&self.tracker.read())
+    }
+
+    /// Record an allocation with the given callstack, outermost frame first,
+    /// as (module, function, line number).
+    pub fn add_allocation(&self, address: usize, size: usize, frames: &[(&str, &str, LineNumber)]) {
+        self.with_tracker(|tracker| tracker.add_allocation_with_callstack(address, size, frames))
+    }
+
+    /// Record a free, returning the size of the allocation if it was known.
+    pub fn free_allocation(&self, address: usize) -> Option<usize> {
+        self.with_tracker(|tracker| tracker.free_allocation(PARENT_PROCESS, address))
+    }
+
+    pub fn current_allocated_bytes(&self) -> usize {
+        self.read_tracker(|tracker| tracker.get_current_allocated_bytes())
+    }
+
+    pub fn peak_allocated_bytes(&self) -> usize {
+        self.with_tracker(|tracker| {
+            tracker.check_if_new_peak();
+            tracker.get_peak_allocated_bytes()
+        })
+    }
+
+    /// See AllocationTracker::top_callstacks().
+    pub fn top_callstacks(&self, count: usize, metric: TopMetric) -> Vec<(String, usize)> {
+        self.read_tracker(|tracker| tracker.top_callstacks(count, metric))
+    }
+
+    /// The profile so far.
+    pub fn snapshot(&self) -> ProfileData {
+        self.with_tracker(|tracker| tracker.snapshot())
+    }
+
+    /// A report to the given directory, with the tracker's report options;
+    /// choose the artifacts, then pass it to dump().
+    pub fn report(&self, path: &str) -> Report {
+        self.with_tracker(|tracker| tracker.report(path))
+    }
+
+    /// Write a report of the profile so far, returning the directory it
+    /// ended up in, if any.
+    pub fn dump(&self, report: &Report) -> Option<PathBuf> {
+        self.with_tracker(|tracker| tracker.dump(report))
+    }
+
+    /// Forget everything tracked so far.
+    pub fn reset(&self, default_path: &str) {
+        self.with_tracker(|tracker| tracker.reset(default_path.to_string()))
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::TrackerHandle;
+    use crate::memorytracking::TopMetric;
+
+    #[test]
+    fn handles_are_independent() {
+        let first = TrackerHandle::new("/tmp");
+        let second = TrackerHandle::new("/tmp");
+        let shared = first.clone();
+        first.add_allocation(1, 100, &[("main", "main", 3)]);
+        shared.add_allocation(2, 50, &[("main", "main", 3), ("data", "load", 7)]);
+        second.add_allocation(1, 7, &[("other", "work", 1)]);
+        assert_eq!(first.current_allocated_bytes(), 150);
+        assert_eq!(second.current_allocated_bytes(), 7);
+
+        assert_eq!(shared.free_allocation(1), Some(100));
+        assert_eq!(first.free_allocation(1), None);
+        assert_eq!(first.current_allocated_bytes(), 50);
+        assert_eq!(first.peak_allocated_bytes(), 150);
+        assert_eq!(second.peak_allocated_bytes(), 7);
+        assert_eq!(first.top_callstacks(1, TopMetric::CurrentBytes)[0].1, 50);
+        assert_eq!(second.snapshot().callstacks.len(), 1);
+
+        first.reset("/tmp");
+        assert_eq!(shared.current_allocated_bytes(), 0);
+        assert_eq!(second.current_allocated_bytes(), 7);
+    }
+}
@@ -0,0 +1,200 @@
+// Hashing for the tracker's maps. The allocation hooks hit a hashmap keyed by
+// address on every malloc() and free(), so the hash function matters. aHash is
+// the default: fast, and randomly seeded so no particular program can make it
+// degrade. FIL_HASHER=fast picks a simpler mixing function instead, a little
+// quicker for the integer keys that dominate, at the cost of that protection:
+//
+//     FIL_HASHER=fast fil-profile run yourscript.py
+//
+// Setting PYTHONHASHSEED to a number makes either one deterministic, to reduce
+// randomness when benchmarking.
+use std::hash::{BuildHasher, Hasher};
+
+use ahash::{AHasher, RandomState as ARandomState};
+
+/// Which hash function maps use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    AHash,
+    Fast,
+}
+
+impl HashAlgorithm {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "ahash" => Some(Self::AHash),
+            "fast" => Some(Self::Fast),
+            _ => None,
+        }
+    }
+}
+
+lazy_static! {
+    // If the PYTHONHASHSEED environment variable is set, we will use it as seed
+    // for Rust hashmaps as well, to reduce randomness when benchmarking.
+    static ref HASH_SEED: Option<u64> = match std::env::var("PYTHONHASHSEED") {
+        Ok(value) => {
+            if value == "random" {
+                None
+            } else {
+                let seed = value.parse::<i64>().unwrap();
+                Some(seed as u64)
+            }
+        }
+        _ => None,
+    };
+
+    static ref HASH_ALGORITHM: HashAlgorithm = match std::env::var("FIL_HASHER") {
+        Ok(value) => HashAlgorithm::parse(&value).unwrap_or_else(|| {
+            eprintln!("=fil-profile= Ignoring unknown FIL_HASHER {:?}", value);
+            HashAlgorithm::AHash
+        }),
+        Err(_) => HashAlgorithm::AHash,
+    };
+}
+
+/// Creates the hashers for a map, using the algorithm chosen by FIL_HASHER.
+#[derive(Clone, Debug)]
+pub enum HashState {
+    AHash(ARandomState),
+    Fast(u64),
+}
+
+impl HashState {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        match (algorithm, *HASH_SEED) {
+            (HashAlgorithm::AHash, Some(seed)) => {
+                Self::AHash(ARandomState::with_seeds(seed, seed + 1, seed + 2, seed + 3))
+            }
+            (HashAlgorithm::AHash, None) => Self::AHash(ARandomState::new()),
+            (HashAlgorithm::Fast, Some(seed)) => Self::Fast(seed),
+            (HashAlgorithm::Fast, None) => {
+                // aHash's seeding is random per map, which is good enough
+                // here too:
+                let mut hasher = ARandomState::new().build_hasher();
+                hasher.write_u8(0);
+                Self::Fast(hasher.finish())
+            }
+        }
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        match self {
+            Self::AHash(_) => HashAlgorithm::AHash,
+            Self::Fast(_) => HashAlgorithm::Fast,
+        }
+    }
+}
+
+impl Default for HashState {
+    fn default() -> Self {
+        Self::new(*HASH_ALGORITHM)
+    }
+}
+
+impl BuildHasher for HashState {
+    type Hasher = FilHasher;
+
+    fn build_hasher(&self) -> FilHasher {
+        match self {
+            Self::AHash(state) => FilHasher::AHash(state.build_hasher()),
+            Self::Fast(seed) => FilHasher::Fast(*seed),
+        }
+    }
+}
+
+/// The finalizer from SplitMix64: every input bit affects every output bit,
+/// so aligned addresses, whose low bits are all zero, still spread across
+/// buckets.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+pub enum FilHasher {
+    AHash(AHasher),
+    Fast(u64),
+}
+
+impl Hasher for FilHasher {
+    fn finish(&self) -> u64 {
+        match self {
+            Self::AHash(hasher) => hasher.finish(),
+            Self::Fast(state) => mix(*state),
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        match self {
+            Self::AHash(hasher) => hasher.write(bytes),
+            Self::Fast(state) => {
+                for chunk in bytes.chunks(8) {
+                    let mut word = [0u8; 8];
+                    word[..chunk.len()].copy_from_slice(chunk);
+                    *state = mix(*state ^ u64::from_le_bytes(word));
+                }
+                // So "a" and "a\0" differ:
+                *state = mix(*state ^ bytes.len() as u64);
+            }
+        }
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        match self {
+            Self::AHash(hasher) => hasher.write_u64(value),
+            Self::Fast(state) => *state = mix(*state ^ value),
+        }
+    }
+
+    fn write_usize(&mut self, value: usize) {
+        self.write_u64(value as u64)
+    }
+
+    fn write_u32(&mut self, value: u32) {
+        self.write_u64(value as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HashAlgorithm, HashState};
+    use std::collections::{HashMap, HashSet};
+    use std::hash::{BuildHasher, Hash};
+
+    fn hash<T: Hash>(state: &HashState, value: T) -> u64 {
+        state.hash_one(value)
+    }
+
+    #[test]
+    fn both_algorithms_work_in_maps() {
+        for algorithm in [HashAlgorithm::AHash, HashAlgorithm::Fast] {
+            let state = HashState::new(algorithm);
+            assert_eq!(state.algorithm(), algorithm);
+            let mut map: HashMap<usize, usize, HashState> = HashMap::with_hasher(state.clone());
+            for address in (0..10_000).map(|i| 0x7f00_0000_0000 + i * 16) {
+                map.insert(address, address);
+            }
+            assert_eq!(map.len(), 10_000);
+            assert_eq!(map[&(0x7f00_0000_0000 + 160)], 0x7f00_0000_0000 + 160);
+            let mut strings: HashMap<String, usize, HashState> = HashMap::with_hasher(state);
+            strings.insert("a".to_string(), 1);
+            strings.insert("a\0".to_string(), 2);
+            assert_eq!(strings["a"], 1);
+        }
+        assert_eq!(HashAlgorithm::parse("fast"), Some(HashAlgorithm::Fast));
+        assert_eq!(HashAlgorithm::parse("sha1"), None);
+    }
+
+    #[test]
+    fn fast_hashes_spread_aligned_addresses() {
+        let state = HashState::Fast(1);
+        // The low bits pick the bucket, so they need to vary even though the
+        // addresses' low bits don't:
+        let buckets: HashSet<u64> = (0..1024u64)
+            .map(|i| hash(&state, (0x5555_0000 + i * 64) as usize) & 1023)
+            .collect();
+        assert!(buckets.len() > 500, "{}", buckets.len());
+        assert_ne!(hash(&state, "a"), hash(&state, "a\0"));
+    }
+}
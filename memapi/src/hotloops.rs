@@ -0,0 +1,175 @@
+// Allocation hot loops: callstacks that allocate many times a second, e.g. a
+// loop building a new list or buffer on every iteration. Each allocation is
+// cheap on its own, so they don't stand out in a memory profile, but the
+// allocator churn adds up; preallocating or reusing a buffer usually fixes
+// it.
+//
+// Allocations are counted per callstack in windows of about a second, and
+// each callstack keeps the highest rate it reached in any window. Reading the
+// clock on every allocation would cost more than the counting, so it's only
+// checked every CHECK_EVERY allocations; a window can therefore run a little
+// long, which only makes the rate an underestimate.
+use std::time::{Duration, Instant};
+
+use crate::memorytracking::CallstackId;
+
+/// How long each counting window is.
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// How many allocations between looks at the clock.
+const CHECK_EVERY: u32 = 1024;
+
+/// Callstacks allocating at least this many times a second are listed as
+/// hotspots, unless FIL_HOT_LOOP_RATE says otherwise.
+pub const DEFAULT_HOT_LOOP_RATE: u64 = 10_000;
+
+#[derive(Clone, Debug)]
+pub struct AllocationRates {
+    window_start: Instant,
+    since_check: u32,
+    // Allocations in the current window:
+    counts: Vec<u32>, // Map CallstackId -> allocations
+    // Callstacks with a non-zero count, so closing a window doesn't have to
+    // look at every callstack:
+    touched: Vec<CallstackId>,
+    // Highest allocations per second in any finished window:
+    peak_rates: Vec<u64>, // Map CallstackId -> allocations per second
+}
+
+impl Default for AllocationRates {
+    fn default() -> Self {
+        Self {
+            window_start: Instant::now(),
+            since_check: 0,
+            counts: vec![],
+            touched: vec![],
+            peak_rates: vec![],
+        }
+    }
+}
+
+/// Allocations per second, counting windows shorter than WINDOW as a full
+/// one so a handful of allocations in a few microseconds isn't a hot loop.
+fn rate(count: u32, elapsed: Duration) -> u64 {
+    (count as f64 / elapsed.max(WINDOW).as_secs_f64()) as u64
+}
+
+impl AllocationRates {
+    /// Count an allocation.
+    #[inline]
+    pub fn record(&mut self, callstack_id: CallstackId) {
+        let index = callstack_id as usize;
+        if index >= self.counts.len() {
+            self.counts.resize(index + 1, 0);
+            self.peak_rates.resize(index + 1, 0);
+        }
+        if self.counts[index] == 0 {
+            self.touched.push(callstack_id);
+        }
+        self.counts[index] = self.counts[index].saturating_add(1);
+        self.since_check += 1;
+        if self.since_check >= CHECK_EVERY {
+            self.since_check = 0;
+            self.check(Instant::now());
+        }
+    }
+
+    fn check(&mut self, now: Instant) {
+        if now.saturating_duration_since(self.window_start) >= WINDOW {
+            self.close_window(now);
+        }
+    }
+
+    /// Fold the current window's counts into the peak rates, and start a
+    /// new window.
+    fn close_window(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        for callstack_id in self.touched.drain(..) {
+            let index = callstack_id as usize;
+            let rate = rate(self.counts[index], elapsed);
+            self.peak_rates[index] = self.peak_rates[index].max(rate);
+            self.counts[index] = 0;
+        }
+        self.window_start = now;
+    }
+
+    /// The highest allocations per second for a callstack so far, including
+    /// the window in progress.
+    pub fn peak_rate(&self, callstack_id: CallstackId) -> u64 {
+        self.peak_rate_at(callstack_id, Instant::now())
+    }
+
+    fn peak_rate_at(&self, callstack_id: CallstackId, now: Instant) -> u64 {
+        let index = callstack_id as usize;
+        match (self.peak_rates.get(index), self.counts.get(index)) {
+            (Some(peak_rate), Some(count)) => (*peak_rate).max(rate(
+                *count,
+                now.saturating_duration_since(self.window_start),
+            )),
+            _ => 0,
+        }
+    }
+
+    /// Roughly how much memory this uses.
+    pub fn memory_usage(&self) -> usize {
+        self.counts.capacity() * std::mem::size_of::<u32>()
+            + self.touched.capacity() * std::mem::size_of::<CallstackId>()
+            + self.peak_rates.capacity() * std::mem::size_of::<u64>()
+    }
+}
+
+/// The threshold from FIL_HOT_LOOP_RATE, e.g. "50k", if set.
+pub fn hot_loop_rate_from_env() -> Option<u64> {
+    let value = std::env::var("FIL_HOT_LOOP_RATE").ok()?;
+    let rate = crate::capacity::parse_count(&value);
+    if rate.is_none() {
+        eprintln!(
+            "=fil-profile= Ignoring invalid FIL_HOT_LOOP_RATE {:?}",
+            value
+        );
+    }
+    rate.map(|rate| rate as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AllocationRates, CHECK_EVERY};
+    use std::time::Duration;
+
+    #[test]
+    fn peak_rate_per_window() {
+        let mut rates = AllocationRates::default();
+        let start = rates.window_start;
+        let at = |millis| start + Duration::from_millis(millis);
+        rates.counts = vec![0, 5000, 0];
+        rates.peak_rates = vec![0, 0, 0];
+        rates.touched = vec![1];
+        // The window in progress counts, as if it were a full second:
+        assert_eq!(rates.peak_rate_at(1, at(100)), 5000);
+        assert_eq!(rates.peak_rate_at(2, at(100)), 0);
+        assert_eq!(rates.peak_rate_at(7, at(100)), 0);
+        // A window that ran long is averaged over its whole length:
+        rates.close_window(at(2000));
+        assert_eq!(rates.peak_rate_at(1, at(2000)), 2500);
+        assert!(rates.touched.is_empty());
+        // A quieter window doesn't lower the peak:
+        rates.counts[1] = 10;
+        rates.touched.push(1);
+        rates.close_window(at(3000));
+        assert_eq!(rates.peak_rate_at(1, at(3000)), 2500);
+    }
+
+    #[test]
+    fn record_grows_and_counts() {
+        let mut rates = AllocationRates::default();
+        for _ in 0..(CHECK_EVERY * 3) {
+            rates.record(4);
+        }
+        rates.record(2);
+        assert_eq!(rates.counts.len(), 5);
+        assert!(rates.peak_rate(4) > 0);
+        assert!(rates.peak_rate(2) <= 1);
+        assert_eq!(rates.peak_rate(3), 0);
+        assert!(rates.memory_usage() > 0);
+    }
+}
@@ -0,0 +1,108 @@
+// CPython subinterpreters. Callstacks from a subinterpreter start with a
+// synthetic frame naming the interpreter, so the same code running in
+// different interpreters ends up in different callstacks, and in separate
+// sections of the flamegraphs. Each subinterpreter's own current and peak
+// usage is tracked too, since the overall peak can hide a subinterpreter's.
+use std::collections::BTreeMap;
+
+/// CPython's interpreter ID; the main interpreter is 0.
+pub type InterpreterId = u64;
+
+pub const MAIN_INTERPRETER: InterpreterId = 0;
+
+/// Filename of the synthetic root frame of subinterpreter callstacks.
+pub const INTERPRETER_FILENAME: &str = "<subinterpreter>";
+
+/// Function name of the synthetic root frame for an interpreter.
+pub fn root_function_name(interpreter: InterpreterId) -> String {
+    format!("interpreter {}", interpreter)
+}
+
+/// Which interpreter a callstack's root frame says it's from.
+pub fn interpreter_for_root(filename: &str, function: &str) -> InterpreterId {
+    if filename != INTERPRETER_FILENAME {
+        return MAIN_INTERPRETER;
+    }
+    function
+        .strip_prefix("interpreter ")
+        .and_then(|id| id.parse().ok())
+        .unwrap_or(MAIN_INTERPRETER)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub current_bytes: usize,
+    pub peak_bytes: usize,
+}
+
+/// Current and peak usage per subinterpreter.
+#[derive(Clone, Debug, Default)]
+pub struct InterpreterUsage {
+    usage: BTreeMap<InterpreterId, Usage>,
+}
+
+impl InterpreterUsage {
+    pub fn add(&mut self, interpreter: InterpreterId, bytes: usize) {
+        let usage = self.usage.entry(interpreter).or_default();
+        usage.current_bytes += bytes;
+        usage.peak_bytes = usage.peak_bytes.max(usage.current_bytes);
+    }
+
+    pub fn remove(&mut self, interpreter: InterpreterId, bytes: usize) {
+        if let Some(usage) = self.usage.get_mut(&interpreter) {
+            usage.current_bytes = usage.current_bytes.saturating_sub(bytes);
+        }
+    }
+
+    /// Usage per subinterpreter, by interpreter ID.
+    pub fn iter(&self) -> impl Iterator<Item = (InterpreterId, Usage)> + '_ {
+        self.usage.iter().map(|(id, usage)| (*id, *usage))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        interpreter_for_root, root_function_name, InterpreterUsage, Usage, INTERPRETER_FILENAME,
+        MAIN_INTERPRETER,
+    };
+
+    #[test]
+    fn root_frames_roundtrip() {
+        assert_eq!(
+            interpreter_for_root(INTERPRETER_FILENAME, &root_function_name(3)),
+            3
+        );
+        assert_eq!(
+            interpreter_for_root("/app/main.py", &root_function_name(3)),
+            MAIN_INTERPRETER
+        );
+    }
+
+    #[test]
+    fn separate_peaks() {
+        let mut usage = InterpreterUsage::default();
+        usage.add(1, 100);
+        usage.remove(1, 100);
+        usage.add(2, 50);
+        assert_eq!(
+            usage.iter().collect::<Vec<_>>(),
+            vec![
+                (
+                    1,
+                    Usage {
+                        current_bytes: 0,
+                        peak_bytes: 100
+                    }
+                ),
+                (
+                    2,
+                    Usage {
+                        current_bytes: 50,
+                        peak_bytes: 50
+                    }
+                ),
+            ]
+        );
+    }
+}
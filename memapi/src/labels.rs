@@ -0,0 +1,210 @@
+// Custom labels, e.g. `endpoint=/upload` or `batch=train`, that the frontend
+// attaches to whatever the current thread allocates, so servers can slice
+// memory by request type. Like threads and subinterpreters, labels become
+// synthetic frames at the start of callstacks, so labelled memory gets its
+// own sections of the flamegraphs, and reports add a table of memory per
+// label.
+use std::collections::BTreeMap;
+
+use crate::memorytracking::{
+    AllocationTracker, CallSiteId, Callstack, CallstackId, VecFunctionLocations,
+};
+use crate::snapshot::ProfileData;
+
+/// Filename of the synthetic root frames for labels.
+pub const LABEL_FILENAME: &str = "<label>";
+
+/// Each thread can only have so many labels at once.
+pub const MAX_LABELS: usize = 8;
+
+/// Shown in the table for memory allocated without any labels.
+const NO_LABELS: &str = "(no labels)";
+
+/// A thread's current labels, sorted by key.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Labels {
+    labels: Vec<(String, String)>,
+}
+
+impl Labels {
+    /// Set a label, replacing any previous value for the key. Returns false
+    /// if there are already too many labels.
+    pub fn set(&mut self, key: &str, value: &str) -> bool {
+        match self.labels.binary_search_by(|(k, _)| k.as_str().cmp(key)) {
+            Ok(index) => self.labels[index].1 = value.to_string(),
+            Err(_) if self.labels.len() >= MAX_LABELS => return false,
+            Err(index) => self
+                .labels
+                .insert(index, (key.to_string(), value.to_string())),
+        }
+        true
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.labels.retain(|(k, _)| k != key);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// The ID of the given callstack with frames for these labels added at
+    /// the start.
+    pub fn apply(
+        &self,
+        tracker: &mut AllocationTracker<VecFunctionLocations>,
+        callstack_id: CallstackId,
+    ) -> CallstackId {
+        if self.labels.is_empty() {
+            return callstack_id;
+        }
+        let mut callstack = Callstack::new();
+        for (key, value) in &self.labels {
+            let function = tracker
+                .functions
+                .add_function(LABEL_FILENAME.to_string(), function_name(key, value));
+            callstack.start_call(0, CallSiteId::new(function, 0));
+        }
+        if let Some(original) = tracker.get_callstack(callstack_id) {
+            for call in original.calls() {
+                callstack.start_call(0, *call);
+            }
+        }
+        tracker.get_callstack_id(&callstack)
+    }
+}
+
+/// Function name of the synthetic frame for a label.
+pub fn function_name(key: &str, value: &str) -> String {
+    format!("{}={}", key, value)
+}
+
+/// Memory per label, at peak or currently, in a table. Each label's total
+/// includes everything allocated while it was set, so totals for different
+/// keys overlap. Returns None if nothing was labelled.
+pub fn label_table(data: &ProfileData, peak: bool) -> Option<String> {
+    let mut totals: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut unlabelled = 0;
+    for usage in &data.callstacks {
+        let bytes = if peak {
+            usage.peak_bytes
+        } else {
+            usage.current_bytes
+        };
+        let mut labelled = false;
+        for frame in usage
+            .frames
+            .iter()
+            .filter(|frame| frame.filename == LABEL_FILENAME)
+        {
+            *totals.entry(&frame.function).or_insert(0) += bytes;
+            labelled = true;
+        }
+        if !labelled {
+            unlabelled += bytes;
+        }
+    }
+    if totals.is_empty() {
+        return None;
+    }
+    let width = totals
+        .keys()
+        .map(|label| label.chars().count())
+        .chain([NO_LABELS.len(), "Label".len()])
+        .max()
+        .unwrap_or(0);
+    let mut table = format!("{:width$}  {:>10}\n", "Label", "MiB", width = width);
+    let mut rows: Vec<(&str, usize)> = totals.into_iter().collect();
+    // Largest first, ties broken by name so output is stable:
+    rows.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+    rows.push((NO_LABELS, unlabelled));
+    for (label, bytes) in rows {
+        table.push_str(&format!(
+            "{:width$}  {:>10.1}\n",
+            label,
+            bytes as f64 / (1024.0 * 1024.0),
+            width = width
+        ));
+    }
+    Some(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{label_table, Labels, MAX_LABELS};
+    use crate::folded::parse_folded_str;
+    use crate::memorytracking::{
+        AllocationTracker, CallSiteId, Callstack, VecFunctionLocations, PARENT_PROCESS,
+    };
+
+    #[test]
+    fn labels_are_sorted_and_limited() {
+        let mut labels = Labels::default();
+        assert!(labels.set("request", "upload"));
+        assert!(labels.set("batch", "train"));
+        assert!(labels.set("request", "download"));
+        assert_eq!(
+            labels.labels,
+            vec![
+                ("batch".to_string(), "train".to_string()),
+                ("request".to_string(), "download".to_string())
+            ]
+        );
+        labels.remove("batch");
+        labels.remove("nonexistent");
+        assert_eq!(labels.labels.len(), 1);
+        for i in 1..MAX_LABELS {
+            assert!(labels.set(&format!("k{}", i), "v"));
+        }
+        assert!(!labels.set("one_too_many", "v"));
+        // Replacing is still fine:
+        assert!(labels.set("request", "upload"));
+    }
+
+    #[test]
+    fn labels_become_root_frames() {
+        let mut tracker = AllocationTracker::new(".".to_string(), VecFunctionLocations::new());
+        let main = tracker
+            .functions
+            .add_function("a.py".to_string(), "main".to_string());
+        let mut callstack = Callstack::new();
+        callstack.start_call(0, CallSiteId::new(main, 3));
+        let unlabelled = tracker.get_callstack_id(&callstack);
+        let mut labels = Labels::default();
+        assert_eq!(labels.apply(&mut tracker, unlabelled), unlabelled);
+        labels.set("request", "upload");
+        let labelled = labels.apply(&mut tracker, unlabelled);
+        assert_ne!(labelled, unlabelled);
+        assert_eq!(labels.apply(&mut tracker, unlabelled), labelled);
+        tracker.add_allocation(PARENT_PROCESS, 1, 100, labelled);
+        let data = tracker.snapshot();
+        let frames = &data.callstacks[0].frames;
+        assert_eq!(frames[0].filename, "<label>");
+        assert_eq!(frames[0].function, "request=upload");
+        assert_eq!(frames[1].function, "main");
+        assert_eq!(frames[1].line_number, 3);
+    }
+
+    #[test]
+    fn table_per_label() {
+        let data = parse_folded_str(
+            "<label>:0 (request=upload);a.py:1 (main) 3145728\n\
+             <label>:0 (batch=train);<label>:0 (request=upload);a.py:1 (main) 1048576\n\
+             <label>:0 (request=download);a.py:1 (main) 1048576\n\
+             a.py:1 (main) 2097152\n",
+        )
+        .unwrap();
+        assert_eq!(
+            label_table(&data, true).unwrap(),
+            "\
+Label                    MiB
+request=upload           4.0
+batch=train              1.0
+request=download         1.0
+(no labels)              2.0
+"
+        );
+        let unlabelled = parse_folded_str("a.py:1 (main) 10\n").unwrap();
+        assert_eq!(label_table(&unlabelled, true), None);
+    }
+}
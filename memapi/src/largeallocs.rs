@@ -0,0 +1,164 @@
+// Alerts for single huge allocations. A one-off giant allocation that's freed
+// again soon never shows up at the peak, but can still be what pushes a
+// machine into swap or the OOM killer. With a threshold set, every allocation
+// at least that big is reported the moment it happens, with its callstack.
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+
+use crate::compression::{self, open_log};
+use crate::snapshot::Frame;
+
+/// Name of the log file, written in the output directory.
+pub const LARGE_ALLOCATION_LOG_FILENAME: &str = "large-allocations.jsonl";
+
+/// After this many alerts, stop printing them; they still get logged.
+const MAX_PRINTED: usize = 20;
+
+pub struct LargeAllocations {
+    threshold: usize,
+    alerts: usize,
+    log: Option<Box<dyn Write + Send + Sync>>,
+}
+
+impl LargeAllocations {
+    pub fn new(threshold: usize) -> Self {
+        Self {
+            threshold,
+            alerts: 0,
+            log: None,
+        }
+    }
+
+    /// Enabled by setting FIL_LARGE_ALLOCATION_BYTES to the threshold.
+    pub fn from_env() -> Option<Self> {
+        let threshold = std::env::var("FIL_LARGE_ALLOCATION_BYTES").ok()?;
+        match threshold.trim().parse() {
+            Ok(threshold) => Some(Self::new(threshold)),
+            Err(_) => {
+                eprintln!(
+                    "=fil-profile= Ignoring invalid FIL_LARGE_ALLOCATION_BYTES: {:?}",
+                    threshold
+                );
+                None
+            }
+        }
+    }
+
+    /// Same settings, no alerts yet; the log gets reopened on the next one.
+    pub fn cleared(&self) -> Self {
+        Self::new(self.threshold)
+    }
+
+    /// Whether an allocation of this size should be alerted on.
+    #[inline]
+    pub fn is_large(&self, size: usize) -> bool {
+        size >= self.threshold
+    }
+
+    /// How many large allocations there have been.
+    pub fn alerts(&self) -> usize {
+        self.alerts
+    }
+
+    /// Print a large allocation's callstack, and log it to the output
+    /// directory.
+    pub fn alert(&mut self, address: usize, size: usize, frames: &[Frame], directory: &str) {
+        self.alerts += 1;
+        if self.alerts <= MAX_PRINTED {
+            eprintln!(
+                "=fil-profile= Large allocation of {:.1} MiB ({} bytes):",
+                size as f64 / (1024.0 * 1024.0),
+                size
+            );
+            for frame in frames {
+                eprintln!(
+                    "=| {}:{} ({})",
+                    frame.filename, frame.line_number, frame.function
+                );
+            }
+            if self.alerts == MAX_PRINTED {
+                eprintln!(
+                    "=fil-profile= Further large allocations will only be logged to {}",
+                    LARGE_ALLOCATION_LOG_FILENAME
+                );
+            }
+        }
+        if self.log.is_none() {
+            match open_log(
+                Path::new(directory),
+                LARGE_ALLOCATION_LOG_FILENAME,
+                compression::enabled_from_env(),
+            ) {
+                Ok(log) => self.log = Some(log),
+                Err(e) => {
+                    eprintln!("=fil-profile= Error opening large allocation log: {}", e);
+                    return;
+                }
+            }
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or(0.0);
+        let stack: Vec<String> = frames
+            .iter()
+            .map(|frame| {
+                format!(
+                    "{}:{} ({})",
+                    frame.filename, frame.line_number, frame.function
+                )
+            })
+            .collect();
+        let entry = json!({
+            "timestamp": timestamp,
+            "address": address,
+            "size": size,
+            "stack": stack.join(";"),
+        });
+        // Flushed right away, since the OOM killer may well be next:
+        if let Some(log) = &mut self.log {
+            if let Err(e) = writeln!(log, "{}", entry).and_then(|_| log.flush()) {
+                eprintln!("=fil-profile= Error writing large allocation log: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LargeAllocations, LARGE_ALLOCATION_LOG_FILENAME};
+    use crate::memorytracking::{
+        AllocationTracker, Callstack, VecFunctionLocations, PARENT_PROCESS,
+    };
+
+    #[test]
+    fn large_allocations_are_logged() {
+        let directory = std::env::temp_dir().join(format!("fil-large-{}", std::process::id()));
+        let mut tracker = AllocationTracker::new(
+            directory.to_str().unwrap().to_string(),
+            VecFunctionLocations::new(),
+        );
+        tracker.large_allocations = Some(LargeAllocations::new(1000));
+        tracker.add_allocation_with_callstack(1, 999, &[("main", "main", 3)]);
+        tracker.add_allocation_with_callstack(2, 5000, &[("main", "main", 3), ("data", "load", 7)]);
+        let callstack_id = tracker.get_callstack_id(&Callstack::new());
+        tracker.add_anon_mmap(PARENT_PROCESS, 4096, 1000, callstack_id);
+        assert_eq!(tracker.large_allocations.as_ref().unwrap().alerts(), 2);
+
+        let log = std::fs::read_to_string(directory.join(LARGE_ALLOCATION_LOG_FILENAME)).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        let lines: Vec<serde_json::Value> = log
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["size"], 5000);
+        assert_eq!(lines[0]["address"], 2);
+        assert_eq!(lines[0]["stack"], "main:3 (main);data:7 (load)");
+        assert_eq!(lines[1]["size"], 1000);
+        assert_eq!(lines[1]["stack"], "");
+    }
+}
@@ -1,12 +1,67 @@
 #![deny(unsafe_op_in_unsafe_fn)]
+pub mod addressspace;
+pub mod ages;
+pub mod arenas;
+pub mod backfill;
+pub mod callcounts;
+pub mod calltree;
+pub mod capacity;
+pub mod cgroup;
+pub mod compression;
+pub mod control;
+#[cfg(feature = "dashboard")]
+pub mod dashboard;
+pub mod doublefree;
+pub mod downsample;
+pub mod errors;
+pub mod exithooks;
 pub mod ffi;
+pub mod filters;
 pub mod flamegraph;
+pub mod folded;
+pub mod formats;
+pub mod handle;
+pub mod hashing;
+pub mod hotloops;
+pub mod interpreters;
+pub mod labels;
+pub mod largeallocs;
+pub mod lifetimepairs;
+pub mod limit;
+pub mod locked;
 pub mod memorytracking;
+pub mod metadata;
+pub mod metric;
 pub mod mmap;
+pub mod native;
+pub mod numa;
 pub mod oom;
-mod python;
+pub mod packages;
+pub mod paths;
+pub mod postdump;
+pub mod provider;
+pub mod python;
 mod rangemap;
+pub mod redact;
+pub mod report;
+pub mod reporting;
+pub mod sequence;
+pub mod sink;
+pub mod sizes;
+pub mod snapshot;
+pub mod stableid;
+pub mod stackstats;
+pub mod streaming;
+pub mod symbolication;
+pub mod sync;
+pub mod threads;
+pub mod timeline;
+pub mod trackedaddresses;
+pub mod trackerstats;
+pub mod units;
 pub mod util;
+pub mod watchpoints;
+pub mod window;
 
 #[macro_use]
 extern crate lazy_static;
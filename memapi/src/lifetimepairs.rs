@@ -0,0 +1,116 @@
+// Which callstack freed memory allocated by which other callstack, and how
+// long it lived. This shows ownership patterns like "allocated in the loader,
+// freed in the worker", which a peak snapshot can't. It costs a timestamp per
+// live allocation plus finding the freeing callstack on every free(), so it's
+// opt-in.
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::hashing::HashState;
+
+use crate::memorytracking::CallstackId;
+use crate::util::new_hashmap;
+
+/// Totals for allocations from one callstack freed by another.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PairStats {
+    pub allocations: usize,
+    pub bytes: usize,
+    pub total_lifetime: Duration,
+    pub max_lifetime: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct LifetimePairs {
+    allocated_at: HashMap<usize, Instant, HashState>,
+    // (allocating callstack, freeing callstack) -> totals:
+    pairs: HashMap<(CallstackId, CallstackId), PairStats, HashState>,
+}
+
+impl Default for LifetimePairs {
+    fn default() -> Self {
+        Self {
+            allocated_at: new_hashmap(),
+            pairs: new_hashmap(),
+        }
+    }
+}
+
+impl LifetimePairs {
+    /// Enabled by setting FIL_LIFETIME_PAIRS=1.
+    pub fn from_env() -> Option<Self> {
+        if std::env::var("FIL_LIFETIME_PAIRS").as_deref() == Ok("1") {
+            Some(Self::default())
+        } else {
+            None
+        }
+    }
+
+    /// Same settings, no data.
+    pub fn cleared(&self) -> Self {
+        Self::default()
+    }
+
+    /// Note when an allocation happened.
+    pub fn allocated(&mut self, address: usize) {
+        self.allocated_at.insert(address, Instant::now());
+    }
+
+    /// Record a free of a tracked allocation.
+    pub fn freed(
+        &mut self,
+        address: usize,
+        size: usize,
+        allocated_by: CallstackId,
+        freed_by: CallstackId,
+    ) {
+        let lifetime = self
+            .allocated_at
+            .remove(&address)
+            .map(|allocated_at| allocated_at.elapsed())
+            .unwrap_or_default();
+        let stats = self.pairs.entry((allocated_by, freed_by)).or_default();
+        stats.allocations += 1;
+        stats.bytes += size;
+        stats.total_lifetime += lifetime;
+        stats.max_lifetime = stats.max_lifetime.max(lifetime);
+    }
+
+    /// Freed without knowing by whom; just stop tracking the address.
+    pub fn forget(&mut self, address: usize) {
+        self.allocated_at.remove(&address);
+    }
+
+    /// Totals per (allocating callstack, freeing callstack), in no
+    /// particular order.
+    pub fn pairs(&self) -> impl Iterator<Item = (&(CallstackId, CallstackId), &PairStats)> {
+        self.pairs.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LifetimePairs;
+
+    #[test]
+    fn pairs_are_aggregated() {
+        let mut pairs = LifetimePairs::default();
+        pairs.allocated(1);
+        pairs.allocated(2);
+        pairs.allocated(3);
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        pairs.freed(1, 100, 7, 8);
+        pairs.freed(2, 50, 7, 8);
+        pairs.freed(3, 10, 7, 9);
+        let mut result: Vec<_> = pairs.pairs().collect();
+        result.sort_by_key(|(key, _)| **key);
+        assert_eq!(result.len(), 2);
+        let (key, stats) = result[0];
+        assert_eq!(*key, (7, 8));
+        assert_eq!(stats.allocations, 2);
+        assert_eq!(stats.bytes, 150);
+        assert!(stats.max_lifetime.as_millis() >= 5);
+        assert!(stats.total_lifetime > stats.max_lifetime);
+        assert_eq!(result[1].1.bytes, 10);
+    }
+}
@@ -0,0 +1,133 @@
+// Memory limit enforcement. Once tracked usage crosses a configured limit, a
+// report of current allocations is written and then the process either aborts
+// or, from the next Python function call, raises an exception; this makes it
+// possible to reproduce and diagnose out-of-memory failures deterministically,
+// e.g. in CI, without needing a machine that's actually out of memory.
+//
+// There can also be a soft limit below the hard one, for a staged response:
+// crossing it writes a report and calls a registered callback, and the
+// program carries on.
+
+/// What happens once the limit has been exceeded and the report written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LimitAction {
+    /// Abort the process.
+    Abort,
+    /// Call a registered callback from Python code, or raise MemoryError.
+    Raise,
+    /// Call the soft limit's registered callback from Python code, if any,
+    /// and carry on.
+    Notify,
+}
+
+impl LimitAction {
+    /// Parse "abort" or "raise".
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "abort" => Some(LimitAction::Abort),
+            "raise" => Some(LimitAction::Raise),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoryLimit {
+    limit: usize,
+    action: LimitAction,
+    // Whether usage has been below the limit since it was last enforced, so
+    // each crossing only triggers once:
+    armed: bool,
+}
+
+impl MemoryLimit {
+    pub fn new(limit: usize, action: LimitAction) -> Self {
+        Self {
+            limit,
+            action,
+            armed: true,
+        }
+    }
+
+    /// Read a limit in bytes from an environment variable.
+    fn limit_from_env(name: &str) -> Option<usize> {
+        let limit = std::env::var(name).ok()?;
+        match limit.trim().parse() {
+            Ok(limit) => Some(limit),
+            Err(_) => {
+                eprintln!("=fil-profile= Ignoring invalid {}: {:?}", name, limit);
+                None
+            }
+        }
+    }
+
+    /// Enabled by setting FIL_MEMORY_LIMIT_BYTES; FIL_MEMORY_LIMIT_ACTION is
+    /// "abort" (the default) or "raise".
+    pub fn from_env() -> Option<Self> {
+        let limit = Self::limit_from_env("FIL_MEMORY_LIMIT_BYTES")?;
+        let action = match std::env::var("FIL_MEMORY_LIMIT_ACTION") {
+            Ok(action) => LimitAction::parse(&action).unwrap_or_else(|| {
+                eprintln!(
+                    "=fil-profile= Unknown FIL_MEMORY_LIMIT_ACTION {:?}, aborting instead",
+                    action
+                );
+                LimitAction::Abort
+            }),
+            Err(_) => LimitAction::Abort,
+        };
+        Some(Self::new(limit, action))
+    }
+
+    /// The soft limit, enabled by setting FIL_SOFT_MEMORY_LIMIT_BYTES.
+    pub fn soft_from_env() -> Option<Self> {
+        let limit = Self::limit_from_env("FIL_SOFT_MEMORY_LIMIT_BYTES")?;
+        Some(Self::new(limit, LimitAction::Notify))
+    }
+
+    /// Same settings, re-armed.
+    pub fn cleared(&self) -> Self {
+        Self::new(self.limit, self.action)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn action(&self) -> LimitAction {
+        self.action
+    }
+
+    /// Call after usage goes up. Returns what to do if usage just crossed the
+    /// limit.
+    #[inline]
+    pub fn check(&mut self, current_bytes: usize) -> Option<LimitAction> {
+        if current_bytes <= self.limit {
+            self.armed = true;
+            None
+        } else if self.armed {
+            self.armed = false;
+            Some(self.action)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LimitAction, MemoryLimit};
+
+    #[test]
+    fn triggers_once_per_crossing() {
+        let mut limit = MemoryLimit::new(100, LimitAction::Raise);
+        assert_eq!(limit.check(50), None);
+        assert_eq!(limit.check(100), None);
+        assert_eq!(limit.check(101), Some(LimitAction::Raise));
+        assert_eq!(limit.check(200), None);
+        assert_eq!(limit.check(90), None);
+        assert_eq!(limit.check(150), Some(LimitAction::Raise));
+        assert_eq!(limit.cleared().check(150), Some(LimitAction::Raise));
+        assert_eq!(LimitAction::parse("abort"), Some(LimitAction::Abort));
+        assert_eq!(LimitAction::parse("explode"), None);
+    }
+}
@@ -0,0 +1,285 @@
+// Memory locked into RAM with mlock() or mlockall(), and ranges advised
+// MADV_HUGEPAGE. Locked pages can't be swapped out or reclaimed, and count
+// against RLIMIT_MEMLOCK rather than just the memory limit, so a process
+// that pins e.g. model weights can be OOM killed in a container even though
+// its memory usage looks fine. Huge pages are similar: with transparent huge
+// pages, a range advised MADV_HUGEPAGE can take up far more RAM than the
+// bytes that were touched.
+//
+// Like the kernel, ranges are rounded out to whole pages, and locking pages
+// that are already locked doesn't count them twice. mlockall() locks
+// everything, which can't be expressed as ranges, so it's only noted.
+use libc::c_int;
+use serde_json::{json, Value};
+
+use crate::addressspace::{page_range, PAGE_SIZE};
+use crate::rangemap::RangeMap;
+use crate::snapshot::get_usize;
+
+/// A call that changes what's locked or advised, made by the program.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockEvent {
+    /// mlock().
+    Lock { address: usize, length: usize },
+    /// munlock().
+    Unlock { address: usize, length: usize },
+    /// mlockall(), locking current and/or future mappings.
+    LockAll { current: bool, future: bool },
+    /// munlockall().
+    UnlockAll,
+    /// madvise(MADV_HUGEPAGE).
+    HugePages { address: usize, length: usize },
+    /// madvise(MADV_NOHUGEPAGE).
+    NoHugePages { address: usize, length: usize },
+}
+
+impl LockEvent {
+    /// The event for mlockall() with these flags.
+    pub fn from_mlockall(flags: c_int) -> Self {
+        LockEvent::LockAll {
+            current: flags & libc::MCL_CURRENT != 0,
+            future: flags & libc::MCL_FUTURE != 0,
+        }
+    }
+
+    /// The event for madvise() with this advice, if it's one that's
+    /// tracked.
+    pub fn from_madvise(address: usize, length: usize, advice: c_int) -> Option<Self> {
+        #[cfg(target_os = "linux")]
+        {
+            match advice {
+                libc::MADV_HUGEPAGE => Some(LockEvent::HugePages { address, length }),
+                libc::MADV_NOHUGEPAGE => Some(LockEvent::NoHugePages { address, length }),
+                _ => None,
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = (address, length, advice);
+            None
+        }
+    }
+}
+
+/// Locked memory as of a snapshot.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LockedMemoryStats {
+    /// Bytes locked with mlock().
+    pub current_bytes: usize,
+    pub peak_bytes: usize,
+    /// Bytes advised MADV_HUGEPAGE.
+    pub huge_page_bytes: usize,
+    /// Whether mlockall() locked everything mapped at the time.
+    pub all_current: bool,
+    /// Whether mlockall() locks every future mapping.
+    pub all_future: bool,
+    /// RLIMIT_MEMLOCK, unless unlimited.
+    pub limit: Option<u64>,
+}
+
+impl LockedMemoryStats {
+    /// Whether the program never locked memory nor asked for huge pages.
+    pub fn is_empty(&self) -> bool {
+        self.peak_bytes == 0 && self.huge_page_bytes == 0 && !self.all_current && !self.all_future
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "current_bytes": self.current_bytes,
+            "peak_bytes": self.peak_bytes,
+            "huge_page_bytes": self.huge_page_bytes,
+            "all_current": self.all_current,
+            "all_future": self.all_future,
+            "limit": self.limit,
+        })
+    }
+
+    pub fn from_json(value: &Value) -> Result<Self, String> {
+        Ok(Self {
+            current_bytes: get_usize(value, "current_bytes")?,
+            peak_bytes: get_usize(value, "peak_bytes")?,
+            huge_page_bytes: get_usize(value, "huge_page_bytes")?,
+            all_current: value["all_current"].as_bool().unwrap_or(false),
+            all_future: value["all_future"].as_bool().unwrap_or(false),
+            limit: value["limit"].as_u64(),
+        })
+    }
+}
+
+/// RLIMIT_MEMLOCK, unless unlimited or unknown.
+fn memlock_limit() -> Option<u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } != 0
+        || limit.rlim_cur == libc::RLIM_INFINITY
+    {
+        return None;
+    }
+    Some(limit.rlim_cur)
+}
+
+#[derive(Clone, Debug)]
+pub struct LockedMemory {
+    page_size: usize,
+    // Page-aligned ranges:
+    locked: RangeMap<()>,
+    huge_pages: RangeMap<()>,
+    peak_bytes: usize,
+    all_current: bool,
+    all_future: bool,
+}
+
+impl Default for LockedMemory {
+    fn default() -> Self {
+        Self::with_page_size(*PAGE_SIZE)
+    }
+}
+
+/// Replace any part of the ranges that overlaps with the given range.
+fn set_range(ranges: &mut RangeMap<()>, start: usize, length: usize, included: bool) {
+    ranges.remove(start, length);
+    if included {
+        ranges.add(start, length, ());
+    }
+}
+
+impl LockedMemory {
+    fn with_page_size(page_size: usize) -> Self {
+        Self {
+            page_size,
+            locked: RangeMap::new(),
+            huge_pages: RangeMap::new(),
+            peak_bytes: 0,
+            all_current: false,
+            all_future: false,
+        }
+    }
+
+    pub fn record(&mut self, event: LockEvent) {
+        match event {
+            LockEvent::Lock { address, length } => self.set(true, address, length, true),
+            LockEvent::Unlock { address, length } => self.set(true, address, length, false),
+            LockEvent::LockAll { current, future } => {
+                // Each call replaces the previous flags:
+                self.all_current = current;
+                self.all_future = future;
+            }
+            LockEvent::UnlockAll => {
+                self.locked = RangeMap::new();
+                self.all_current = false;
+                self.all_future = false;
+            }
+            LockEvent::HugePages { address, length } => self.set(false, address, length, true),
+            LockEvent::NoHugePages { address, length } => self.set(false, address, length, false),
+        }
+        self.peak_bytes = self.peak_bytes.max(self.locked.size());
+    }
+
+    fn set(&mut self, locked: bool, address: usize, length: usize, included: bool) {
+        if length == 0 {
+            return;
+        }
+        let (start, length) = page_range(address, length, self.page_size);
+        let ranges = if locked {
+            &mut self.locked
+        } else {
+            &mut self.huge_pages
+        };
+        set_range(ranges, start, length, included);
+    }
+
+    /// A munmap(), which also unlocks the pages and drops their advice.
+    pub fn unmapped(&mut self, address: usize, length: usize) {
+        self.set(true, address, length, false);
+        self.set(false, address, length, false);
+    }
+
+    pub fn stats(&self) -> LockedMemoryStats {
+        LockedMemoryStats {
+            current_bytes: self.locked.size(),
+            peak_bytes: self.peak_bytes,
+            huge_page_bytes: self.huge_pages.size(),
+            all_current: self.all_current,
+            all_future: self.all_future,
+            limit: memlock_limit(),
+        }
+    }
+
+    /// Roughly how much memory this uses.
+    pub fn memory_usage(&self) -> usize {
+        self.locked.memory_usage() + self.huge_pages.memory_usage()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LockEvent, LockedMemory, LockedMemoryStats};
+
+    #[test]
+    fn locked_ranges_are_pages() {
+        let mut locked = LockedMemory::with_page_size(4096);
+        locked.record(LockEvent::Lock {
+            address: 0x10000,
+            length: 100,
+        });
+        assert_eq!(locked.stats().current_bytes, 4096);
+        // Locking the same pages again doesn't count them twice:
+        locked.record(LockEvent::Lock {
+            address: 0x10000,
+            length: 8192,
+        });
+        assert_eq!(locked.stats().current_bytes, 8192);
+        locked.record(LockEvent::Unlock {
+            address: 0x11000,
+            length: 1,
+        });
+        assert_eq!(locked.stats().current_bytes, 4096);
+        locked.record(LockEvent::HugePages {
+            address: 0x20000,
+            length: 2 * 4096,
+        });
+        // Unmapping unlocks, and forgets the advice:
+        locked.unmapped(0x10000, 0x11000);
+        let stats = locked.stats();
+        assert_eq!(
+            (stats.current_bytes, stats.peak_bytes, stats.huge_page_bytes),
+            (0, 8192, 4096)
+        );
+        assert!(!stats.is_empty());
+        assert_eq!(LockedMemoryStats::from_json(&stats.to_json()), Ok(stats));
+    }
+
+    #[test]
+    fn lock_all() {
+        let mut locked = LockedMemory::with_page_size(4096);
+        assert!(locked.stats().is_empty());
+        locked.record(LockEvent::from_mlockall(
+            libc::MCL_CURRENT | libc::MCL_FUTURE,
+        ));
+        locked.record(LockEvent::Lock {
+            address: 0,
+            length: 4096,
+        });
+        let stats = locked.stats();
+        assert!(stats.all_current && stats.all_future);
+        locked.record(LockEvent::UnlockAll);
+        let stats = locked.stats();
+        assert!(!stats.all_current && !stats.all_future);
+        assert_eq!((stats.current_bytes, stats.peak_bytes), (0, 4096));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn madvise_events() {
+        assert_eq!(
+            LockEvent::from_madvise(4096, 10, libc::MADV_HUGEPAGE),
+            Some(LockEvent::HugePages {
+                address: 4096,
+                length: 10
+            })
+        );
+        assert_eq!(LockEvent::from_madvise(4096, 10, libc::MADV_DONTNEED), None);
+    }
+}
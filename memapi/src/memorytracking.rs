@@ -1,20 +1,49 @@
-use crate::flamegraph::filter_to_useful_callstacks;
-use crate::flamegraph::write_flamegraphs;
-use crate::python::get_runpy_path;
+use crate::addressspace::{AddressSpace, AddressSpaceStats};
+use crate::ages::AllocationAges;
+use crate::arenas::{Arenas, SubAllocation};
+use crate::callcounts::CallCounts;
+use crate::capacity::Capacity;
+use crate::doublefree::{Freed, RecentFrees};
+use crate::hotloops::AllocationRates;
+use crate::interpreters::{
+    interpreter_for_root, InterpreterId, InterpreterUsage, MAIN_INTERPRETER,
+};
+use crate::largeallocs::LargeAllocations;
+use crate::lifetimepairs::LifetimePairs;
+use crate::limit::{LimitAction, MemoryLimit};
+use crate::locked::{LockEvent, LockedMemory};
+use crate::metadata::ProcessMetadata;
+use crate::metric::{Memory, Metric};
+use crate::mmap::MMAP_FILENAME;
+use crate::numa::NumaStats;
+use crate::postdump::PostDumpHooks;
+use crate::report::ReportOptions;
+use crate::sequence::Reconciler;
+use crate::sizes::AllocationSizes;
+use crate::snapshot::{
+    get_string, get_usize, CallstackAges, CallstackUsage, Frame, FunctionCalls, InterpreterStats,
+    LifetimePair, ProfileData, ProfilerMemory,
+};
+use crate::stableid::{callsite_id, callstack_id, StableId};
+use crate::streaming::EventStream;
+use crate::timeline::Timeline;
+use crate::trackedaddresses::TrackedAddresses;
+use crate::trackerstats::TrackerStats;
+use crate::watchpoints::{Watchpoint, Watchpoints};
+use crate::window::WindowedPeak;
 
 use super::rangemap::RangeMap;
-use super::util::new_hashmap;
-use ahash::RandomState as ARandomState;
+use super::util::{hashmap_bytes, new_hashmap};
+use crate::hashing::HashState;
+use crate::sync::Arc;
 use im::Vector as ImVector;
-use itertools::Itertools;
 use std::borrow::Cow;
 use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::marker::PhantomData;
 use std::path::Path;
 
-extern "C" {
-    fn _exit(exit_code: std::os::raw::c_int);
-}
+use serde_json::{json, Value};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct FunctionId(u64);
@@ -31,21 +60,63 @@ impl FunctionId {
     }
 }
 
-/// A function location in the Python source code, e.g. "example() in foo.py".
+/// Index into the interned filenames of a VecFunctionLocations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FilenameId(u32);
+
+/// A function location in the Python source code, e.g. "Foo.example() in
+/// foo.py".
 #[derive(Clone)]
 struct FunctionLocation {
-    filename: String,
-    function_name: String,
+    filename: FilenameId,
+    // Qualified name, e.g. "Foo.example", "outer.<locals>.inner" or
+    // "<lambda>", so methods and nested functions can be told apart.
+    qualname: String,
+    // Dotted module name, e.g. "foo.bar", if known.
+    module: Option<String>,
 }
 
 pub trait FunctionLocations {
     fn get_function_and_filename(&self, id: FunctionId) -> (&str, &str);
+
+    /// Get the dotted module name, if it was registered.
+    fn get_module(&self, _id: FunctionId) -> Option<&str> {
+        None
+    }
+
+    /// If this is the synthetic root frame of a subinterpreter's callstacks,
+    /// which interpreter.
+    fn get_interpreter(&self, _id: FunctionId) -> InterpreterId {
+        MAIN_INTERPRETER
+    }
+
+    /// Roughly how much memory this uses.
+    fn memory_usage(&self) -> usize {
+        0
+    }
+
+    /// How many functions are registered.
+    fn function_count(&self) -> usize {
+        0
+    }
+
+    /// Make room for at least this many functions in total; see
+    /// capacity.rs.
+    fn reserve(&mut self, _functions: usize) {}
 }
 
 /// Stores FunctionLocations, returns a FunctionId
 #[derive(Clone)]
 pub struct VecFunctionLocations {
     functions: Vec<FunctionLocation>,
+    // Many functions share a file, so filenames are only stored once:
+    filenames: Vec<String>,
+    filename_ids: HashMap<String, FilenameId, HashState>,
+    // The same function registered twice gets the same id, so e.g. functions
+    // restored from a checkpoint match those registered by the C tracing code:
+    function_ids: HashMap<(FilenameId, String, Option<String>), FunctionId, HashState>,
+    // Synthetic root frames of subinterpreter callstacks:
+    interpreter_roots: HashMap<FunctionId, InterpreterId, HashState>,
 }
 
 impl VecFunctionLocations {
@@ -53,33 +124,162 @@ impl VecFunctionLocations {
     pub fn new() -> Self {
         Self {
             functions: Vec::with_capacity(8192),
+            filenames: Vec::with_capacity(1024),
+            filename_ids: new_hashmap(),
+            function_ids: new_hashmap(),
+            interpreter_roots: new_hashmap(),
         }
     }
 
-    /// Register a function, get back its id.
-    pub fn add_function(&mut self, filename: String, function_name: String) -> FunctionId {
+    /// Get the id for a function known only by its module, registering it if
+    /// it's new. The module is used as the filename too.
+    pub fn get_or_add_module_function(&mut self, module: &str, qualname: &str) -> FunctionId {
+        self.push(
+            module.to_string(),
+            qualname.to_string(),
+            Some(module.to_string()),
+        )
+    }
+
+    /// Register a function, get back its id. The name should be the
+    /// qualified name (`co_qualname`) where available.
+    pub fn add_function(&mut self, filename: String, qualname: String) -> FunctionId {
+        self.push(filename, qualname, None)
+    }
+
+    /// Register a function along with its dotted module name, get back its id.
+    pub fn add_function_with_module(
+        &mut self,
+        filename: String,
+        qualname: String,
+        module: String,
+    ) -> FunctionId {
+        self.push(filename, qualname, Some(module))
+    }
+
+    /// Get the id for a filename, interning it if it's new.
+    pub fn intern_filename(&mut self, filename: String) -> FilenameId {
+        if let Some(id) = self.filename_ids.get(&filename) {
+            return *id;
+        }
+        let id = FilenameId(self.filenames.len() as u32);
+        self.filenames.push(filename.clone());
+        self.filename_ids.insert(filename, id);
+        id
+    }
+
+    /// Get the filename for a FilenameId.
+    pub fn get_filename(&self, id: FilenameId) -> &str {
+        &self.filenames[id.0 as usize]
+    }
+
+    fn push(&mut self, filename: String, qualname: String, module: Option<String>) -> FunctionId {
+        let filename = self.intern_filename(filename);
+        let key = (filename, qualname, module);
+        if let Some(id) = self.function_ids.get(&key) {
+            return *id;
+        }
         self.functions.push(FunctionLocation {
             filename,
-            function_name,
+            qualname: key.1.clone(),
+            module: key.2.clone(),
         });
         // If we ever have 2 ** 32 or more functions in our program, this will
         // break. Seems unlikely, even with long running workers.
-        FunctionId((self.functions.len() - 1) as u64)
+        let id = FunctionId((self.functions.len() - 1) as u64);
+        let interpreter = interpreter_for_root(self.get_filename(filename), &key.1);
+        if interpreter != MAIN_INTERPRETER {
+            self.interpreter_roots.insert(id, interpreter);
+        }
+        self.function_ids.insert(key, id);
+        id
     }
 }
 
 impl FunctionLocations for VecFunctionLocations {
-    /// Get the function name and filename.
+    /// Get the qualified function name and filename.
     fn get_function_and_filename(&self, id: FunctionId) -> (&str, &str) {
         if id == FunctionId::UNKNOWN {
             return ("UNKNOWN", "UNKNOWN DUE TO BUG");
         }
         let location = &self.functions[id.0 as usize];
-        (&location.function_name, &location.filename)
+        (&location.qualname, self.get_filename(location.filename))
+    }
+
+    fn get_module(&self, id: FunctionId) -> Option<&str> {
+        if id == FunctionId::UNKNOWN {
+            return None;
+        }
+        self.functions[id.0 as usize].module.as_deref()
+    }
+
+    fn get_interpreter(&self, id: FunctionId) -> InterpreterId {
+        self.interpreter_roots
+            .get(&id)
+            .copied()
+            .unwrap_or(MAIN_INTERPRETER)
+    }
+
+    fn memory_usage(&self) -> usize {
+        let names: usize = self
+            .functions
+            .iter()
+            .map(|location| {
+                // Counted twice, since function_ids has a copy:
+                2 * (location.qualname.capacity()
+                    + location.module.as_ref().map_or(0, |m| m.capacity()))
+            })
+            .sum();
+        let filenames: usize = self
+            .filenames
+            .iter()
+            .map(|filename| 2 * filename.capacity())
+            .sum();
+        self.functions.capacity() * std::mem::size_of::<FunctionLocation>()
+            + self.filenames.capacity() * std::mem::size_of::<String>()
+            + names
+            + filenames
+            + hashmap_bytes(&self.filename_ids)
+            + hashmap_bytes(&self.function_ids)
+            + hashmap_bytes(&self.interpreter_roots)
+    }
+
+    fn function_count(&self) -> usize {
+        self.functions.len()
+    }
+
+    fn reserve(&mut self, functions: usize) {
+        let additional = functions.saturating_sub(self.functions.len());
+        self.functions.reserve(additional);
+        self.function_ids.reserve(additional);
     }
 }
 
-pub type LineNumber = u16; // TODO u32, newtype
+/// What to rank callstacks by in AllocationTracker::top_callstacks().
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TopMetric {
+    CurrentBytes,
+    PeakBytes,
+    CurrentAllocations,
+    PeakAllocations,
+}
+
+impl TopMetric {
+    /// Parse "current_bytes", "peak_bytes", "current_allocations" or
+    /// "peak_allocations".
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "current_bytes" => Some(TopMetric::CurrentBytes),
+            "peak_bytes" => Some(TopMetric::PeakBytes),
+            "current_allocations" => Some(TopMetric::CurrentAllocations),
+            "peak_allocations" => Some(TopMetric::PeakAllocations),
+            _ => None,
+        }
+    }
+}
+
+/// Line numbers are u32 since generated code can easily exceed 65535 lines.
+pub type LineNumber = u32;
 
 /// A specific location: file + function + line number.
 #[derive(Clone, Debug, PartialEq, Eq, Copy, Hash)]
@@ -91,12 +291,32 @@ pub struct CallSiteId {
 }
 
 impl CallSiteId {
-    pub fn new(function: FunctionId, line_number: u16) -> CallSiteId {
+    pub fn new(function: FunctionId, line_number: LineNumber) -> CallSiteId {
         CallSiteId {
             function,
             line_number,
         }
     }
+
+    pub fn function(&self) -> FunctionId {
+        self.function
+    }
+
+    pub fn line_number(&self) -> LineNumber {
+        self.line_number
+    }
+
+    /// An ID that's the same in every run, unlike FunctionIds; see
+    /// stableid.rs.
+    pub fn stable_id(&self, functions: &dyn FunctionLocations) -> StableId {
+        let (function, filename) = functions.get_function_and_filename(self.function);
+        callsite_id(
+            functions.get_module(self.function),
+            filename,
+            function,
+            self.line_number,
+        )
+    }
 }
 
 /// The current Python callstack.
@@ -105,7 +325,7 @@ impl CallSiteId {
 pub struct Callstack {
     calls: Vec<CallSiteId>,
     #[derivative(Hash = "ignore", PartialEq = "ignore")]
-    cached_callstack_id: Option<(u16, CallstackId)>, // first bit is line number
+    cached_callstack_id: Option<(LineNumber, Generation, CallstackId)>, // first bit is line number
 }
 
 impl Callstack {
@@ -123,7 +343,7 @@ impl Callstack {
         }
     }
 
-    pub fn start_call(&mut self, parent_line_number: u16, callsite_id: CallSiteId) {
+    pub fn start_call(&mut self, parent_line_number: LineNumber, callsite_id: CallSiteId) {
         if parent_line_number != 0 {
             if let Some(mut call) = self.calls.last_mut() {
                 call.line_number = parent_line_number;
@@ -138,14 +358,30 @@ impl Callstack {
         self.cached_callstack_id = None;
     }
 
-    pub fn id_for_new_allocation<F>(&mut self, line_number: u16, get_callstack_id: F) -> CallstackId
+    /// The calls, outermost first.
+    pub fn calls(&self) -> &[CallSiteId] {
+        &self.calls
+    }
+
+    /// Get the CallstackId for a new allocation at the given line number.
+    ///
+    /// The cached ID is only reused if it was calculated in the same tracker
+    /// generation; a reset() in between means the ID may no longer exist.
+    pub fn id_for_new_allocation<F>(
+        &mut self,
+        line_number: LineNumber,
+        generation: Generation,
+        get_callstack_id: F,
+    ) -> CallstackId
     where
         F: FnOnce(&Callstack) -> CallstackId,
     {
         // If same line number as last callstack, and we have cached callstack
-        // ID, reuse it:
-        if let Some((previous_line_number, callstack_id)) = self.cached_callstack_id {
-            if line_number == previous_line_number {
+        // ID from the current generation, reuse it:
+        if let Some((previous_line_number, previous_generation, callstack_id)) =
+            self.cached_callstack_id
+        {
+            if line_number == previous_line_number && generation == previous_generation {
                 return callstack_id;
             }
         }
@@ -159,102 +395,77 @@ impl Callstack {
 
         // Calculate callstack ID, cache it, and then return it;
         let callstack_id = get_callstack_id(self);
-        self.cached_callstack_id = Some((line_number, callstack_id));
+        self.cached_callstack_id = Some((line_number, generation, callstack_id));
         callstack_id
     }
 
-    pub fn as_string(
-        &self,
-        to_be_post_processed: bool,
-        functions: &dyn FunctionLocations,
-        separator: &'static str,
-    ) -> String {
-        if self.calls.is_empty() {
-            return "[No Python stack]".to_string();
-        }
-        let calls: Vec<(CallSiteId, (&str, &str))> = self
-            .calls
+    /// The frames, outermost first, resolved to strings.
+    pub fn frames(&self, functions: &dyn FunctionLocations) -> Vec<Frame> {
+        self.calls
             .iter()
-            .map(|id| (*id, functions.get_function_and_filename(id.function)))
-            .collect();
-        let skip_prefix = if cfg!(feature = "fil4prod") {
-            0
-        } else {
-            // Due to implementation details we have some runpy() frames at the
-            // start; remove them.
-            runpy_prefix_length(calls.iter())
-        };
-        calls
-            .into_iter()
-            .skip(skip_prefix)
-            .map(|(id, (function, filename))| {
-                if to_be_post_processed {
-                    // Get Python code.
-                    let code = crate::python::get_source_line(filename, id.line_number)
-                        .unwrap_or_else(|_| "".to_string());
-                    // Leading whitespace is dropped by SVG, so we'd like to
-                    // replace it with non-breaking space. However, inferno
-                    // trims whitespace
-                    // (https://github.com/jonhoo/inferno/blob/de3f7d94d4718bfee57655c1fddd4d2714bc78d0/src/flamegraph/merge.rs#L126)
-                    // and that causes incorrect "unsorted lines" errors
-                    // which I can't be bothered to fix right now, so for
-                    // now do hack where we shove in some other character
-                    // that can be fixed in post-processing.
-                    let code = code.replace(" ", "\u{12e4}");
-                    // Semicolons are used as separator in the flamegraph
-                    // input format, so need to replace them with some other
-                    // character. We use "full-width semicolon", and then
-                    // replace it back in post-processing.
-                    let code = code.replace(";", "\u{ff1b}");
-                    // The \u{2800} is to ensure we don't have empty lines,
-                    // and that whitespace doesn't get trimmed from start;
-                    // we'll get rid of this in post-processing.
-                    format!(
-                        "{filename}:{line} ({function});\u{2800}{code}",
-                        filename = filename,
-                        line = id.line_number,
-                        function = function,
-                        code = &code.trim_end(),
-                    )
-                } else {
-                    format!(
-                        "{filename}:{line} ({function})",
-                        filename = filename,
-                        line = id.line_number,
-                        function = function,
-                    )
+            .map(|call| {
+                let (function, filename) = functions.get_function_and_filename(call.function);
+                Frame {
+                    function: function.to_string(),
+                    filename: filename.to_string(),
+                    line_number: call.line_number,
+                    module: functions.get_module(call.function).map(|m| m.to_string()),
                 }
             })
-            .join(separator)
+            .collect()
     }
-}
 
-fn runpy_prefix_length(calls: std::slice::Iter<(CallSiteId, (&str, &str))>) -> usize {
-    let mut length = 0;
-    let runpy_path = get_runpy_path();
-    for (_, (_, filename)) in calls {
-        if *filename == runpy_path {
-            length += 1;
-        } else {
-            return length;
-        }
+    /// An ID that's the same in every run, unlike CallstackIds; see
+    /// stableid.rs.
+    pub fn stable_id(&self, functions: &dyn FunctionLocations) -> StableId {
+        callstack_id(self.calls.iter().map(|call| call.stable_id(functions)))
+    }
+
+    /// All the frames as filename:line (function), outermost first,
+    /// separated by ;. Unlike as_string() this doesn't call into Python, so
+    /// it's safe to use while handling an allocation.
+    pub fn as_plain_string(&self, functions: &dyn FunctionLocations) -> String {
+        self.calls
+            .iter()
+            .map(|call| {
+                let (function, filename) = functions.get_function_and_filename(call.function);
+                format!("{}:{} ({})", filename, call.line_number, function)
+            })
+            .collect::<Vec<_>>()
+            .join(";")
     }
-    0
 }
 
 pub type CallstackId = u32;
 
-/// Maps Functions to integer identifiers used in CallStacks.
+/// Incremented every time the tracker is reset. Anything derived from tracker
+/// state (e.g. thread-local cached CallstackIds) is only valid for the
+/// generation it was created in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct Generation(u64);
+
+impl Generation {
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Maps Callstacks to integer identifiers, and back.
+///
+/// IDs are handed out sequentially, so the reverse mapping is just a Vec
+/// indexed by ID; it's kept up to date on insert rather than rebuilt on every
+/// dump, which matters once there are tens of thousands of callstacks. The
+/// callstacks themselves are shared between the two, rather than copied.
 pub struct CallstackInterner {
-    max_id: CallstackId,
-    callstack_to_id: HashMap<Callstack, u32, ARandomState>,
+    callstack_to_id: HashMap<std::sync::Arc<Callstack>, CallstackId, HashState>,
+    id_to_callstack: Vec<std::sync::Arc<Callstack>>,
 }
 
 impl CallstackInterner {
     pub fn new() -> Self {
         CallstackInterner {
-            max_id: 0,
             callstack_to_id: new_hashmap(),
+            id_to_callstack: vec![],
         }
     }
 
@@ -264,31 +475,67 @@ impl CallstackInterner {
         callstack: Cow<Callstack>,
         call_on_new: F,
     ) -> CallstackId {
-        let max_id = &mut self.max_id;
         if let Some(result) = self.callstack_to_id.get(&*callstack) {
             *result
         } else {
-            let new_id = *max_id;
-            *max_id += 1;
-            self.callstack_to_id.insert(callstack.into_owned(), new_id);
+            let new_id = self.id_to_callstack.len() as CallstackId;
+            let callstack = std::sync::Arc::new(callstack.into_owned());
+            self.id_to_callstack.push(callstack.clone());
+            self.callstack_to_id.insert(callstack, new_id);
             call_on_new();
             new_id
         }
     }
 
-    /// Get map from IDs to Callstacks.
-    fn get_reverse_map(&self) -> HashMap<CallstackId, &Callstack, ARandomState> {
-        let mut result = new_hashmap();
-        for (call_site, csid) in self.callstack_to_id.iter() {
-            result.insert(*csid, call_site);
-        }
-        result
+    /// Make room for at least this many callstacks in total.
+    fn reserve(&mut self, callstacks: usize) {
+        let additional = callstacks.saturating_sub(self.id_to_callstack.len());
+        self.id_to_callstack.reserve(additional);
+        self.callstack_to_id.reserve(additional);
+    }
+
+    /// Roughly how much memory this uses.
+    fn memory_usage(&self) -> usize {
+        let calls: usize = self
+            .id_to_callstack
+            .iter()
+            .map(|callstack| {
+                // The Arc's reference counts, plus the calls themselves:
+                2 * std::mem::size_of::<usize>()
+                    + std::mem::size_of::<Callstack>()
+                    + callstack.calls.capacity() * std::mem::size_of::<CallSiteId>()
+            })
+            .sum();
+        hashmap_bytes(&self.callstack_to_id)
+            + self.id_to_callstack.capacity() * std::mem::size_of::<std::sync::Arc<Callstack>>()
+            + calls
+    }
+
+    /// Look up a single callstack by ID.
+    fn get_callstack(&self, callstack_id: CallstackId) -> Option<&Callstack> {
+        self.id_to_callstack
+            .get(callstack_id as usize)
+            .map(|callstack| &**callstack)
+    }
+
+    /// All callstacks, in order of ID.
+    fn iter(&self) -> impl Iterator<Item = (CallstackId, &Callstack)> {
+        self.id_to_callstack
+            .iter()
+            .enumerate()
+            .map(|(callstack_id, callstack)| (callstack_id as CallstackId, &**callstack))
     }
 }
 
 const MIB: usize = 1024 * 1024;
+
+/// Bumped whenever the checkpoint format changes incompatibly.
+const CHECKPOINT_VERSION: u64 = 1;
 const HIGH_32BIT: u32 = 1 << 31;
 
+/// No allocation can be bigger than this, so bigger sizes are bogus.
+pub const MAX_ALLOCATION_SIZE: usize = isize::MAX as usize;
+
 /// A unique identifier for a process. The idea is that each subprocess will be
 /// given a unique identifier from a counter, and that >4 billion processes is
 /// unlikely. But the internal representation can change!
@@ -332,12 +579,19 @@ impl Allocation {
     }
 }
 
-/// The main data structure tracking everything.
-pub struct AllocationTracker<FL: FunctionLocations> {
-    // malloc()/calloc():
-    current_allocations: BTreeMap<ProcessUid, HashMap<usize, Allocation, ARandomState>>,
+/// The main data structure tracking everything. Amounts are bytes of memory,
+/// unless a different Metric is given; see metric.rs.
+pub struct AllocationTracker<FL: FunctionLocations, M: Metric = Memory> {
+    // malloc()/calloc(). Peaks are aggregated by callstack, so this never
+    // gets copied, and a plain HashMap is much faster than a persistent one
+    // (see examples/allocation_map_bench.rs):
+    current_allocations: BTreeMap<ProcessUid, HashMap<usize, Allocation, HashState>>,
     // anonymous mmap(), i.e. not file backed:
     current_anon_mmaps: BTreeMap<ProcessUid, RangeMap<CallstackId>>,
+    // Requested alignment of live aligned_alloc()/posix_memalign()/memalign()
+    // allocations in the parent process, which are rare enough that storing
+    // it separately is cheaper than growing every Allocation:
+    alignments: HashMap<usize, usize, HashState>, // Map address -> alignment
 
     // Map FunctionIds to function + filename strings, so we can store the
     // former and save memory.
@@ -347,11 +601,52 @@ pub struct AllocationTracker<FL: FunctionLocations> {
     // memory:
     interner: CallstackInterner,
 
-    // Both malloc() and mmap():
+    // Both malloc() and mmap(). The peak is stored already aggregated by
+    // callstack, never as a copy of the per-address allocations; and since
+    // these are persistent vectors, capturing a new peak is cheap: chunks are
+    // shared with the current usage until they're next modified:
     current_memory_usage: ImVector<usize>, // Map CallstackId -> total memory usage
     peak_memory_usage: ImVector<usize>,    // Map CallstackId -> total memory usage
     current_allocated_bytes: usize,
     peak_allocated_bytes: usize,
+
+    // The mmap() part of the above, so the heap's own peak can be found too;
+    // it needn't be at the same moment as the combined peak, e.g. a big
+    // mmap() that came and went while the heap was small. The heap at its
+    // peak is the difference of the two vectors, kept as cheap copies rather
+    // than subtracted on every new peak:
+    current_mmap_usage: ImVector<usize>, // Map CallstackId -> mmap()ed bytes
+    current_mmap_bytes: usize,
+    heap_peak_memory_usage: ImVector<usize>, // Map CallstackId -> total memory usage
+    heap_peak_mmap_usage: ImVector<usize>,   // Map CallstackId -> mmap()ed bytes
+    peak_heap_bytes: usize,
+    peak_mmap_bytes: usize, // mmap()ed bytes at the time of the combined peak
+
+    // Virtual address space, with its own peak; see addressspace.rs:
+    address_space: AddressSpace,
+
+    // mlock()ed ranges and huge page advice; see locked.rs:
+    locked_memory: LockedMemory,
+
+    // Subinterpreter usage, and which interpreter each callstack is from:
+    interpreter_usage: InterpreterUsage,
+    callstack_interpreters: Vec<InterpreterId>, // Map CallstackId -> interpreter
+
+    // Sizes of every allocation made, freed or not:
+    allocation_sizes: Vec<AllocationSizes>, // Map CallstackId -> sizes
+
+    // Allocations per second, to find hot loops; see hotloops.rs:
+    allocation_rates: AllocationRates,
+
+    // Number of live malloc()-style allocations, which can matter even when
+    // the bytes don't, e.g. millions of tiny objects:
+    current_allocation_counts: ImVector<usize>, // Map CallstackId -> live allocations
+    peak_allocation_counts: ImVector<usize>,    // Map CallstackId -> live allocations
+    // Live allocations when memory usage peaked, which is usually a different
+    // moment than when the allocation count peaked:
+    allocation_counts_at_peak: ImVector<usize>, // Map CallstackId -> live allocations
+    current_allocation_count: usize,
+    peak_allocation_count: usize,
     // Default directory to write out data lacking other info:
     default_path: String,
 
@@ -360,34 +655,182 @@ pub struct AllocationTracker<FL: FunctionLocations> {
 
     // free()/realloc() of unknown address. Not relevant for sampling profiler.
     failed_deallocations: usize,
+
+    // Events that can't happen if the hooks and the bookkeeping are correct,
+    // e.g. freeing more bytes than a callstack has. They're counted, and the
+    // numbers clamped, rather than panicking or wrapping around, since a
+    // profiler shouldn't crash or corrupt the program it's profiling.
+    accounting_errors: usize,
+
+    // free() of an address that was recently freed, if detection is enabled.
+    double_frees: usize,
+
+    // Where batched events may have arrived out of order; see sequence.rs.
+    reconciler: Reconciler,
+
+    // Pieces of arenas that libraries reported handing out; see arenas.rs.
+    arenas: Arenas,
+
+    // Expected table sizes, applied again after reset(); see capacity.rs.
+    capacity: Capacity,
+
+    // Bumped by reset(), so stale cached state can be detected.
+    generation: Generation,
+
+    // Command line, environment etc. as of when tracking started.
+    metadata: ProcessMetadata,
+
+    // How reports get written.
+    pub report_options: ReportOptions,
+
+    // Which NUMA nodes back large allocations, if enabled.
+    pub numa: Option<NumaStats>,
+
+    // Recently freed addresses, to detect double frees, if enabled.
+    pub recent_frees: Option<RecentFrees>,
+
+    // Which callstacks free which callstacks' allocations, if enabled.
+    pub lifetime_pairs: Option<LifetimePairs>,
+
+    // How old live allocations are, if enabled.
+    pub allocation_ages: Option<AllocationAges>,
+
+    // Alerts for allocations above a threshold, if enabled.
+    pub large_allocations: Option<LargeAllocations>,
+
+    // What to do when usage crosses a limit, if enabled.
+    pub memory_limit: Option<MemoryLimit>,
+
+    // A lower limit that only notifies, if enabled.
+    pub soft_memory_limit: Option<MemoryLimit>,
+
+    // Peak usage over a recent time window, if enabled.
+    pub windowed_peak: Option<WindowedPeak>,
+
+    // Total usage over time, for the timeline chart.
+    timeline: Timeline,
+
+    // Callsites whose allocations get logged.
+    watchpoints: Watchpoints,
+
+    // Live events for an external viewer, if enabled.
+    stream: Option<EventStream>,
+
+    // What to run after a report is written.
+    pub post_dump: PostDumpHooks,
+
+    // How many times each function was called. Updated without the tracker
+    // lock, so it's shared.
+    pub call_counts: Arc<CallCounts>,
+
+    // Which parent process addresses might be tracked, so frees of untracked
+    // memory can skip the lock. Checked without the tracker lock, so it's
+    // shared.
+    pub tracked_addresses: Arc<TrackedAddresses>,
+
+    // What's being counted:
+    metric: PhantomData<fn() -> M>,
 }
 
 impl<FL: FunctionLocations> AllocationTracker<FL> {
+    /// A tracker of memory, as Fil usually does.
     pub fn new(default_path: String, functions: FL) -> AllocationTracker<FL> {
+        Self::with_metric(default_path, functions)
+    }
+}
+
+impl<FL: FunctionLocations, M: Metric> AllocationTracker<FL, M> {
+    /// A tracker of some other resource; see metric.rs.
+    pub fn with_metric(default_path: String, functions: FL) -> Self {
         AllocationTracker {
             current_allocations: BTreeMap::from([(PARENT_PROCESS, new_hashmap())]),
             current_anon_mmaps: BTreeMap::from([(PARENT_PROCESS, RangeMap::new())]),
+            alignments: new_hashmap(),
             interner: CallstackInterner::new(),
             current_memory_usage: ImVector::new(),
             peak_memory_usage: ImVector::new(),
             functions,
             current_allocated_bytes: 0,
             peak_allocated_bytes: 0,
+            current_mmap_usage: ImVector::new(),
+            current_mmap_bytes: 0,
+            heap_peak_memory_usage: ImVector::new(),
+            heap_peak_mmap_usage: ImVector::new(),
+            peak_heap_bytes: 0,
+            peak_mmap_bytes: 0,
+            address_space: AddressSpace::default(),
+            locked_memory: LockedMemory::default(),
+            interpreter_usage: InterpreterUsage::default(),
+            callstack_interpreters: vec![],
+            allocation_sizes: vec![],
+            allocation_rates: AllocationRates::default(),
+            current_allocation_counts: ImVector::new(),
+            peak_allocation_counts: ImVector::new(),
+            allocation_counts_at_peak: ImVector::new(),
+            current_allocation_count: 0,
+            peak_allocation_count: 0,
             missing_allocated_bytes: 0,
             failed_deallocations: 0,
+            accounting_errors: 0,
+            double_frees: 0,
+            reconciler: Reconciler::default(),
+            arenas: Arenas::default(),
+            capacity: Capacity::default(),
             default_path,
+            generation: Generation::default(),
+            metadata: ProcessMetadata::collect(),
+            report_options: ReportOptions::default(),
+            numa: None,
+            recent_frees: None,
+            lifetime_pairs: None,
+            allocation_ages: None,
+            large_allocations: None,
+            memory_limit: None,
+            soft_memory_limit: None,
+            windowed_peak: None,
+            timeline: Timeline::default(),
+            watchpoints: Watchpoints::new(),
+            stream: None,
+            post_dump: PostDumpHooks::default(),
+            call_counts: Arc::new(CallCounts::default()),
+            tracked_addresses: Arc::new(TrackedAddresses::default()),
+            metric: PhantomData,
         }
     }
 
+    /// Directory reports get written to unless told otherwise.
+    pub fn default_path(&self) -> &str {
+        &self.default_path
+    }
+
+    /// Information about the process, included in every report.
+    pub fn metadata(&self) -> &ProcessMetadata {
+        &self.metadata
+    }
+
+    /// Record that a child process will write its report to the given path.
+    pub fn register_child_output_path(&mut self, path: String) {
+        self.metadata.child_output_paths.push(path);
+    }
+
+    /// The current generation; changes every time reset() is called.
+    pub fn generation(&self) -> Generation {
+        self.generation
+    }
+
     /// Print a traceback for the given CallstackId.
     pub fn print_traceback(&self, message: &'static str, callstack_id: CallstackId) {
-        let id_to_callstack = self.interner.get_reverse_map();
-        let callstack = id_to_callstack[&callstack_id];
+        let callstack = self
+            .interner
+            .get_callstack(callstack_id)
+            .expect("callstack IDs always come from the interner");
         eprintln!("=fil-profile= {}", message);
-        eprintln!(
-            "=| {}",
-            callstack.as_string(false, &self.functions, "\n=| ")
-        );
+        for frame in callstack.frames(&self.functions) {
+            eprintln!(
+                "=| {}:{} ({})",
+                frame.filename, frame.line_number, frame.function
+            );
+        }
     }
 
     pub fn get_current_allocated_bytes(&self) -> usize {
@@ -398,6 +841,94 @@ impl<FL: FunctionLocations> AllocationTracker<FL> {
         self.peak_allocated_bytes
     }
 
+    /// The peak of malloc()-style allocations alone, without mmap().
+    pub fn get_peak_heap_bytes(&self) -> usize {
+        self.peak_heap_bytes
+    }
+
+    /// Current and peak virtual address space; see addressspace.rs.
+    pub fn get_address_space(&self) -> AddressSpaceStats {
+        self.address_space.stats()
+    }
+
+    /// The program locked or unlocked memory, or gave huge page advice; see
+    /// locked.rs.
+    pub fn record_lock_event(&mut self, event: LockEvent) {
+        self.locked_memory.record(event);
+    }
+
+    /// The peak over the recent window, if enabled; see window.rs.
+    pub fn get_window_peak_allocated_bytes(&mut self) -> Option<usize> {
+        let current = self.current_allocated_bytes;
+        self.windowed_peak
+            .as_mut()
+            .map(|windowed_peak| windowed_peak.peak(current))
+    }
+
+    /// The ID of a callstack that's the same in every run; see stableid.rs.
+    pub fn stable_callstack_id(&self, callstack_id: CallstackId) -> Option<StableId> {
+        self.interner
+            .get_callstack(callstack_id)
+            .map(|callstack| callstack.stable_id(&self.functions))
+    }
+
+    /// Mark the current moment on the timeline chart, e.g. the end of a
+    /// phase of the program.
+    pub fn add_timeline_marker(&mut self, label: &str) {
+        self.timeline.add_marker(label);
+    }
+
+    /// The top callstacks by the given metric, largest first, as plain
+    /// strings (see Callstack::as_plain_string()). This is cheap enough to
+    /// poll, unlike writing out a report.
+    ///
+    /// It doesn't change the tracker, so it can be used with only read access.
+    pub fn top_callstacks(&self, count: usize, metric: TopMetric) -> Vec<(String, usize)> {
+        // Peaks are only recorded by check_if_new_peak(), so if we're at a
+        // new peak right now the current values are the peak values:
+        let values = match metric {
+            TopMetric::CurrentBytes => &self.current_memory_usage,
+            TopMetric::PeakBytes if self.current_allocated_bytes > self.peak_allocated_bytes => {
+                &self.current_memory_usage
+            }
+            TopMetric::PeakBytes => &self.peak_memory_usage,
+            TopMetric::CurrentAllocations => &self.current_allocation_counts,
+            TopMetric::PeakAllocations
+                if self.current_allocation_count > self.peak_allocation_count =>
+            {
+                &self.current_allocation_counts
+            }
+            TopMetric::PeakAllocations => &self.peak_allocation_counts,
+        };
+        let mut usage: Vec<(CallstackId, usize)> = values
+            .iter()
+            .enumerate()
+            .filter(|(_, bytes)| **bytes > 0)
+            .map(|(callstack_id, bytes)| (callstack_id as CallstackId, *bytes))
+            .collect();
+        usage.sort_by_key(|(_, bytes)| std::cmp::Reverse(*bytes));
+        usage.truncate(count);
+        let interner = &self.interner;
+        let functions = &self.functions;
+        usage
+            .into_iter()
+            .filter_map(|(callstack_id, bytes)| {
+                Some((
+                    interner
+                        .get_callstack(callstack_id)?
+                        .as_plain_string(functions),
+                    bytes,
+                ))
+            })
+            .collect()
+    }
+
+    /// The most allocations that were ever live at the same time.
+    pub fn get_peak_allocation_count(&self) -> usize {
+        self.peak_allocation_count
+            .max(self.current_allocation_count)
+    }
+
     pub fn get_allocation_size(&self, process: ProcessUid, address: usize) -> usize {
         if let Some(allocation) = self
             .current_allocations
@@ -415,30 +946,178 @@ impl<FL: FunctionLocations> AllocationTracker<FL> {
     pub fn check_if_new_peak(&mut self) {
         if self.current_allocated_bytes > self.peak_allocated_bytes {
             self.peak_allocated_bytes = self.current_allocated_bytes;
+            self.peak_mmap_bytes = self.current_mmap_bytes;
             self.peak_memory_usage
                 .clone_from(&self.current_memory_usage);
+            self.allocation_counts_at_peak
+                .clone_from(&self.current_allocation_counts);
+            if let Some(ages) = &mut self.allocation_ages {
+                ages.new_peak();
+            }
+        }
+        if self.current_allocation_count > self.peak_allocation_count {
+            self.peak_allocation_count = self.current_allocation_count;
+            self.peak_allocation_counts
+                .clone_from(&self.current_allocation_counts);
+        }
+        let heap_bytes = self
+            .current_allocated_bytes
+            .saturating_sub(self.current_mmap_bytes);
+        if heap_bytes > self.peak_heap_bytes {
+            self.peak_heap_bytes = heap_bytes;
+            self.heap_peak_memory_usage
+                .clone_from(&self.current_memory_usage);
+            self.heap_peak_mmap_usage
+                .clone_from(&self.current_mmap_usage);
+        }
+    }
+
+    /// Record an impossible event; see accounting_errors.
+    fn accounting_error(&mut self, description: &str) {
+        self.accounting_errors += 1;
+        if *crate::util::DEBUG_MODE {
+            eprintln!("=fil-profile= Accounting error: {}", description);
+            eprintln!("=| {:?}", backtrace::Backtrace::new());
         }
     }
 
     fn add_memory_usage(&mut self, callstack_id: CallstackId, bytes: usize) {
-        self.current_allocated_bytes += bytes;
+        self.current_allocated_bytes = self.current_allocated_bytes.saturating_add(bytes);
         let index = callstack_id as usize;
-        self.current_memory_usage[index] += bytes;
+        let usage = &mut self.current_memory_usage[index];
+        *usage = usage.saturating_add(bytes);
+        let interpreter = self.callstack_interpreters[index];
+        if interpreter != MAIN_INTERPRETER {
+            self.interpreter_usage.add(interpreter, bytes);
+        }
+        if let Some(windowed_peak) = &mut self.windowed_peak {
+            windowed_peak.update(self.current_allocated_bytes);
+        }
+        self.timeline.update(self.current_allocated_bytes);
+        if let Some(stream) = &mut self.stream {
+            stream.allocated(callstack_id, bytes);
+            stream.maybe_summarize(
+                self.current_allocated_bytes,
+                self.peak_allocated_bytes.max(self.current_allocated_bytes),
+            );
+        }
     }
 
     fn remove_memory_usage(&mut self, callstack_id: CallstackId, bytes: usize) {
-        self.current_allocated_bytes -= bytes;
         let index = callstack_id as usize;
-        // TODO what if goes below zero? add a check I guess, in case of bugs.
-        self.current_memory_usage[index] -= bytes;
+        if bytes > self.current_allocated_bytes || bytes > self.current_memory_usage[index] {
+            self.accounting_error("freed more bytes than were allocated");
+        }
+        self.current_allocated_bytes = self.current_allocated_bytes.saturating_sub(bytes);
+        let usage = &mut self.current_memory_usage[index];
+        *usage = usage.saturating_sub(bytes);
+        if let Some(windowed_peak) = &mut self.windowed_peak {
+            windowed_peak.update(self.current_allocated_bytes);
+        }
+        self.timeline.update(self.current_allocated_bytes);
+        let interpreter = self.callstack_interpreters[index];
+        if interpreter != MAIN_INTERPRETER {
+            self.interpreter_usage.remove(interpreter, bytes);
+        }
+        if let Some(stream) = &mut self.stream {
+            stream.freed(callstack_id, bytes);
+            stream.maybe_summarize(self.current_allocated_bytes, self.peak_allocated_bytes);
+        }
+    }
+
+    /// The mmap()ed part of add_memory_usage(), which must also be called.
+    fn add_mmap_usage(&mut self, callstack_id: CallstackId, bytes: usize) {
+        self.current_mmap_bytes = self.current_mmap_bytes.saturating_add(bytes);
+        let usage = &mut self.current_mmap_usage[callstack_id as usize];
+        *usage = usage.saturating_add(bytes);
+    }
+
+    /// The mmap()ed part of remove_memory_usage(), which must also be
+    /// called.
+    fn remove_mmap_usage(&mut self, callstack_id: CallstackId, bytes: usize) {
+        let index = callstack_id as usize;
+        if bytes > self.current_mmap_bytes || bytes > self.current_mmap_usage[index] {
+            self.accounting_error("unmapped more bytes than were mapped");
+        }
+        self.current_mmap_bytes = self.current_mmap_bytes.saturating_sub(bytes);
+        let usage = &mut self.current_mmap_usage[index];
+        *usage = usage.saturating_sub(bytes);
+    }
+
+    fn add_allocation_count(&mut self, callstack_id: CallstackId) {
+        self.current_allocation_count += 1;
+        self.current_allocation_counts[callstack_id as usize] += 1;
+    }
+
+    fn remove_allocation_count(&mut self, callstack_id: CallstackId) {
+        let index = callstack_id as usize;
+        if self.current_allocation_count == 0 || self.current_allocation_counts[index] == 0 {
+            self.accounting_error("freed more allocations than were made");
+        }
+        self.current_allocation_count = self.current_allocation_count.saturating_sub(1);
+        let count = &mut self.current_allocation_counts[index];
+        *count = count.saturating_sub(1);
+    }
+
+    /// The callstack with the given ID, if it exists.
+    pub fn get_callstack(&self, callstack_id: CallstackId) -> Option<&Callstack> {
+        self.interner.get_callstack(callstack_id)
     }
 
     pub fn get_callstack_id(&mut self, callstack: &Callstack) -> CallstackId {
         let current_memory_usage = &mut self.current_memory_usage;
-        self.interner
+        let current_mmap_usage = &mut self.current_mmap_usage;
+        let current_allocation_counts = &mut self.current_allocation_counts;
+        let mut is_new = false;
+        let callstack_id = self
+            .interner
             .get_or_insert_id(Cow::Borrowed(callstack), || {
-                current_memory_usage.push_back(0)
-            })
+                current_memory_usage.push_back(0);
+                current_mmap_usage.push_back(0);
+                current_allocation_counts.push_back(0);
+                is_new = true;
+            });
+        if is_new {
+            let interpreter = match callstack.calls.first() {
+                Some(root) => self.functions.get_interpreter(root.function),
+                None => MAIN_INTERPRETER,
+            };
+            self.callstack_interpreters.push(interpreter);
+            self.allocation_sizes.push(AllocationSizes::default());
+            self.watchpoints
+                .check_callstack(callstack_id, callstack, &self.functions);
+            if let Some(stream) = &mut self.stream {
+                stream.new_callstack(callstack_id, callstack, &self.functions);
+            }
+        }
+        callstack_id
+    }
+
+    /// Start (or stop) streaming live events. Existing callstacks are sent
+    /// first, so the reader can make sense of later events.
+    pub fn set_event_stream(&mut self, mut stream: Option<EventStream>) {
+        if let Some(stream) = &mut stream {
+            for (callstack_id, callstack) in self.interner.iter() {
+                stream.new_callstack(callstack_id, callstack, &self.functions);
+            }
+            for (callstack_id, bytes) in self.current_memory_usage.iter().enumerate() {
+                if *bytes > 0 {
+                    stream.allocated(callstack_id as CallstackId, *bytes);
+                }
+            }
+        }
+        self.stream = stream;
+    }
+
+    /// Log all future allocations from a callsite.
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.add(watchpoint);
+        // Check callstacks we've already seen:
+        self.watchpoints.clear();
+        for (callstack_id, callstack) in self.interner.iter() {
+            self.watchpoints
+                .check_callstack(callstack_id, callstack, &self.functions);
+        }
     }
 
     /// Add a new allocation based off the current callstack.
@@ -449,14 +1128,40 @@ impl<FL: FunctionLocations> AllocationTracker<FL> {
         size: usize,
         callstack_id: CallstackId,
     ) {
+        if size > MAX_ALLOCATION_SIZE {
+            self.accounting_error("allocation size is impossibly large");
+            return;
+        }
+        self.check_large_allocation(address, size, callstack_id);
+        self.allocation_sizes[callstack_id as usize].record(size);
+        self.allocation_rates.record(callstack_id);
         let alloc = Allocation::new(callstack_id, size);
         let compressed_size = alloc.size();
+        if process == PARENT_PROCESS {
+            if let Some(recent_frees) = &mut self.recent_frees {
+                recent_frees.forget(address);
+            }
+            if let Some(lifetime_pairs) = &mut self.lifetime_pairs {
+                lifetime_pairs.allocated(address);
+            }
+            if let Some(ages) = &mut self.allocation_ages {
+                ages.allocated(address);
+            }
+            if !self.alignments.is_empty() {
+                self.alignments.remove(&address);
+            }
+            self.tracked_addresses.insert(address);
+        }
         if let Some(previous) = self
             .current_allocations
             .entry(process)
             .or_default()
             .insert(address, alloc)
         {
+            // Still tracked, but only once:
+            if process == PARENT_PROCESS {
+                self.tracked_addresses.remove(address);
+            }
             // In production use (proposed commercial product) allocations are
             // only sampled, so missing allocations are common and not the sign
             // of an error.
@@ -468,8 +1173,19 @@ impl<FL: FunctionLocations> AllocationTracker<FL> {
                 // outcomes. Apparently also happening on Linux, hope to fix this
                 // soon (https://github.com/pythonspeed/filprofiler/issues/149).
                 self.missing_allocated_bytes += previous.size();
+                // Maybe its free() is buffered somewhere and will show up
+                // late:
+                if process == PARENT_PROCESS {
+                    self.reconciler.replaced(address, previous.size());
+                    if !self.arenas.is_empty() {
+                        let released = self.arenas.release_arena(address);
+                        self.return_to_arenas(released);
+                    }
+                }
                 // Cleanup the previous allocation, since we never saw its free():
                 self.remove_memory_usage(previous.callstack_id, previous.size());
+                self.remove_allocation_count(previous.callstack_id);
+                self.address_space.remove_heap(previous.size());
                 if *crate::util::DEBUG_MODE {
                     self.print_traceback(
                         "The allocation from this traceback disappeared:",
@@ -487,6 +1203,250 @@ impl<FL: FunctionLocations> AllocationTracker<FL> {
             }
         }
         self.add_memory_usage(callstack_id, compressed_size as usize);
+        self.add_allocation_count(callstack_id);
+        self.address_space.add_heap(compressed_size);
+        self.watchpoints
+            .log_allocation(callstack_id, address, size, &self.default_path);
+    }
+
+    /// Alert on the allocation if it's large enough; see largeallocs.rs.
+    #[inline]
+    fn check_large_allocation(&mut self, address: usize, size: usize, callstack_id: CallstackId) {
+        let large = match &mut self.large_allocations {
+            Some(large) if large.is_large(size) => large,
+            _ => return,
+        };
+        let functions = &self.functions;
+        let frames = self
+            .interner
+            .get_callstack(callstack_id)
+            .map(|callstack| callstack.frames(functions))
+            .unwrap_or_default();
+        large.alert(address, size, &frames, &self.default_path);
+    }
+
+    /// Call after adding allocations: if usage just crossed the memory limit,
+    /// returns what the frontend should do about it, after writing a report
+    /// with memory_limit_dump(). See limit.rs.
+    #[inline]
+    pub fn check_memory_limit(&mut self) -> Option<LimitAction> {
+        let current = self.current_allocated_bytes;
+        let soft = match &mut self.soft_memory_limit {
+            Some(limit) => limit.check(current),
+            None => None,
+        };
+        let hard = match &mut self.memory_limit {
+            Some(limit) => limit.check(current),
+            None => None,
+        };
+        // If one allocation crosses both, the hard limit's report and action
+        // are what matter:
+        hard.or(soft)
+    }
+
+    /// Add an allocation made by calloc(), of `count` elements of `size`
+    /// bytes each. calloc() fails rather than wrapping around if the total
+    /// overflows, so in that case nothing was allocated and nothing is
+    /// recorded. Returns the total size if it was recorded.
+    pub fn add_calloc(
+        &mut self,
+        process: ProcessUid,
+        address: usize,
+        count: usize,
+        size: usize,
+        callstack_id: CallstackId,
+    ) -> Option<usize> {
+        let total = count.checked_mul(size)?;
+        self.add_allocation(process, address, total, callstack_id);
+        Some(total)
+    }
+
+    /// Add an allocation from aligned_alloc(), posix_memalign() or
+    /// memalign(). It's counted like any other allocation, and the requested
+    /// alignment is recorded too; see aligned_usage().
+    pub fn add_aligned_allocation(
+        &mut self,
+        process: ProcessUid,
+        address: usize,
+        size: usize,
+        alignment: usize,
+        callstack_id: CallstackId,
+    ) {
+        self.add_allocation(process, address, size, callstack_id);
+        if process == PARENT_PROCESS {
+            self.alignments.insert(address, alignment);
+        }
+    }
+
+    /// Live aligned allocations in the parent process, as alignment ->
+    /// (number of allocations, bytes).
+    pub fn aligned_usage(&self) -> BTreeMap<usize, (usize, usize)> {
+        let mut result: BTreeMap<usize, (usize, usize)> = BTreeMap::new();
+        for (address, alignment) in self.alignments.iter() {
+            let usage = result.entry(*alignment).or_default();
+            usage.0 += 1;
+            usage.1 += self.get_allocation_size(PARENT_PROCESS, *address);
+        }
+        result
+    }
+
+    /// Add multiple allocations from the same callstack, as (address, size,
+    /// sequence number). Sequence numbers are from next_sequence(), or
+    /// UNKNOWN_SEQUENCE.
+    pub fn add_allocations(
+        &mut self,
+        process: ProcessUid,
+        allocations: &[(usize, usize, u64)],
+        callstack_id: CallstackId,
+    ) {
+        for (address, size, sequence) in allocations {
+            self.add_allocation_sequenced(process, *address, *size, callstack_id, *sequence);
+        }
+    }
+
+    /// Free multiple allocations, as (address, sequence number), returning
+    /// the total bytes removed.
+    pub fn free_allocations(&mut self, process: ProcessUid, addresses: &[(usize, u64)]) -> usize {
+        addresses
+            .iter()
+            .filter_map(|(address, sequence)| {
+                self.free_allocation_sequenced(process, *address, *sequence)
+            })
+            .sum()
+    }
+
+    /// Add an allocation that may have been buffered, and so may arrive after
+    /// its own free(); see sequence.rs.
+    pub fn add_allocation_sequenced(
+        &mut self,
+        process: ProcessUid,
+        address: usize,
+        size: usize,
+        callstack_id: CallstackId,
+        sequence: u64,
+    ) {
+        if process == PARENT_PROCESS && self.reconciler.stale_allocation(address, sequence) {
+            // Already freed, so there's nothing to track:
+            return;
+        }
+        self.add_allocation(process, address, size, callstack_id);
+    }
+
+    /// Free an allocation where the free() may have been buffered, and so may
+    /// arrive after the address was reused; see sequence.rs.
+    pub fn free_allocation_sequenced(
+        &mut self,
+        process: ProcessUid,
+        address: usize,
+        sequence: u64,
+    ) -> Option<usize> {
+        if process == PARENT_PROCESS {
+            if let Some(size) = self.reconciler.stale_free(address, sequence) {
+                // The replaced allocation was already removed when the
+                // address was reused, it just wasn't missing after all:
+                self.missing_allocated_bytes = self.missing_allocated_bytes.saturating_sub(size);
+                return None;
+            }
+        }
+        self.free_allocation(process, address)
+    }
+
+    /// Size the tables for the expected number of live allocations,
+    /// callstacks and functions, so they don't have to grow, and rehash,
+    /// while the program runs. It lasts across reset(). Tables are never
+    /// shrunk, so asking for less than they already hold does nothing.
+    pub fn reserve(&mut self, capacity: Capacity) {
+        self.capacity = capacity;
+        let allocations = self
+            .current_allocations
+            .entry(PARENT_PROCESS)
+            .or_insert_with(new_hashmap);
+        allocations.reserve(capacity.live_allocations.saturating_sub(allocations.len()));
+        self.interner.reserve(capacity.callstacks);
+        self.functions.reserve(capacity.functions);
+    }
+
+    /// A library handed out `size` bytes at `address` from an arena it
+    /// allocated earlier, which starts at `arena`; the bytes are blamed on
+    /// `callstack_id` rather than the arena's callstack until they're given
+    /// back. Returns false if the arena isn't a tracked allocation or mmap()
+    /// in this process, or the piece doesn't fit in what's left of it.
+    pub fn add_suballocation(
+        &mut self,
+        arena: usize,
+        address: usize,
+        size: usize,
+        callstack_id: CallstackId,
+    ) -> bool {
+        let arena = match self.find_arena(arena) {
+            Some(arena) => arena,
+            None => return false,
+        };
+        if !self.arenas.lend(arena, address, size, callstack_id) {
+            return false;
+        }
+        // Moving bytes between callstacks can lower the arena's callstack, so
+        // let's check if we've previously hit a peak:
+        self.check_if_new_peak();
+        self.remove_memory_usage(arena.2, size);
+        self.add_memory_usage(callstack_id, size);
+        // Pieces of an mmap()ed arena are still mmap()ed memory:
+        if self.is_mmap_arena(arena.0) {
+            self.remove_mmap_usage(arena.2, size);
+            self.add_mmap_usage(callstack_id, size);
+        }
+        true
+    }
+
+    /// Whether an arena is mmap()ed rather than a malloc()ed allocation.
+    /// Arenas are released before they're removed, so this works while
+    /// they're being freed too.
+    fn is_mmap_arena(&self, arena: usize) -> bool {
+        !self
+            .current_allocations
+            .get(&PARENT_PROCESS)
+            .is_some_and(|allocations| allocations.contains_key(&arena))
+    }
+
+    /// A piece handed out by add_suballocation() went back to its arena.
+    /// Returns how many bytes it had, if any.
+    pub fn free_suballocation(&mut self, address: usize) -> Option<usize> {
+        let suballocation = self.arenas.give_back(address)?;
+        let size = suballocation.size;
+        self.return_to_arenas(vec![suballocation]);
+        Some(size)
+    }
+
+    /// The arena starting at this address, as (start, size, callstack).
+    /// Arenas are either malloc()ed or mmap()ed; for the latter the address
+    /// can be anywhere in the mapping.
+    fn find_arena(&self, address: usize) -> Option<(usize, usize, CallstackId)> {
+        if let Some(allocation) = self
+            .current_allocations
+            .get(&PARENT_PROCESS)
+            .and_then(|allocations| allocations.get(&address))
+        {
+            return Some((address, allocation.size(), allocation.callstack_id));
+        }
+        self.current_anon_mmaps
+            .get(&PARENT_PROCESS)?
+            .find(address)
+            .map(|(start, length, callstack_id)| (start, length, *callstack_id))
+    }
+
+    fn return_to_arenas(&mut self, suballocations: Vec<SubAllocation>) {
+        if suballocations.is_empty() {
+            return;
+        }
+        self.check_if_new_peak();
+        for suballocation in suballocations {
+            self.remove_memory_usage(suballocation.callstack_id, suballocation.size);
+            self.add_memory_usage(suballocation.arena_callstack_id, suballocation.size);
+            if self.is_mmap_arena(suballocation.arena) {
+                self.remove_mmap_usage(suballocation.callstack_id, suballocation.size);
+                self.add_mmap_usage(suballocation.arena_callstack_id, suballocation.size);
+            }
+        }
     }
 
     /// Free an existing allocation, return how much was removed, if any.
@@ -494,18 +1454,45 @@ impl<FL: FunctionLocations> AllocationTracker<FL> {
         // Before we reduce memory, let's check if we've previously hit a peak:
         self.check_if_new_peak();
 
+        if let (Some(lifetime_pairs), PARENT_PROCESS) = (&mut self.lifetime_pairs, process) {
+            lifetime_pairs.forget(address);
+        }
+        if process == PARENT_PROCESS && !self.alignments.is_empty() {
+            self.alignments.remove(&address);
+        }
+        if process == PARENT_PROCESS && !self.arenas.is_empty() {
+            let released = self.arenas.release_arena(address);
+            self.return_to_arenas(released);
+        }
         if let Some(removed) = self
             .current_allocations
             .entry(process)
             .or_default()
             .remove(&address)
         {
+            if process == PARENT_PROCESS {
+                self.tracked_addresses.remove(address);
+            }
             self.remove_memory_usage(removed.callstack_id, removed.size());
+            self.remove_allocation_count(removed.callstack_id);
+            self.address_space.remove_heap(removed.size());
+            // The memory hasn't actually been freed yet, so we can still see
+            // where it lives:
+            if let (Some(numa), PARENT_PROCESS) = (&mut self.numa, process) {
+                numa.record(address, removed.size());
+            }
+            if let (Some(ages), PARENT_PROCESS) = (&mut self.allocation_ages, process) {
+                ages.freed(address, removed.size(), removed.callstack_id);
+            }
             Some(removed.size())
         } else {
             // This allocation doesn't exist; often this will be something
             // allocated before Fil tracking was started, but it might also be a
-            // bug.
+            // bug, or its allocation is buffered somewhere and will show up
+            // late.
+            if process == PARENT_PROCESS {
+                self.reconciler.unmatched_free(address);
+            }
             #[cfg(not(feature = "fil4prod"))]
             if *crate::util::DEBUG_MODE {
                 self.failed_deallocations += 1;
@@ -518,6 +1505,146 @@ impl<FL: FunctionLocations> AllocationTracker<FL> {
         }
     }
 
+    /// Whether free_allocation_from() is worth the cost of finding the
+    /// freeing callstack.
+    pub fn wants_free_callstacks(&self) -> bool {
+        self.recent_frees.is_some() || self.lifetime_pairs.is_some()
+    }
+
+    /// Free an allocation, knowing which callstack freed it, so double frees
+    /// can be detected and lifetime pairs recorded, if enabled.
+    pub fn free_allocation_from(
+        &mut self,
+        process: ProcessUid,
+        address: usize,
+        callstack_id: CallstackId,
+    ) -> Option<usize> {
+        let allocated_by = self
+            .current_allocations
+            .get(&process)
+            .and_then(|allocations| allocations.get(&address))
+            .map(|allocation| allocation.callstack_id);
+        if let (Some(lifetime_pairs), Some(allocated_by), PARENT_PROCESS) =
+            (&mut self.lifetime_pairs, allocated_by, process)
+        {
+            let size = self.current_allocations[&process][&address].size();
+            lifetime_pairs.freed(address, size, allocated_by, callstack_id);
+        }
+        let result = self.free_allocation(process, address);
+        if process != PARENT_PROCESS {
+            return result;
+        }
+        match (result, allocated_by) {
+            (Some(size), Some(allocated_by)) => {
+                if let Some(recent_frees) = &mut self.recent_frees {
+                    recent_frees.record(
+                        address,
+                        Freed {
+                            size,
+                            allocated_by,
+                            freed_by: callstack_id,
+                        },
+                    );
+                }
+            }
+            _ => {
+                if let Some(previous) = self
+                    .recent_frees
+                    .as_ref()
+                    .and_then(|recent_frees| recent_frees.check(address))
+                {
+                    self.double_frees += 1;
+                    eprintln!(
+                        "=fil-profile= WARNING: Double free of {} bytes at address {:#x}.",
+                        previous.size, address
+                    );
+                    self.print_traceback("It was allocated here:", previous.allocated_by);
+                    self.print_traceback("It was first freed here:", previous.freed_by);
+                    self.print_traceback("And then freed again here:", callstack_id);
+                }
+            }
+        }
+        result
+    }
+
+    /// How many double frees were detected.
+    pub fn get_double_frees(&self) -> usize {
+        self.double_frees
+    }
+
+    /// Roughly how much memory the tracker's own data structures use. This
+    /// is the profiler's overhead, and none of it is in the tracked totals.
+    pub fn profiler_memory(&self) -> ProfilerMemory {
+        let usize_bytes = std::mem::size_of::<usize>();
+        ProfilerMemory {
+            callsites: self.functions.memory_usage()
+                + self.interner.memory_usage()
+                + self.callstack_interpreters.capacity() * std::mem::size_of::<InterpreterId>()
+                + self.allocation_sizes.capacity() * std::mem::size_of::<AllocationSizes>()
+                + self.allocation_rates.memory_usage(),
+            allocations: self
+                .current_allocations
+                .values()
+                .map(hashmap_bytes)
+                .sum::<usize>()
+                + hashmap_bytes(&self.alignments)
+                + self
+                    .allocation_ages
+                    .as_ref()
+                    .map_or(0, |ages| ages.memory_usage())
+                + self
+                    .windowed_peak
+                    .as_ref()
+                    .map_or(0, |windowed_peak| windowed_peak.memory_usage())
+                + self.timeline.memory_usage()
+                + self.address_space.memory_usage()
+                + self.locked_memory.memory_usage()
+                + self.tracked_addresses.memory_usage()
+                + self.reconciler.memory_usage()
+                + self.arenas.memory_usage()
+                + self
+                    .current_anon_mmaps
+                    .values()
+                    .map(|mmaps| mmaps.memory_usage())
+                    .sum::<usize>(),
+            // An overestimate, since current and peak share unmodified
+            // chunks:
+            usage: (self.current_memory_usage.len()
+                + self.peak_memory_usage.len()
+                + self.current_mmap_usage.len()
+                + self.heap_peak_memory_usage.len()
+                + self.heap_peak_mmap_usage.len()
+                + self.current_allocation_counts.len()
+                + self.peak_allocation_counts.len()
+                + self.allocation_counts_at_peak.len())
+                * usize_bytes,
+        }
+    }
+
+    /// Numbers about the tracker itself; see trackerstats.rs. Lock
+    /// contention is left for the caller to fill in.
+    pub fn tracker_stats(&self) -> TrackerStats {
+        TrackerStats {
+            live_allocations: self.current_allocation_count,
+            live_mmaps: self
+                .current_anon_mmaps
+                .values()
+                .map(|mmaps| mmaps.iter().count())
+                .sum(),
+            functions: self.functions.function_count(),
+            callstacks: self.interner.iter().count(),
+            peak_snapshot_bytes: (self.peak_memory_usage.len()
+                + self.heap_peak_memory_usage.len()
+                + self.heap_peak_mmap_usage.len()
+                + self.peak_allocation_counts.len()
+                + self.allocation_counts_at_peak.len())
+                * std::mem::size_of::<usize>(),
+            profiler_memory: self.profiler_memory(),
+            generation: self.generation.as_u64(),
+            lock: Default::default(),
+        }
+    }
+
     /// Add a new anonymous mmap() based of the current callstack.
     pub fn add_anon_mmap(
         &mut self,
@@ -526,16 +1653,40 @@ impl<FL: FunctionLocations> AllocationTracker<FL> {
         size: usize,
         callstack_id: CallstackId,
     ) {
+        if size > MAX_ALLOCATION_SIZE {
+            self.accounting_error("mmap() size is impossibly large");
+            return;
+        }
+        self.check_large_allocation(address, size, callstack_id);
+        self.allocation_sizes[callstack_id as usize].record(size);
+        self.allocation_rates.record(callstack_id);
+        self.watchpoints
+            .log_allocation(callstack_id, address, size, &self.default_path);
         self.current_anon_mmaps
             .entry(process)
             .or_default()
             .add(address, size, callstack_id);
+        self.address_space.add_mapping(process, address, size);
         self.add_memory_usage(callstack_id, size);
+        self.add_mmap_usage(callstack_id, size);
     }
 
     pub fn free_anon_mmap(&mut self, process: ProcessUid, address: usize, size: usize) {
         // Before we reduce memory, let's check if we've previously hit a peak:
         self.check_if_new_peak();
+        if let (Some(numa), PARENT_PROCESS) = (&mut self.numa, process) {
+            numa.record(address, size);
+        }
+        self.address_space.remove_mapping(process, address, size);
+        if process == PARENT_PROCESS {
+            self.locked_memory.unmapped(address, size);
+        }
+        if process == PARENT_PROCESS && !self.arenas.is_empty() {
+            let released = self
+                .arenas
+                .release_range(address, address.saturating_add(size));
+            self.return_to_arenas(released);
+        }
         // Now remove, and update totoal memory tracking:
         for (callstack_id, removed) in self
             .current_anon_mmaps
@@ -544,6 +1695,7 @@ impl<FL: FunctionLocations> AllocationTracker<FL> {
             .remove(address, size)
         {
             self.remove_memory_usage(callstack_id, removed);
+            self.remove_mmap_usage(callstack_id, removed);
         }
     }
 
@@ -552,204 +1704,613 @@ impl<FL: FunctionLocations> AllocationTracker<FL> {
         // Before we reduce memory, let's check if we've previously hit a peak:
         self.check_if_new_peak();
 
+        // Arena pieces go back to their arenas, which are about to go away:
+        if process == PARENT_PROCESS {
+            let released = self.arenas.drain();
+            self.return_to_arenas(released);
+        }
+
         // Drop anon mmaps, call remove_memory_usage on all entries.
         if let Some(mmaps_for_process) = self.current_anon_mmaps.remove(&process) {
-            for (size, callstack_id) in mmaps_for_process.into_iter() {
-                self.remove_memory_usage(callstack_id, size);
+            for (callstack_id, size) in mmaps_for_process.sizes_by_value() {
+                self.remove_memory_usage(*callstack_id, *size);
+                self.remove_mmap_usage(*callstack_id, *size);
             }
         }
+        self.address_space.drop_process(process);
+        if process == PARENT_PROCESS {
+            self.locked_memory = LockedMemory::default();
+        }
 
         // Drop allocations, call remove_memory_usage on all entries.
+        if process == PARENT_PROCESS {
+            self.tracked_addresses.clear();
+        }
         if let Some(allocations_for_process) = self.current_allocations.remove(&process) {
             for allocation in allocations_for_process.values() {
                 self.remove_memory_usage(allocation.callstack_id, allocation.size());
+                self.remove_allocation_count(allocation.callstack_id);
+                self.address_space.remove_heap(allocation.size());
             }
         }
     }
 
-    /// Combine Callstacks and make them human-readable. Duplicate callstacks
-    /// have their allocated memory summed.
-    fn combine_callstacks(
-        &self,
-        // If false, will do the current allocations:
-        peak: bool,
-    ) -> HashMap<CallstackId, usize, ARandomState> {
-        // Would be nice to validate if data is consistent. However, there are
-        // edge cases that make it slightly inconsistent (e.g. see the
-        // unexpected code path in add_allocation() above), and blowing up
-        // without giving the user their data just because of a small
-        // inconsistency doesn't seem ideal. Perhaps if validate() merely
-        // reported problems, or maybe validate() should only be enabled in
-        // development mode.
-        //self.validate();
-
-        // We get a LOT of tiny allocations. To reduce overhead of creating
-        // flamegraph (which currently loads EVERYTHING into memory), just do
-        // the top 99% of allocations.
-        let callstacks = if peak {
-            &self.peak_memory_usage
-        } else {
-            &self.current_memory_usage
+    /// Everything reporting needs to know, as plain data. Only callstacks
+    /// that are using memory are included.
+    ///
+    /// Everything is sorted, so that identical runs give identical
+    /// snapshots, regardless of hashing or which thread got which
+    /// CallstackId first.
+    pub fn snapshot(&mut self) -> ProfileData {
+        // First, make sure peaks are correct:
+        self.check_if_new_peak();
+        let current_bytes = self.current_allocated_bytes;
+        let window_peak = self
+            .windowed_peak
+            .as_mut()
+            .map(|windowed_peak| windowed_peak.stats(current_bytes));
+        let allocation_ages = self.allocation_ages_snapshot();
+        let frames = |callstack_id: &CallstackId| {
+            self.interner
+                .get_callstack(*callstack_id)
+                .map(|callstack| callstack.frames(&self.functions))
+                .unwrap_or_default()
         };
-        let sum = callstacks.iter().sum();
-        filter_to_useful_callstacks(callstacks.iter().enumerate(), sum)
+        let mut lifetime_pairs: Vec<_> = self
+            .lifetime_pairs
+            .iter()
+            .flat_map(|pairs| pairs.pairs())
+            .map(|((allocated_by, freed_by), stats)| LifetimePair {
+                allocated_by: frames(allocated_by),
+                freed_by: frames(freed_by),
+                allocations: stats.allocations,
+                bytes: stats.bytes,
+                total_lifetime: stats.total_lifetime.as_secs_f64(),
+                max_lifetime: stats.max_lifetime.as_secs_f64(),
+            })
+            .collect();
+        lifetime_pairs
+            .sort_by(|a, b| (&a.allocated_by, &a.freed_by).cmp(&(&b.allocated_by, &b.freed_by)));
+        let mut callstacks = vec![];
+        for (callstack_id, callstack) in self.interner.iter() {
+            let index = callstack_id as usize;
+            // After oom_break_glass() the peak data is gone, hence get():
+            let usage = CallstackUsage {
+                frames: vec![],
+                peak_bytes: self.peak_memory_usage.get(index).copied().unwrap_or(0),
+                current_bytes: self.current_memory_usage.get(index).copied().unwrap_or(0),
+                peak_allocations: self.peak_allocation_counts.get(index).copied().unwrap_or(0),
+                allocations_at_peak: self
+                    .allocation_counts_at_peak
+                    .get(index)
+                    .copied()
+                    .unwrap_or(0),
+                sizes: self.allocation_sizes[index],
+                peak_allocation_rate: self.allocation_rates.peak_rate(callstack_id),
+                heap_peak_bytes: self
+                    .heap_peak_memory_usage
+                    .get(index)
+                    .copied()
+                    .unwrap_or(0)
+                    .saturating_sub(self.heap_peak_mmap_usage.get(index).copied().unwrap_or(0)),
+            };
+            if usage.peak_bytes == 0
+                && usage.current_bytes == 0
+                && usage.peak_allocations == 0
+                && usage.peak_allocation_rate == 0
+                && usage.heap_peak_bytes == 0
+            {
+                continue;
+            }
+            callstacks.push(CallstackUsage {
+                frames: callstack.frames(&self.functions),
+                ..usage
+            });
+        }
+        callstacks.sort_by(|a, b| a.frames.cmp(&b.frames));
+        let mut function_calls: Vec<_> = self
+            .call_counts
+            .non_zero()
             .into_iter()
-            .map(|(k, v)| (k as CallstackId, v))
-            .collect()
+            .map(|(function_id, calls)| {
+                let (function, filename) = self.functions.get_function_and_filename(function_id);
+                FunctionCalls {
+                    function: function.to_string(),
+                    filename: filename.to_string(),
+                    calls,
+                }
+            })
+            .collect();
+        function_calls.sort_by(|a, b| (&a.filename, &a.function).cmp(&(&b.filename, &b.function)));
+        ProfileData {
+            resource: M::RESOURCE.to_string(),
+            callstacks,
+            peak_bytes: self.peak_allocated_bytes,
+            current_bytes: self.current_allocated_bytes,
+            peak_allocations: self.peak_allocation_count,
+            missing_bytes: self.missing_allocated_bytes,
+            failed_deallocations: self.failed_deallocations,
+            accounting_errors: self.accounting_errors,
+            metadata: self.metadata.clone(),
+            numa: self.numa_including_live(),
+            function_calls,
+            lifetime_pairs,
+            interpreters: self
+                .interpreter_usage
+                .iter()
+                .map(|(interpreter, usage)| InterpreterStats {
+                    interpreter,
+                    current_bytes: usage.current_bytes,
+                    peak_bytes: usage.peak_bytes,
+                })
+                .collect(),
+            profiler_memory: self.profiler_memory(),
+            address_space: self.address_space.stats(),
+            locked_memory: self.locked_memory.stats(),
+            window_peak,
+            timeline: self.timeline.data(current_bytes),
+            allocation_ages,
+            // If the heap was as big at the combined peak as it ever got, the
+            // combined peak shows the heap at its peak too:
+            heap_peaked_separately: self.peak_heap_bytes
+                > self
+                    .peak_allocated_bytes
+                    .saturating_sub(self.peak_mmap_bytes),
+        }
     }
 
-    /// Dump all callstacks in peak memory usage to various files describing the
-    /// memory usage.
-    pub fn dump_peak_to_flamegraph(&mut self, path: &str) {
-        self.dump_to_flamegraph(path, true, "peak-memory", "Peak Tracked Memory Usage", true);
-    }
-
-    pub fn to_lines(
-        &self,
-        peak: bool,
-        to_be_post_processed: bool,
-    ) -> impl ExactSizeIterator<Item = String> + '_ {
-        let by_call = self.combine_callstacks(peak).into_iter();
-        let id_to_callstack = self.interner.get_reverse_map();
-        by_call.map(move |(callstack_id, size)| {
-            format!(
-                "{} {}",
-                id_to_callstack.get(&callstack_id).unwrap().as_string(
-                    to_be_post_processed,
-                    &self.functions,
-                    ";"
-                ),
-                size,
-            )
-        })
+    /// Ages of live allocations per callstack, if enabled.
+    fn allocation_ages_snapshot(&self) -> Vec<CallstackAges> {
+        let ages = match &self.allocation_ages {
+            Some(ages) => ages,
+            None => return vec![],
+        };
+        let live = self
+            .current_allocations
+            .get(&PARENT_PROCESS)
+            .into_iter()
+            .flat_map(|allocations| allocations.iter())
+            .map(|(address, allocation)| (*address, allocation.size(), allocation.callstack_id));
+        let mut result: Vec<_> = ages
+            .histograms(live)
+            .into_iter()
+            .filter_map(|(callstack_id, (peak_bytes, current_bytes))| {
+                Some(CallstackAges {
+                    frames: self
+                        .interner
+                        .get_callstack(callstack_id)?
+                        .frames(&self.functions),
+                    peak_bytes,
+                    current_bytes,
+                })
+            })
+            .collect();
+        result.sort_by(|a, b| a.frames.cmp(&b.frames));
+        result
+    }
+
+    /// Make sure logs written as we go, e.g. for watchpoints, are up to date.
+    pub fn flush_logs(&mut self) {
+        self.watchpoints.flush();
+    }
+
+    /// Per-NUMA-node totals, including allocations that are still live.
+    fn numa_including_live(&self) -> Option<NumaStats> {
+        let mut numa = self.numa.as_ref()?.clone();
+        if let Some(allocations) = self.current_allocations.get(&PARENT_PROCESS) {
+            for (address, allocation) in allocations.iter() {
+                numa.record(*address, allocation.size());
+            }
+        }
+        if let Some(mmaps) = self.current_anon_mmaps.get(&PARENT_PROCESS) {
+            for (address, size, _) in mmaps.iter() {
+                numa.record(address, size);
+            }
+        }
+        Some(numa)
     }
 
-    fn dump_to_flamegraph(
+    /// Clear memory we won't be needing anymore, since we're going to exit out.
+    pub fn oom_break_glass(&mut self) {
+        self.current_allocations.clear();
+        self.tracked_addresses.clear();
+        self.peak_memory_usage.clear();
+        self.allocation_counts_at_peak.clear();
+        self.heap_peak_memory_usage.clear();
+        self.heap_peak_mmap_usage.clear();
+    }
+
+    /// Validate internal state is in a good state. This won't pass until
+    /// check_if_new_peak() is called.
+    fn validate(&self) {
+        assert!(self.peak_allocated_bytes >= self.current_allocated_bytes);
+        let current_allocations: usize = self
+            .current_anon_mmaps
+            .values()
+            .map(|maps| maps.size())
+            .sum::<usize>()
+            + self
+                .current_allocations
+                .values()
+                .flat_map(|allocs| allocs.iter())
+                .map(|(_, alloc)| alloc.size())
+                .sum::<usize>();
+        assert!(
+            current_allocations == self.current_allocated_bytes,
+            "{} != {}",
+            current_allocations,
+            self.current_allocated_bytes
+        );
+        assert!(self.current_memory_usage.iter().sum::<usize>() == self.current_allocated_bytes);
+        assert!(self.peak_memory_usage.iter().sum::<usize>() == self.peak_allocated_bytes);
+        assert!(self.current_mmap_usage.iter().sum::<usize>() == self.current_mmap_bytes);
+        assert!(
+            self.current_anon_mmaps
+                .values()
+                .map(|maps| maps.size())
+                .sum::<usize>()
+                == self.current_mmap_bytes
+        );
+        assert!(
+            self.peak_heap_bytes
+                >= self
+                    .current_allocated_bytes
+                    .saturating_sub(self.current_mmap_bytes)
+        );
+        let current_count: usize = self
+            .current_allocations
+            .values()
+            .map(|allocs| allocs.len())
+            .sum();
+        assert!(current_count == self.current_allocation_count);
+        assert!(
+            self.current_allocation_counts.iter().sum::<usize>() == self.current_allocation_count
+        );
+        assert!(self.peak_allocation_counts.iter().sum::<usize>() == self.peak_allocation_count);
+    }
+
+    /// Reset internal state, starting a new generation.
+    ///
+    /// Callstack IDs are forgotten too, so callers that cache them (see
+    /// Callstack::id_for_new_allocation()) must pass in the current
+    /// generation; since the caller holds the tracker exclusively for both
+    /// reset() and new allocations, events either happen entirely before the
+    /// reset or entirely after it.
+    pub fn reset(&mut self, default_path: String) {
+        self.current_allocations.clear();
+        self.current_anon_mmaps = BTreeMap::from([(PARENT_PROCESS, RangeMap::new())]);
+        self.alignments.clear();
+        self.interner = CallstackInterner::new();
+        self.current_memory_usage = ImVector::new();
+        self.peak_memory_usage = ImVector::new();
+        self.current_allocated_bytes = 0;
+        self.peak_allocated_bytes = 0;
+        self.current_mmap_usage = ImVector::new();
+        self.current_mmap_bytes = 0;
+        self.heap_peak_memory_usage = ImVector::new();
+        self.heap_peak_mmap_usage = ImVector::new();
+        self.peak_heap_bytes = 0;
+        self.peak_mmap_bytes = 0;
+        self.address_space = AddressSpace::default();
+        self.locked_memory = LockedMemory::default();
+        self.interpreter_usage = InterpreterUsage::default();
+        self.callstack_interpreters.clear();
+        self.allocation_sizes.clear();
+        self.allocation_rates = AllocationRates::default();
+        self.current_allocation_counts = ImVector::new();
+        self.peak_allocation_counts = ImVector::new();
+        self.allocation_counts_at_peak = ImVector::new();
+        self.current_allocation_count = 0;
+        self.peak_allocation_count = 0;
+        self.missing_allocated_bytes = 0;
+        self.failed_deallocations = 0;
+        self.accounting_errors = 0;
+        self.double_frees = 0;
+        self.reconciler.clear();
+        self.arenas = Arenas::default();
+        self.reserve(self.capacity);
+        self.default_path = default_path;
+        self.numa = self.numa.as_ref().map(|numa| numa.cleared());
+        self.recent_frees = self.recent_frees.as_ref().map(|recent| recent.cleared());
+        self.lifetime_pairs = self.lifetime_pairs.as_ref().map(|pairs| pairs.cleared());
+        self.allocation_ages = self.allocation_ages.as_ref().map(|ages| ages.cleared());
+        self.large_allocations = self.large_allocations.as_ref().map(|large| large.cleared());
+        self.memory_limit = self.memory_limit.as_ref().map(|limit| limit.cleared());
+        self.soft_memory_limit = self.soft_memory_limit.as_ref().map(|limit| limit.cleared());
+        self.windowed_peak = self
+            .windowed_peak
+            .as_ref()
+            .map(|windowed_peak| windowed_peak.cleared());
+        self.timeline = Timeline::default();
+        self.watchpoints.clear();
+        self.call_counts.clear();
+        self.tracked_addresses.clear();
+        if let Some(stream) = &mut self.stream {
+            stream.reset();
+        }
+        self.generation = Generation(self.generation.0 + 1);
+        // A reset means a new profiling session is starting:
+        self.metadata = ProcessMetadata::collect();
+        self.validate();
+    }
+}
+
+impl<M: Metric> AllocationTracker<VecFunctionLocations, M> {
+    /// Add an allocation in the parent process with an explicitly given
+    /// callstack, outermost frame first, as (module, function, line number).
+    /// This is for embedders that aren't Python, or replaying recorded
+    /// events, where there's no thread-local callstack to use.
+    pub fn add_allocation_with_callstack(
         &mut self,
+        address: usize,
+        size: usize,
+        frames: &[(&str, &str, LineNumber)],
+    ) {
+        let calls = frames
+            .iter()
+            .map(|(module, function, line_number)| {
+                CallSiteId::new(
+                    self.functions.get_or_add_module_function(module, function),
+                    *line_number,
+                )
+            })
+            .collect();
+        let callstack_id = self.get_callstack_id(&Callstack::from_vec(calls));
+        self.add_allocation(PARENT_PROCESS, address, size, callstack_id);
+    }
+
+    /// Add a file-backed mmap(). It's attributed to the given callstack plus
+    /// a synthetic leaf frame naming the file, so the mapped files show up
+    /// in reports.
+    pub fn add_file_mmap(
+        &mut self,
+        process: ProcessUid,
+        address: usize,
+        size: usize,
+        callstack_id: CallstackId,
         path: &str,
-        peak: bool,
-        base_filename: &str,
-        title: &str,
-        to_be_post_processed: bool,
     ) {
-        // First, make sure peaks are correct:
+        let callstack_id = self.with_leaf_frame(callstack_id, MMAP_FILENAME, path);
+        self.add_anon_mmap(process, address, size, callstack_id);
+    }
+
+    /// The ID of a callstack with an extra synthetic innermost frame, e.g.
+    /// naming a mapped file or the library an allocation came from.
+    pub fn with_leaf_frame(
+        &mut self,
+        callstack_id: CallstackId,
+        filename: &str,
+        function: &str,
+    ) -> CallstackId {
+        let mut callstack = self
+            .interner
+            .get_callstack(callstack_id)
+            .cloned()
+            .unwrap_or_else(Callstack::new);
+        let function = self
+            .functions
+            .add_function(filename.to_string(), function.to_string());
+        callstack.start_call(0, CallSiteId::new(function, 0));
+        self.get_callstack_id(&callstack)
+    }
+
+    /// Serialize the tracker state to JSON: callstacks with their current and
+    /// peak usage, plus the parent process' live allocations and mmaps.
+    /// Functions are stored by name, since ids are only meaningful within a
+    /// process.
+    pub fn checkpoint(&mut self) -> Value {
         self.check_if_new_peak();
+        let mut function_indexes: HashMap<FunctionId, usize, HashState> = new_hashmap();
+        let mut functions = vec![];
+        let mut callstacks = vec![];
+        let mut callstack_indexes: HashMap<CallstackId, usize, HashState> = new_hashmap();
+        for (callstack_id, callstack) in self.interner.iter() {
+            let calls = callstack
+                .calls
+                .iter()
+                .map(|call| {
+                    let index = *function_indexes.entry(call.function).or_insert_with(|| {
+                        let (function, filename) =
+                            self.functions.get_function_and_filename(call.function);
+                        functions.push(json!({
+                            "function": function,
+                            "filename": filename,
+                            "module": self.functions.get_module(call.function),
+                        }));
+                        functions.len() - 1
+                    });
+                    json!([index, call.line_number])
+                })
+                .collect::<Vec<_>>();
+            let index = callstack_id as usize;
+            callstack_indexes.insert(callstack_id, callstacks.len());
+            callstacks.push(json!({
+                "calls": calls,
+                "peak_bytes": self.peak_memory_usage.get(index).copied().unwrap_or(0),
+                "peak_allocations": self.peak_allocation_counts.get(index).copied().unwrap_or(0),
+            }));
+        }
+        let allocations = self
+            .current_allocations
+            .get(&PARENT_PROCESS)
+            .into_iter()
+            .flat_map(|allocations| allocations.iter())
+            .map(|(address, allocation)| {
+                json!([
+                    address,
+                    allocation.size(),
+                    callstack_indexes[&allocation.callstack_id]
+                ])
+            })
+            .collect::<Vec<_>>();
+        let anon_mmaps = self
+            .current_anon_mmaps
+            .get(&PARENT_PROCESS)
+            .into_iter()
+            .flat_map(|mmaps| mmaps.iter())
+            .map(|(address, size, callstack_id)| {
+                json!([address, size, callstack_indexes[callstack_id]])
+            })
+            .collect::<Vec<_>>();
+        json!({
+            "version": CHECKPOINT_VERSION,
+            "functions": functions,
+            "callstacks": callstacks,
+            "allocations": allocations,
+            "anon_mmaps": anon_mmaps,
+            "missing_bytes": self.missing_allocated_bytes,
+            "failed_deallocations": self.failed_deallocations,
+        })
+    }
 
-        // Print warning if we're missing allocations.
-        #[cfg(not(feature = "fil4prod"))]
-        {
-            let allocated_bytes = if peak {
-                self.peak_allocated_bytes
-            } else {
-                self.current_allocated_bytes
-            };
-            if self.missing_allocated_bytes > 0 {
-                eprintln!("=fil-profile= WARNING: {:.2}% ({} bytes) of tracked memory somehow disappeared. If this is a small percentage you can just ignore this warning, since the missing allocations won't impact the profiling results. If the % is high, please run `export FIL_DEBUG=1` to get more output', re-run Fil on your script, and then file a bug report at https://github.com/pythonspeed/filprofiler/issues/new", self.missing_allocated_bytes as f64 * 100.0 / allocated_bytes as f64, self.missing_allocated_bytes);
+    /// Replace the tracker state with a checkpoint from checkpoint(). Peak
+    /// usage is always restored, so a restarted process keeps accumulating
+    /// towards the same peak. Live allocations only make sense if the address
+    /// space survived too (e.g. checkpoint/restore of the whole process), so
+    /// they're only restored if asked for.
+    pub fn restore(&mut self, checkpoint: &Value, live_allocations: bool) -> Result<(), String> {
+        if checkpoint["version"].as_u64() != Some(CHECKPOINT_VERSION) {
+            return Err("unsupported checkpoint version".to_string());
+        }
+        let as_array = |value: &Value, key: &str| -> Result<Vec<Value>, String> {
+            value[key]
+                .as_array()
+                .cloned()
+                .ok_or_else(|| format!("missing or invalid {}", key))
+        };
+        let as_usize = |value: &Value| -> Result<usize, String> {
+            value
+                .as_u64()
+                .map(|n| n as usize)
+                .ok_or_else(|| "invalid number".to_string())
+        };
+        // Parse everything before touching the tracker, so a bad checkpoint
+        // leaves it as it was:
+        let mut functions = vec![];
+        for function in as_array(checkpoint, "functions")? {
+            functions.push((
+                get_string(&function, "filename")?,
+                get_string(&function, "function")?,
+                function["module"].as_str().map(|m| m.to_string()),
+            ));
+        }
+        let mut callstacks = vec![];
+        for usage in as_array(checkpoint, "callstacks")? {
+            let mut calls = vec![];
+            for call in as_array(&usage, "calls")? {
+                let function = as_usize(&call[0])?;
+                if function >= functions.len() {
+                    return Err("invalid function index".to_string());
+                }
+                calls.push((function, as_usize(&call[1])? as LineNumber));
             }
-            if self.failed_deallocations > 0 {
-                eprintln!("=fil-profile= WARNING: Encountered {} deallocations of untracked allocations. A certain number are expected in normal operation, of allocations created before Fil started tracking, and even more if you're using the Fil API to turn tracking on and off.", self.failed_deallocations);
+            callstacks.push((
+                calls,
+                get_usize(&usage, "peak_bytes")?,
+                get_usize(&usage, "peak_allocations")?,
+            ));
+        }
+        let mut live = vec![];
+        for (key, is_mmap) in [("allocations", false), ("anon_mmaps", true)] {
+            for entry in as_array(checkpoint, key)? {
+                let callstack = as_usize(&entry[2])?;
+                if callstack >= callstacks.len() {
+                    return Err("invalid callstack index".to_string());
+                }
+                live.push((
+                    is_mmap,
+                    as_usize(&entry[0])?,
+                    as_usize(&entry[1])?,
+                    callstack,
+                ));
             }
         }
+        let missing_bytes = get_usize(checkpoint, "missing_bytes")?;
+        let failed_deallocations = get_usize(checkpoint, "failed_deallocations")?;
 
-        eprintln!("=fil-profile= Preparing to write to {}", path);
-        let directory_path = Path::new(path);
-
-        let title = format!(
-            "{} ({:.1} MiB)",
-            title,
-            self.peak_allocated_bytes as f64 / (1024.0 * 1024.0)
-        );
-        #[cfg(not(feature = "fil4prod"))]
-        let subtitle = r#"Made with the Fil profiler. <a href="https://pythonspeed.com/fil/" style="text-decoration: underline;" target="_parent">Try it on your code!</a>"#;
-        #[cfg(feature = "fil4prod")]
-        let subtitle = r#"Made with the Fil4prod profiler. <a href="https://pythonspeed.com/products/fil4prod/" style="text-decoration: underline;" target="_parent">Try it on your code!</a>"#;
-        write_flamegraphs(
-            directory_path,
-            base_filename,
-            &title,
-            subtitle,
-            "bytes",
-            to_be_post_processed,
-            |tbpp| self.to_lines(peak, tbpp),
-        )
-    }
-
-    /// Clear memory we won't be needing anymore, since we're going to exit out.
-    pub fn oom_break_glass(&mut self) {
-        self.current_allocations.clear();
-        self.peak_memory_usage.clear();
-    }
-
-    /// Dump information about where we are.
-    pub fn oom_dump(&mut self) {
-        eprintln!(
-            "=fil-profile= We'll try to dump out SVGs. Note that no HTML file will be written."
-        );
-        let default_path = self.default_path.clone();
-        self.dump_to_flamegraph(
-            &default_path,
-            false,
-            "out-of-memory",
-            "Current allocations at out-of-memory time",
-            false,
-        );
-        unsafe {
-            _exit(53);
+        self.reset(self.default_path.clone());
+        let function_ids = functions
+            .into_iter()
+            .map(|(filename, qualname, module)| self.functions.push(filename, qualname, module))
+            .collect::<Vec<_>>();
+        let mut callstack_ids = vec![];
+        let mut peaks = vec![];
+        for (calls, peak_bytes, peak_allocations) in callstacks {
+            let callstack = Callstack::from_vec(
+                calls
+                    .into_iter()
+                    .map(|(function, line)| CallSiteId::new(function_ids[function], line))
+                    .collect(),
+            );
+            let callstack_id = self.get_callstack_id(&callstack);
+            callstack_ids.push(callstack_id);
+            peaks.push((callstack_id, peak_bytes, peak_allocations));
+        }
+        let mut peak_memory_usage = self.current_memory_usage.clone();
+        let mut peak_allocation_counts = self.current_allocation_counts.clone();
+        for (callstack_id, peak_bytes, peak_allocations) in peaks {
+            peak_memory_usage[callstack_id as usize] += peak_bytes;
+            peak_allocation_counts[callstack_id as usize] += peak_allocations;
+        }
+        self.peak_allocated_bytes = peak_memory_usage.iter().sum();
+        self.peak_allocation_count = peak_allocation_counts.iter().sum();
+        self.peak_memory_usage = peak_memory_usage;
+        self.peak_allocation_counts = peak_allocation_counts;
+        // Checkpoints don't tell mmap() apart, so the heap peaked then too:
+        self.heap_peak_memory_usage = self.peak_memory_usage.clone();
+        self.peak_heap_bytes = self.peak_allocated_bytes;
+        self.peak_mmap_bytes = 0;
+        if live_allocations {
+            for (is_mmap, address, size, callstack) in live {
+                let callstack_id = callstack_ids[callstack];
+                if is_mmap {
+                    self.add_anon_mmap(PARENT_PROCESS, address, size, callstack_id);
+                } else {
+                    self.add_allocation(PARENT_PROCESS, address, size, callstack_id);
+                }
+            }
+            self.missing_allocated_bytes = missing_bytes;
+            self.failed_deallocations = failed_deallocations;
         }
+        self.check_if_new_peak();
+        self.validate();
+        Ok(())
     }
 
-    /// Validate internal state is in a good state. This won't pass until
-    /// check_if_new_peak() is called.
-    fn validate(&self) {
-        assert!(self.peak_allocated_bytes >= self.current_allocated_bytes);
-        let current_allocations: usize = self
-            .current_anon_mmaps
-            .values()
-            .map(|maps| maps.size())
-            .sum::<usize>()
-            + self
-                .current_allocations
-                .values()
-                .flat_map(|allocs| allocs.iter())
-                .map(|(_, alloc)| alloc.size())
-                .sum::<usize>();
-        assert!(
-            current_allocations == self.current_allocated_bytes,
-            "{} != {}",
-            current_allocations,
-            self.current_allocated_bytes
-        );
-        assert!(self.current_memory_usage.iter().sum::<usize>() == self.current_allocated_bytes);
-        assert!(self.peak_memory_usage.iter().sum::<usize>() == self.peak_allocated_bytes);
+    /// Write a checkpoint to a file, gzipped if the path ends in ".gz".
+    pub fn write_checkpoint(&mut self, path: &Path) -> std::io::Result<()> {
+        let mut data = serde_json::to_vec(&self.checkpoint())?;
+        if path.extension() == Some("gz".as_ref()) {
+            data = crate::compression::gzip(&data)?;
+        }
+        std::fs::write(path, data)
     }
 
-    /// Reset internal state in way that doesn't invalidate e.g. thread-local
-    /// caching of callstack ID.
-    pub fn reset(&mut self, default_path: String) {
-        self.current_allocations.clear();
-        self.current_anon_mmaps = BTreeMap::from([(PARENT_PROCESS, RangeMap::new())]);
-        for i in self.current_memory_usage.iter_mut() {
-            *i = 0;
-        }
-        self.peak_memory_usage = ImVector::new();
-        self.current_allocated_bytes = 0;
-        self.peak_allocated_bytes = 0;
-        self.default_path = default_path;
-        self.validate();
+    /// Restore from a checkpoint file written by write_checkpoint().
+    pub fn restore_from_file(&mut self, path: &Path, live_allocations: bool) -> Result<(), String> {
+        let data = crate::compression::read(path).map_err(|e| e.to_string())?;
+        let checkpoint: Value = serde_json::from_slice(&data).map_err(|e| e.to_string())?;
+        self.restore(&checkpoint, live_allocations)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::capacity::Capacity;
     use crate::memorytracking::{ProcessUid, PARENT_PROCESS};
 
+    use crate::doublefree::RecentFrees;
+    use crate::limit::{LimitAction, MemoryLimit};
+    use crate::mmap::MMAP_FILENAME;
+    use crate::sequence::{next_sequence, UNKNOWN_SEQUENCE};
+
     use super::{
         Allocation, AllocationTracker, CallSiteId, Callstack, CallstackInterner, FunctionId,
-        FunctionLocations, VecFunctionLocations, HIGH_32BIT, MIB,
+        FunctionLocations, Generation, TopMetric, VecFunctionLocations, HIGH_32BIT, MIB,
     };
     use proptest::prelude::*;
     use std::borrow::Cow;
-    use std::collections::HashMap;
+    use std::collections::BTreeMap;
 
     fn new_tracker() -> AllocationTracker<VecFunctionLocations> {
         AllocationTracker::new(".".to_string(), VecFunctionLocations::new())
@@ -914,6 +2475,635 @@ mod tests {
 
     }
 
+    #[test]
+    fn top_callstacks_by_metric() {
+        let mut tracker = new_tracker();
+        let fid = tracker
+            .functions
+            .add_function("a.py".to_string(), "f".to_string());
+        let big = tracker.get_callstack_id(&Callstack::from_vec(vec![CallSiteId::new(fid, 1)]));
+        let many = tracker.get_callstack_id(&Callstack::from_vec(vec![CallSiteId::new(fid, 2)]));
+        tracker.add_allocation(PARENT_PROCESS, 1, 1000, big);
+        for address in 10..15 {
+            tracker.add_allocation(PARENT_PROCESS, address, 10, many);
+        }
+        tracker.free_allocation(PARENT_PROCESS, 1);
+        assert_eq!(
+            tracker.top_callstacks(5, TopMetric::CurrentBytes),
+            vec![("a.py:2 (f)".to_string(), 50)]
+        );
+        assert_eq!(
+            tracker.top_callstacks(1, TopMetric::PeakBytes),
+            vec![("a.py:1 (f)".to_string(), 1000)]
+        );
+        assert_eq!(
+            tracker.top_callstacks(5, TopMetric::PeakAllocations),
+            vec![("a.py:2 (f)".to_string(), 5), ("a.py:1 (f)".to_string(), 1)]
+        );
+        assert_eq!(
+            TopMetric::parse("current_allocations"),
+            Some(TopMetric::CurrentAllocations)
+        );
+        assert_eq!(TopMetric::parse("nope"), None);
+    }
+
+    #[test]
+    fn batched_allocations() {
+        let mut tracker = new_tracker();
+        let cs_id = tracker.get_callstack_id(&Callstack::new());
+        tracker.add_allocations(
+            PARENT_PROCESS,
+            &[
+                (1, 100, UNKNOWN_SEQUENCE),
+                (2, 200, UNKNOWN_SEQUENCE),
+                (3, 300, UNKNOWN_SEQUENCE),
+            ],
+            cs_id,
+        );
+        assert_eq!(tracker.get_current_allocated_bytes(), 600);
+        assert_eq!(tracker.current_allocation_count, 3);
+        // Unknown addresses are skipped:
+        assert_eq!(
+            tracker.free_allocations(
+                PARENT_PROCESS,
+                &[
+                    (1, UNKNOWN_SEQUENCE),
+                    (3, UNKNOWN_SEQUENCE),
+                    (17, UNKNOWN_SEQUENCE)
+                ]
+            ),
+            400
+        );
+        assert_eq!(tracker.get_current_allocated_bytes(), 200);
+        tracker.validate();
+    }
+
+    #[test]
+    fn out_of_order_batched_events_are_reconciled() {
+        let mut tracker = new_tracker();
+        let cs_a = tracker.get_callstack_id(&Callstack::new());
+        let mut callstack = Callstack::new();
+        callstack.start_call(0, CallSiteId::new(FunctionId(1), 2));
+        let cs_b = tracker.get_callstack_id(&callstack);
+
+        // Thread A allocates 0x1000, then frees it, but the free is buffered.
+        let a_alloc = next_sequence();
+        tracker.add_allocation_sequenced(PARENT_PROCESS, 0x1000, 100, cs_a, a_alloc);
+        let a_free = next_sequence();
+        // Thread B gets the same address from malloc() and records it first:
+        tracker.add_allocation_sequenced(PARENT_PROCESS, 0x1000, 200, cs_b, next_sequence());
+        // Then A's stale free arrives, and mustn't remove B's allocation:
+        assert_eq!(
+            tracker.free_allocations(PARENT_PROCESS, &[(0x1000, a_free)]),
+            0
+        );
+        assert_eq!(tracker.get_current_allocated_bytes(), 200);
+        assert_eq!(tracker.get_allocation_size(PARENT_PROCESS, 0x1000), 200);
+        // A's allocation wasn't missing after all:
+        assert_eq!(tracker.missing_allocated_bytes, 0);
+        // B's own free still works:
+        assert_eq!(
+            tracker.free_allocation_sequenced(PARENT_PROCESS, 0x1000, next_sequence()),
+            Some(200)
+        );
+        assert_eq!(tracker.get_current_allocated_bytes(), 0);
+
+        // The other way around: an allocation is buffered, and its memory is
+        // freed by another thread before the allocation arrives.
+        let buffered_alloc = next_sequence();
+        assert_eq!(tracker.free_allocation(PARENT_PROCESS, 0x2000), None);
+        tracker.add_allocations(PARENT_PROCESS, &[(0x2000, 300, buffered_alloc)], cs_a);
+        assert_eq!(tracker.get_current_allocated_bytes(), 0);
+        assert_eq!(tracker.current_allocation_count, 0);
+        // A later allocation at the same address is tracked as usual:
+        tracker.add_allocation_sequenced(PARENT_PROCESS, 0x2000, 50, cs_a, next_sequence());
+        assert_eq!(tracker.get_current_allocated_bytes(), 50);
+        tracker.validate();
+    }
+
+    #[test]
+    fn arena_suballocations_move_blame() {
+        let mut tracker = new_tracker();
+        let cs_arena = tracker.get_callstack_id(&Callstack::new());
+        let mut callstack = Callstack::new();
+        callstack.start_call(0, CallSiteId::new(FunctionId(1), 2));
+        let cs_piece = tracker.get_callstack_id(&callstack);
+        let usage = |tracker: &AllocationTracker<VecFunctionLocations>| {
+            (
+                tracker.current_memory_usage[cs_arena as usize],
+                tracker.current_memory_usage[cs_piece as usize],
+            )
+        };
+
+        // Unknown arenas, and pieces that don't fit, are ignored:
+        assert!(!tracker.add_suballocation(0x1000, 0x1000, 10, cs_piece));
+        tracker.add_allocation(PARENT_PROCESS, 0x1000, 1000, cs_arena);
+        assert!(!tracker.add_suballocation(0x1000, 0x1300, 1000, cs_piece));
+
+        assert!(tracker.add_suballocation(0x1000, 0x1100, 300, cs_piece));
+        assert_eq!(usage(&tracker), (700, 300));
+        assert_eq!(tracker.get_current_allocated_bytes(), 1000);
+        assert_eq!(tracker.free_suballocation(0x1100), Some(300));
+        assert_eq!(tracker.free_suballocation(0x1100), None);
+        assert_eq!(usage(&tracker), (1000, 0));
+
+        // Freeing the arena takes its pieces with it:
+        assert!(tracker.add_suballocation(0x1000, 0x1000, 400, cs_piece));
+        assert_eq!(tracker.free_allocation(PARENT_PROCESS, 0x1000), Some(1000));
+        assert_eq!(usage(&tracker), (0, 0));
+
+        // mmap()ed arenas work too:
+        tracker.add_anon_mmap(PARENT_PROCESS, 0x10000, 4096, cs_arena);
+        assert!(tracker.add_suballocation(0x10000, 0x10800, 100, cs_piece));
+        assert_eq!(usage(&tracker), (3996, 100));
+        // The piece is still mmap()ed memory:
+        assert_eq!(tracker.current_mmap_usage[cs_piece as usize], 100);
+        assert_eq!(tracker.current_mmap_bytes, 4096);
+        tracker.free_anon_mmap(PARENT_PROCESS, 0x10000, 4096);
+        assert_eq!(usage(&tracker), (0, 0));
+        assert_eq!(tracker.get_current_allocated_bytes(), 0);
+        assert_eq!(tracker.accounting_errors, 0);
+        tracker.validate();
+    }
+
+    #[test]
+    fn allocation_with_explicit_callstack() {
+        let mut tracker = new_tracker();
+        let frames = [("app.main", "run", 10), ("app.loader", "Loader.load", 22)];
+        tracker.add_allocation_with_callstack(1, 1000, &frames);
+        tracker.add_allocation_with_callstack(2, 500, &frames);
+        tracker.add_allocation_with_callstack(3, 7, &frames[..1]);
+        assert_eq!(tracker.get_current_allocated_bytes(), 1507);
+        let data = tracker.snapshot();
+        // The same frames map to the same callstack:
+        assert_eq!(data.callstacks.len(), 2);
+        let usage = data
+            .callstacks
+            .iter()
+            .find(|usage| usage.frames.len() == 2)
+            .unwrap();
+        assert_eq!(usage.current_bytes, 1500);
+        assert_eq!(usage.frames[1].function, "Loader.load");
+        assert_eq!(usage.frames[1].line_number, 22);
+        assert_eq!(usage.frames[1].module.as_deref(), Some("app.loader"));
+        tracker.free_allocation(PARENT_PROCESS, 1);
+        assert_eq!(tracker.get_current_allocated_bytes(), 507);
+    }
+
+    #[test]
+    fn checkpoint_and_restore() {
+        let mut tracker = new_tracker();
+        let frames = [("app.main", "run", 10), ("app.loader", "Loader.load", 22)];
+        tracker.add_allocation_with_callstack(1, 1000, &frames);
+        tracker.add_allocation_with_callstack(2, 500, &frames[..1]);
+        tracker.free_allocation(PARENT_PROCESS, 1);
+        let checkpoint = tracker.checkpoint();
+        let checkpoint: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&checkpoint).unwrap()).unwrap();
+
+        // A restarted process: only the peak carries over.
+        let mut restored = new_tracker();
+        restored.restore(&checkpoint, false).unwrap();
+        assert_eq!(restored.get_current_allocated_bytes(), 0);
+        assert_eq!(restored.get_peak_allocated_bytes(), 1500);
+        // New allocations from the same code add to the same callstacks:
+        restored.add_allocation_with_callstack(5, 2000, &frames);
+        restored.check_if_new_peak();
+        let data = restored.snapshot();
+        assert_eq!(data.callstacks.len(), 2);
+        assert_eq!(data.peak_bytes, 2000);
+
+        // Same address space: live allocations carry over too.
+        let mut restored = new_tracker();
+        restored.restore(&checkpoint, true).unwrap();
+        assert_eq!(restored.get_current_allocated_bytes(), 500);
+        assert_eq!(restored.get_peak_allocated_bytes(), 1500);
+        assert_eq!(restored.free_allocation(PARENT_PROCESS, 2), Some(500));
+
+        // Bad checkpoints leave the tracker alone:
+        assert!(restored.restore(&serde_json::json!({}), true).is_err());
+        assert_eq!(restored.get_peak_allocated_bytes(), 1500);
+    }
+
+    #[test]
+    fn stable_ids_dont_depend_on_order_of_first_use() {
+        let frames = [("app.main", "run", 10), ("app.loader", "Loader.load", 22)];
+        let mut first = new_tracker();
+        first.add_allocation_with_callstack(1, 1000, &frames);
+        let mut second = new_tracker();
+        second.add_allocation_with_callstack(1, 10, &[("app.other", "other", 3)]);
+        second.add_allocation_with_callstack(2, 1000, &frames);
+        let first_id = first.stable_callstack_id(0).unwrap();
+        // Different CallstackIds, same stable ID:
+        assert_eq!(second.stable_callstack_id(1), Some(first_id));
+        assert_ne!(second.stable_callstack_id(0), Some(first_id));
+        assert_eq!(second.stable_callstack_id(2), None);
+        // Snapshots agree:
+        assert_eq!(first.snapshot().callstacks[0].stable_id(), first_id);
+    }
+
+    #[test]
+    fn double_free_detection() {
+        let mut tracker = new_tracker();
+        tracker.recent_frees = Some(RecentFrees::new(10));
+        let cs_id = tracker.get_callstack_id(&Callstack::new());
+        tracker.add_allocation(PARENT_PROCESS, 1, 1000, cs_id);
+        tracker.add_allocation(PARENT_PROCESS, 2, 1000, cs_id);
+        assert_eq!(
+            tracker.free_allocation_from(PARENT_PROCESS, 1, cs_id),
+            Some(1000)
+        );
+        assert_eq!(tracker.free_allocation_from(PARENT_PROCESS, 1, cs_id), None);
+        assert_eq!(tracker.get_double_frees(), 1);
+        // Once reallocated, freeing is fine again:
+        tracker.add_allocation(PARENT_PROCESS, 1, 1000, cs_id);
+        assert_eq!(
+            tracker.free_allocation_from(PARENT_PROCESS, 1, cs_id),
+            Some(1000)
+        );
+        // Never allocated (e.g. before tracking started) isn't a double free:
+        assert_eq!(tracker.free_allocation_from(PARENT_PROCESS, 3, cs_id), None);
+        assert_eq!(tracker.get_double_frees(), 1);
+    }
+
+    #[test]
+    fn lifetime_pairs() {
+        let mut tracker = new_tracker();
+        tracker.lifetime_pairs = Some(Default::default());
+        let loader = [("app.loader", "load", 10)];
+        let worker = [("app.worker", "work", 20)];
+        tracker.add_allocation_with_callstack(1, 1000, &loader);
+        tracker.add_allocation_with_callstack(2, 500, &loader);
+        let work = tracker
+            .functions
+            .get_or_add_module_function("app.worker", "work");
+        let worker_id =
+            tracker.get_callstack_id(&Callstack::from_vec(vec![CallSiteId::new(work, 20)]));
+        tracker.free_allocation_from(PARENT_PROCESS, 1, worker_id);
+        tracker.free_allocation_from(PARENT_PROCESS, 2, worker_id);
+        // Freeing something we never saw allocated isn't recorded:
+        tracker.free_allocation_from(PARENT_PROCESS, 3, worker_id);
+        let data = tracker.snapshot();
+        assert_eq!(data.lifetime_pairs.len(), 1);
+        let pair = &data.lifetime_pairs[0];
+        assert_eq!(pair.allocations, 2);
+        assert_eq!(pair.bytes, 1500);
+        assert_eq!(pair.allocated_by[0].function, loader[0].1);
+        assert_eq!(pair.freed_by[0].function, worker[0].1);
+        assert!(pair.max_lifetime >= 0.0);
+    }
+
+    #[test]
+    fn allocation_ages() {
+        let mut tracker = new_tracker();
+        tracker.allocation_ages = Some(Default::default());
+        let cache = [("app.cache", "fill", 10)];
+        let work = [("app.worker", "work", 20)];
+        tracker.add_allocation_with_callstack(1, 1000, &cache);
+        tracker.add_allocation_with_callstack(2, 500, &work);
+        tracker.free_allocation(PARENT_PROCESS, 2);
+        // Allocated after the peak:
+        tracker.add_allocation_with_callstack(3, 100, &work);
+        let data = tracker.snapshot();
+        assert_eq!(data.allocation_ages.len(), 2);
+        let (cache_ages, work_ages) = (&data.allocation_ages[0], &data.allocation_ages[1]);
+        assert_eq!(cache_ages.frames[0].function, "fill");
+        assert_eq!(cache_ages.peak_bytes, [1000, 0, 0, 0, 0]);
+        assert_eq!(cache_ages.current_bytes, [1000, 0, 0, 0, 0]);
+        assert_eq!(work_ages.peak_bytes, [500, 0, 0, 0, 0]);
+        assert_eq!(work_ages.current_bytes, [100, 0, 0, 0, 0]);
+        // Off by default:
+        assert!(new_tracker().snapshot().allocation_ages.is_empty());
+    }
+
+    #[test]
+    fn file_mmaps_get_a_leaf_frame() {
+        let mut tracker = new_tracker();
+        tracker.add_allocation_with_callstack(1, 100, &[("app", "main", 3)]);
+        let data = tracker.snapshot();
+        let main = tracker.functions.get_or_add_module_function("app", "main");
+        let callstack_id =
+            tracker.get_callstack_id(&Callstack::from_vec(vec![CallSiteId::new(main, 3)]));
+        tracker.add_file_mmap(PARENT_PROCESS, 4096, 8192, callstack_id, "/data/big.bin");
+        assert_eq!(tracker.get_current_allocated_bytes(), 8292);
+        let mmap_data = tracker.snapshot();
+        assert_eq!(mmap_data.callstacks.len(), data.callstacks.len() + 1);
+        let usage = mmap_data
+            .callstacks
+            .iter()
+            .find(|usage| usage.current_bytes == 8192)
+            .unwrap();
+        let functions: Vec<_> = usage.frames.iter().map(|f| f.function.as_str()).collect();
+        assert_eq!(functions, vec!["main", "/data/big.bin"]);
+        assert_eq!(usage.frames[1].filename, MMAP_FILENAME);
+        // munmap() works the same as for anonymous mmaps:
+        tracker.free_anon_mmap(PARENT_PROCESS, 4096, 8192);
+        assert_eq!(tracker.get_current_allocated_bytes(), 100);
+    }
+
+    #[test]
+    fn aligned_allocations() {
+        let mut tracker = new_tracker();
+        let cs_id = tracker.get_callstack_id(&Callstack::new());
+        tracker.add_aligned_allocation(PARENT_PROCESS, 64, 1000, 64, cs_id);
+        tracker.add_aligned_allocation(PARENT_PROCESS, 128, 24, 64, cs_id);
+        tracker.add_aligned_allocation(PARENT_PROCESS, 4096, 8192, 4096, cs_id);
+        tracker.add_allocation(PARENT_PROCESS, 10, 5, cs_id);
+        assert_eq!(tracker.get_current_allocated_bytes(), 9221);
+        assert_eq!(
+            tracker.aligned_usage(),
+            BTreeMap::from([(64, (2, 1024)), (4096, (1, 8192))])
+        );
+        // Freed, or reused by a plain malloc():
+        tracker.free_allocation(PARENT_PROCESS, 4096);
+        tracker.free_allocation(PARENT_PROCESS, 64);
+        tracker.add_allocation(PARENT_PROCESS, 64, 7, cs_id);
+        assert_eq!(tracker.aligned_usage(), BTreeMap::from([(64, (1, 24))]));
+        assert_eq!(tracker.get_current_allocated_bytes(), 36);
+    }
+
+    #[test]
+    fn impossible_accounting() {
+        let mut tracker = new_tracker();
+        let cs_id = tracker.get_callstack_id(&Callstack::new());
+        tracker.add_allocation(PARENT_PROCESS, 1, usize::MAX, cs_id);
+        tracker.add_anon_mmap(PARENT_PROCESS, 4096, usize::MAX - 10, cs_id);
+        assert_eq!(tracker.get_current_allocated_bytes(), 0);
+        assert_eq!(tracker.snapshot().accounting_errors, 2);
+        // Removing more than there is clamps to zero rather than wrapping:
+        tracker.add_allocation(PARENT_PROCESS, 2, 100, cs_id);
+        tracker.remove_memory_usage(cs_id, 1000);
+        tracker.remove_allocation_count(cs_id);
+        tracker.remove_allocation_count(cs_id);
+        assert_eq!(tracker.get_current_allocated_bytes(), 0);
+        let data = tracker.snapshot();
+        assert_eq!(data.accounting_errors, 4);
+        assert_eq!(data.current_bytes, 0);
+        tracker.reset(".".to_string());
+        assert_eq!(tracker.snapshot().accounting_errors, 0);
+    }
+
+    #[test]
+    fn memory_limit() {
+        let mut tracker = new_tracker();
+        let cs_id = tracker.get_callstack_id(&Callstack::new());
+        tracker.add_allocation(PARENT_PROCESS, 1, 1000, cs_id);
+        assert_eq!(tracker.check_memory_limit(), None);
+        tracker.memory_limit = Some(MemoryLimit::new(1500, LimitAction::Abort));
+        assert_eq!(tracker.check_memory_limit(), None);
+        tracker.add_allocation(PARENT_PROCESS, 2, 1000, cs_id);
+        assert_eq!(tracker.check_memory_limit(), Some(LimitAction::Abort));
+        tracker.add_allocation(PARENT_PROCESS, 3, 1000, cs_id);
+        assert_eq!(tracker.check_memory_limit(), None);
+        tracker.reset(".".to_string());
+        tracker.add_allocation_with_callstack(1, 2000, &[("a", "f", 1)]);
+        assert_eq!(tracker.check_memory_limit(), Some(LimitAction::Abort));
+    }
+
+    #[test]
+    fn soft_and_hard_memory_limits() {
+        let mut tracker = new_tracker();
+        let cs_id = tracker.get_callstack_id(&Callstack::new());
+        tracker.soft_memory_limit = Some(MemoryLimit::new(1500, LimitAction::Notify));
+        tracker.memory_limit = Some(MemoryLimit::new(2500, LimitAction::Raise));
+        tracker.add_allocation(PARENT_PROCESS, 1, 1000, cs_id);
+        assert_eq!(tracker.check_memory_limit(), None);
+        tracker.add_allocation(PARENT_PROCESS, 2, 1000, cs_id);
+        assert_eq!(tracker.check_memory_limit(), Some(LimitAction::Notify));
+        tracker.add_allocation(PARENT_PROCESS, 3, 1000, cs_id);
+        assert_eq!(tracker.check_memory_limit(), Some(LimitAction::Raise));
+        // Crossing both at once only reports the hard limit:
+        tracker.reset(".".to_string());
+        tracker.add_allocation_with_callstack(1, 3000, &[("a", "f", 1)]);
+        assert_eq!(tracker.check_memory_limit(), Some(LimitAction::Raise));
+        assert_eq!(tracker.check_memory_limit(), None);
+    }
+
+    #[test]
+    fn calloc_overflow() {
+        let mut tracker = new_tracker();
+        let cs_id = tracker.get_callstack_id(&Callstack::new());
+        assert_eq!(
+            tracker.add_calloc(PARENT_PROCESS, 1, usize::MAX, 2, cs_id),
+            None
+        );
+        assert_eq!(
+            tracker.add_calloc(PARENT_PROCESS, 2, 1 << 33, 1 << 31, cs_id),
+            None
+        );
+        assert_eq!(tracker.get_current_allocated_bytes(), 0);
+        assert_eq!(tracker.get_allocation_size(PARENT_PROCESS, 1), 0);
+        assert_eq!(
+            tracker.add_calloc(PARENT_PROCESS, 3, 10, 3, cs_id),
+            Some(30)
+        );
+        assert_eq!(tracker.get_current_allocated_bytes(), 30);
+        assert_eq!(tracker.get_allocation_size(PARENT_PROCESS, 3), 30);
+    }
+
+    #[test]
+    fn tracker_stats() {
+        let mut tracker = new_tracker();
+        tracker.add_allocation_with_callstack(1, 10, &[("a.py", "f", 1)]);
+        tracker.add_allocation_with_callstack(2, 10, &[("a.py", "f", 1), ("a.py", "g", 2)]);
+        let cs_id = tracker.get_callstack_id(&Callstack::new());
+        tracker.add_anon_mmap(PARENT_PROCESS, 0x100000, 4096, cs_id);
+        let stats = tracker.tracker_stats();
+        assert_eq!(stats.live_allocations, 2);
+        assert_eq!(stats.live_mmaps, 1);
+        assert_eq!(stats.functions, 2);
+        assert_eq!(stats.callstacks, 3);
+        assert_eq!(stats.profiler_memory, tracker.profiler_memory());
+    }
+
+    #[test]
+    fn tracked_addresses_follow_allocations() {
+        let mut tracker = new_tracker();
+        let cs_id = tracker.get_callstack_id(&Callstack::new());
+        let tracked = tracker.tracked_addresses.clone();
+        tracker.add_allocation(PARENT_PROCESS, 0x1000, 10, cs_id);
+        // Replacing an allocation we never saw freed:
+        tracker.add_allocation(PARENT_PROCESS, 0x1000, 10, cs_id);
+        assert!(tracked.may_contain(0x1000));
+        tracker.free_allocation(PARENT_PROCESS, 0x1000);
+        assert!(!tracked.may_contain(0x1000));
+        tracker.add_allocation(PARENT_PROCESS, 0x2000, 10, cs_id);
+        tracker.reset("/tmp".to_string());
+        assert!(!tracked.may_contain(0x2000));
+    }
+
+    #[test]
+    fn allocation_sizes_include_freed_allocations() {
+        let mut tracker = new_tracker();
+        let cs_id = tracker.get_callstack_id(&Callstack::new());
+        tracker.add_allocation(PARENT_PROCESS, 1, 100, cs_id);
+        tracker.free_allocation(PARENT_PROCESS, 1);
+        tracker.add_allocation(PARENT_PROCESS, 1, 20, cs_id);
+        tracker.add_anon_mmap(PARENT_PROCESS, 0x100000, 3000, cs_id);
+        let sizes = tracker.snapshot().callstacks[0].sizes;
+        assert_eq!(sizes.count, 3);
+        assert_eq!(sizes.min_bytes, 20);
+        assert_eq!(sizes.max_bytes, 3000);
+        assert_eq!(sizes.average(), Some(1040.0));
+    }
+
+    #[test]
+    fn address_space_peak_is_separate() {
+        let mut tracker = new_tracker();
+        let cs_id = tracker.get_callstack_id(&Callstack::new());
+        // A big mapping, then a bigger total of heap allocations:
+        tracker.add_anon_mmap(PARENT_PROCESS, 0x100000, 1_000_000, cs_id);
+        tracker.free_anon_mmap(PARENT_PROCESS, 0x100000, 1_000_000);
+        tracker.add_allocation(PARENT_PROCESS, 1, 600_000, cs_id);
+        tracker.add_allocation(PARENT_PROCESS, 2, 600_000, cs_id);
+        let space = tracker.get_address_space();
+        assert_eq!(space.current_bytes, 1_200_000);
+        // Rounded up to whole pages:
+        assert!(space.peak_bytes >= 1_200_000);
+        tracker.free_allocation(PARENT_PROCESS, 1);
+        tracker.add_anon_mmap(PARENT_PROCESS, 0x100000, 1_000_000, cs_id);
+        let data = tracker.snapshot();
+        assert_eq!(data.peak_bytes, 1_600_000);
+        assert!(data.address_space.peak_bytes > 1_600_000);
+        tracker.drop_process(PARENT_PROCESS);
+        assert_eq!(tracker.get_address_space().current_bytes, 0);
+    }
+
+    #[test]
+    fn heap_peak_is_separate() {
+        let mut tracker = new_tracker();
+        let cs_heap = tracker.get_callstack_id(&Callstack::new());
+        let cs_mmap = tracker.with_leaf_frame(cs_heap, "big.py", "load");
+        // The heap peaks first, then the combined peak is a big mmap() with a
+        // smaller heap:
+        tracker.add_allocation(PARENT_PROCESS, 1, 500, cs_heap);
+        tracker.add_allocation(PARENT_PROCESS, 2, 300, cs_mmap);
+        tracker.free_allocation(PARENT_PROCESS, 1);
+        tracker.add_anon_mmap(PARENT_PROCESS, 0x100000, 10_000, cs_mmap);
+        tracker.add_allocation(PARENT_PROCESS, 3, 100, cs_heap);
+        tracker.free_anon_mmap(PARENT_PROCESS, 0x100000, 10_000);
+        assert_eq!(tracker.get_peak_allocated_bytes(), 10_400);
+        assert_eq!(tracker.get_peak_heap_bytes(), 800);
+        let data = tracker.snapshot();
+        // Sorted by frames, so the empty callstack is first:
+        let usage = |index: usize| {
+            let usage = &data.callstacks[index];
+            (usage.peak_bytes, usage.heap_peak_bytes)
+        };
+        assert_eq!(usage(0), (100, 500));
+        assert_eq!(usage(1), (10_300, 300));
+        assert_eq!(data.heap_peak_bytes(), 800);
+        let heap = data.heap_peak().unwrap();
+        assert_eq!(heap.peak_bytes, 800);
+        assert_eq!(heap.callstacks[1].peak_bytes, 300);
+        tracker.validate();
+
+        // Without mmap()s the peaks are the same:
+        tracker.reset("/tmp".to_string());
+        let cs_id = tracker.get_callstack_id(&Callstack::new());
+        tracker.add_allocation(PARENT_PROCESS, 1, 500, cs_id);
+        assert_eq!(tracker.snapshot().heap_peak(), None);
+        // Nor with an mmap() that's still there when the heap peaks:
+        tracker.add_anon_mmap(PARENT_PROCESS, 0x100000, 10_000, cs_id);
+        tracker.add_allocation(PARENT_PROCESS, 2, 100, cs_id);
+        let data = tracker.snapshot();
+        assert_eq!(data.heap_peak_bytes(), 600);
+        assert_eq!(data.heap_peak(), None);
+    }
+
+    #[test]
+    fn profiler_memory_is_separate() {
+        let mut tracker = new_tracker();
+        let before = tracker.profiler_memory();
+        for i in 0..1000 {
+            tracker.add_allocation_with_callstack(i + 1, 10, &[("a.py", "f", i as u32)]);
+        }
+        let after = tracker.profiler_memory();
+        assert!(after.callsites > before.callsites);
+        assert!(after.allocations > before.allocations);
+        assert!(after.usage >= 2 * 1000 * std::mem::size_of::<usize>());
+        // The user's numbers don't include it:
+        let data = tracker.snapshot();
+        assert_eq!(data.current_bytes, 10_000);
+        assert!(data.profiler_memory.total() > 0);
+    }
+
+    #[test]
+    fn tables_can_be_presized() {
+        let mut tracker = new_tracker();
+        let before = tracker.profiler_memory();
+        tracker.reserve(Capacity {
+            live_allocations: 100_000,
+            callstacks: 5_000,
+            functions: 20_000,
+        });
+        let capacity = |tracker: &AllocationTracker<VecFunctionLocations>| {
+            (
+                tracker.current_allocations[&PARENT_PROCESS].capacity(),
+                tracker.interner.callstack_to_id.capacity(),
+                tracker.functions.functions.capacity(),
+            )
+        };
+        let (allocations, callstacks, functions) = capacity(&tracker);
+        assert!(allocations >= 100_000);
+        assert!(callstacks >= 5_000);
+        assert!(functions >= 20_000);
+        // The memory is Fil's own:
+        assert!(tracker.profiler_memory().allocations > before.allocations);
+
+        // Filling it up to the expected size doesn't grow the tables:
+        for i in 0..100_000 {
+            tracker.add_allocation_with_callstack(i + 1, 10, &[("a.py", "f", (i % 5_000) as u32)]);
+        }
+        assert_eq!(capacity(&tracker).0, allocations);
+        assert_eq!(capacity(&tracker).1, callstacks);
+
+        // And it lasts across resets:
+        tracker.reset("/tmp".to_string());
+        let (allocations, callstacks, _) = capacity(&tracker);
+        assert!(allocations >= 100_000);
+        assert!(callstacks >= 5_000);
+    }
+
+    #[test]
+    fn snapshots_are_deterministic() {
+        let a = [("a.py", "a", 1)];
+        let b = [("b.py", "b", 2)];
+        let mut first = new_tracker();
+        first.add_allocation_with_callstack(1, 100, &a);
+        first.add_allocation_with_callstack(2, 100, &b);
+        // Same allocations, but the callstacks get different CallstackIds:
+        let mut second = new_tracker();
+        second.add_allocation_with_callstack(2, 100, &b);
+        second.add_allocation_with_callstack(1, 100, &a);
+        let mut first = first.snapshot();
+        let second = second.snapshot();
+        first.metadata = second.metadata.clone();
+        // The timeline ends at the time of the snapshot, which can differ by
+        // a millisecond:
+        first.timeline = second.timeline.clone();
+        assert_eq!(first, second);
+        assert_eq!(first.callstacks[0].frames[0].function, "a");
+    }
+
+    #[test]
+    fn subinterpreters_are_separate() {
+        let mut tracker = new_tracker();
+        let sub = [("<subinterpreter>", "interpreter 2", 0), ("app", "run", 1)];
+        tracker.add_allocation_with_callstack(1, 1000, &sub);
+        tracker.add_allocation_with_callstack(2, 300, &sub[1..]);
+        tracker.free_allocation(PARENT_PROCESS, 1);
+        tracker.add_allocation_with_callstack(3, 200, &sub);
+        let data = tracker.snapshot();
+        // Same code, but different interpreters, so different callstacks:
+        assert_eq!(data.callstacks.len(), 2);
+        assert_eq!(data.interpreters.len(), 1);
+        let stats = &data.interpreters[0];
+        assert_eq!(stats.interpreter, 2);
+        assert_eq!(stats.current_bytes, 200);
+        assert_eq!(stats.peak_bytes, 1000);
+    }
+
     #[test]
     fn untracked_allocation_removal() {
         let mut tracker = new_tracker();
@@ -989,56 +3179,67 @@ mod tests {
         assert_ne!(id1, id3);
         assert_ne!(id2, id3);
         assert_eq!(id3, id3b);
-        let mut expected = HashMap::default();
-        expected.insert(id1, &cs1);
-        expected.insert(id2, &cs2);
-        expected.insert(id3, &cs3);
-        assert_eq!(interner.get_reverse_map(), expected);
+        assert_eq!(
+            interner.iter().collect::<Vec<_>>(),
+            vec![(id1, &cs1), (id2, &cs2), (id3, &cs3)]
+        );
+        assert_eq!(interner.get_callstack(id2), Some(&cs2));
+        assert_eq!(interner.get_callstack(id3 + 1), None);
     }
 
     #[test]
     fn callstack_id_for_new_allocation() {
         let mut interner = CallstackInterner::new();
+        let generation = Generation::default();
 
         let mut cs1 = Callstack::new();
-        let id0 =
-            cs1.id_for_new_allocation(0, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
-        let id0b =
-            cs1.id_for_new_allocation(0, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
+        let id0 = cs1.id_for_new_allocation(0, generation, |cs| {
+            interner.get_or_insert_id(Cow::Borrowed(&cs), || ())
+        });
+        let id0b = cs1.id_for_new_allocation(0, generation, |cs| {
+            interner.get_or_insert_id(Cow::Borrowed(&cs), || ())
+        });
         assert_eq!(id0, id0b);
 
         let fid1 = FunctionId::new(1u64);
 
         cs1.start_call(0, CallSiteId::new(fid1, 2));
-        let id1 =
-            cs1.id_for_new_allocation(1, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
-        let id2 =
-            cs1.id_for_new_allocation(2, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
-        let id1b =
-            cs1.id_for_new_allocation(1, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
+        let id1 = cs1.id_for_new_allocation(1, generation, |cs| {
+            interner.get_or_insert_id(Cow::Borrowed(&cs), || ())
+        });
+        let id2 = cs1.id_for_new_allocation(2, generation, |cs| {
+            interner.get_or_insert_id(Cow::Borrowed(&cs), || ())
+        });
+        let id1b = cs1.id_for_new_allocation(1, generation, |cs| {
+            interner.get_or_insert_id(Cow::Borrowed(&cs), || ())
+        });
         assert_eq!(id1, id1b);
         assert_ne!(id2, id0);
         assert_ne!(id2, id1);
 
         cs1.start_call(3, CallSiteId::new(fid1, 2));
-        let id3 =
-            cs1.id_for_new_allocation(4, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
+        let id3 = cs1.id_for_new_allocation(4, generation, |cs| {
+            interner.get_or_insert_id(Cow::Borrowed(&cs), || ())
+        });
         assert_ne!(id3, id0);
         assert_ne!(id3, id1);
         assert_ne!(id3, id2);
 
         cs1.finish_call();
-        let id2b =
-            cs1.id_for_new_allocation(2, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
+        let id2b = cs1.id_for_new_allocation(2, generation, |cs| {
+            interner.get_or_insert_id(Cow::Borrowed(&cs), || ())
+        });
         assert_eq!(id2, id2b);
-        let id1c =
-            cs1.id_for_new_allocation(1, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
+        let id1c = cs1.id_for_new_allocation(1, generation, |cs| {
+            interner.get_or_insert_id(Cow::Borrowed(&cs), || ())
+        });
         assert_eq!(id1, id1c);
 
         // Check for cache invalidation in start_call:
         cs1.start_call(1, CallSiteId::new(fid1, 1));
-        let id4 =
-            cs1.id_for_new_allocation(1, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
+        let id4 = cs1.id_for_new_allocation(1, generation, |cs| {
+            interner.get_or_insert_id(Cow::Borrowed(&cs), || ())
+        });
         assert_ne!(id4, id0);
         assert_ne!(id4, id1);
         assert_ne!(id4, id2);
@@ -1046,11 +3247,42 @@ mod tests {
 
         // Check for cache invalidation in finish_call:
         cs1.finish_call();
-        let id1d =
-            cs1.id_for_new_allocation(1, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
+        let id1d = cs1.id_for_new_allocation(1, generation, |cs| {
+            interner.get_or_insert_id(Cow::Borrowed(&cs), || ())
+        });
         assert_eq!(id1, id1d);
     }
 
+    #[test]
+    fn reset_invalidates_cached_callstack_ids() {
+        let mut tracker = new_tracker();
+        let mut cs = Callstack::new();
+        cs.start_call(0, CallSiteId::new(FunctionId::new(1), 2));
+        let mut cs2 = Callstack::new();
+        cs2.start_call(0, CallSiteId::new(FunctionId::new(3), 4));
+
+        // Intern a couple of callstacks, so IDs from before reset are bigger
+        // than anything that exists after reset:
+        let generation = tracker.generation();
+        tracker.get_callstack_id(&cs2);
+        let id = cs.id_for_new_allocation(2, generation, |c| tracker.get_callstack_id(c));
+        assert_eq!(id, 1);
+        tracker.add_allocation(PARENT_PROCESS, 1, 100, id);
+
+        tracker.reset(".".to_string());
+        assert_ne!(tracker.generation(), generation);
+        assert_eq!(tracker.get_current_allocated_bytes(), 0);
+
+        // Same line number, but the cached ID is from the old generation, so
+        // it gets recalculated:
+        let generation = tracker.generation();
+        let new_id = cs.id_for_new_allocation(2, generation, |c| tracker.get_callstack_id(c));
+        assert_eq!(new_id, 0);
+        tracker.add_allocation(PARENT_PROCESS, 1, 100, new_id);
+        tracker.check_if_new_peak();
+        tracker.validate();
+    }
+
     #[test]
     fn peak_allocations_only_updated_on_new_peaks() {
         let fid1 = FunctionId::new(1u64);
@@ -1138,67 +3370,42 @@ mod tests {
     }
 
     #[test]
-    fn combine_callstacks_and_sum_allocations() {
+    fn peak_allocation_count_tracked_separately_from_bytes() {
         pyo3::prepare_freethreaded_python();
         let mut tracker = new_tracker();
         let fid1 = tracker
             .functions
-            .add_function("a".to_string(), "af".to_string());
+            .add_function("a.py".to_string(), "af".to_string());
         let fid2 = tracker
             .functions
-            .add_function("b".to_string(), "bf".to_string());
-        let fid3 = tracker
-            .functions
-            .add_function("c".to_string(), "cf".to_string());
-
-        let id1 = CallSiteId::new(fid1, 1);
-        // Same function, different line number—should be different item:
-        let id1_different = CallSiteId::new(fid1, 7);
-        let id2 = CallSiteId::new(fid2, 2);
-
-        let id3 = CallSiteId::new(fid3, 3);
+            .add_function("b.py".to_string(), "bf".to_string());
         let mut cs1 = Callstack::new();
-        cs1.start_call(0, id1);
-        cs1.start_call(0, id2.clone());
+        cs1.start_call(0, CallSiteId::new(fid1, 1));
         let mut cs2 = Callstack::new();
-        cs2.start_call(0, id3);
-        let mut cs3 = Callstack::new();
-        cs3.start_call(0, id1_different);
-        cs3.start_call(0, id2);
+        cs2.start_call(0, CallSiteId::new(fid2, 2));
         let cs1_id = tracker.get_callstack_id(&cs1);
         let cs2_id = tracker.get_callstack_id(&cs2);
-        let cs3_id = tracker.get_callstack_id(&cs3);
-        tracker.add_allocation(PARENT_PROCESS, 1, 1000, cs1_id);
-        tracker.add_allocation(PARENT_PROCESS, 2, 234, cs2_id);
-        tracker.add_anon_mmap(PARENT_PROCESS, 3, 50000, cs1_id);
-        tracker.add_allocation(PARENT_PROCESS, 4, 6000, cs3_id);
-
-        // Make sure we notice new peak.
+        // Many small allocations:
+        for i in 0..10 {
+            tracker.add_allocation(PARENT_PROCESS, 100 + i, 10, cs1_id);
+        }
+        for i in 0..10 {
+            tracker.free_allocation(PARENT_PROCESS, 100 + i);
+        }
+        // One big allocation, the byte peak:
+        tracker.add_allocation(PARENT_PROCESS, 1, 1000, cs2_id);
         tracker.check_if_new_peak();
-
-        // 234 allocation is too small, below the 99% total allocations
-        // threshold, but we always guarantee at least 100 allocations.
-
-        // TODO figure out how to test this...
-        // let mut expected = vec![
-        //     "a:1 (af);TB@@a:1@@TB;b:2 (bf);TB@@b:2@@TB 51000".to_string(),
-        //     "c:3 (cf);TB@@c:3@@TB 234".to_string(),
-        //     "a:7 (af);TB@@a:7@@TB;b:2 (bf);TB@@b:2@@TB 6000".to_string(),
-        // ];
-        // let mut result: Vec<String> = tracker.to_lines(true, true).collect();
-        // result.sort();
-        // expected.sort();
-        // assert_eq!(expected, result);
-
-        let mut expected2 = vec![
-            "a:1 (af);b:2 (bf) 51000",
-            "c:3 (cf) 234",
-            "a:7 (af);b:2 (bf) 6000",
-        ];
-        let mut result2: Vec<String> = tracker.to_lines(true, false).collect();
-        result2.sort();
-        expected2.sort();
-        assert_eq!(expected2, result2);
+        tracker.validate();
+        assert_eq!(tracker.get_peak_allocated_bytes(), 1000);
+        assert_eq!(tracker.get_peak_allocation_count(), 10);
+        // At the byte peak only the big allocation was live:
+        let data = tracker.snapshot();
+        let at_peak: Vec<_> = data
+            .callstacks
+            .iter()
+            .map(|usage| (usage.peak_allocations, usage.allocations_at_peak))
+            .collect();
+        assert_eq!(at_peak, vec![(10, 0), (0, 1)]);
     }
 
     #[test]
@@ -1209,5 +3416,26 @@ mod tests {
         assert_eq!(function, "UNKNOWN");
     }
 
+    #[test]
+    fn filenames_are_interned() {
+        let mut func_locations = VecFunctionLocations::new();
+        let method = func_locations.add_function("a.py".to_string(), "Foo.run".to_string());
+        let other = func_locations.add_function("a.py".to_string(), "Bar.run".to_string());
+        let lambda = func_locations.add_function("b.py".to_string(), "<lambda>".to_string());
+        assert_eq!(func_locations.filenames, vec!["a.py", "b.py"]);
+        assert_eq!(
+            func_locations.get_function_and_filename(method),
+            ("Foo.run", "a.py")
+        );
+        assert_eq!(
+            func_locations.get_function_and_filename(other),
+            ("Bar.run", "a.py")
+        );
+        assert_eq!(
+            func_locations.get_function_and_filename(lambda),
+            ("<lambda>", "b.py")
+        );
+    }
+
     // TODO test to_lines(false)
 }
@@ -0,0 +1,194 @@
+/// Information about the profiled process, so reports can still be understood
+/// weeks after they were written.
+use std::ffi::CStr;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+
+use crate::snapshot::get_string;
+
+/// Environment variables that affect memory usage or how the program runs, and
+/// are therefore worth recording. Anything else might contain secrets.
+const ENVIRONMENT_VARIABLES: &[&str] = &[
+    "PYTHONHASHSEED",
+    "PYTHONPATH",
+    "PYTHONMALLOC",
+    "VIRTUAL_ENV",
+    "CONDA_DEFAULT_ENV",
+    "OMP_NUM_THREADS",
+    "MKL_NUM_THREADS",
+    "OPENBLAS_NUM_THREADS",
+    "NUMEXPR_NUM_THREADS",
+    "CUDA_VISIBLE_DEVICES",
+];
+
+/// Prefixes of environment variables that are always recorded.
+const ENVIRONMENT_PREFIXES: &[&str] = &["FIL_"];
+
+/// Snapshot of the process, taken when profiling starts.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProcessMetadata {
+    /// The command line.
+    pub argv: Vec<String>,
+    /// Selected environment variables, sorted by name.
+    pub environment: Vec<(String, String)>,
+    pub pid: u32,
+    pub hostname: String,
+    /// When profiling started, in seconds since the UNIX epoch.
+    pub start_time: u64,
+    /// Where child processes were told to write their reports, so they can
+    /// be found and merged later.
+    pub child_output_paths: Vec<String>,
+}
+
+impl ProcessMetadata {
+    /// Snapshot the current process.
+    pub fn collect() -> Self {
+        Self {
+            argv: std::env::args_os()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect(),
+            environment: select_environment(std::env::vars_os().map(|(k, v)| {
+                (
+                    k.to_string_lossy().into_owned(),
+                    v.to_string_lossy().into_owned(),
+                )
+            })),
+            pid: std::process::id(),
+            hostname: get_hostname(),
+            start_time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            child_output_paths: Vec::new(),
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let environment: serde_json::Map<String, serde_json::Value> = self
+            .environment
+            .iter()
+            .map(|(k, v)| (k.clone(), json!(v)))
+            .collect();
+        json!({
+            "argv": self.argv,
+            "environment": environment,
+            "pid": self.pid,
+            "hostname": self.hostname,
+            "start_time": self.start_time,
+            "child_output_paths": self.child_output_paths,
+        })
+    }
+
+    /// The inverse of to_json().
+    pub fn from_json(value: &serde_json::Value) -> Result<Self, String> {
+        let strings = |key: &str| -> Result<Vec<String>, String> {
+            value[key]
+                .as_array()
+                .ok_or_else(|| format!("missing or invalid {}", key))?
+                .iter()
+                .map(|v| {
+                    v.as_str()
+                        .map(|s| s.to_string())
+                        .ok_or_else(|| format!("invalid {}", key))
+                })
+                .collect()
+        };
+        let environment = value["environment"]
+            .as_object()
+            .ok_or("missing or invalid environment")?
+            .iter()
+            .map(|(k, v)| {
+                v.as_str()
+                    .map(|v| (k.clone(), v.to_string()))
+                    .ok_or_else(|| "invalid environment".to_string())
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Self {
+            argv: strings("argv")?,
+            environment: select_environment(environment.into_iter()),
+            pid: value["pid"].as_u64().ok_or("missing or invalid pid")? as u32,
+            hostname: get_string(value, "hostname")?,
+            start_time: value["start_time"]
+                .as_u64()
+                .ok_or("missing or invalid start_time")?,
+            child_output_paths: strings("child_output_paths")?,
+        })
+    }
+
+    /// Write out as JSON.
+    pub fn write<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        serde_json::to_writer_pretty(out, &self.to_json())?;
+        Ok(())
+    }
+}
+
+/// Only keep environment variables that are known to be interesting.
+fn select_environment<I: Iterator<Item = (String, String)>>(vars: I) -> Vec<(String, String)> {
+    let mut result: Vec<(String, String)> = vars
+        .filter(|(k, _)| {
+            ENVIRONMENT_VARIABLES.contains(&k.as_str())
+                || ENVIRONMENT_PREFIXES.iter().any(|p| k.starts_with(p))
+        })
+        .collect();
+    result.sort();
+    result
+}
+
+fn get_hostname() -> String {
+    let mut buffer = [0u8; 256];
+    let result =
+        unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) };
+    if result != 0 {
+        return "unknown".to_string();
+    }
+    // Truncated hostnames aren't guaranteed to be NUL-terminated:
+    buffer[buffer.len() - 1] = 0;
+    unsafe { CStr::from_ptr(buffer.as_ptr() as *const libc::c_char) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{select_environment, ProcessMetadata};
+
+    #[test]
+    fn only_interesting_environment_variables_are_kept() {
+        let vars = vec![
+            ("AWS_SECRET_ACCESS_KEY", "hunter2"),
+            ("OMP_NUM_THREADS", "4"),
+            ("FIL_DEBUG", "1"),
+            ("HOME", "/root"),
+            ("CUDA_VISIBLE_DEVICES", "0,1"),
+        ];
+        let result = select_environment(
+            vars.into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string())),
+        );
+        assert_eq!(
+            result,
+            vec![
+                ("CUDA_VISIBLE_DEVICES".to_string(), "0,1".to_string()),
+                ("FIL_DEBUG".to_string(), "1".to_string()),
+                ("OMP_NUM_THREADS".to_string(), "4".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn collect_describes_this_process() {
+        let metadata = ProcessMetadata::collect();
+        assert_eq!(metadata.pid, std::process::id());
+        assert!(!metadata.argv.is_empty());
+        assert!(!metadata.hostname.is_empty());
+        assert!(metadata.start_time > 0);
+        let mut metadata = metadata;
+        metadata.child_output_paths.push("/tmp/child-1".to_string());
+        let json = metadata.to_json();
+        assert_eq!(json["pid"], metadata.pid);
+        assert_eq!(json["child_output_paths"][0], "/tmp/child-1");
+        assert_eq!(ProcessMetadata::from_json(&json), Ok(metadata));
+    }
+}
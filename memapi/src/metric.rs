@@ -0,0 +1,39 @@
+// What an AllocationTracker counts. Fil tracks bytes of memory, but the same
+// machinery, attributing amounts to callstacks, peaks, flamegraphs, works for
+// any resource that's acquired and released at an address or handle: GPU
+// memory, pinned memory, file descriptors. Embedders tracking something else
+// give the tracker a Metric type, e.g.
+//
+//     struct FileDescriptors;
+//     impl Metric for FileDescriptors {
+//         const RESOURCE: &'static str = "file-descriptors";
+//         fn units() -> Option<Units> {
+//             Some(Units::raw("file descriptors"))
+//         }
+//     }
+//
+// and record each descriptor as an "allocation" of size 1 at the descriptor
+// number. Snapshots and reports then carry the resource's name.
+use crate::units::Units;
+
+pub trait Metric {
+    /// The resource's name, e.g. "gpu-memory". It's recorded in snapshots,
+    /// and used in report titles and filenames.
+    const RESOURCE: &'static str;
+
+    /// How amounts are shown in reports; None means Fil's usual display of
+    /// bytes. Report options can still override this.
+    fn units() -> Option<Units> {
+        None
+    }
+}
+
+/// The name of what Fil usually tracks.
+pub const MEMORY: &str = "memory";
+
+/// Bytes of memory, what Fil itself tracks.
+pub struct Memory;
+
+impl Metric for Memory {
+    const RESOURCE: &'static str = MEMORY;
+}
@@ -1,9 +1,34 @@
 use super::ffi::LIBC;
+use crate::locked::LockEvent;
 /// mmap API business logic.
 use std::os::raw::{c_int, c_void};
 
 /// Need to use pattern here: https://stackoverflow.com/a/37608197/6214034
 
+/// Filename of the synthetic leaf frame added to file-backed mmap()s, whose
+/// function name is the path of the mapped file.
+pub const MMAP_FILENAME: &str = "<mmap>";
+
+/// The path of the file an open file descriptor refers to, if it can be
+/// found, e.g. to attribute a file-backed mmap() to its file.
+pub fn fd_path(fd: c_int) -> Option<String> {
+    #[cfg(target_os = "linux")]
+    {
+        std::fs::read_link(format!("/proc/self/fd/{}", fd))
+            .ok()
+            .map(|path| path.to_string_lossy().into_owned())
+    }
+    #[cfg(target_os = "macos")]
+    {
+        let mut buffer = [0 as std::os::raw::c_char; libc::PATH_MAX as usize];
+        if unsafe { libc::fcntl(fd, libc::F_GETPATH, buffer.as_mut_ptr()) } == -1 {
+            return None;
+        }
+        let path = unsafe { std::ffi::CStr::from_ptr(buffer.as_ptr()) };
+        Some(path.to_string_lossy().into_owned())
+    }
+}
+
 pub trait MmapAPI {
     /// Call if we're not reentrant.
     fn call_if_tracking<F: FnMut()>(&self, f: F);
@@ -13,6 +38,9 @@ pub trait MmapAPI {
 
     /// Return whether C module is initialized.
     fn is_initialized(&self) -> bool;
+
+    /// Implement recording of mlock() and friends; see locked.rs.
+    fn record_lock_event(&self, event: LockEvent);
 }
 
 /// # Safety
@@ -37,13 +65,33 @@ pub unsafe fn munmap_wrapper<A: MmapAPI>(addr: *mut c_void, len: usize, api: &A)
     unsafe { (LIBC.munmap)(addr, len) }
 }
 
+/// Run mlock(), madvise() or similar, and if it succeeded record the event.
+/// Unlike munmap() the memory isn't going away, so there's no race in
+/// recording afterwards.
+pub fn lock_wrapper<A: MmapAPI, F: FnOnce() -> c_int>(
+    event: Option<LockEvent>,
+    api: &A,
+    call: F,
+) -> c_int {
+    let result = call();
+    if let Some(event) = event {
+        if result == 0 && api.is_initialized() {
+            api.call_if_tracking(|| api.record_lock_event(event));
+        }
+    }
+    result
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{munmap_wrapper, MmapAPI};
+    use super::{fd_path, lock_wrapper, munmap_wrapper, MmapAPI};
     use crate::ffi::LIBC;
+    use crate::locked::LockEvent;
 
+    #[derive(Default)]
     struct TrackMunmap {
         tracking_removed: std::cell::Cell<(usize, usize)>,
+        lock_events: std::cell::Cell<usize>,
     }
 
     impl MmapAPI for TrackMunmap {
@@ -58,6 +106,9 @@ mod tests {
             assert!(exists_in_maps(addr, len));
             self.tracking_removed.set((addr, len));
         }
+        fn record_lock_event(&self, _event: LockEvent) {
+            self.lock_events.set(self.lock_events.get() + 1);
+        }
     }
 
     // Return whether given mmap() exists for this process.
@@ -85,11 +136,35 @@ mod tests {
             )
         };
         assert!(exists_in_maps(addr as usize, size));
-        let fake_api = TrackMunmap {
-            tracking_removed: std::cell::Cell::new((0, 0)),
-        };
+        let fake_api = TrackMunmap::default();
         unsafe { munmap_wrapper(addr, size, &fake_api) };
         assert_eq!(fake_api.tracking_removed.get(), (addr as usize, size));
         assert!(!exists_in_maps(addr as usize, size));
     }
+
+    #[test]
+    fn only_successful_locks_are_recorded() {
+        let fake_api = TrackMunmap::default();
+        let event = Some(LockEvent::UnlockAll);
+        assert_eq!(lock_wrapper(event, &fake_api, || -1), -1);
+        assert_eq!(fake_api.lock_events.get(), 0);
+        assert_eq!(lock_wrapper(None, &fake_api, || 0), 0);
+        assert_eq!(fake_api.lock_events.get(), 0);
+        assert_eq!(lock_wrapper(event, &fake_api, || 0), 0);
+        assert_eq!(fake_api.lock_events.get(), 1);
+    }
+
+    #[test]
+    fn fd_path_finds_the_file() {
+        use std::os::unix::io::AsRawFd;
+        let path = std::env::temp_dir().join(format!("fil-fd-path-{}", std::process::id()));
+        let file = std::fs::File::create(&path).unwrap();
+        let found = fd_path(file.as_raw_fd());
+        drop(file);
+        std::fs::remove_file(&path).unwrap();
+        // The temporary directory may be behind a symlink:
+        let found = found.unwrap();
+        assert!(found.ends_with(path.file_name().unwrap().to_str().unwrap()));
+        assert_eq!(fd_path(-1), None);
+    }
 }
@@ -0,0 +1,280 @@
+// Breakdown of allocations made with no Python frames on the stack: by
+// native threads, during interpreter startup and shutdown, or by C libraries'
+// own worker threads. By default these all end up in a single "[No Python
+// stack]" bucket. When enabled, their callstacks instead get synthetic frames
+// naming the thread, the shared library that called malloc(), and the size
+// class of the allocation, so there's at least a hint of which native
+// component is responsible.
+//
+// Separately, and more cheaply than capturing native callstacks, every
+// allocation can get an extra innermost frame naming the library that called
+// malloc(), which is enough to tell e.g. libtorch memory from libopenblas
+// memory.
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::os::raw::c_void;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::memorytracking::{
+    AllocationTracker, CallSiteId, Callstack, CallstackId, VecFunctionLocations,
+};
+use crate::threads::{self, ThreadId, THREAD_FILENAME};
+
+/// Filename of the synthetic library and size class frames.
+pub const NATIVE_FILENAME: &str = "<native>";
+
+/// Function name of the library frame when the caller couldn't be resolved.
+pub const UNKNOWN_LIBRARY: &str = "[unknown library]";
+
+/// Upper bounds (exclusive) of the size classes, and their names.
+const SIZE_CLASSES: [(usize, &str); 5] = [
+    (256, "under 256 B"),
+    (4 * 1024, "256 B to 4 KiB"),
+    (64 * 1024, "4 KiB to 64 KiB"),
+    (1024 * 1024, "64 KiB to 1 MiB"),
+    (16 * 1024 * 1024, "1 MiB to 16 MiB"),
+];
+
+/// Only so many return addresses are cached before starting over.
+const MAX_CACHED_ADDRESSES: usize = 100_000;
+
+/// Enabled by setting FIL_NATIVE_BREAKDOWN=1.
+pub fn enabled_from_env() -> bool {
+    std::env::var("FIL_NATIVE_BREAKDOWN")
+        .map(|v| v == "1")
+        .unwrap_or(false)
+}
+
+/// Whether allocations get a frame naming the calling library; enabled by
+/// setting FIL_CALLER_LIBRARY=1.
+pub fn caller_library_from_env() -> bool {
+    std::env::var("FIL_CALLER_LIBRARY")
+        .map(|v| v == "1")
+        .unwrap_or(false)
+}
+
+/// The name of an allocation's size class.
+pub fn size_class(size: usize) -> &'static str {
+    SIZE_CLASSES
+        .iter()
+        .find(|(limit, _)| size < *limit)
+        .map(|(_, name)| *name)
+        .unwrap_or("16 MiB or more")
+}
+
+/// The filename, without directories, of the shared library or executable
+/// containing a code address, e.g. a return address.
+///
+/// dladdr() takes the dynamic linker's lock, and a thread inside dlopen() can
+/// hold that lock while it waits for the tracker's, so don't call this while
+/// holding the tracker.
+pub fn library_for_address(address: usize) -> Option<String> {
+    if address == 0 {
+        return None;
+    }
+    let mut info: libc::Dl_info = unsafe { std::mem::zeroed() };
+    if unsafe { libc::dladdr(address as *const c_void, &mut info) } == 0 || info.dli_fname.is_null()
+    {
+        return None;
+    }
+    let path = unsafe { CStr::from_ptr(info.dli_fname) }.to_string_lossy();
+    let name = path.rsplit('/').next().unwrap_or(&path);
+    if name.is_empty() {
+        None
+    } else {
+        Some(name.to_string())
+    }
+}
+
+/// Caches library_for_address(), since dladdr() walks the list of loaded
+/// libraries and the same few call sites do most allocations. Entries are
+/// never invalidated, so if a library is unloaded and another one is loaded
+/// at the same address, the old name may still be used.
+#[derive(Default)]
+pub struct LibraryCache {
+    libraries: Mutex<HashMap<usize, Option<Arc<str>>>>,
+}
+
+impl LibraryCache {
+    /// Like library_for_address(), with the same caveat about locks.
+    pub fn library_for_address(&self, address: usize) -> Option<Arc<str>> {
+        if let Some(library) = self.libraries.lock().get(&address) {
+            return library.clone();
+        }
+        // The cache isn't locked while calling dladdr(), for the same reason
+        // the tracker mustn't be:
+        let library: Option<Arc<str>> = library_for_address(address).map(Arc::from);
+        let mut libraries = self.libraries.lock();
+        if libraries.len() >= MAX_CACHED_ADDRESSES {
+            libraries.clear();
+        }
+        libraries.insert(address, library.clone());
+        library
+    }
+}
+
+/// Add a frame naming the calling library, from LibraryCache, to the end of
+/// a callstack.
+pub fn with_library_frame(
+    tracker: &mut AllocationTracker<VecFunctionLocations>,
+    callstack_id: CallstackId,
+    library: Option<&str>,
+) -> CallstackId {
+    tracker.with_leaf_frame(
+        callstack_id,
+        NATIVE_FILENAME,
+        library.unwrap_or(UNKNOWN_LIBRARY),
+    )
+}
+
+/// Where an allocation without a Python stack came from.
+pub struct NativeCaller {
+    pub thread: ThreadId,
+    /// From LibraryCache.
+    pub library: Option<Arc<str>>,
+}
+
+impl NativeCaller {
+    /// The callstack to attribute an allocation of the given size to:
+    /// thread, then library, then size class.
+    pub fn callstack_id(
+        &self,
+        tracker: &mut AllocationTracker<VecFunctionLocations>,
+        size: usize,
+    ) -> CallstackId {
+        let functions = [
+            (THREAD_FILENAME, threads::root_function_name(self.thread)),
+            (
+                NATIVE_FILENAME,
+                self.library
+                    .as_deref()
+                    .unwrap_or(UNKNOWN_LIBRARY)
+                    .to_string(),
+            ),
+            (NATIVE_FILENAME, size_class(size).to_string()),
+        ];
+        let mut callstack = Callstack::new();
+        for (filename, function) in functions {
+            let function = tracker
+                .functions
+                .add_function(filename.to_string(), function);
+            callstack.start_call(0, CallSiteId::new(function, 0));
+        }
+        tracker.get_callstack_id(&callstack)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        library_for_address, size_class, with_library_frame, LibraryCache, NativeCaller,
+        NATIVE_FILENAME,
+    };
+    use crate::memorytracking::{
+        AllocationTracker, CallSiteId, Callstack, VecFunctionLocations, PARENT_PROCESS,
+    };
+    use crate::threads::split_by_thread;
+
+    #[test]
+    fn size_classes() {
+        assert_eq!(size_class(0), "under 256 B");
+        assert_eq!(size_class(256), "256 B to 4 KiB");
+        assert_eq!(size_class(100_000), "64 KiB to 1 MiB");
+        assert_eq!(size_class(1 << 30), "16 MiB or more");
+    }
+
+    #[test]
+    fn libraries_are_found() {
+        let library = library_for_address(libc::malloc as *const () as usize).unwrap();
+        assert!(library.starts_with("libc"), "{}", library);
+        assert!(!library.contains('/'));
+        assert_eq!(library_for_address(0), None);
+        let cache = LibraryCache::default();
+        let address = libc::malloc as *const () as usize;
+        assert_eq!(
+            cache.library_for_address(address).as_deref(),
+            Some(&*library)
+        );
+        assert_eq!(
+            cache.library_for_address(address).as_deref(),
+            Some(&*library)
+        );
+        assert_eq!(cache.libraries.lock().len(), 1);
+    }
+
+    #[test]
+    fn library_frame_is_innermost() {
+        let mut tracker = AllocationTracker::new(".".to_string(), VecFunctionLocations::new());
+        let main = tracker
+            .functions
+            .add_function("a.py".to_string(), "main".to_string());
+        let mut callstack = Callstack::new();
+        callstack.start_call(0, CallSiteId::new(main, 3));
+        let python = tracker.get_callstack_id(&callstack);
+        let torch = with_library_frame(&mut tracker, python, Some("libtorch_cpu.so"));
+        assert_eq!(
+            torch,
+            with_library_frame(&mut tracker, python, Some("libtorch_cpu.so"))
+        );
+        let unknown = with_library_frame(&mut tracker, python, None);
+        tracker.add_allocation(PARENT_PROCESS, 1, 100, torch);
+        tracker.add_allocation(PARENT_PROCESS, 2, 10, unknown);
+        let data = tracker.snapshot();
+        let mut names: Vec<_> = data
+            .callstacks
+            .iter()
+            .map(|usage| {
+                let frames = &usage.frames;
+                assert_eq!(frames[0].line_number, 3);
+                frames[1].function.clone()
+            })
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["[unknown library]", "libtorch_cpu.so"]);
+    }
+
+    #[test]
+    fn callstacks_name_thread_library_and_size() {
+        let mut tracker = AllocationTracker::new(".".to_string(), VecFunctionLocations::new());
+        let caller = NativeCaller {
+            thread: 12,
+            library: Some("libopenblas.so.0".into()),
+        };
+        let small = caller.callstack_id(&mut tracker, 100);
+        assert_eq!(small, caller.callstack_id(&mut tracker, 200));
+        let large = caller.callstack_id(&mut tracker, 10_000);
+        assert_ne!(small, large);
+        let unknown = NativeCaller {
+            thread: 12,
+            library: None,
+        }
+        .callstack_id(&mut tracker, 100);
+        tracker.add_allocation(PARENT_PROCESS, 1, 100, small);
+        tracker.add_allocation(PARENT_PROCESS, 2, 10_000, large);
+        tracker.add_allocation(PARENT_PROCESS, 3, 50, unknown);
+
+        let data = tracker.snapshot();
+        let usage = data
+            .callstacks
+            .iter()
+            .find(|usage| usage.current_bytes == 10_000)
+            .unwrap();
+        let names: Vec<_> = usage
+            .frames
+            .iter()
+            .map(|frame| (frame.filename.as_str(), frame.function.as_str()))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("<thread>", "thread 12"),
+                (NATIVE_FILENAME, "libopenblas.so.0"),
+                (NATIVE_FILENAME, "4 KiB to 64 KiB"),
+            ]
+        );
+        // The thread frame means per-thread reports include these:
+        assert_eq!(split_by_thread(&data)[&12].current_bytes, 10_150);
+    }
+}
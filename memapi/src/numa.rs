@@ -0,0 +1,203 @@
+// Which NUMA nodes back large allocations. On multi-socket machines memory
+// on the "wrong" node is slower to access, which can explain otherwise
+// mysterious slowdowns.
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use crate::snapshot::get_usize;
+
+/// Node used for pages that aren't backed by physical memory (yet).
+pub const NOT_RESIDENT: i32 = -1;
+
+/// We don't check every page of huge allocations, just a sample.
+const MAX_SAMPLED_PAGES: usize = 1024;
+
+/// Per-node totals for allocations above a size threshold, measured just
+/// before they're freed (or when a report is written, if still live), since
+/// pages typically aren't backed by memory until they're first written.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NumaStats {
+    threshold: usize,
+    bytes_per_node: BTreeMap<i32, usize>,
+}
+
+impl NumaStats {
+    pub fn new(threshold: usize) -> Self {
+        Self {
+            threshold,
+            bytes_per_node: BTreeMap::new(),
+        }
+    }
+
+    /// Enabled by setting FIL_NUMA_THRESHOLD_MB to the minimum allocation
+    /// size, in MiB, to check.
+    pub fn from_env() -> Option<Self> {
+        std::env::var("FIL_NUMA_THRESHOLD_MB")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .map(|mb| Self::new(mb * 1024 * 1024))
+    }
+
+    /// Same settings, no data.
+    pub fn cleared(&self) -> Self {
+        Self::new(self.threshold)
+    }
+
+    /// Check where a memory range lives, if it's large enough to care.
+    pub fn record(&mut self, address: usize, size: usize) {
+        if size < self.threshold {
+            return;
+        }
+        if let Some(per_node) = bytes_per_node(address, size) {
+            self.add(&per_node);
+        }
+    }
+
+    fn add(&mut self, per_node: &BTreeMap<i32, usize>) {
+        for (node, bytes) in per_node {
+            *self.bytes_per_node.entry(*node).or_insert(0) += bytes;
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "threshold": self.threshold,
+            "bytes_per_node": self
+                .bytes_per_node
+                .iter()
+                .map(|(node, bytes)| (node.to_string(), serde_json::json!(bytes)))
+                .collect::<serde_json::Map<_, _>>(),
+        })
+    }
+
+    /// The inverse of to_json().
+    pub fn from_json(value: &serde_json::Value) -> Result<Self, String> {
+        let mut result = Self::new(get_usize(value, "threshold")?);
+        for (node, bytes) in value["bytes_per_node"]
+            .as_object()
+            .ok_or("missing or invalid bytes_per_node")?
+        {
+            let node = node.parse().map_err(|_| "invalid NUMA node".to_string())?;
+            let bytes = bytes.as_u64().ok_or("invalid NUMA byte count")?;
+            result.bytes_per_node.insert(node, bytes as usize);
+        }
+        Ok(result)
+    }
+
+    /// Write out a table of per-node totals.
+    pub fn write<W: Write>(&self, file: &mut W) -> std::io::Result<()> {
+        writeln!(
+            file,
+            "Allocations of at least {:.1} MiB, by NUMA node:",
+            self.threshold as f64 / (1024.0 * 1024.0)
+        )?;
+        writeln!(file, "{:16}  {:>10}", "Node", "MiB")?;
+        for (node, bytes) in &self.bytes_per_node {
+            let node = if *node == NOT_RESIDENT {
+                "Not resident".to_string()
+            } else {
+                node.to_string()
+            };
+            writeln!(
+                file,
+                "{:16}  {:>10.1}",
+                node,
+                *bytes as f64 / (1024.0 * 1024.0)
+            )?;
+        }
+        file.flush()
+    }
+}
+
+/// Figure out how many bytes of a memory range are on each NUMA node, by
+/// sampling pages. Returns None if this isn't supported.
+#[cfg(target_os = "linux")]
+pub fn bytes_per_node(address: usize, size: usize) -> Option<BTreeMap<i32, usize>> {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let first_page = address / page_size * page_size;
+    let num_pages = (address + size - first_page).div_ceil(page_size);
+    if size == 0 || num_pages == 0 {
+        return Some(BTreeMap::new());
+    }
+    let sampled = num_pages.min(MAX_SAMPLED_PAGES);
+    let pages: Vec<*mut libc::c_void> = (0..sampled)
+        .map(|i| (first_page + (i * num_pages / sampled) * page_size) as *mut libc::c_void)
+        .collect();
+    let mut status: Vec<libc::c_int> = vec![0; sampled];
+    // With a NULL nodes argument, move_pages() doesn't move anything, it just
+    // reports where each page is:
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_move_pages,
+            0,
+            sampled as libc::c_ulong,
+            pages.as_ptr(),
+            std::ptr::null::<libc::c_int>(),
+            status.as_mut_ptr(),
+            0,
+        )
+    };
+    if result != 0 {
+        return None;
+    }
+    let mut per_node = BTreeMap::new();
+    // Spread the size evenly over the samples, with any rounding error going
+    // to the first sample:
+    let per_sample = size / sampled;
+    for (i, node) in status.into_iter().enumerate() {
+        let node = if node < 0 { NOT_RESIDENT } else { node };
+        let bytes = if i == 0 {
+            size - per_sample * (sampled - 1)
+        } else {
+            per_sample
+        };
+        *per_node.entry(node).or_insert(0) += bytes;
+    }
+    Some(per_node)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn bytes_per_node(_address: usize, _size: usize) -> Option<BTreeMap<i32, usize>> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bytes_per_node, NumaStats, NOT_RESIDENT};
+    use std::collections::BTreeMap;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn touched_pages_are_resident() {
+        let size = 8 * 1024 * 1024;
+        let mut buffer = vec![0u8; size];
+        for i in (0..size).step_by(4096) {
+            buffer[i] = 1;
+        }
+        if let Some(per_node) = bytes_per_node(buffer.as_ptr() as usize, size) {
+            assert_eq!(per_node.values().sum::<usize>(), size);
+            assert!(!per_node.contains_key(&NOT_RESIDENT));
+        }
+    }
+
+    #[test]
+    fn small_allocations_are_ignored() {
+        let mut stats = NumaStats::new(1024);
+        let buffer = vec![1u8; 100];
+        stats.record(buffer.as_ptr() as usize, buffer.len());
+        assert_eq!(stats, NumaStats::new(1024));
+    }
+
+    #[test]
+    fn totals_accumulate() {
+        let mut stats = NumaStats::new(0);
+        stats.add(&BTreeMap::from([(0, 100), (1, 50)]));
+        stats.add(&BTreeMap::from([(1, 25), (NOT_RESIDENT, 10)]));
+        assert_eq!(
+            stats.bytes_per_node,
+            BTreeMap::from([(NOT_RESIDENT, 10), (0, 100), (1, 75)])
+        );
+        assert_eq!(stats.cleared(), NumaStats::new(0));
+        assert_eq!(NumaStats::from_json(&stats.to_json()), Ok(stats));
+    }
+}
@@ -1,4 +1,5 @@
-use std::fs::read_to_string;
+#[cfg(target_os = "linux")]
+use crate::cgroup::CgroupMemory;
 
 /// Logic for handling out-of-memory situations.
 
@@ -17,7 +18,7 @@ pub trait MemoryInfo {
 ///
 /// First, we need to define what "running out of memory" means. As a first
 /// pass, 100MB or less of non-swap memory availability, minimum of OS in
-/// general and current cgroup. Don't count swap because goal is
+/// general and current cgroup (see cgroup.rs). Don't count swap because goal is
 /// to avoid slowness, if someone wants to fallback to disk they should use
 /// mmap().
 ///
@@ -44,10 +45,11 @@ impl OutOfMemoryEstimator {
     pub fn new(memory_info: Box<dyn MemoryInfo + Sync + Send>) -> Self {
         Self {
             check_threshold_bytes: 0,
-            // Either 100MB or 2% of available memory, whatever is bigger.
-            minimal_required_available_bytes: std::cmp::max(
-                100 * 1024 * 1024,
-                memory_info.total_memory() / 50,
+            // Either 100MB or 2% of available memory, whatever is bigger, but
+            // no more than a quarter of it, for small containers.
+            minimal_required_available_bytes: std::cmp::min(
+                std::cmp::max(100 * 1024 * 1024, memory_info.total_memory() / 50),
+                memory_info.total_memory() / 4,
             ),
 
             memory_info,
@@ -131,66 +133,21 @@ impl OutOfMemoryEstimator {
     }
 }
 
-#[cfg(target_os = "linux")]
-fn get_cgroup_paths<'a>(proc_cgroups: &'a str) -> Vec<&'a str> {
-    let mut result = vec![];
-    for line in proc_cgroups.lines() {
-        // TODO better error handling?
-        let mut parts = line.splitn(3, ":");
-        let subsystems = parts.nth(1).unwrap();
-        if (subsystems == "") || subsystems.split(",").any(|s| s == "memory") {
-            let cgroup_path = parts.nth(0).unwrap().strip_prefix("/").unwrap();
-            result.push(cgroup_path);
-        }
-    }
-    result
-}
-
 /// Real system information.
 pub struct RealMemoryInfo {
     // The current process.
     process: psutil::process::Process,
-    // On Linux, the current cgroup _at startup_. If it changes after startup,
+    // On Linux, the memory cgroup _at startup_. If it changes after startup,
     // we'll be wrong, but that's unlikely.
     #[cfg(target_os = "linux")]
-    cgroup: Option<cgroups_rs::Cgroup>,
+    cgroup: Option<CgroupMemory>,
 }
 
 impl RealMemoryInfo {
     #[cfg(target_os = "linux")]
     pub fn new() -> Self {
-        let get_cgroup = || {
-            let contents = match read_to_string("/proc/self/cgroup") {
-                Ok(contents) => contents,
-                Err(err) => {
-                    eprintln!("=fil-profile= Couldn't read /proc/self/cgroup ({:})", err);
-                    return None;
-                }
-            };
-            let cgroup_paths = get_cgroup_paths(&contents);
-            for path in cgroup_paths {
-                let h = cgroups_rs::hierarchies::auto();
-                let cgroup = cgroups_rs::Cgroup::load(h, path);
-                // Make sure memory_stat() works. Sometimes it doesn't
-                // (https://github.com/pythonspeed/filprofiler/issues/147). If
-                // it doesn't, this'll panic.
-                let mem: &cgroups_rs::memory::MemController = cgroup.controller_of().unwrap();
-                let _mem = mem.memory_stat();
-                return Some(cgroup);
-            }
-            None
-        };
-        let cgroup_result = std::panic::catch_unwind(get_cgroup);
-        let cgroup = match cgroup_result {
-            Ok(c) => c,
-            Err(err) => {
-                eprintln!(
-                    "=fil-profile= Error retrieving cgroup memory, per-container/per-cgroup memory limits won't be respected (error: {:?}). This is expected behavior on old versions of Linux, e.g. RHEL 7. If you're on a newer version, please file a bug at https://github.com/pythonspeed/filprofiler/issues/new/choose.", err);
-                None
-            }
-        };
         Self {
-            cgroup: cgroup,
+            cgroup: CgroupMemory::find(),
             process: psutil::process::Process::current().unwrap(),
         }
     }
@@ -204,34 +161,37 @@ impl RealMemoryInfo {
 
     #[cfg(target_os = "linux")]
     pub fn get_cgroup_available_memory(&self) -> usize {
-        let mut result = std::usize::MAX;
-        if let Some(cgroup) = &self.cgroup {
-            if let Some(mem) = cgroup.controller_of::<cgroups_rs::memory::MemController>() {
-                let mem = mem.memory_stat();
-                if mem.limit_in_bytes == 0 {
-                    // A limit of 0 is nonsensical. Seen on Docker with cgroups v1
-                    // with no limit set, and the usage was also 0. So just assume
-                    // there is no limit.
-                    return result;
-                }
-                result = std::cmp::min(
-                    result,
-                    (mem.limit_in_bytes - mem.usage_in_bytes as i64) as usize,
-                );
-            }
-        }
-        result
+        self.cgroup
+            .as_ref()
+            .and_then(|cgroup| cgroup.available())
+            .unwrap_or(usize::MAX)
     }
 
     #[cfg(target_os = "macos")]
     pub fn get_cgroup_available_memory(&self) -> usize {
         std::usize::MAX
     }
+
+    #[cfg(target_os = "linux")]
+    fn get_cgroup_limit(&self) -> usize {
+        self.cgroup
+            .as_ref()
+            .and_then(|cgroup| cgroup.limit())
+            .unwrap_or(usize::MAX)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn get_cgroup_limit(&self) -> usize {
+        std::usize::MAX
+    }
 }
 
 impl MemoryInfo for RealMemoryInfo {
+    /// The host's memory, or the cgroup (e.g. container) limit if that's
+    /// lower.
     fn total_memory(&self) -> usize {
-        psutil::memory::virtual_memory().unwrap().total() as usize
+        let total = psutil::memory::virtual_memory().unwrap().total() as usize;
+        std::cmp::min(total, self.get_cgroup_limit())
     }
 
     /// Return how much free memory we have, as bytes.
@@ -257,12 +217,7 @@ impl MemoryInfo for RealMemoryInfo {
         #[cfg(target_os = "linux")]
         eprintln!(
             "=fil-profile= cgroup (e.g. container) memory info: {:?}",
-            if let Some(cgroup) = &self.cgroup {
-                let mem: &cgroups_rs::memory::MemController = cgroup.controller_of().unwrap();
-                Some(mem.memory_stat())
-            } else {
-                None
-            }
+            self.cgroup.as_ref().map(|cgroup| cgroup.usages())
         );
         eprintln!(
             "=fil-profile= Process memory info: {:?}",
@@ -0,0 +1,219 @@
+/// Roll up memory usage by top-level package, e.g. "pandas" or "torch", for a
+/// coarse overview of where memory went.
+use std::collections::HashMap;
+use std::io::Write;
+
+/// Package used for allocations with no Python frames at all.
+pub const NO_PYTHON_STACK: &str = "[No Python stack]";
+
+/// Figure out the top-level package for a Python source file.
+///
+/// `modules` maps filenames to dotted module names, as found in
+/// `sys.modules`. If the file isn't in there we guess from the path.
+pub fn package_for_filename(filename: &str, modules: &HashMap<String, String>) -> String {
+    if let Some(module) = modules.get(filename) {
+        return top_level(module).to_string();
+    }
+    // e.g. "<frozen importlib._bootstrap>":
+    if let Some(frozen) = filename
+        .strip_prefix("<frozen ")
+        .and_then(|f| f.strip_suffix('>'))
+    {
+        return top_level(frozen).to_string();
+    }
+    // Third-party packages:
+    for marker in ["site-packages/", "dist-packages/"] {
+        if let Some(index) = filename.rfind(marker) {
+            if let Some(package) = first_component(&filename[index + marker.len()..]) {
+                return package;
+            }
+        }
+    }
+    // The standard library, e.g. "/usr/lib/python3.9/json/decoder.py":
+    if let Some(index) = filename.rfind("/lib/python") {
+        let rest = &filename[index + "/lib/python".len()..];
+        if let Some(slash) = rest.find('/') {
+            if let Some(package) = first_component(&rest[slash + 1..]) {
+                return package;
+            }
+        }
+    }
+    // Something else, most likely the user's own script:
+    let basename = filename.rsplit('/').next().unwrap_or(filename);
+    top_level(basename).to_string()
+}
+
+/// The top-level package for a dotted module name, e.g. "pandas" for
+/// "pandas.core.frame".
+pub fn package_for_module(module: &str) -> String {
+    top_level(module).to_string()
+}
+
+/// "a.b.c" -> "a".
+fn top_level(dotted: &str) -> &str {
+    dotted.split('.').next().unwrap_or(dotted)
+}
+
+/// "pandas/core/frame.py" -> "pandas", "six.py" -> "six".
+fn first_component(path: &str) -> Option<String> {
+    let component = path.split('/').next()?;
+    if component.is_empty() {
+        return None;
+    }
+    Some(top_level(component).to_string())
+}
+
+/// Sum up sizes per package, largest first. Each item is the list of packages
+/// for each frame in a callstack, outermost first; the innermost frame is
+/// the one that gets the blame.
+pub fn sum_by_package<'a, I>(stacks: I) -> Vec<(String, usize)>
+where
+    I: Iterator<Item = (&'a [String], usize)>,
+{
+    let mut totals: HashMap<&str, usize> = HashMap::new();
+    for (packages, size) in stacks {
+        let package = packages
+            .last()
+            .map(|p| p.as_str())
+            .unwrap_or(NO_PYTHON_STACK);
+        *totals.entry(package).or_insert(0) += size;
+    }
+    let mut result: Vec<(String, usize)> = totals
+        .into_iter()
+        .filter(|(_, size)| *size > 0)
+        .map(|(package, size)| (package.to_string(), size))
+        .collect();
+    // Largest first, ties broken by name so output is stable:
+    result.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    result
+}
+
+/// Collapse consecutive frames in the same package, so the flamegraph shows
+/// transitions between packages rather than individual functions.
+pub fn coarse_stack(packages: &[String]) -> String {
+    if packages.is_empty() {
+        return NO_PYTHON_STACK.to_string();
+    }
+    let mut result: Vec<&str> = Vec::with_capacity(packages.len());
+    for package in packages {
+        if result.last() != Some(&package.as_str()) {
+            result.push(package);
+        }
+    }
+    result.join(";")
+}
+
+/// Write a human-readable table of per-package totals.
+pub fn write_package_table<W: Write>(
+    totals: &[(String, usize)],
+    file: &mut W,
+) -> std::io::Result<()> {
+    let sum: usize = totals.iter().map(|(_, size)| size).sum();
+    let width = totals
+        .iter()
+        .map(|(package, _)| package.chars().count())
+        .chain(std::iter::once("Package".len()))
+        .max()
+        .unwrap_or(0);
+    writeln!(
+        file,
+        "{:width$}  {:>8}  {:>6}",
+        "Package",
+        "MiB",
+        "%",
+        width = width
+    )?;
+    for (package, size) in totals {
+        writeln!(
+            file,
+            "{:width$}  {:>8.1}  {:>5.1}%",
+            package,
+            *size as f64 / (1024.0 * 1024.0),
+            if sum == 0 {
+                0.0
+            } else {
+                *size as f64 * 100.0 / sum as f64
+            },
+            width = width
+        )?;
+    }
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{coarse_stack, package_for_filename, sum_by_package, NO_PYTHON_STACK};
+    use std::collections::HashMap;
+
+    #[test]
+    fn package_from_known_module() {
+        let mut modules = HashMap::new();
+        modules.insert(
+            "/src/mycode/train.py".to_string(),
+            "mycode.train".to_string(),
+        );
+        assert_eq!(
+            package_for_filename("/src/mycode/train.py", &modules),
+            "mycode"
+        );
+    }
+
+    #[test]
+    fn package_guessed_from_path() {
+        let modules = HashMap::new();
+        for (filename, expected) in [
+            (
+                "/venv/lib/python3.9/site-packages/pandas/core/frame.py",
+                "pandas",
+            ),
+            ("/usr/lib/python3/dist-packages/six.py", "six"),
+            ("/usr/lib/python3.9/json/decoder.py", "json"),
+            ("/usr/lib/python3.9/threading.py", "threading"),
+            ("<frozen importlib._bootstrap>", "importlib"),
+            ("/home/user/script.py", "script"),
+        ] {
+            assert_eq!(package_for_filename(filename, &modules), expected);
+        }
+    }
+
+    #[test]
+    fn sums_by_innermost_package() {
+        let a = vec!["mycode".to_string(), "pandas".to_string()];
+        let b = vec!["mycode".to_string()];
+        let c = vec![
+            "mycode".to_string(),
+            "pandas".to_string(),
+            "numpy".to_string(),
+        ];
+        let empty: Vec<String> = vec![];
+        let result = sum_by_package(
+            vec![
+                (&a[..], 100),
+                (&b[..], 30),
+                (&a[..], 50),
+                (&c[..], 30),
+                (&empty[..], 7),
+            ]
+            .into_iter(),
+        );
+        assert_eq!(
+            result,
+            vec![
+                ("pandas".to_string(), 150),
+                ("mycode".to_string(), 30),
+                ("numpy".to_string(), 30),
+                (NO_PYTHON_STACK.to_string(), 7),
+            ]
+        );
+    }
+
+    #[test]
+    fn coarse_stack_merges_adjacent_frames() {
+        let stack: Vec<String> = ["mycode", "mycode", "pandas", "numpy", "pandas"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(coarse_stack(&stack), "mycode;pandas;numpy;pandas");
+        assert_eq!(coarse_stack(&[]), NO_PYTHON_STACK);
+    }
+}
@@ -0,0 +1,170 @@
+// Output paths as users write them. Paths may come from the command line, a
+// config file, or an environment variable, so they don't necessarily go
+// through a shell: "~/fil-results/$JOB_ID" needs expanding here. And reports
+// usually go into a timestamped subdirectory of a directory that may not
+// exist yet, so all the missing parents get created, with errors that say
+// which one was the problem.
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// Expand a leading `~` or `~/` to the home directory, and `$NAME` or
+/// `${NAME}` to environment variables. `$$` is a literal `$`. Unset
+/// variables are an error rather than silently becoming empty, since that
+/// would put reports somewhere unexpected.
+pub fn expand_path(path: &str) -> Result<PathBuf, String> {
+    expand_path_with(path, |name| std::env::var(name).ok())
+}
+
+fn expand_path_with(
+    path: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<PathBuf, String> {
+    let mut result = String::with_capacity(path.len());
+    let mut rest = path;
+    if rest == "~" || rest.starts_with("~/") {
+        let home = lookup("HOME")
+            .ok_or_else(|| format!("can't expand ~ in {:?}: HOME isn't set", path))?;
+        result.push_str(home.trim_end_matches('/'));
+        rest = &rest[1..];
+        if rest.is_empty() && result.is_empty() {
+            result.push('/');
+        }
+    }
+    let mut chars = rest.char_indices();
+    while let Some((index, c)) = chars.next() {
+        if c != '$' {
+            result.push(c);
+            continue;
+        }
+        let after = &rest[index + 1..];
+        let (name, length) = if after.starts_with('$') {
+            result.push('$');
+            chars.next();
+            continue;
+        } else if let Some(braced) = after.strip_prefix('{') {
+            let end = braced
+                .find('}')
+                .ok_or_else(|| format!("unclosed ${{ in {:?}", path))?;
+            (&braced[..end], end + 2)
+        } else {
+            let end = after
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(after.len());
+            (&after[..end], end)
+        };
+        if name.is_empty() {
+            // A lone "$", e.g. at the end of a path:
+            result.push('$');
+            continue;
+        }
+        let value = lookup(name).ok_or_else(|| {
+            format!(
+                "can't expand {:?}: environment variable {} isn't set",
+                path, name
+            )
+        })?;
+        result.push_str(&value);
+        for _ in 0..length {
+            chars.next();
+        }
+    }
+    Ok(PathBuf::from(result))
+}
+
+/// Create a directory and any missing parents. Unlike
+/// `fs::create_dir_all()`, errors say which directory couldn't be created
+/// and why, e.g. that a parent is actually a file.
+pub fn create_directory(directory: &Path) -> std::io::Result<()> {
+    if directory.is_dir() {
+        return Ok(());
+    }
+    if directory.exists() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{:?} exists but isn't a directory", directory),
+        ));
+    }
+    // Find the closest ancestor that exists, which is where creation will
+    // either start or go wrong:
+    let missing: Vec<&Path> = directory
+        .ancestors()
+        .take_while(|ancestor| !ancestor.as_os_str().is_empty() && !ancestor.exists())
+        .collect();
+    if let Some(parent) = missing.last().and_then(|first| first.parent()) {
+        if !parent.as_os_str().is_empty() && !parent.is_dir() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "can't create {:?}: {:?} isn't a directory",
+                    directory, parent
+                ),
+            ));
+        }
+    }
+    for ancestor in missing.iter().rev() {
+        match std::fs::create_dir(ancestor) {
+            Ok(()) => {}
+            // Someone else, e.g. another process, got there first:
+            Err(_) if ancestor.is_dir() => {}
+            Err(e) => {
+                return Err(Error::new(
+                    e.kind(),
+                    format!("can't create {:?}: {}", ancestor, e),
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{create_directory, expand_path_with};
+    use std::path::PathBuf;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "HOME" => Some("/home/me/".to_string()),
+            "JOB" => Some("job-7".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn expansion() {
+        let expand = |path| expand_path_with(path, lookup);
+        assert_eq!(expand("~"), Ok(PathBuf::from("/home/me")));
+        assert_eq!(
+            expand("~/results/$JOB/${JOB}x"),
+            Ok(PathBuf::from("/home/me/results/job-7/job-7x"))
+        );
+        // Only a leading ~ is special:
+        assert_eq!(expand("a/~/b"), Ok(PathBuf::from("a/~/b")));
+        assert_eq!(expand("~other/b"), Ok(PathBuf::from("~other/b")));
+        assert_eq!(expand("cost$$/$"), Ok(PathBuf::from("cost$/$")));
+        assert!(expand("$MISSING/x")
+            .unwrap_err()
+            .contains("MISSING isn't set"));
+        assert!(expand("${JOB").unwrap_err().contains("unclosed"));
+    }
+
+    #[test]
+    fn nested_creation() {
+        let root = std::env::temp_dir().join(format!("fil-paths-{}", std::process::id()));
+        let nested = root.join("a").join("b").join("c");
+        create_directory(&nested).unwrap();
+        assert!(nested.is_dir());
+        // Already there is fine:
+        create_directory(&nested).unwrap();
+
+        let file = root.join("file");
+        std::fs::write(&file, b"").unwrap();
+        let error = create_directory(&file).unwrap_err().to_string();
+        assert!(error.contains("isn't a directory"), "{}", error);
+        let error = create_directory(&file.join("x").join("y"))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("file\" isn't a directory"), "{}", error);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,82 @@
+// Things to do once a report has been written, e.g. uploading it to object
+// storage or telling a dashboard there's a new report. Embedders can register
+// callbacks, and anyone can set FIL_POST_DUMP_COMMAND to a shell command,
+// which gets the report's directory as $1.
+use std::path::Path;
+use std::process::Command;
+
+/// Called with the directory the report was written to.
+pub type PostDumpCallback = Box<dyn FnMut(&Path) + Send + Sync>;
+
+#[derive(Default)]
+pub struct PostDumpHooks {
+    command: Option<String>,
+    callbacks: Vec<PostDumpCallback>,
+}
+
+impl PostDumpHooks {
+    /// Set the command from FIL_POST_DUMP_COMMAND, keeping any callbacks.
+    pub fn command_from_env(&mut self) {
+        self.command = std::env::var("FIL_POST_DUMP_COMMAND")
+            .ok()
+            .filter(|command| !command.trim().is_empty());
+    }
+
+    /// Add a callback, run after the command, in registration order.
+    pub fn register(&mut self, callback: PostDumpCallback) {
+        self.callbacks.push(callback);
+    }
+
+    /// Run the command and the callbacks for a report written to the given
+    /// directory. The command is waited for, so a report written at exit is
+    /// handled before the process is gone; commands that take a while can
+    /// background themselves.
+    pub fn run(&mut self, directory: &Path) {
+        if let Some(command) = &self.command {
+            let status = Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .arg("fil-post-dump")
+                .arg(directory)
+                // Don't profile the command:
+                .env_remove("LD_PRELOAD")
+                .env_remove("DYLD_INSERT_LIBRARIES")
+                .status();
+            match status {
+                Ok(status) if status.success() => {}
+                Ok(status) => eprintln!("=fil-profile= Post-dump command failed: {}", status),
+                Err(e) => eprintln!("=fil-profile= Couldn't run post-dump command: {}", e),
+            }
+        }
+        for callback in self.callbacks.iter_mut() {
+            callback(directory);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PostDumpHooks;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn command_and_callbacks_get_the_directory() {
+        let directory = std::env::temp_dir().join(format!("fil-postdump-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let mut hooks = PostDumpHooks {
+            command: Some("echo done > \"$1/uploaded.txt\"".to_string()),
+            ..PostDumpHooks::default()
+        };
+        let seen = Arc::new(Mutex::new(vec![]));
+        let callback_seen = seen.clone();
+        hooks.register(Box::new(move |path: &Path| {
+            // The command has already run:
+            assert!(path.join("uploaded.txt").exists());
+            callback_seen.lock().unwrap().push(path.to_path_buf());
+        }));
+        hooks.run(&directory);
+        assert_eq!(*seen.lock().unwrap(), vec![PathBuf::from(&directory)]);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
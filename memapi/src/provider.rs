@@ -0,0 +1,159 @@
+// Where callstacks come from. The CPython frontend pushes and pops frames from
+// its tracing hook into a thread-local callstack, but other runtimes (PyPy,
+// Julia, Node, a C++ application with its own scope markers) may only be able
+// to walk their stack when asked. Both feed the same tracker and reports.
+use std::cell::RefCell;
+use std::thread::LocalKey;
+
+use crate::memorytracking::{
+    AllocationTracker, CallSiteId, Callstack, CallstackId, LineNumber, VecFunctionLocations,
+};
+
+/// Gets the current thread's callstack for a new allocation or free.
+pub trait CallstackProvider {
+    /// The current thread's CallstackId, registering the callstack with the
+    /// tracker if it's new. `line_number` is the current line of the
+    /// innermost frame, or 0 if unknown. Returns None if there's no
+    /// callstack available, e.g. during thread shutdown.
+    fn callstack_id(
+        &self,
+        tracker: &mut AllocationTracker<VecFunctionLocations>,
+        line_number: LineNumber,
+    ) -> Option<CallstackId>;
+}
+
+/// Callstacks maintained incrementally in a thread-local, by calling
+/// Callstack::start_call() and finish_call() as functions are entered and
+/// exited. This is how the CPython frontend works.
+pub struct PushedCallstacks(pub &'static LocalKey<RefCell<Callstack>>);
+
+impl CallstackProvider for PushedCallstacks {
+    fn callstack_id(
+        &self,
+        tracker: &mut AllocationTracker<VecFunctionLocations>,
+        line_number: LineNumber,
+    ) -> Option<CallstackId> {
+        // Cached callstack IDs are only valid for the generation they were
+        // created in; the caller has the tracker, so reset() can't happen
+        // until we're done.
+        let generation = tracker.generation();
+        self.0
+            .try_with(|callstack| {
+                callstack
+                    .borrow_mut()
+                    .id_for_new_allocation(line_number, generation, |callstack| {
+                        tracker.get_callstack_id(callstack)
+                    })
+            })
+            .ok()
+    }
+}
+
+/// A frame as reported by a runtime walking its own stack.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WalkedFrame {
+    pub filename: String,
+    pub function: String,
+    pub line_number: LineNumber,
+}
+
+/// A runtime that can list the current thread's frames on demand.
+pub trait StackWalker {
+    /// Append the current thread's frames to `frames`, outermost first.
+    /// Returns false if the stack isn't available.
+    fn walk(&self, frames: &mut Vec<WalkedFrame>) -> bool;
+}
+
+/// Callstacks from walking the stack on every allocation. Slower than
+/// PushedCallstacks, but needs no hooks on function entry and exit.
+pub struct WalkedCallstacks<W: StackWalker>(pub W);
+
+impl<W: StackWalker> CallstackProvider for WalkedCallstacks<W> {
+    fn callstack_id(
+        &self,
+        tracker: &mut AllocationTracker<VecFunctionLocations>,
+        line_number: LineNumber,
+    ) -> Option<CallstackId> {
+        let mut frames = vec![];
+        if !self.0.walk(&mut frames) {
+            return None;
+        }
+        if line_number != 0 {
+            if let Some(innermost) = frames.last_mut() {
+                innermost.line_number = line_number;
+            }
+        }
+        let calls = frames
+            .into_iter()
+            .map(|frame| {
+                let function = tracker
+                    .functions
+                    .add_function(frame.filename, frame.function);
+                CallSiteId::new(function, frame.line_number)
+            })
+            .collect();
+        Some(tracker.get_callstack_id(&Callstack::from_vec(calls)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::{CallstackProvider, PushedCallstacks, StackWalker, WalkedCallstacks, WalkedFrame};
+    use crate::memorytracking::{
+        AllocationTracker, CallSiteId, Callstack, VecFunctionLocations, PARENT_PROCESS,
+    };
+
+    thread_local!(static CALLSTACK: RefCell<Callstack> = RefCell::new(Callstack::new()));
+
+    struct FixedStack(Vec<(&'static str, &'static str, u16)>);
+
+    impl StackWalker for FixedStack {
+        fn walk(&self, frames: &mut Vec<WalkedFrame>) -> bool {
+            frames.extend(
+                self.0
+                    .iter()
+                    .map(|(filename, function, line_number)| WalkedFrame {
+                        filename: filename.to_string(),
+                        function: function.to_string(),
+                        line_number: (*line_number).into(),
+                    }),
+            );
+            true
+        }
+    }
+
+    #[test]
+    fn providers_agree() {
+        let mut tracker = AllocationTracker::new("/tmp".to_string(), VecFunctionLocations::new());
+        let main = tracker
+            .functions
+            .add_function("main.js".to_string(), "main".to_string());
+        let load = tracker
+            .functions
+            .add_function("load.js".to_string(), "load".to_string());
+        CALLSTACK.with(|callstack| {
+            let mut callstack = callstack.borrow_mut();
+            callstack.start_call(0, CallSiteId::new(main, 3));
+            callstack.start_call(0, CallSiteId::new(load, 7));
+        });
+        let pushed = PushedCallstacks(&CALLSTACK)
+            .callstack_id(&mut tracker, 8)
+            .unwrap();
+        let walked = WalkedCallstacks(FixedStack(vec![
+            ("main.js", "main", 3),
+            ("load.js", "load", 1),
+        ]))
+        .callstack_id(&mut tracker, 8)
+        .unwrap();
+        assert_eq!(pushed, walked);
+
+        tracker.add_allocation(PARENT_PROCESS, 1, 100, walked);
+        let data = tracker.snapshot();
+        assert_eq!(data.callstacks.len(), 1);
+        let frames = &data.callstacks[0].frames;
+        assert_eq!(frames[1].function, "load");
+        assert_eq!(frames[1].line_number, 8);
+    }
+}
@@ -1,10 +1,29 @@
 // Interactions with Python APIs.
 use once_cell::sync::Lazy;
 use pyo3::prelude::*;
-use pyo3::types::PyModule;
+use pyo3::types::{PyDict, PyModule};
+use std::collections::HashMap;
+
+use crate::memorytracking::LineNumber;
+
+// Whether the Python interpreter can be used. It can't once it's been
+// finalized, e.g. in C atexit() handlers.
+pub fn is_python_running() -> bool {
+    unsafe { pyo3::ffi::Py_IsInitialized() != 0 }
+}
+
+// Run a function with the GIL held, if Python can be used. Code that needs
+// both the GIL and the tracker lock should get the GIL first, since the
+// Python thread holds the GIL while it allocates.
+pub fn with_gil_if_running<R>(f: impl FnOnce() -> R) -> Option<R> {
+    if !is_python_running() {
+        return None;
+    }
+    Some(Python::with_gil(|_py| f()))
+}
 
 // Get the source code line from a given filename.
-pub fn get_source_line(filename: &str, line_number: u16) -> PyResult<String> {
+pub fn get_source_line(filename: &str, line_number: LineNumber) -> PyResult<String> {
     Python::with_gil(|py| {
         let linecache = PyModule::import(py, "linecache")?;
         let result: String = linecache
@@ -25,3 +44,23 @@ pub fn get_runpy_path() -> &'static str {
     });
     PATH.as_str()
 }
+
+// Map source filenames to dotted module names, for all loaded modules.
+pub fn get_module_names() -> PyResult<HashMap<String, String>> {
+    Python::with_gil(|py| {
+        let sys = PyModule::import(py, "sys")?;
+        // Copy, since other threads might be importing things:
+        let modules: &PyDict = sys.getattr("modules")?.call_method0("copy")?.downcast()?;
+        let mut result = HashMap::new();
+        for (name, module) in modules.iter() {
+            if let Ok(filename) = module.getattr("__file__") {
+                if let (Ok(filename), Ok(name)) =
+                    (filename.extract::<String>(), name.extract::<String>())
+                {
+                    result.insert(filename, name);
+                }
+            }
+        }
+        Ok(result)
+    })
+}
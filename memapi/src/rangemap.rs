@@ -1,6 +1,9 @@
+use crate::hashing::HashState;
 use std::cmp::{max, min};
-#[cfg(test)]
 use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::util::hashmap_bytes;
 
 /// Open-ended range in memory, [A...B).
 #[derive(Clone, Debug, PartialEq)]
@@ -41,19 +44,31 @@ impl Range {
 /// The intended use case is tracking anonymous mmap(), where munmap() can
 /// deallocate chunks of an allocation, or even multiple allocations.
 #[derive(Clone, Debug, PartialEq, Default)]
-pub struct RangeMap<V: Clone> {
+pub struct RangeMap<V: Clone + Eq + Hash> {
     ranges: Vec<(Range, V)>,
+    // Total size for each value, kept up to date as ranges are added and
+    // removed, so totals don't require walking all the ranges:
+    totals: HashMap<V, usize, HashState>,
 }
 
-impl<V: Clone> RangeMap<V> {
+impl<V: Clone + Eq + Hash> RangeMap<V> {
     pub fn new() -> Self {
-        RangeMap { ranges: vec![] }
+        RangeMap {
+            ranges: vec![],
+            totals: HashMap::default(),
+        }
+    }
+
+    /// Roughly how much memory this uses.
+    pub fn memory_usage(&self) -> usize {
+        self.ranges.capacity() * std::mem::size_of::<(Range, V)>() + hashmap_bytes(&self.totals)
     }
 
     pub fn add(&mut self, start: usize, length: usize, value: V) {
         if length == 0 {
             return;
         }
+        *self.totals.entry(value.clone()).or_insert(0) += length;
         self.ranges.push((Range::new(start, length), value));
     }
 
@@ -114,16 +129,36 @@ impl<V: Clone> RangeMap<V> {
             }
         }
         self.ranges = new_ranges;
+        for (value, size) in removed.iter() {
+            if let Some(total) = self.totals.get_mut(value) {
+                *total -= size;
+                if *total == 0 {
+                    self.totals.remove(value);
+                }
+            }
+        }
         removed
     }
 
     pub fn size(&self) -> usize {
-        self.ranges.iter().map(|(r, _)| r.size()).sum()
+        self.totals.values().sum()
     }
 
-    /// Return iterator of (length, value).
-    pub fn into_iter(self) -> impl Iterator<Item = (usize, V)> {
-        self.ranges.into_iter().map(|(r, v)| (r.size(), v))
+    /// Total size of the ranges for each value, e.g. mmap()ed bytes per
+    /// callstack.
+    pub fn sizes_by_value(&self) -> &HashMap<V, usize, HashState> {
+        &self.totals
+    }
+
+    /// Return iterator of (start, length, value).
+    pub fn iter(&self) -> impl Iterator<Item = (usize, usize, &V)> {
+        self.ranges.iter().map(|(r, v)| (r.start, r.size(), v))
+    }
+
+    /// The range containing the address, as (start, length, value).
+    pub fn find(&self, address: usize) -> Option<(usize, usize, &V)> {
+        self.iter()
+            .find(|(start, length, _)| (*start..start + length).contains(&address))
     }
 
     #[cfg(test)]
@@ -177,6 +212,14 @@ mod tests {
             self.items.len()
         }
 
+        fn sizes_by_value(&self) -> HashMap<V, usize> {
+            let mut result = HashMap::new();
+            for value in self.items.values() {
+                *result.entry(*value).or_insert(0) += 1;
+            }
+            result
+        }
+
         fn as_hashmap(&self) -> HashMap<usize, (usize, &V)> {
             let mut result = HashMap::new();
             let mut last_entry: Option<&mut (usize, &V)> = None;
@@ -244,6 +287,10 @@ mod tests {
                 prop_assert_eq!(removed1_map, removed2_map);
                 prop_assert_eq!(real_rangemap.size(), stupid_rangemap.size());
                 prop_assert_eq!(real_rangemap.as_hashmap(), stupid_rangemap.as_hashmap());
+                prop_assert_eq!(
+                    real_rangemap.sizes_by_value().iter().map(|(k, v)| (*k, *v)).collect::<HashMap<_, _>>(),
+                    stupid_rangemap.sizes_by_value()
+                );
             }
         }
     }
@@ -0,0 +1,208 @@
+// Redaction of reports, so they can be shared without revealing the structure
+// of proprietary code. Function, file and module names are replaced with
+// opaque identifiers derived from a hash, so the same name gets the same
+// identifier across runs; a mapping back to the real names can be kept
+// locally.
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::metadata::ProcessMetadata;
+use crate::snapshot::{Frame, ProfileData};
+use crate::stableid::Fnv;
+use crate::threads::THREAD_FILENAME;
+
+/// A stable 64-bit hash, the same across runs and Rust versions.
+fn stable_hash(value: &str) -> u64 {
+    let mut hash = Fnv::new();
+    hash.write(value.as_bytes());
+    hash.0
+}
+
+/// Replaces names with opaque identifiers, remembering the originals.
+#[derive(Default)]
+pub struct Redactor {
+    // opaque identifier -> original:
+    mapping: BTreeMap<String, String>,
+}
+
+impl Redactor {
+    fn opaque(&mut self, kind: &str, original: &str) -> String {
+        let result = format!("{}-{:012x}", kind, stable_hash(original) >> 16);
+        self.mapping
+            .entry(result.clone())
+            .or_insert_with(|| original.to_string());
+        result
+    }
+
+    /// Dotted module names are redacted component by component, so rolling
+    /// up by top-level package still works.
+    fn module(&mut self, module: &str) -> String {
+        module
+            .split('.')
+            .map(|component| self.opaque("m", component))
+            .collect::<Vec<_>>()
+            .join(".")
+    }
+
+    fn frame(&mut self, frame: &mut Frame) {
+        // Thread IDs aren't revealing, and per-thread reports need them:
+        if frame.filename == THREAD_FILENAME {
+            return;
+        }
+        frame.function = self.opaque("f", &frame.function);
+        frame.filename = self.opaque("file", &frame.filename);
+        frame.module = frame.module.as_ref().map(|m| self.module(m));
+    }
+
+    /// Redact names in a snapshot. Line numbers and sizes are kept.
+    pub fn redact(&mut self, mut data: ProfileData) -> ProfileData {
+        for usage in data.callstacks.iter_mut() {
+            for frame in usage.frames.iter_mut() {
+                self.frame(frame);
+            }
+        }
+        for pair in data.lifetime_pairs.iter_mut() {
+            for frame in pair.allocated_by.iter_mut().chain(pair.freed_by.iter_mut()) {
+                self.frame(frame);
+            }
+        }
+        for ages in data.allocation_ages.iter_mut() {
+            for frame in ages.frames.iter_mut() {
+                self.frame(frame);
+            }
+        }
+        for calls in data.function_calls.iter_mut() {
+            calls.function = self.opaque("f", &calls.function);
+            calls.filename = self.opaque("file", &calls.filename);
+        }
+        // Marker labels are free text, which may well describe the code:
+        for marker in data.timeline.markers.iter_mut() {
+            marker.label = self.opaque("marker", &marker.label);
+        }
+        data.metadata = self.redact_metadata(data.metadata);
+        data
+    }
+
+    /// The command line, paths in environment variables and the hostname can
+    /// all be revealing.
+    fn redact_metadata(&mut self, mut metadata: ProcessMetadata) -> ProcessMetadata {
+        metadata.argv = metadata
+            .argv
+            .iter()
+            .map(|arg| self.opaque("arg", arg))
+            .collect();
+        metadata.environment = metadata
+            .environment
+            .iter()
+            .map(|(key, value)| (key.clone(), self.opaque("env", value)))
+            .collect();
+        metadata.hostname = self.opaque("host", &metadata.hostname);
+        metadata.child_output_paths = metadata
+            .child_output_paths
+            .iter()
+            .map(|path| self.opaque("path", path))
+            .collect();
+        metadata
+    }
+
+    /// Write the mapping from opaque identifiers back to real names, as JSON.
+    /// This should be kept locally, not shared with the report.
+    pub fn write_mapping(&self, path: &Path) -> std::io::Result<()> {
+        let data = serde_json::to_vec_pretty(&self.mapping)?;
+        std::fs::write(path, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{stable_hash, Redactor};
+    use crate::metadata::ProcessMetadata;
+    use crate::metric::MEMORY;
+    use crate::packages::package_for_module;
+    use crate::snapshot::{CallstackUsage, Frame, FunctionCalls, ProfileData};
+    use crate::timeline::{TimelineData, TimelineMarker};
+
+    fn data() -> ProfileData {
+        ProfileData {
+            resource: MEMORY.to_string(),
+            callstacks: vec![CallstackUsage {
+                frames: vec![Frame {
+                    function: "SecretModel.train".to_string(),
+                    filename: "/src/acme/secret.py".to_string(),
+                    line_number: 12,
+                    module: Some("acme.secret".to_string()),
+                }],
+                peak_bytes: 100,
+                current_bytes: 0,
+                peak_allocations: 1,
+                allocations_at_peak: 1,
+                sizes: Default::default(),
+                peak_allocation_rate: 0,
+                heap_peak_bytes: 100,
+            }],
+            peak_bytes: 100,
+            current_bytes: 0,
+            peak_allocations: 1,
+            missing_bytes: 0,
+            failed_deallocations: 0,
+            accounting_errors: 0,
+            metadata: ProcessMetadata::collect(),
+            numa: None,
+            function_calls: vec![FunctionCalls {
+                function: "SecretModel.train".to_string(),
+                filename: "/src/acme/secret.py".to_string(),
+                calls: 3,
+            }],
+            lifetime_pairs: vec![],
+            interpreters: vec![],
+            profiler_memory: Default::default(),
+            address_space: Default::default(),
+            locked_memory: Default::default(),
+            allocation_ages: vec![],
+            window_peak: None,
+            timeline: TimelineData {
+                samples: vec![],
+                markers: vec![TimelineMarker {
+                    elapsed_ms: 10,
+                    label: "SecretModel loaded".to_string(),
+                }],
+            },
+            heap_peaked_separately: false,
+        }
+    }
+
+    #[test]
+    fn hash_is_stable() {
+        // If this changes, identifiers in previously shared reports no longer
+        // match new ones.
+        assert_eq!(stable_hash(""), 0xcbf29ce484222325);
+        assert_eq!(stable_hash("a"), 0xaf63dc4c8601ec8c);
+    }
+
+    #[test]
+    fn names_are_replaced_consistently() {
+        let mut redactor = Redactor::default();
+        let redacted = redactor.redact(data());
+        let frame = &redacted.callstacks[0].frames[0];
+        assert!(frame.function.starts_with("f-"));
+        assert!(frame.filename.starts_with("file-"));
+        assert_eq!(frame.line_number, 12);
+        assert_eq!(redacted.callstacks[0].peak_bytes, 100);
+        let debug = format!("{:?}", redacted);
+        assert!(!debug.contains("Secret"));
+        assert!(!debug.contains("acme"));
+        assert_eq!(redactor.mapping[&frame.function], "SecretModel.train");
+
+        // Package rollups still work, and the same package gets the same
+        // identifier:
+        let module = frame.module.clone().unwrap();
+        assert_eq!(
+            package_for_module(&module),
+            Redactor::default().module("acme.other")[..14]
+        );
+
+        // Same names, same identifiers:
+        let again = Redactor::default().redact(data());
+        assert_eq!(&again.callstacks[0].frames[0], frame);
+    }
+}
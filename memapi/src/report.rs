@@ -0,0 +1,730 @@
+// Options controlling what gets written out when dumping a report.
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+
+use crate::downsample::cap_lines;
+use crate::errors::record_error;
+use crate::formats::OutputFormats;
+use crate::python::get_module_names;
+use crate::reporting::{to_lines, write_artifacts};
+use crate::sink::{DirectorySink, OutputSink};
+use crate::snapshot::ProfileData;
+use crate::units::Units;
+
+/// Return whether an environment variable is set to "1".
+fn env_flag(name: &str) -> bool {
+    std::env::var(name).map(|v| v == "1").unwrap_or(false)
+}
+
+/// How source files are shown in reports.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FrameDisplay {
+    /// The filename as Python reported it, typically an absolute path.
+    #[default]
+    AbsolutePath,
+    /// Paths relative to the current working directory, where possible.
+    RelativePath,
+    /// Dotted module names, e.g. "pandas.core.frame", where known.
+    Module,
+}
+
+impl FrameDisplay {
+    /// Parse "absolute", "relative" or "module".
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "absolute" => Some(FrameDisplay::AbsolutePath),
+            "relative" => Some(FrameDisplay::RelativePath),
+            "module" => Some(FrameDisplay::Module),
+            _ => None,
+        }
+    }
+}
+
+/// What "auto" stands for in ReportOptions::strip_prefixes: everything up to
+/// a site-packages or dist-packages directory, i.e. the virtualenv or Python
+/// installation; the standard library's directory; and Bazel's runfiles and
+/// sandbox directories.
+pub const AUTO_STRIP_PREFIXES: [&str; 4] = [
+    r".*/(?:site|dist)-packages/",
+    r".*/lib/(?:python|pypy)\d+(?:\.\d+)?/",
+    r".*\.runfiles/[^/]+/",
+    r".*/execroot/[^/]+/",
+];
+
+/// Turns filenames into what gets displayed, per a FrameDisplay. Any
+/// expensive lookups happen once, on creation, so create one per report.
+#[derive(Default)]
+pub struct FilenameDisplay {
+    format: FrameDisplay,
+    // For RelativePath, the current directory with a trailing slash:
+    current_dir: Option<String>,
+    // For Module, filename -> module for modules that didn't register one:
+    modules: HashMap<String, String>,
+    // Machine-specific prefixes to remove from paths:
+    strip_prefixes: Option<Regex>,
+}
+
+impl FilenameDisplay {
+    pub fn new(format: FrameDisplay) -> Self {
+        let mut result = Self {
+            format,
+            ..Self::default()
+        };
+        match format {
+            FrameDisplay::AbsolutePath => {}
+            FrameDisplay::RelativePath => {
+                result.current_dir = std::env::current_dir()
+                    .ok()
+                    .map(|d| format!("{}/", d.to_string_lossy().trim_end_matches('/')));
+            }
+            FrameDisplay::Module => {
+                result.modules = get_module_names().unwrap_or_default();
+            }
+        }
+        result
+    }
+
+    /// Also remove the first of these regexes that matches at the start of
+    /// a displayed path; "auto" stands for AUTO_STRIP_PREFIXES.
+    pub fn with_stripped_prefixes(mut self, patterns: &[String]) -> Result<Self, String> {
+        if patterns.is_empty() {
+            return Ok(self);
+        }
+        let alternatives = patterns
+            .iter()
+            .flat_map(|pattern| {
+                if pattern == "auto" {
+                    AUTO_STRIP_PREFIXES.to_vec()
+                } else {
+                    vec![pattern.as_str()]
+                }
+            })
+            .map(|pattern| format!("(?:{})", pattern))
+            .collect::<Vec<_>>()
+            .join("|");
+        self.strip_prefixes = Some(
+            Regex::new(&format!("^(?:{})", alternatives))
+                .map_err(|e| format!("invalid regex: {}", e))?,
+        );
+        Ok(self)
+    }
+
+    /// Get what to display for a filename, given its module if known.
+    pub fn display<'a>(&'a self, filename: &'a str, module: Option<&'a str>) -> &'a str {
+        match self.format {
+            FrameDisplay::AbsolutePath => self.strip(filename),
+            FrameDisplay::RelativePath => self
+                .current_dir
+                .as_ref()
+                .and_then(|dir| filename.strip_prefix(dir.as_str()))
+                .unwrap_or_else(|| self.strip(filename)),
+            FrameDisplay::Module => module
+                .or_else(|| self.modules.get(filename).map(|m| m.as_str()))
+                .unwrap_or_else(|| self.strip(filename)),
+        }
+    }
+
+    /// Remove a machine-specific prefix from a path, unless that would leave
+    /// nothing.
+    fn strip<'a>(&self, path: &'a str) -> &'a str {
+        match self
+            .strip_prefixes
+            .as_ref()
+            .and_then(|regex| regex.find(path))
+        {
+            Some(prefix) if prefix.end() < path.len() => &path[prefix.end()..],
+            _ => path,
+        }
+    }
+}
+
+/// How small frames can get before they're left out of flamegraph SVGs, to
+/// keep the SVGs of very wide profiles small. The .prof files still have
+/// everything, unless they're capped with `max_lines`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FramePruning {
+    /// As a percentage of the flamegraph's total, e.g. 0.1; the default is
+    /// inferno's, 0.01.
+    pub min_percent: Option<f64>,
+    /// Frames with fewer bytes than this are left out too. Doesn't apply to
+    /// flamegraphs of allocation counts.
+    pub min_bytes: Option<usize>,
+    /// The most callstacks each flamegraph and .prof file can have; the
+    /// smallest are merged into their callers until they fit, see
+    /// downsample.rs.
+    pub max_lines: Option<usize>,
+}
+
+impl FramePruning {
+    /// The minimum width to give inferno, as a percentage, for a flamegraph
+    /// with the given total and units.
+    pub fn min_width(&self, total: usize, count_name: &str) -> Option<f64> {
+        let from_bytes = match self.min_bytes {
+            Some(min_bytes) if count_name == "bytes" && total > 0 => {
+                Some(min_bytes as f64 * 100.0 / total as f64)
+            }
+            _ => None,
+        };
+        match (self.min_percent, from_bytes) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+/// What to do when a report's directory can't be created, e.g. because the
+/// disk is full or read-only.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutputFallback {
+    /// Write the report to a directory under the system's temporary
+    /// directory instead.
+    pub temp_dir: bool,
+    /// As a last resort, print the main flamegraph's data to stderr in
+    /// folded format, one callstack per line, so it isn't lost entirely.
+    pub stderr: bool,
+}
+
+impl Default for OutputFallback {
+    fn default() -> Self {
+        Self {
+            temp_dir: true,
+            stderr: true,
+        }
+    }
+}
+
+impl OutputFallback {
+    /// Parse "none", or a comma-separated list of "temp" and "stderr".
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut result = Self {
+            temp_dir: false,
+            stderr: false,
+        };
+        for fallback in value.split(',').map(|fallback| fallback.trim()) {
+            match fallback {
+                "none" | "" => {}
+                "temp" => result.temp_dir = true,
+                "stderr" => result.stderr = true,
+                _ => return Err(format!("unknown fallback {:?}", fallback)),
+            }
+        }
+        Ok(result)
+    }
+
+    /// From FIL_OUTPUT_FALLBACK, by default both fallbacks.
+    pub fn from_env() -> Self {
+        match std::env::var("FIL_OUTPUT_FALLBACK") {
+            Ok(value) => Self::parse(&value).unwrap_or_else(|e| {
+                eprintln!("=fil-profile= Ignoring FIL_OUTPUT_FALLBACK: {}", e);
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+}
+
+/// Knobs for report generation. These only affect how data is rendered, not
+/// what gets tracked, so they can be changed at any time before a dump.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReportOptions {
+    /// Also write flamegraphs where frames are source files rather than
+    /// functions, with consecutive frames in the same file merged.
+    pub group_by_file: bool,
+
+    /// How source files are shown.
+    pub frame_display: FrameDisplay,
+
+    /// Ignore line numbers, merging frames of the same function, for a
+    /// function-level rather than line-level view.
+    pub merge_lines: bool,
+
+    /// Replace function, file and module names with opaque identifiers, so
+    /// reports can be shared.
+    pub redact: bool,
+
+    /// If redacting, where to write the mapping back to the real names.
+    pub redaction_mapping_path: Option<String>,
+
+    /// Functions to collapse out of callstacks, e.g. decorator wrappers. `*`
+    /// matches anything.
+    pub collapse_frames: Vec<String>,
+
+    /// If set, also write flamegraphs of only the callstacks with a frame
+    /// matching this regex.
+    pub include_stacks: Option<String>,
+
+    /// If set, also write flamegraphs without the frames matching this
+    /// regex.
+    pub exclude_frames: Option<String>,
+
+    /// Attribute callstacks to threads, and write flamegraphs for each
+    /// thread. Unlike the other options, the frontend needs to know this
+    /// before tracking starts.
+    pub per_thread: bool,
+
+    /// Gzip the raw outputs, i.e. .prof and JSON files; see compression.rs.
+    pub compress: bool,
+
+    /// Leave tiny frames out of flamegraph SVGs.
+    pub frame_pruning: FramePruning,
+
+    /// Regexes for prefixes to remove from paths, e.g. virtualenv or build
+    /// sandbox directories, so callstacks from different machines match;
+    /// "auto" stands for AUTO_STRIP_PREFIXES.
+    pub strip_prefixes: Vec<String>,
+
+    /// If set, the formats to write instead of the usual ones; see
+    /// formats.rs.
+    pub formats: Option<OutputFormats>,
+
+    /// If set, the units for titles, summaries and flamegraph tooltips;
+    /// otherwise titles and summaries use MiB and tooltips use bytes. See
+    /// units.rs.
+    pub units: Option<Units>,
+
+    /// What to do if the report's directory can't be created.
+    pub output_fallback: OutputFallback,
+
+    /// Allocations per second above which the summary lists a callstack as
+    /// an allocation hotspot; if unset, DEFAULT_HOT_LOOP_RATE. See
+    /// hotloops.rs.
+    pub hot_loop_rate: Option<u64>,
+}
+
+impl ReportOptions {
+    /// Load options from FIL_* environment variables.
+    pub fn from_env() -> Self {
+        Self {
+            group_by_file: env_flag("FIL_GROUP_BY_FILE"),
+            frame_display: std::env::var("FIL_FRAME_DISPLAY")
+                .ok()
+                .and_then(|v| FrameDisplay::parse(&v))
+                .unwrap_or_default(),
+            merge_lines: env_flag("FIL_MERGE_LINES"),
+            redact: env_flag("FIL_REDACT"),
+            redaction_mapping_path: std::env::var("FIL_REDACT_MAPPING").ok(),
+            collapse_frames: std::env::var("FIL_COLLAPSE_FRAMES")
+                .map(|patterns| {
+                    patterns
+                        .split(',')
+                        .map(|pattern| pattern.trim().to_string())
+                        .filter(|pattern| !pattern.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            include_stacks: std::env::var("FIL_INCLUDE_STACKS").ok(),
+            exclude_frames: std::env::var("FIL_EXCLUDE_FRAMES").ok(),
+            per_thread: env_flag("FIL_PER_THREAD"),
+            compress: crate::compression::enabled_from_env(),
+            strip_prefixes: std::env::var("FIL_STRIP_PREFIXES")
+                .map(|patterns| {
+                    patterns
+                        .split(',')
+                        .map(|pattern| pattern.trim().to_string())
+                        .filter(|pattern| !pattern.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            formats: OutputFormats::from_env(),
+            units: Units::from_env(),
+            output_fallback: OutputFallback::from_env(),
+            hot_loop_rate: crate::hotloops::hot_loop_rate_from_env(),
+            frame_pruning: FramePruning {
+                min_percent: std::env::var("FIL_MIN_FRAME_PERCENT")
+                    .ok()
+                    .and_then(|v| v.parse().ok()),
+                min_bytes: std::env::var("FIL_MIN_FRAME_BYTES")
+                    .ok()
+                    .and_then(|v| v.parse().ok()),
+                max_lines: std::env::var("FIL_MAX_LINES")
+                    .ok()
+                    .and_then(|v| v.parse().ok()),
+            },
+        }
+    }
+}
+
+/// Which files make up a report.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Artifacts {
+    /// The main flamegraph, `{base}.svg`.
+    pub flamegraph: bool,
+    /// The same flamegraph upside down, `{base}-reversed.svg`.
+    pub reversed: bool,
+    /// The raw data, `{base}.prof`, for tests and re-rendering.
+    pub prof: bool,
+    /// The whole snapshot as JSON, `{base}.json`.
+    pub json: bool,
+    /// A plain-text summary with the top callstacks, `{base}-summary.txt`.
+    pub summary: bool,
+    /// The per-function and per-callsite tables as CSV.
+    pub csv: bool,
+    /// The flamegraph's data for pprof, `{base}.pb.gz`.
+    pub pprof: bool,
+    /// The flamegraph's data for speedscope, `{base}.speedscope.json`.
+    pub speedscope: bool,
+    /// Everything else: the by-file, filtered and per-thread variants of the
+    /// selected flamegraphs, allocation count and lifetime flamegraphs, the
+    /// tables, and the process metadata.
+    pub extras: bool,
+}
+
+impl Artifacts {
+    /// What Fil's reports have always included.
+    pub const STANDARD: Self = Self {
+        flamegraph: true,
+        reversed: true,
+        prof: true,
+        json: false,
+        summary: false,
+        csv: true,
+        pprof: false,
+        speedscope: false,
+        extras: true,
+    };
+}
+
+/// A report to write, built up by choosing exactly which artifacts to
+/// produce, e.g.
+/// `Report::new(dir).with_flamegraph().with_json().write(&data)`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Report {
+    directory: String,
+    pub(crate) options: ReportOptions,
+    pub(crate) artifacts: Artifacts,
+    pub(crate) peak: bool,
+    pub(crate) base_filename: String,
+    pub(crate) title: String,
+    pub(crate) to_be_post_processed: bool,
+}
+
+impl Report {
+    /// A peak memory report to be written to the given directory, with no
+    /// artifacts selected yet.
+    pub fn new(directory: &str) -> Self {
+        Self {
+            directory: directory.to_string(),
+            options: ReportOptions::default(),
+            artifacts: Artifacts::default(),
+            peak: true,
+            base_filename: "peak-memory".to_string(),
+            title: "Peak Tracked Memory Usage".to_string(),
+            to_be_post_processed: false,
+        }
+    }
+
+    pub fn directory(&self) -> &str {
+        &self.directory
+    }
+
+    pub fn with_flamegraph(mut self) -> Self {
+        self.artifacts.flamegraph = true;
+        self
+    }
+
+    pub fn with_reversed(mut self) -> Self {
+        self.artifacts.reversed = true;
+        self
+    }
+
+    pub fn with_prof(mut self) -> Self {
+        self.artifacts.prof = true;
+        self
+    }
+
+    pub fn with_json(mut self) -> Self {
+        self.artifacts.json = true;
+        self
+    }
+
+    pub fn with_summary(mut self) -> Self {
+        self.artifacts.summary = true;
+        self
+    }
+
+    pub fn with_csv(mut self) -> Self {
+        self.artifacts.csv = true;
+        self
+    }
+
+    pub fn with_pprof(mut self) -> Self {
+        self.artifacts.pprof = true;
+        self
+    }
+
+    pub fn with_speedscope(mut self) -> Self {
+        self.artifacts.speedscope = true;
+        self
+    }
+
+    pub fn with_extras(mut self) -> Self {
+        self.artifacts.extras = true;
+        self
+    }
+
+    /// Everything a standard Fil report includes, in addition to whatever
+    /// was already selected.
+    pub fn with_standard_artifacts(self) -> Self {
+        self.with_flamegraph()
+            .with_reversed()
+            .with_prof()
+            .with_csv()
+            .with_extras()
+    }
+
+    pub fn with_options(mut self, options: ReportOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Report current allocations rather than those at peak.
+    pub fn current_allocations(mut self) -> Self {
+        self.peak = false;
+        self
+    }
+
+    /// Filenames start with `base_filename`, e.g. "peak-memory", and
+    /// flamegraphs are titled `title`.
+    pub fn named(mut self, base_filename: &str, title: &str) -> Self {
+        self.base_filename = base_filename.to_string();
+        self.title = title.to_string();
+        self
+    }
+
+    /// The SVGs will be post-processed by the Python code that writes the
+    /// HTML report, which adds the source code lines.
+    pub fn for_html(mut self) -> Self {
+        self.to_be_post_processed = true;
+        self
+    }
+
+    /// Write the report to its directory, creating it if necessary, and
+    /// return the directory it was written to. If the directory can't be
+    /// created, the error is recorded (see errors.rs) and the options'
+    /// OutputFallback is tried; the result is only an error if the report
+    /// didn't end up in any directory.
+    pub fn write(&self, data: &ProfileData) -> std::io::Result<PathBuf> {
+        eprintln!("=fil-profile= Preparing to write to {}", self.directory);
+        let error = match DirectorySink::new(&self.directory) {
+            Ok(mut sink) => {
+                self.write_to_sink(data, &mut sink);
+                return Ok(sink.directory().to_path_buf());
+            }
+            Err(e) => e,
+        };
+        let message = format!("Couldn't create output directory: {}", error);
+        eprintln!("=fil-profile= {}", message);
+        record_error(&message);
+
+        let fallback = self.options.output_fallback;
+        if fallback.temp_dir {
+            let directory = temp_directory_for(&self.directory);
+            match DirectorySink::new(&directory) {
+                Ok(mut sink) => {
+                    eprintln!(
+                        "=fil-profile= Writing the report to {:?} instead",
+                        directory
+                    );
+                    self.write_to_sink(data, &mut sink);
+                    return Ok(directory);
+                }
+                Err(e) => eprintln!(
+                    "=fil-profile= Couldn't create fallback directory {:?} either: {}",
+                    directory, e
+                ),
+            }
+        }
+        if fallback.stderr {
+            let stderr = std::io::stderr();
+            if let Err(e) = self.write_folded(data, &mut stderr.lock()) {
+                eprintln!("=fil-profile= Couldn't print the report: {}", e);
+            }
+        }
+        Err(error)
+    }
+
+    /// Write the main flamegraph's data in folded format, between marker
+    /// lines so it can be cut out of the rest of the output.
+    fn write_folded(&self, data: &ProfileData, out: &mut dyn Write) -> std::io::Result<()> {
+        let display = FilenameDisplay::new(self.options.frame_display);
+        writeln!(
+            out,
+            "=fil-profile= Begin {} in folded format:",
+            self.base_filename
+        )?;
+        let mut lines = to_lines(data, self.peak, false, &display);
+        if let Some(max_lines) = self.options.frame_pruning.max_lines {
+            lines = cap_lines(lines, max_lines);
+        }
+        for line in lines {
+            writeln!(out, "{}", line)?;
+        }
+        writeln!(out, "=fil-profile= End {}", self.base_filename)
+    }
+
+    /// Write the report to an arbitrary sink, ignoring the directory.
+    pub fn write_to_sink(&self, data: &ProfileData, sink: &mut dyn OutputSink) {
+        write_artifacts(data, self, sink);
+    }
+}
+
+/// Where a report that couldn't be written to `directory` goes instead:
+/// a per-process directory under the system's temporary directory, keeping
+/// the original directory's name, which is typically a timestamp.
+fn temp_directory_for(directory: &str) -> PathBuf {
+    let name = Path::new(directory)
+        .file_name()
+        .map_or_else(|| "report".into(), |name| name.to_os_string());
+    std::env::temp_dir()
+        .join(format!("fil-result-{}", std::process::id()))
+        .join(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        FilenameDisplay, FrameDisplay, FramePruning, OutputFallback, Report, ReportOptions,
+    };
+    use crate::errors::take_last_error;
+    use crate::folded::parse_folded_str;
+
+    #[test]
+    fn absolute_paths_are_unchanged() {
+        let display = FilenameDisplay::new(FrameDisplay::AbsolutePath);
+        assert_eq!(display.display("/a/b.py", Some("b")), "/a/b.py");
+    }
+
+    #[test]
+    fn relative_paths_strip_current_directory() {
+        let display = FilenameDisplay::new(FrameDisplay::RelativePath);
+        let cwd = std::env::current_dir().unwrap();
+        let inside = cwd.join("pkg/mod.py");
+        assert_eq!(
+            display.display(inside.to_str().unwrap(), None),
+            "pkg/mod.py"
+        );
+        assert_eq!(display.display("/elsewhere/x.py", None), "/elsewhere/x.py");
+    }
+
+    #[test]
+    fn module_names_preferred_when_known() {
+        let mut display = FilenameDisplay::default();
+        display.format = FrameDisplay::Module;
+        display
+            .modules
+            .insert("/a/c.py".to_string(), "a.c".to_string());
+        assert_eq!(display.display("/a/b.py", Some("a.b")), "a.b");
+        assert_eq!(display.display("/a/c.py", None), "a.c");
+        assert_eq!(display.display("/a/d.py", None), "/a/d.py");
+    }
+
+    #[test]
+    fn prefixes_are_stripped() {
+        let display = FilenameDisplay::new(FrameDisplay::AbsolutePath)
+            .with_stripped_prefixes(&["auto".to_string(), "/srv/app/".to_string()])
+            .unwrap();
+        for (path, expected) in [
+            (
+                "/home/me/.venv/lib/python3.10/site-packages/numpy/core/fromnumeric.py",
+                "numpy/core/fromnumeric.py",
+            ),
+            ("/usr/lib/python3/dist-packages/yaml/loader.py", "yaml/loader.py"),
+            ("/usr/lib/python3.10/json/decoder.py", "json/decoder.py"),
+            (
+                "/tmp/bazel/sandbox/linux-sandbox/12/execroot/myrepo/app/main.py",
+                "app/main.py",
+            ),
+            (
+                "/x/execroot/myrepo/bazel-out/k8-fastbuild/bin/app/server.runfiles/myrepo/app/main.py",
+                "app/main.py",
+            ),
+            ("/srv/app/main.py", "main.py"),
+            ("/srv/app/", "/srv/app/"),
+            ("/elsewhere/main.py", "/elsewhere/main.py"),
+        ] {
+            assert_eq!(display.display(path, None), expected);
+        }
+        // Module names aren't paths:
+        let mut display = FilenameDisplay::default()
+            .with_stripped_prefixes(&["/srv/".to_string()])
+            .unwrap();
+        display.format = FrameDisplay::Module;
+        assert_eq!(display.display("/srv/a.py", Some("srv.a")), "srv.a");
+        assert_eq!(display.display("/srv/b.py", None), "b.py");
+        assert!(FilenameDisplay::default()
+            .with_stripped_prefixes(&["(".to_string()])
+            .is_err());
+    }
+
+    #[test]
+    fn pruning_uses_the_larger_minimum() {
+        assert_eq!(FramePruning::default().min_width(1000, "bytes"), None);
+        let pruning = FramePruning {
+            min_percent: Some(0.1),
+            min_bytes: Some(10),
+            ..FramePruning::default()
+        };
+        assert_eq!(pruning.min_width(1000, "bytes"), Some(1.0));
+        assert_eq!(pruning.min_width(1_000_000, "bytes"), Some(0.1));
+        // Byte floors don't apply to counts of allocations:
+        assert_eq!(pruning.min_width(1000, "allocations"), Some(0.1));
+        let pruning = FramePruning {
+            min_bytes: Some(10),
+            ..FramePruning::default()
+        };
+        assert_eq!(pruning.min_width(0, "bytes"), None);
+    }
+
+    #[test]
+    fn parse_frame_display() {
+        assert_eq!(FrameDisplay::parse("module"), Some(FrameDisplay::Module));
+        assert_eq!(
+            FrameDisplay::parse("relative"),
+            Some(FrameDisplay::RelativePath)
+        );
+        assert_eq!(
+            FrameDisplay::parse("absolute"),
+            Some(FrameDisplay::AbsolutePath)
+        );
+        assert_eq!(FrameDisplay::parse("nope"), None);
+    }
+
+    #[test]
+    fn unwritable_directories_fall_back() {
+        pyo3::prepare_freethreaded_python();
+        let data = parse_folded_str("a.py:1 (main);b.py:2 (f) 100\n").unwrap();
+        // A directory can't be created inside a regular file:
+        let file = std::env::temp_dir().join(format!("fil-not-a-dir-{}", std::process::id()));
+        std::fs::write(&file, b"").unwrap();
+        let directory = file.join("2026-01-01T00:00:00");
+        let report = |fallback: &str| {
+            Report::new(directory.to_str().unwrap())
+                .with_json()
+                .with_options(ReportOptions {
+                    output_fallback: OutputFallback::parse(fallback).unwrap(),
+                    ..ReportOptions::default()
+                })
+        };
+
+        let written = report("temp").write(&data).unwrap();
+        assert!(written.starts_with(std::env::temp_dir()));
+        assert!(written.ends_with("2026-01-01T00:00:00"));
+        assert!(written.join("peak-memory.json").exists());
+        assert!(take_last_error()
+            .unwrap()
+            .starts_with("Couldn't create output directory"));
+        std::fs::remove_dir_all(written.parent().unwrap()).unwrap();
+
+        assert!(report("none").write(&data).is_err());
+        let mut folded = vec![];
+        report("stderr").write_folded(&data, &mut folded).unwrap();
+        let folded = String::from_utf8(folded).unwrap();
+        assert!(folded.starts_with("=fil-profile= Begin peak-memory in folded format:\n"));
+        assert!(folded.contains(" 100\n"));
+        assert!(OutputFallback::parse("temp,floppy").is_err());
+        std::fs::remove_file(&file).unwrap();
+    }
+}
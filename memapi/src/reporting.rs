@@ -0,0 +1,2197 @@
+// Turning a ProfileData snapshot into report files. The tracker itself knows
+// nothing about flamegraphs or files; it just hands over a snapshot.
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use filrender::escape_frame_text;
+use itertools::Itertools;
+use serde_json::json;
+
+use crate::calltree::call_tree;
+use crate::compression::{gzip, CompressingSink};
+use crate::filters::{merge_lines, FrameCollapser, StackFilter};
+use crate::flamegraph::{filter_to_useful_callstacks, write_flamegraph, write_flamegraphs};
+use crate::formats::{to_pprof, to_speedscope, WeightedStack};
+use crate::hotloops::DEFAULT_HOT_LOOP_RATE;
+use crate::interpreters::INTERPRETER_FILENAME;
+use crate::labels::{label_table, LABEL_FILENAME};
+use crate::limit::LimitAction;
+use crate::memorytracking::{AllocationTracker, FunctionLocations, LineNumber};
+use crate::metric::{Metric, MEMORY};
+use crate::packages::{
+    coarse_stack, package_for_filename, package_for_module, sum_by_package, write_package_table,
+};
+use crate::paths::expand_path;
+use crate::python::{get_module_names, get_runpy_path, get_source_line, is_python_running};
+use crate::redact::Redactor;
+use crate::report::{Artifacts, FilenameDisplay, FramePruning, Report, ReportOptions};
+use crate::sink::OutputSink;
+use crate::sizes::sizes_per_callsite;
+use crate::snapshot::{ages_to_json, Frame, ProfileData};
+use crate::stackstats::StackStats;
+use crate::threads::{split_by_thread, THREAD_FILENAME};
+use crate::timeline::render_svg as render_timeline;
+use crate::units::Units;
+
+extern "C" {
+    fn _exit(exit_code: std::os::raw::c_int);
+}
+
+/// Frames added by to_lifetime_lines().
+pub const FREED_LATER_FRAME: &str = "[freed before the end]";
+pub const STILL_ALLOCATED_FRAME: &str = "[still allocated at the end]";
+
+/// How many functions to include in the bytes-per-call table.
+const MAX_BYTES_PER_CALL_ROWS: usize = 200;
+
+/// Only the allocating/freeing callstack pairs that freed the most memory are
+/// listed.
+const MAX_LIFETIME_PAIR_ROWS: usize = 200;
+
+/// How many callstacks the summary lists.
+const MAX_SUMMARY_CALLSTACKS: usize = 10;
+
+/// What gets shown for callstacks without any Python frames.
+pub(crate) const NO_PYTHON_STACK: &str = "[No Python stack]";
+
+/// The frames worth showing, skipping ones that are just implementation
+/// details.
+fn visible_frames(frames: &[Frame]) -> impl Iterator<Item = &Frame> {
+    // Synthetic frames naming labels, the thread or the interpreter come
+    // first:
+    let roots = frames
+        .iter()
+        .take_while(|frame| {
+            frame.filename == LABEL_FILENAME
+                || frame.filename == THREAD_FILENAME
+                || frame.filename == INTERPRETER_FILENAME
+        })
+        .count();
+    let (roots, frames) = frames.split_at(roots);
+    let mut prefix = 0;
+    if !cfg!(feature = "fil4prod") {
+        // Due to implementation details we have some runpy() frames at the
+        // start; remove them.
+        let runpy_path = get_runpy_path();
+        prefix = frames
+            .iter()
+            .take_while(|frame| frame.filename == runpy_path)
+            .count();
+        if prefix == frames.len() {
+            // All runpy, so presumably not an implementation detail after
+            // all:
+            prefix = 0;
+        }
+    }
+    roots.iter().chain(frames[prefix..].iter())
+}
+
+/// A callstack in flamegraph input format.
+pub fn frames_as_string(
+    frames: &[Frame],
+    to_be_post_processed: bool,
+    separator: &str,
+    display: &FilenameDisplay,
+) -> String {
+    if frames.is_empty() {
+        return NO_PYTHON_STACK.to_string();
+    }
+    visible_frames(frames)
+        .map(|frame| {
+            let displayed_filename =
+                escape_frame_text(display.display(&frame.filename, frame.module.as_deref()));
+            let function = escape_frame_text(&frame.function);
+            if frame.line_number == 0 {
+                // Line numbers were merged away, so there's no source code
+                // to show either:
+                format!(
+                    "{filename} ({function})",
+                    filename = displayed_filename,
+                    function = function,
+                )
+            } else if to_be_post_processed {
+                // Get Python code.
+                let code = get_source_line(&frame.filename, frame.line_number)
+                    .unwrap_or_else(|_| "".to_string());
+                // Leading whitespace is dropped by SVG, so we'd like to
+                // replace it with non-breaking space. However, inferno
+                // trims whitespace
+                // (https://github.com/jonhoo/inferno/blob/de3f7d94d4718bfee57655c1fddd4d2714bc78d0/src/flamegraph/merge.rs#L126)
+                // and that causes incorrect "unsorted lines" errors
+                // which I can't be bothered to fix right now, so for
+                // now do hack where we shove in some other character
+                // that can be fixed in post-processing.
+                let code = code.replace(" ", "\u{12e4}");
+                // Semicolons are used as separator in the flamegraph
+                // input format, so need to replace them with some other
+                // character. We use "full-width semicolon", and then
+                // replace it back in post-processing.
+                let code = code.replace(";", "\u{ff1b}");
+                // The \u{2800} is to ensure we don't have empty lines,
+                // and that whitespace doesn't get trimmed from start;
+                // we'll get rid of this in post-processing.
+                format!(
+                    "{filename}:{line} ({function});\u{2800}{code}",
+                    filename = displayed_filename,
+                    line = frame.line_number,
+                    function = function,
+                    code = &code.trim_end(),
+                )
+            } else {
+                format!(
+                    "{filename}:{line} ({function})",
+                    filename = displayed_filename,
+                    line = frame.line_number,
+                    function = function,
+                )
+            }
+        })
+        .join(separator)
+}
+
+/// Like frames_as_string(), but frames are source files; consecutive calls
+/// within the same file are merged into a single frame.
+pub fn frames_as_file_string(
+    frames: &[Frame],
+    separator: &str,
+    display: &FilenameDisplay,
+) -> String {
+    if frames.is_empty() {
+        return NO_PYTHON_STACK.to_string();
+    }
+    visible_frames(frames)
+        .map(|frame| escape_frame_text(display.display(&frame.filename, frame.module.as_deref())))
+        .dedup()
+        .join(separator)
+}
+
+/// The top-level package of each frame, outermost first.
+pub fn frames_packages(frames: &[Frame], modules: &HashMap<String, String>) -> Vec<String> {
+    visible_frames(frames)
+        .map(|frame| match &frame.module {
+            Some(module) => package_for_module(module),
+            None => package_for_filename(&frame.filename, modules),
+        })
+        .collect()
+}
+
+/// Bytes per callstack, indexed like data.callstacks.
+fn bytes_per_callstack(data: &ProfileData, peak: bool) -> Vec<usize> {
+    data.callstacks
+        .iter()
+        .map(|usage| {
+            if peak {
+                usage.peak_bytes
+            } else {
+                usage.current_bytes
+            }
+        })
+        .collect()
+}
+
+/// The callstacks worth showing and their bytes. We get a LOT of tiny
+/// allocations; to reduce overhead of creating flamegraph (which currently
+/// loads EVERYTHING into memory), just do the top 99% of allocations.
+fn useful_callstacks(data: &ProfileData, peak: bool) -> Vec<(usize, usize)> {
+    let sizes = bytes_per_callstack(data, peak);
+    let sum = sizes.iter().sum();
+    filter_to_useful_callstacks(sizes.iter().enumerate(), sum).collect()
+}
+
+/// Callstacks and their memory usage in flamegraph input format.
+pub fn to_lines(
+    data: &ProfileData,
+    peak: bool,
+    to_be_post_processed: bool,
+    display: &FilenameDisplay,
+) -> Vec<String> {
+    useful_callstacks(data, peak)
+        .into_iter()
+        .map(|(index, size)| {
+            format!(
+                "{} {}",
+                frames_as_string(
+                    &data.callstacks[index].frames,
+                    to_be_post_processed,
+                    ";",
+                    display
+                ),
+                size,
+            )
+        })
+        .collect()
+}
+
+/// Like to_lines(), but grouped by source file rather than function.
+/// Different callstacks can end up as the same list of files, so these
+/// are summed.
+pub fn to_file_lines(data: &ProfileData, peak: bool, display: &FilenameDisplay) -> Vec<String> {
+    let mut by_files: HashMap<String, usize> = HashMap::new();
+    for (index, size) in useful_callstacks(data, peak) {
+        let files = frames_as_file_string(&data.callstacks[index].frames, ";", display);
+        *by_files.entry(files).or_insert(0) += size;
+    }
+    by_files
+        .into_iter()
+        .map(|(files, size)| format!("{} {}", files, size))
+        .collect()
+}
+
+/// Callstacks of allocations that were live when the most allocations
+/// were live at once, weighted by number of allocations rather than bytes.
+pub fn to_peak_count_lines(data: &ProfileData, display: &FilenameDisplay) -> Vec<String> {
+    let counts: Vec<usize> = data
+        .callstacks
+        .iter()
+        .map(|usage| usage.peak_allocations)
+        .collect();
+    filter_to_useful_callstacks(counts.iter().enumerate(), data.peak_allocations)
+        .map(|(index, count)| {
+            format!(
+                "{} {}",
+                frames_as_string(&data.callstacks[index].frames, false, ";", display),
+                count
+            )
+        })
+        .collect()
+}
+
+/// The number of live allocations at the time of peak memory usage.
+pub fn to_count_at_peak_lines(data: &ProfileData, display: &FilenameDisplay) -> Vec<String> {
+    let counts: Vec<usize> = data
+        .callstacks
+        .iter()
+        .map(|usage| usage.allocations_at_peak)
+        .collect();
+    let total = counts.iter().sum();
+    filter_to_useful_callstacks(counts.iter().enumerate(), total)
+        .map(|(index, count)| {
+            format!(
+                "{} {}",
+                frames_as_string(&data.callstacks[index].frames, false, ";", display),
+                count
+            )
+        })
+        .collect()
+}
+
+/// Peak memory, with an extra frame at the end of each callstack saying
+/// whether that memory was freed by the time of the dump, or is still
+/// allocated. This helps tell apart temporary spikes from leaks.
+///
+/// We don't know which specific allocations made up the peak, so this is
+/// approximated per callstack: memory still allocated by a callstack is
+/// assumed to be part of its peak.
+pub fn to_lifetime_lines(data: &ProfileData, display: &FilenameDisplay) -> Vec<String> {
+    let mut lines = vec![];
+    for (index, peak_size) in useful_callstacks(data, true) {
+        let usage = &data.callstacks[index];
+        let still_allocated = std::cmp::min(peak_size, usage.current_bytes);
+        let freed = peak_size - still_allocated;
+        let stack = frames_as_string(&usage.frames, false, ";", display);
+        if freed > 0 {
+            lines.push(format!("{};{} {}", stack, FREED_LATER_FRAME, freed));
+        }
+        if still_allocated > 0 {
+            lines.push(format!(
+                "{};{} {}",
+                stack, STILL_ALLOCATED_FRAME, still_allocated
+            ));
+        }
+    }
+    lines
+}
+
+/// The top-level packages of each callstack's frames, with the memory
+/// used by that callstack. Unlike to_lines() this includes everything,
+/// since there's no per-callstack output to keep small.
+fn packages_per_callstack(data: &ProfileData, peak: bool) -> Vec<(Vec<String>, usize)> {
+    let modules = get_module_names().unwrap_or_default();
+    data.callstacks
+        .iter()
+        .zip(bytes_per_callstack(data, peak))
+        .filter(|(_, size)| *size > 0)
+        .map(|(usage, size)| (frames_packages(&usage.frames, &modules), size))
+        .collect()
+}
+
+/// Memory usage summed by the top-level package that did the allocation,
+/// largest first.
+pub fn combine_by_package(data: &ProfileData, peak: bool) -> Vec<(String, usize)> {
+    let per_callstack = packages_per_callstack(data, peak);
+    sum_by_package(
+        per_callstack
+            .iter()
+            .map(|(packages, size)| (&packages[..], *size)),
+    )
+}
+
+/// Memory allocated beneath a function, and how often it was called.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BytesPerCall {
+    pub function: String,
+    pub filename: String,
+    /// Zero if calls weren't counted, e.g. for functions already running
+    /// when tracking started.
+    pub calls: u64,
+    /// Bytes allocated by this function or anything it called, i.e.
+    /// inclusive bytes.
+    pub bytes: usize,
+    /// Bytes allocated directly by this function, where it was the innermost
+    /// frame.
+    pub self_bytes: usize,
+}
+
+impl BytesPerCall {
+    pub fn average(&self) -> Option<f64> {
+        if self.calls == 0 {
+            None
+        } else {
+            Some(self.bytes as f64 / self.calls as f64)
+        }
+    }
+}
+
+/// For every function that allocated memory (directly or via things it
+/// called), the bytes and number of calls; largest first. This helps tell
+/// apart "called once, allocated a lot" from "called a million times,
+/// allocated a little each time", and, like a CPU profiler's self and
+/// inclusive time, functions that allocate from those that merely call
+/// things that do.
+pub fn bytes_per_call(data: &ProfileData, peak: bool) -> Vec<BytesPerCall> {
+    let mut bytes: HashMap<(&str, &str), usize> = HashMap::new();
+    let mut self_bytes: HashMap<(&str, &str), usize> = HashMap::new();
+    for (usage, size) in data.callstacks.iter().zip(bytes_per_callstack(data, peak)) {
+        if size == 0 {
+            continue;
+        }
+        if let Some(leaf) = usage.frames.last() {
+            *self_bytes
+                .entry((leaf.filename.as_str(), leaf.function.as_str()))
+                .or_insert(0) += size;
+        }
+        // Recursive functions appear multiple times, but the memory should
+        // only count once:
+        for key in usage
+            .frames
+            .iter()
+            .map(|frame| (frame.filename.as_str(), frame.function.as_str()))
+            .unique()
+        {
+            *bytes.entry(key).or_insert(0) += size;
+        }
+    }
+    let calls: HashMap<(&str, &str), u64> = data
+        .function_calls
+        .iter()
+        .map(|calls| {
+            (
+                (calls.filename.as_str(), calls.function.as_str()),
+                calls.calls,
+            )
+        })
+        .collect();
+    bytes
+        .into_iter()
+        .map(|((filename, function), bytes)| BytesPerCall {
+            function: function.to_string(),
+            filename: filename.to_string(),
+            calls: calls.get(&(filename, function)).copied().unwrap_or(0),
+            bytes,
+            self_bytes: self_bytes.get(&(filename, function)).copied().unwrap_or(0),
+        })
+        .sorted_by(|a, b| {
+            b.bytes
+                .cmp(&a.bytes)
+                .then_with(|| a.filename.cmp(&b.filename))
+                .then_with(|| a.function.cmp(&b.function))
+        })
+        .collect()
+}
+
+/// Write a table of bytes allocated per call, for the functions that
+/// allocated the most.
+fn write_bytes_per_call_table(
+    rows: &[BytesPerCall],
+    sink: &mut dyn OutputSink,
+    base_filename: &str,
+    display: &FilenameDisplay,
+) {
+    let mut table = format!(
+        "{:>10}  {:>10}  {:>12}  {:>14}  Function\n",
+        "Incl MiB", "Self MiB", "Calls", "Bytes/call"
+    );
+    for row in rows.iter().take(MAX_BYTES_PER_CALL_ROWS) {
+        let (calls, average) = match row.average() {
+            Some(average) => (row.calls.to_string(), format!("{:.0}", average)),
+            None => ("-".to_string(), "-".to_string()),
+        };
+        table.push_str(&format!(
+            "{:>10.1}  {:>10.1}  {:>12}  {:>14}  {} ({})\n",
+            row.bytes as f64 / (1024.0 * 1024.0),
+            row.self_bytes as f64 / (1024.0 * 1024.0),
+            calls,
+            average,
+            row.function,
+            display.display(&row.filename, None),
+        ));
+    }
+    let name = format!("{}-bytes-per-call.txt", base_filename);
+    if let Err(e) = sink.write_file(&name, table.as_bytes()) {
+        eprintln!("=fil-profile= Error writing bytes per call: {}", e);
+    }
+}
+
+/// Write the bytes per call table and/or CSV, whichever are selected.
+fn write_bytes_per_call(
+    data: &ProfileData,
+    sink: &mut dyn OutputSink,
+    artifacts: &Artifacts,
+    peak: bool,
+    base_filename: &str,
+    display: &FilenameDisplay,
+) {
+    if !(artifacts.extras || artifacts.csv) {
+        return;
+    }
+    let rows = bytes_per_call(data, peak);
+    if artifacts.extras {
+        write_bytes_per_call_table(&rows, sink, base_filename, display);
+    }
+    if !artifacts.csv {
+        return;
+    }
+    let mut csv = "function,filename,calls,self_bytes,inclusive_bytes\n".to_string();
+    for row in rows.iter() {
+        csv.push_str(&format!(
+            "{},{},{},{},{}\n",
+            csv_field(&row.function),
+            csv_field(display.display(&row.filename, None)),
+            row.calls,
+            row.self_bytes,
+            row.bytes,
+        ));
+    }
+    let name = format!("{}-bytes-per-call.csv", base_filename);
+    if let Err(e) = sink.write_file(&name, csv.as_bytes()) {
+        eprintln!("=fil-profile= Error writing bytes per call: {}", e);
+    }
+}
+
+/// Write the smallest, average and largest allocation made at each callsite,
+/// as CSV.
+fn write_allocation_sizes(
+    data: &ProfileData,
+    sink: &mut dyn OutputSink,
+    base_filename: &str,
+    display: &FilenameDisplay,
+) {
+    let callsites = sizes_per_callsite(data);
+    if callsites.is_empty() {
+        return;
+    }
+    let mut csv =
+        "function,filename,line,allocations,min_bytes,average_bytes,max_bytes,total_bytes\n"
+            .to_string();
+    for (frame, sizes) in callsites {
+        csv.push_str(&format!(
+            "{},{},{},{},{},{:.0},{},{}\n",
+            csv_field(&frame.function),
+            csv_field(display.display(&frame.filename, frame.module.as_deref())),
+            frame.line_number,
+            sizes.count,
+            sizes.min_bytes,
+            sizes.average().unwrap_or(0.0),
+            sizes.max_bytes,
+            sizes.total_bytes,
+        ));
+    }
+    let name = format!("{}-allocation-sizes.csv", base_filename);
+    if let Err(e) = sink.write_file(&name, csv.as_bytes()) {
+        eprintln!("=fil-profile= Error writing allocation sizes: {}", e);
+    }
+}
+
+/// Write every callstack's bytes as CSV, largest first, with the IDs and
+/// fingerprints external tools can use to match callstacks across runs; see
+/// stableid.rs.
+fn write_callstacks_csv(
+    data: &ProfileData,
+    sink: &mut dyn OutputSink,
+    peak: bool,
+    base_filename: &str,
+    display: &FilenameDisplay,
+) {
+    let mut callstacks: Vec<_> = data
+        .callstacks
+        .iter()
+        .map(|usage| {
+            let bytes = if peak {
+                usage.peak_bytes
+            } else {
+                usage.current_bytes
+            };
+            (usage, bytes)
+        })
+        .filter(|(_, bytes)| *bytes > 0)
+        .collect();
+    callstacks.sort_by_key(|(_, bytes)| std::cmp::Reverse(*bytes));
+    let mut csv = "fingerprint,id,bytes,callstack\n".to_string();
+    for (usage, bytes) in callstacks {
+        csv.push_str(&format!(
+            "{},{},{},{}\n",
+            usage.fingerprint(),
+            usage.stable_id(),
+            bytes,
+            csv_field(&frames_as_string(&usage.frames, false, ";", display)),
+        ));
+    }
+    let name = format!("{}-callstacks.csv", base_filename);
+    if let Err(e) = sink.write_file(&name, csv.as_bytes()) {
+        eprintln!("=fil-profile= Error writing callstacks: {}", e);
+    }
+}
+
+/// Quote a CSV field if necessary.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Write which callstacks freed memory allocated by which, if that was
+/// recorded, largest totals first.
+fn write_lifetime_pairs(
+    data: &ProfileData,
+    sink: &mut dyn OutputSink,
+    base_filename: &str,
+    display: &FilenameDisplay,
+) {
+    if data.lifetime_pairs.is_empty() {
+        return;
+    }
+    let mut pairs: Vec<_> = data.lifetime_pairs.iter().collect();
+    pairs.sort_by_key(|pair| std::cmp::Reverse(pair.bytes));
+    let mut table = format!(
+        "{:>10}  {:>12}  {:>14}  {:>14}\n",
+        "MiB", "Allocations", "Avg lifetime", "Max lifetime"
+    );
+    for pair in pairs.into_iter().take(MAX_LIFETIME_PAIR_ROWS) {
+        table.push_str(&format!(
+            "{:>10.1}  {:>12}  {:>13.3}s  {:>13.3}s\n",
+            pair.bytes as f64 / (1024.0 * 1024.0),
+            pair.allocations,
+            pair.average_lifetime(),
+            pair.max_lifetime,
+        ));
+        table.push_str(&format!(
+            "    allocated by: {}\n    freed by:     {}\n\n",
+            frames_as_string(&pair.allocated_by, false, " > ", display),
+            frames_as_string(&pair.freed_by, false, " > ", display),
+        ));
+    }
+    let name = format!("{}-lifetime-pairs.txt", base_filename);
+    if let Err(e) = sink.write_file(&name, table.as_bytes()) {
+        eprintln!("=fil-profile= Error writing lifetime pairs: {}", e);
+    }
+}
+
+/// Write how old live allocations were per callstack, if that was recorded,
+/// as JSON; see ages.rs.
+fn write_allocation_ages(data: &ProfileData, sink: &mut dyn OutputSink, base_filename: &str) {
+    if data.allocation_ages.is_empty() {
+        return;
+    }
+    let name = format!("{}-allocation-ages.json", base_filename);
+    if let Err(e) = serde_json::to_vec_pretty(&ages_to_json(&data.allocation_ages))
+        .map_err(std::io::Error::from)
+        .and_then(|json| sink.write_file(&name, &json))
+    {
+        eprintln!("=fil-profile= Error writing allocation ages: {}", e);
+    }
+}
+
+/// Write the whole snapshot as JSON; see ProfileData::to_json(). Per-function
+/// self and inclusive bytes for the report's moment are added under
+/// "functions"; loading the file ignores them.
+fn write_json(data: &ProfileData, sink: &mut dyn OutputSink, peak: bool, base_filename: &str) {
+    let name = format!("{}.json", base_filename);
+    let mut json = data.to_json();
+    json["functions"] = bytes_per_call(data, peak)
+        .into_iter()
+        .map(|row| {
+            json!({
+                "function": row.function,
+                "filename": row.filename,
+                "calls": row.calls,
+                "self_bytes": row.self_bytes,
+                "inclusive_bytes": row.bytes,
+            })
+        })
+        .collect();
+    json["call_tree"] = call_tree(data, peak).to_json();
+    if let Err(e) = serde_json::to_vec_pretty(&json)
+        .map_err(std::io::Error::from)
+        .and_then(|json| sink.write_file(&name, &json))
+    {
+        eprintln!("=fil-profile= Error writing JSON report: {}", e);
+    }
+}
+
+/// Memory attributed to one line of source code, as shown in the
+/// flamegraph.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FrameBytes {
+    pub filename: String,
+    pub line_number: LineNumber,
+    pub function: String,
+    /// The frame's text in the flamegraph, e.g. "a.py:12 (main)".
+    pub label: String,
+    /// Bytes allocated by this line or anything it called.
+    pub bytes: usize,
+    /// Bytes allocated directly by this line.
+    pub self_bytes: usize,
+}
+
+/// For every frame in the flamegraph, the bytes it's responsible for,
+/// sorted by filename and line so editors can look up a file's lines.
+pub fn bytes_per_frame(
+    data: &ProfileData,
+    peak: bool,
+    display: &FilenameDisplay,
+) -> Vec<FrameBytes> {
+    let mut rows: HashMap<&Frame, FrameBytes> = HashMap::new();
+    for (usage, size) in data.callstacks.iter().zip(bytes_per_callstack(data, peak)) {
+        if size == 0 {
+            continue;
+        }
+        let frames: Vec<&Frame> = visible_frames(&usage.frames).collect();
+        // Recursion shouldn't count the same memory twice:
+        for frame in frames.iter().unique() {
+            let row = rows.entry(frame).or_insert_with(|| FrameBytes {
+                filename: frame.filename.clone(),
+                line_number: frame.line_number,
+                function: frame.function.clone(),
+                label: format!(
+                    "{}:{} ({})",
+                    display.display(&frame.filename, frame.module.as_deref()),
+                    frame.line_number,
+                    frame.function
+                ),
+                bytes: 0,
+                self_bytes: 0,
+            });
+            row.bytes += size;
+        }
+        if let Some(leaf) = frames.last() {
+            if let Some(row) = rows.get_mut(leaf) {
+                row.self_bytes += size;
+            }
+        }
+    }
+    rows.into_values()
+        .sorted_by(|a, b| {
+            a.filename
+                .cmp(&b.filename)
+                .then_with(|| a.line_number.cmp(&b.line_number))
+                .then_with(|| a.function.cmp(&b.function))
+        })
+        .collect()
+}
+
+/// Write `{base}-frames.json`, the flamegraph's frames with their source
+/// locations, for editor plugins to annotate source code with.
+fn write_frames_json(
+    data: &ProfileData,
+    sink: &mut dyn OutputSink,
+    peak: bool,
+    base_filename: &str,
+    display: &FilenameDisplay,
+) {
+    let name = format!("{}-frames.json", base_filename);
+    let frames: Vec<_> = bytes_per_frame(data, peak, display)
+        .into_iter()
+        .map(|row| {
+            json!({
+                "file": row.filename,
+                "line": row.line_number,
+                "function": row.function,
+                "label": row.label,
+                "bytes": row.bytes,
+                "self_bytes": row.self_bytes,
+            })
+        })
+        .collect();
+    let json = json!({
+        "resource": data.resource,
+        "total_bytes": if peak { data.peak_bytes } else { data.current_bytes },
+        "frames": frames,
+    });
+    if let Err(e) = serde_json::to_vec_pretty(&json)
+        .map_err(std::io::Error::from)
+        .and_then(|json| sink.write_file(&name, &json))
+    {
+        eprintln!("=fil-profile= Error writing frame metadata: {}", e);
+    }
+}
+
+/// The flamegraph's callstacks, with displayed filenames, for the formats
+/// other tools read.
+fn weighted_stacks(
+    data: &ProfileData,
+    peak: bool,
+    display: &FilenameDisplay,
+) -> Vec<WeightedStack> {
+    useful_callstacks(data, peak)
+        .into_iter()
+        .map(|(index, size)| {
+            let mut frames: Vec<Frame> = visible_frames(&data.callstacks[index].frames)
+                .map(|frame| Frame {
+                    filename: display
+                        .display(&frame.filename, frame.module.as_deref())
+                        .to_string(),
+                    ..frame.clone()
+                })
+                .collect();
+            if frames.is_empty() {
+                frames.push(Frame {
+                    function: NO_PYTHON_STACK.to_string(),
+                    filename: String::new(),
+                    line_number: 0,
+                    module: None,
+                });
+            }
+            (frames, size)
+        })
+        .collect()
+}
+
+/// Write the pprof and speedscope files, if selected.
+#[allow(clippy::too_many_arguments)]
+fn write_exports(
+    data: &ProfileData,
+    sink: &mut dyn OutputSink,
+    artifacts: &Artifacts,
+    peak: bool,
+    base_filename: &str,
+    title: &str,
+    display: &FilenameDisplay,
+    units: &Units,
+) {
+    if !(artifacts.pprof || artifacts.speedscope) {
+        return;
+    }
+    let stacks = weighted_stacks(data, peak, display);
+    if artifacts.pprof {
+        let name = format!("{}.pb.gz", base_filename);
+        if let Err(e) = gzip(&to_pprof(&stacks, &units.count_name))
+            .and_then(|pprof| sink.write_file(&name, &pprof))
+        {
+            eprintln!("=fil-profile= Error writing pprof profile: {}", e);
+        }
+    }
+    if artifacts.speedscope {
+        let name = format!("{}.speedscope.json", base_filename);
+        if let Err(e) = serde_json::to_vec(&to_speedscope(&stacks, title, &units.count_name))
+            .map_err(std::io::Error::from)
+            .and_then(|json| sink.write_file(&name, &json))
+        {
+            eprintln!("=fil-profile= Error writing speedscope profile: {}", e);
+        }
+    }
+}
+
+/// Write a short plain-text summary: the totals, the callstacks that used
+/// the most memory, and any allocating at least `hot_loop_rate` times a
+/// second.
+#[allow(clippy::too_many_arguments)]
+fn write_summary(
+    data: &ProfileData,
+    sink: &mut dyn OutputSink,
+    peak: bool,
+    base_filename: &str,
+    title: &str,
+    display: &FilenameDisplay,
+    units: &Units,
+    hot_loop_rate: u64,
+) {
+    let total = if peak {
+        data.peak_bytes
+    } else {
+        data.current_bytes
+    };
+    let mut summary = format!(
+        "{}\n\nPeak {}: {}\n",
+        title,
+        data.resource,
+        units.format(data.peak_bytes),
+    );
+    if let Some(window_peak) = &data.window_peak {
+        summary.push_str(&format!(
+            "Peak {} in the last {} seconds: {}\n",
+            data.resource,
+            window_peak.window_seconds,
+            units.format(window_peak.peak_bytes),
+        ));
+    }
+    if data
+        .callstacks
+        .iter()
+        .any(|usage| usage.heap_peak_bytes != usage.peak_bytes)
+    {
+        summary.push_str(&format!(
+            "Peak heap, without mmap(): {}\n",
+            units.format(data.heap_peak_bytes()),
+        ));
+    }
+    if data.address_space.peak_bytes > 0 {
+        summary.push_str(&format!(
+            "Peak address space: {}\n",
+            units.format(data.address_space.peak_bytes),
+        ));
+    }
+    let locked = &data.locked_memory;
+    if !locked.is_empty() {
+        summary.push_str(&format!(
+            "Locked memory (mlock()): {}, peak {}, RLIMIT_MEMLOCK {}\n",
+            units.format(locked.current_bytes),
+            units.format(locked.peak_bytes),
+            locked.limit.map_or("unlimited".to_string(), |limit| units
+                .format(limit as usize)),
+        ));
+        let all = match (locked.all_current, locked.all_future) {
+            (true, true) => Some("all current and future mappings"),
+            (true, false) => Some("all current mappings"),
+            (false, true) => Some("all future mappings"),
+            (false, false) => None,
+        };
+        if let Some(all) = all {
+            summary.push_str(&format!("mlockall() locked {}\n", all));
+        }
+        if locked.huge_page_bytes > 0 {
+            summary.push_str(&format!(
+                "Advised MADV_HUGEPAGE: {}\n",
+                units.format(locked.huge_page_bytes),
+            ));
+        }
+    }
+    summary.push_str(&format!(
+        "Current {}: {}\nPeak live allocations: {}\n",
+        data.resource,
+        units.format(data.current_bytes),
+        data.peak_allocations,
+    ));
+    let shape = StackStats::new(data);
+    summary.push_str(&format!(
+        "\nProfile shape:\n  Unique callstacks: {}\n  Callsites: {}\n  Max stack depth: {}\n  Average stack depth: {}\n\nTop callstacks:\n",
+        shape.callstacks,
+        shape.callsites,
+        shape.max_depth,
+        shape
+            .average_depth
+            .map_or("-".to_string(), |depth| format!("{:.1}", depth)),
+    ));
+    let bytes = bytes_per_callstack(data, peak);
+    let mut top: Vec<_> = bytes.iter().enumerate().filter(|(_, b)| **b > 0).collect();
+    top.sort_by_key(|(_, bytes)| std::cmp::Reverse(**bytes));
+    for (index, bytes) in top.into_iter().take(MAX_SUMMARY_CALLSTACKS) {
+        summary.push_str(&format!(
+            "{:>14}  {:>5.1}%  {}\n",
+            units.format(*bytes),
+            *bytes as f64 * 100.0 / total.max(1) as f64,
+            frames_as_string(&data.callstacks[index].frames, false, " > ", display),
+        ));
+    }
+    summary.push_str("\nTop functions, by inclusive and self bytes:\n");
+    for row in bytes_per_call(data, peak)
+        .into_iter()
+        .take(MAX_SUMMARY_CALLSTACKS)
+    {
+        summary.push_str(&format!(
+            "{:>14}  {:>14}  {} ({})\n",
+            units.format(row.bytes),
+            units.format(row.self_bytes),
+            row.function,
+            display.display(&row.filename, None),
+        ));
+    }
+    let mut hotspots: Vec<_> = data
+        .callstacks
+        .iter()
+        .filter(|usage| usage.peak_allocation_rate >= hot_loop_rate.max(1))
+        .collect();
+    if !hotspots.is_empty() {
+        hotspots.sort_by_key(|usage| std::cmp::Reverse(usage.peak_allocation_rate));
+        summary.push_str(&format!(
+            "\nAllocation hotspots, allocating at least {} times a second; consider preallocating or reusing buffers:\n",
+            hot_loop_rate,
+        ));
+        for usage in hotspots.into_iter().take(MAX_SUMMARY_CALLSTACKS) {
+            summary.push_str(&format!(
+                "{:>12}/s  {}\n",
+                usage.peak_allocation_rate,
+                frames_as_string(&usage.frames, false, " > ", display),
+            ));
+        }
+    }
+    let name = format!("{}-summary.txt", base_filename);
+    if let Err(e) = sink.write_file(&name, summary.as_bytes()) {
+        eprintln!("=fil-profile= Error writing summary: {}", e);
+    }
+}
+
+/// Write a chart of total usage over time; see timeline.rs.
+fn write_timeline(data: &ProfileData, sink: &mut dyn OutputSink, base_filename: &str) {
+    if data.timeline.samples.is_empty() {
+        return;
+    }
+    let svg = render_timeline(&data.timeline, "Tracked memory over time");
+    let name = format!("{}-timeline.svg", base_filename);
+    if let Err(e) = sink.write_file(&name, svg.as_bytes()) {
+        eprintln!("=fil-profile= Error writing timeline: {}", e);
+    }
+}
+
+/// Write memory per label, if anything was labelled.
+fn write_labels(data: &ProfileData, sink: &mut dyn OutputSink, peak: bool, base_filename: &str) {
+    if let Some(table) = label_table(data, peak) {
+        let name = format!("{}-labels.txt", base_filename);
+        if let Err(e) = sink.write_file(&name, table.as_bytes()) {
+            eprintln!("=fil-profile= Error writing per-label usage: {}", e);
+        }
+    }
+}
+
+/// Write each subinterpreter's own current and peak usage, if any
+/// subinterpreters allocated memory.
+fn write_interpreters(data: &ProfileData, sink: &mut dyn OutputSink, base_filename: &str) {
+    if data.interpreters.is_empty() {
+        return;
+    }
+    let mut table = format!(
+        "{:>12}  {:>10}  {:>10}\n",
+        "Interpreter", "Peak MiB", "Now MiB"
+    );
+    for stats in &data.interpreters {
+        table.push_str(&format!(
+            "{:>12}  {:>10.1}  {:>10.1}\n",
+            stats.interpreter,
+            stats.peak_bytes as f64 / (1024.0 * 1024.0),
+            stats.current_bytes as f64 / (1024.0 * 1024.0),
+        ));
+    }
+    let name = format!("{}-interpreters.txt", base_filename);
+    if let Err(e) = sink.write_file(&name, table.as_bytes()) {
+        eprintln!("=fil-profile= Error writing subinterpreter usage: {}", e);
+    }
+}
+
+/// Write a per-package table and a coarse flamegraph where frames are
+/// packages rather than functions.
+fn write_packages(
+    data: &ProfileData,
+    sink: &mut dyn OutputSink,
+    peak: bool,
+    base_filename: &str,
+    title: &str,
+    pruning: &FramePruning,
+    units: &Units,
+) {
+    let per_callstack = packages_per_callstack(data, peak);
+    let totals = sum_by_package(
+        per_callstack
+            .iter()
+            .map(|(packages, size)| (&packages[..], *size)),
+    );
+    let mut table = vec![];
+    if let Err(e) = write_package_table(&totals, &mut table)
+        .and_then(|_| sink.write_file(&format!("{}-packages.txt", base_filename), &table))
+    {
+        eprintln!("=fil-profile= Error writing per-package table: {}", e);
+    }
+    let mut coarse: HashMap<String, usize> = HashMap::new();
+    for (packages, size) in per_callstack.iter() {
+        *coarse.entry(coarse_stack(packages)).or_insert(0) += size;
+    }
+    let svg_name = format!("{}-packages.svg", base_filename);
+    match write_flamegraph(
+        coarse
+            .into_iter()
+            .map(|(stack, size)| format!("{} {}", stack, size)),
+        sink,
+        &svg_name,
+        false,
+        &format!("{}, by package", title),
+        "",
+        units,
+        false,
+        pruning,
+    ) {
+        Ok(_) => {
+            eprintln!(
+                "=fil-profile= Wrote per-package flamegraph to {}",
+                sink.describe(&svg_name)
+            );
+        }
+        Err(e) => {
+            eprintln!("=fil-profile= Error writing per-package SVG: {}", e);
+        }
+    }
+}
+
+/// Redact a snapshot, writing out the mapping back to real names if asked.
+fn redact(data: &ProfileData, options: &ReportOptions) -> ProfileData {
+    let mut data = data.clone();
+    // Runpy frames are recognized by filename, so drop them first:
+    for usage in data.callstacks.iter_mut() {
+        usage.frames = visible_frames(&usage.frames).cloned().collect();
+    }
+    let mut redactor = Redactor::default();
+    let data = redactor.redact(data);
+    if let Some(mapping_path) = &options.redaction_mapping_path {
+        match expand_path(mapping_path)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+            .and_then(|path| redactor.write_mapping(&path))
+        {
+            Ok(_) => eprintln!(
+                "=fil-profile= Wrote mapping of redacted names to {:?}",
+                mapping_path
+            ),
+            Err(e) => eprintln!("=fil-profile= Error writing redaction mapping: {}", e),
+        }
+    }
+    data
+}
+
+/// Write all the standard report files for a snapshot to a sink.
+pub fn write_report(
+    data: &ProfileData,
+    options: &ReportOptions,
+    sink: &mut dyn OutputSink,
+    peak: bool,
+    base_filename: &str,
+    title: &str,
+    to_be_post_processed: bool,
+) {
+    // The directory is irrelevant when writing to a sink:
+    let mut report = Report::new("")
+        .with_standard_artifacts()
+        .with_options(options.clone())
+        .named(base_filename, title);
+    if !peak {
+        report = report.current_allocations();
+    }
+    if to_be_post_processed {
+        report = report.for_html();
+    }
+    write_artifacts(data, &report, sink);
+}
+
+/// Write the files a report asks for; see Report.
+pub(crate) fn write_artifacts(data: &ProfileData, report: &Report, sink: &mut dyn OutputSink) {
+    let options = &report.options;
+    let artifacts = &match &options.formats {
+        Some(formats) => formats.apply(report.artifacts),
+        None => report.artifacts,
+    };
+    let peak = report.peak;
+    let base_filename = report.base_filename.as_str();
+    let title = report.title.as_str();
+    let to_be_post_processed = report.to_be_post_processed;
+    let mut compressing;
+    let sink: &mut dyn OutputSink = if options.compress {
+        compressing = CompressingSink::new(sink);
+        &mut compressing
+    } else {
+        sink
+    };
+    // Print warning if we're missing allocations.
+    #[cfg(not(feature = "fil4prod"))]
+    {
+        let allocated_bytes = if peak {
+            data.peak_bytes
+        } else {
+            data.current_bytes
+        };
+        if data.missing_bytes > 0 {
+            eprintln!("=fil-profile= WARNING: {:.2}% ({} bytes) of tracked memory somehow disappeared. If this is a small percentage you can just ignore this warning, since the missing allocations won't impact the profiling results. If the % is high, please run `export FIL_DEBUG=1` to get more output', re-run Fil on your script, and then file a bug report at https://github.com/pythonspeed/filprofiler/issues/new", data.missing_bytes as f64 * 100.0 / allocated_bytes as f64, data.missing_bytes);
+        }
+        if data.failed_deallocations > 0 {
+            eprintln!("=fil-profile= WARNING: Encountered {} deallocations of untracked allocations. A certain number are expected in normal operation, of allocations created before Fil started tracking, and even more if you're using the Fil API to turn tracking on and off.", data.failed_deallocations);
+        }
+    }
+    if data.accounting_errors > 0 {
+        eprintln!("=fil-profile= WARNING: Ignored {} impossible memory accounting events, e.g. absurdly large allocations, or freeing more memory than was allocated. The report may be somewhat inaccurate; please run `export FIL_DEBUG=1` to get more output, re-run Fil on your script, and then file a bug report at https://github.com/pythonspeed/filprofiler/issues/new", data.accounting_errors);
+    }
+
+    // Collapsing happens before redaction, so patterns match real names:
+    let collapsed;
+    let data = match FrameCollapser::new(&options.collapse_frames) {
+        Some(collapser) => {
+            collapsed = collapser.apply(data);
+            &collapsed
+        }
+        None => data,
+    };
+    let merged;
+    let data = if options.merge_lines {
+        merged = merge_lines(data);
+        &merged
+    } else {
+        data
+    };
+    let redacted;
+    let data = if options.redact {
+        redacted = redact(data, options);
+        &redacted
+    } else {
+        data
+    };
+    let display = match FilenameDisplay::new(options.frame_display)
+        .with_stripped_prefixes(&options.strip_prefixes)
+    {
+        Ok(display) => display,
+        Err(e) => {
+            eprintln!("=fil-profile= Not stripping path prefixes: {}", e);
+            FilenameDisplay::new(options.frame_display)
+        }
+    };
+
+    // Titles and summaries use MiB unless told otherwise, while tooltips
+    // have always shown exact byte counts:
+    let units = options.units.clone().unwrap_or_default();
+    let frame_units = options.units.clone().unwrap_or_else(|| Units::raw("bytes"));
+    let count_units = Units::raw("allocations");
+    let title = format!("{} ({})", title, units.format(data.peak_bytes));
+    // Fil's own overhead isn't in any of the numbers above:
+    if data.profiler_memory.total() > 0 {
+        eprintln!(
+            "=fil-profile= Fil's own memory use, not included in the report: {:.1} MiB",
+            data.profiler_memory.total() as f64 / (1024.0 * 1024.0)
+        );
+    }
+    #[cfg(not(feature = "fil4prod"))]
+    let subtitle = r#"Made with the Fil profiler. <a href="https://pythonspeed.com/fil/" style="text-decoration: underline;" target="_parent">Try it on your code!</a>"#;
+    #[cfg(feature = "fil4prod")]
+    let subtitle = r#"Made with the Fil4prod profiler. <a href="https://pythonspeed.com/products/fil4prod/" style="text-decoration: underline;" target="_parent">Try it on your code!</a>"#;
+    write_flamegraphs(
+        sink,
+        artifacts,
+        base_filename,
+        &title,
+        subtitle,
+        &frame_units,
+        to_be_post_processed,
+        &options.frame_pruning,
+        |tbpp| to_lines(data, peak, tbpp, &display),
+    );
+    if artifacts.json {
+        write_json(data, sink, peak, base_filename);
+    }
+    if artifacts.flamegraph {
+        write_frames_json(data, sink, peak, base_filename, &display);
+    }
+    if artifacts.summary {
+        let hot_loop_rate = options.hot_loop_rate.unwrap_or(DEFAULT_HOT_LOOP_RATE);
+        write_summary(
+            data,
+            sink,
+            peak,
+            base_filename,
+            &title,
+            &display,
+            &units,
+            hot_loop_rate,
+        );
+    }
+    write_exports(
+        data,
+        sink,
+        artifacts,
+        peak,
+        base_filename,
+        &title,
+        &display,
+        &frame_units,
+    );
+    write_bytes_per_call(data, sink, artifacts, peak, base_filename, &display);
+    if artifacts.csv {
+        write_allocation_sizes(data, sink, base_filename, &display);
+        write_callstacks_csv(data, sink, peak, base_filename, &display);
+    }
+    if !artifacts.extras {
+        return;
+    }
+    if options.group_by_file {
+        write_flamegraphs(
+            sink,
+            artifacts,
+            &format!("{}-by-file", base_filename),
+            &format!("{}, by file", title),
+            subtitle,
+            &frame_units,
+            false,
+            &options.frame_pruning,
+            |_| to_file_lines(data, peak, &display),
+        );
+    }
+    match StackFilter::from_options(options) {
+        Ok(Some(filter)) => {
+            let filtered = filter.apply(data);
+            write_flamegraphs(
+                sink,
+                artifacts,
+                &format!("{}-filtered", base_filename),
+                &format!("{}, filtered", title),
+                subtitle,
+                &frame_units,
+                to_be_post_processed,
+                &options.frame_pruning,
+                |tbpp| to_lines(&filtered, peak, tbpp, &display),
+            );
+        }
+        Ok(None) => {}
+        Err(e) => eprintln!("=fil-profile= Not writing filtered flamegraphs: {}", e),
+    }
+    if options.per_thread {
+        for (thread, thread_data) in split_by_thread(data) {
+            write_flamegraphs(
+                sink,
+                artifacts,
+                &format!("{}-thread-{}", base_filename, thread),
+                &format!(
+                    "{}, thread {}: {}",
+                    title,
+                    thread,
+                    units.format(thread_data.peak_bytes)
+                ),
+                subtitle,
+                &frame_units,
+                to_be_post_processed,
+                &options.frame_pruning,
+                |tbpp| to_lines(&thread_data, peak, tbpp, &display),
+            );
+        }
+    }
+    if peak {
+        if let Err(e) = write_flamegraph(
+            to_peak_count_lines(data, &display),
+            sink,
+            &format!("{}-allocation-count.svg", base_filename),
+            false,
+            &format!(
+                "Peak Number of Live Allocations ({})",
+                data.peak_allocations
+            ),
+            subtitle,
+            &count_units,
+            false,
+            &options.frame_pruning,
+        ) {
+            eprintln!("=fil-profile= Error writing SVG: {}", e);
+        }
+        // Millions of tiny objects barely show up when weighted by bytes:
+        if let Err(e) = write_flamegraph(
+            to_count_at_peak_lines(data, &display),
+            sink,
+            &format!("{}-allocation-count-at-peak.svg", base_filename),
+            false,
+            &format!("{}, number of live allocations", title),
+            subtitle,
+            &count_units,
+            false,
+            &options.frame_pruning,
+        ) {
+            eprintln!("=fil-profile= Error writing SVG: {}", e);
+        }
+        if let Err(e) = write_flamegraph(
+            to_lifetime_lines(data, &display),
+            sink,
+            &format!("{}-lifetime.svg", base_filename),
+            false,
+            &format!("{}, freed later vs. still allocated", title),
+            subtitle,
+            &frame_units,
+            false,
+            &options.frame_pruning,
+        ) {
+            eprintln!("=fil-profile= Error writing SVG: {}", e);
+        }
+        // With mmap()s the heap alone may have peaked at another moment,
+        // and that's the snapshot to look at when shrinking the heap:
+        if let Some(heap) = data.heap_peak() {
+            write_flamegraphs(
+                sink,
+                artifacts,
+                &format!("{}-heap", base_filename),
+                &format!(
+                    "Peak Heap Memory Usage, without mmap() ({})",
+                    units.format(heap.peak_bytes)
+                ),
+                subtitle,
+                &frame_units,
+                to_be_post_processed,
+                &options.frame_pruning,
+                |tbpp| to_lines(&heap, true, tbpp, &display),
+            );
+        }
+    }
+    write_packages(
+        data,
+        sink,
+        peak,
+        base_filename,
+        &title,
+        &options.frame_pruning,
+        &frame_units,
+    );
+    write_timeline(data, sink, base_filename);
+    write_lifetime_pairs(data, sink, base_filename, &display);
+    write_allocation_ages(data, sink, base_filename);
+    write_interpreters(data, sink, base_filename);
+    write_labels(data, sink, peak, base_filename);
+    if let Some(numa) = &data.numa {
+        let mut table = vec![];
+        if let Err(e) = numa
+            .write(&mut table)
+            .and_then(|_| sink.write_file(&format!("{}-numa.txt", base_filename), &table))
+        {
+            eprintln!("=fil-profile= Error writing NUMA report: {}", e);
+        }
+    }
+    let mut metadata = vec![];
+    if let Err(e) = data
+        .metadata
+        .write(&mut metadata)
+        .and_then(|_| sink.write_file(&format!("{}-metadata.json", base_filename), &metadata))
+    {
+        eprintln!("=fil-profile= Error writing process metadata: {}", e);
+    }
+}
+
+/// Writing reports straight from the tracker.
+impl<FL: FunctionLocations, M: Metric> AllocationTracker<FL, M> {
+    /// A report to the given directory, with the tracker's report options.
+    /// Choose the artifacts, then pass it to dump().
+    pub fn report(&self, path: &str) -> Report {
+        let mut options = self.report_options.clone();
+        if options.units.is_none() {
+            options.units = M::units();
+        }
+        let report = Report::new(path).with_options(options);
+        if M::RESOURCE == MEMORY {
+            report
+        } else {
+            report.named(
+                &format!("peak-{}", M::RESOURCE),
+                &format!("Peak Tracked Usage of {}", M::RESOURCE),
+            )
+        }
+    }
+
+    /// Write a report of the current snapshot, then run the post-dump hooks,
+    /// unless the report is for HTML; whoever writes the HTML runs them once
+    /// that's done. Returns the directory the report ended up in, which may
+    /// be a fallback (see OutputFallback), or None if it couldn't be written
+    /// anywhere.
+    pub fn dump(&mut self, report: &Report) -> Option<PathBuf> {
+        self.flush_logs();
+        let data = self.snapshot();
+        match report.write(&data) {
+            Ok(directory) => {
+                if !report.to_be_post_processed {
+                    self.post_dump.run(&directory);
+                }
+                Some(directory)
+            }
+            Err(e) => {
+                eprintln!(
+                    "=fil-profile= Couldn't write to {}: {}",
+                    report.directory(),
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    /// Write a standard report to an arbitrary sink.
+    pub fn dump_to_sink(
+        &mut self,
+        sink: &mut dyn OutputSink,
+        peak: bool,
+        base_filename: &str,
+        title: &str,
+        to_be_post_processed: bool,
+    ) {
+        self.flush_logs();
+        let data = self.snapshot();
+        write_report(
+            &data,
+            &self.report_options,
+            sink,
+            peak,
+            base_filename,
+            title,
+            to_be_post_processed,
+        );
+    }
+
+    /// Dump peak memory SVGs, for when the Python code that writes the HTML
+    /// report won't be running.
+    pub fn dump_peak_svgs(&mut self, path: &str) {
+        let report = self.report(path).with_standard_artifacts();
+        self.dump(&report);
+    }
+
+    /// Dump SVGs of the memory allocated right now, rather than at peak, e.g.
+    /// to see what a long-running service is holding on to at the moment.
+    pub fn dump_current_svgs(&mut self, path: &str) {
+        let report = self
+            .report(path)
+            .with_standard_artifacts()
+            .current_allocations()
+            .named("current-memory", "Current Tracked Memory Usage");
+        self.dump(&report);
+    }
+
+    /// Write a report of current allocations because an exception, e.g.
+    /// MemoryError, is propagating, to the given directory or by default the
+    /// output directory. The exception type is marked on the timeline and
+    /// in the flamegraph titles, and the JSON is included for post-mortem
+    /// tooling.
+    pub fn exception_dump(&mut self, path: Option<&str>, exception_type: &str) {
+        eprintln!(
+            "=fil-profile= Writing a report of current allocations, since {} was raised.",
+            exception_type
+        );
+        self.add_timeline_marker(exception_type);
+        let path = path.unwrap_or_else(|| self.default_path()).to_string();
+        let report = self
+            .report(&path)
+            .with_standard_artifacts()
+            .with_json()
+            .current_allocations()
+            .named(
+                "exception",
+                &format!("Current allocations when {} was raised", exception_type),
+            );
+        self.dump(&report);
+    }
+
+    /// Dump information about where we are.
+    pub fn oom_dump(&mut self) {
+        eprintln!(
+            "=fil-profile= We'll try to dump out SVGs. Note that no HTML file will be written."
+        );
+        let report = self
+            .report(self.default_path())
+            .with_standard_artifacts()
+            .current_allocations()
+            .named("out-of-memory", "Current allocations at out-of-memory time");
+        self.dump(&report);
+        // We already wrote a report, no need for exit hooks to write another:
+        crate::exithooks::disarm();
+        unsafe {
+            _exit(53);
+        }
+    }
+
+    /// Write a report of current allocations because usage crossed the memory
+    /// limit, or for LimitAction::Notify the soft limit. What happens next is
+    /// up to the caller.
+    pub fn memory_limit_dump(&mut self, action: LimitAction) {
+        let (limit, kind, base_filename, title) = if action == LimitAction::Notify {
+            (
+                &self.soft_memory_limit,
+                "soft limit",
+                "soft-memory-limit",
+                "Current allocations when the soft memory limit was exceeded",
+            )
+        } else {
+            (
+                &self.memory_limit,
+                "limit",
+                "memory-limit",
+                "Current allocations when the memory limit was exceeded",
+            )
+        };
+        let limit = limit.as_ref().map(|limit| limit.limit()).unwrap_or(0);
+        eprintln!(
+            "=fil-profile= Tracked memory usage ({:.1} MiB) exceeded the {} of {:.1} MiB.",
+            self.get_current_allocated_bytes() as f64 / (1024.0 * 1024.0),
+            kind,
+            limit as f64 / (1024.0 * 1024.0)
+        );
+        let report = self
+            .report(self.default_path())
+            .with_standard_artifacts()
+            .current_allocations()
+            .named(base_filename, title);
+        self.dump(&report);
+    }
+
+    /// Best-effort dump of peak memory usage when the process is about to die
+    /// unexpectedly, e.g. due to a crash.
+    pub fn emergency_dump(&mut self, reason: &str) {
+        if self.get_peak_allocated_bytes() == 0 {
+            // Not tracking, or the report was already written out.
+            return;
+        }
+        if std::process::id() != self.metadata().pid {
+            // A fork()ed child; the data belongs to the parent.
+            return;
+        }
+        if !is_python_running() {
+            eprintln!(
+                "=fil-profile= {}, but Python has already shut down so no report can be written.",
+                reason
+            );
+            return;
+        }
+        eprintln!(
+            "=fil-profile= {}, so we'll try to dump out SVGs. Note that no HTML file will be written.",
+            reason
+        );
+        let default_path = self.default_path().to_string();
+        self.dump_peak_svgs(&default_path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        bytes_per_call, bytes_per_frame, combine_by_package, csv_field, to_file_lines,
+        to_lifetime_lines, to_lines, to_peak_count_lines, write_report, FREED_LATER_FRAME,
+        STILL_ALLOCATED_FRAME,
+    };
+    use crate::formats::OutputFormats;
+    use crate::locked::LockEvent;
+    use crate::memorytracking::{
+        AllocationTracker, CallSiteId, Callstack, VecFunctionLocations, PARENT_PROCESS,
+    };
+    use crate::metric::Metric;
+    use crate::report::{FilenameDisplay, FrameDisplay, Report, ReportOptions};
+    use crate::sink::MemorySink;
+    use crate::snapshot::ProfileData;
+    use crate::units::Units;
+    use crate::util::name_from_bytes;
+
+    fn new_tracker() -> AllocationTracker<VecFunctionLocations> {
+        AllocationTracker::new(".".to_string(), VecFunctionLocations::new())
+    }
+
+    #[test]
+    fn combine_callstacks_and_sum_allocations() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = new_tracker();
+        let fid1 = tracker
+            .functions
+            .add_function("a".to_string(), "af".to_string());
+        let fid2 = tracker
+            .functions
+            .add_function("b".to_string(), "bf".to_string());
+        let fid3 = tracker
+            .functions
+            .add_function("c".to_string(), "cf".to_string());
+
+        let id1 = CallSiteId::new(fid1, 1);
+        // Same function, different line number—should be different item:
+        let id1_different = CallSiteId::new(fid1, 7);
+        let id2 = CallSiteId::new(fid2, 2);
+
+        let id3 = CallSiteId::new(fid3, 3);
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, id1);
+        cs1.start_call(0, id2.clone());
+        let mut cs2 = Callstack::new();
+        cs2.start_call(0, id3);
+        let mut cs3 = Callstack::new();
+        cs3.start_call(0, id1_different);
+        cs3.start_call(0, id2);
+        let cs1_id = tracker.get_callstack_id(&cs1);
+        let cs2_id = tracker.get_callstack_id(&cs2);
+        let cs3_id = tracker.get_callstack_id(&cs3);
+        tracker.add_allocation(PARENT_PROCESS, 1, 1000, cs1_id);
+        tracker.add_allocation(PARENT_PROCESS, 2, 234, cs2_id);
+        tracker.add_anon_mmap(PARENT_PROCESS, 3, 50000, cs1_id);
+        tracker.add_allocation(PARENT_PROCESS, 4, 6000, cs3_id);
+
+        // 234 allocation is too small, below the 99% total allocations
+        // threshold, but we always guarantee at least 100 allocations.
+        let mut expected = vec![
+            "a:1 (af);b:2 (bf) 51000",
+            "c:3 (cf) 234",
+            "a:7 (af);b:2 (bf) 6000",
+        ];
+        let data = tracker.snapshot();
+        let mut result = to_lines(&data, true, false, &FilenameDisplay::default());
+        result.sort();
+        expected.sort();
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn file_lines_merge_functions_in_same_file() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = new_tracker();
+        let fid1 = tracker
+            .functions
+            .add_function("a.py".to_string(), "af".to_string());
+        let fid2 = tracker
+            .functions
+            .add_function("a.py".to_string(), "helper".to_string());
+        let fid3 = tracker
+            .functions
+            .add_function("b.py".to_string(), "bf".to_string());
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid1, 1));
+        cs1.start_call(0, CallSiteId::new(fid2, 10));
+        cs1.start_call(0, CallSiteId::new(fid3, 3));
+        let mut cs2 = Callstack::new();
+        cs2.start_call(0, CallSiteId::new(fid1, 2));
+        cs2.start_call(0, CallSiteId::new(fid3, 3));
+        let cs1_id = tracker.get_callstack_id(&cs1);
+        let cs2_id = tracker.get_callstack_id(&cs2);
+        tracker.add_allocation(PARENT_PROCESS, 1, 1000, cs1_id);
+        tracker.add_allocation(PARENT_PROCESS, 2, 234, cs2_id);
+        let data = tracker.snapshot();
+        let result = to_file_lines(&data, true, &FilenameDisplay::default());
+        assert_eq!(result, vec!["a.py;b.py 1234"]);
+    }
+
+    #[test]
+    fn peak_count_lines_use_allocation_counts() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = new_tracker();
+        let fid1 = tracker
+            .functions
+            .add_function("a.py".to_string(), "af".to_string());
+        let fid2 = tracker
+            .functions
+            .add_function("b.py".to_string(), "bf".to_string());
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid1, 1));
+        let mut cs2 = Callstack::new();
+        cs2.start_call(0, CallSiteId::new(fid2, 2));
+        let cs1_id = tracker.get_callstack_id(&cs1);
+        let cs2_id = tracker.get_callstack_id(&cs2);
+        // Many small allocations:
+        for i in 0..10 {
+            tracker.add_allocation(PARENT_PROCESS, 100 + i, 10, cs1_id);
+        }
+        for i in 0..10 {
+            tracker.free_allocation(PARENT_PROCESS, 100 + i);
+        }
+        // One big allocation, the byte peak:
+        tracker.add_allocation(PARENT_PROCESS, 1, 1000, cs2_id);
+        let data = tracker.snapshot();
+        let result = to_peak_count_lines(&data, &FilenameDisplay::default());
+        assert_eq!(result, vec!["a.py:1 (af) 10"]);
+    }
+
+    #[test]
+    fn lifetime_lines_split_freed_and_still_allocated() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = new_tracker();
+        let fid1 = tracker
+            .functions
+            .add_function("a.py".to_string(), "af".to_string());
+        let fid2 = tracker
+            .functions
+            .add_function("b.py".to_string(), "bf".to_string());
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid1, 1));
+        let mut cs2 = Callstack::new();
+        cs2.start_call(0, CallSiteId::new(fid2, 2));
+        let cs1_id = tracker.get_callstack_id(&cs1);
+        let cs2_id = tracker.get_callstack_id(&cs2);
+        tracker.add_allocation(PARENT_PROCESS, 1, 1000, cs1_id);
+        tracker.add_allocation(PARENT_PROCESS, 2, 300, cs2_id);
+        tracker.add_allocation(PARENT_PROCESS, 3, 200, cs2_id);
+        tracker.check_if_new_peak();
+        tracker.free_allocation(PARENT_PROCESS, 1);
+        tracker.free_allocation(PARENT_PROCESS, 3);
+        let data = tracker.snapshot();
+        let mut result = to_lifetime_lines(&data, &FilenameDisplay::default());
+        result.sort();
+        assert_eq!(
+            result,
+            vec![
+                format!("a.py:1 (af);{} 1000", FREED_LATER_FRAME),
+                format!("b.py:2 (bf);{} 200", FREED_LATER_FRAME),
+                format!("b.py:2 (bf);{} 300", STILL_ALLOCATED_FRAME),
+            ]
+        );
+    }
+
+    #[test]
+    fn combine_by_package_blames_innermost_frame() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = new_tracker();
+        let fid1 = tracker
+            .functions
+            .add_function("/src/mycode.py".to_string(), "main".to_string());
+        let fid2 = tracker.functions.add_function(
+            "/venv/lib/python3.9/site-packages/pandas/core/frame.py".to_string(),
+            "__init__".to_string(),
+        );
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid1, 1));
+        let mut cs2 = cs1.clone();
+        cs2.start_call(0, CallSiteId::new(fid2, 2));
+        let cs1_id = tracker.get_callstack_id(&cs1);
+        let cs2_id = tracker.get_callstack_id(&cs2);
+        let cs3_id = tracker.get_callstack_id(&Callstack::new());
+        tracker.add_allocation(PARENT_PROCESS, 1, 1000, cs1_id);
+        tracker.add_allocation(PARENT_PROCESS, 2, 3000, cs2_id);
+        tracker.add_allocation(PARENT_PROCESS, 3, 5, cs3_id);
+        let data = tracker.snapshot();
+        assert_eq!(
+            combine_by_package(&data, true),
+            vec![
+                ("pandas".to_string(), 3000),
+                ("mycode".to_string(), 1000),
+                ("[No Python stack]".to_string(), 5)
+            ]
+        );
+    }
+
+    #[test]
+    fn names_that_were_not_utf8() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = new_tracker();
+        let fid = tracker.functions.add_function(
+            name_from_bytes(b"/data/caf\xe9.py"),
+            name_from_bytes(b"load\x01<listcomp>"),
+        );
+        let mut cs = Callstack::new();
+        cs.start_call(0, CallSiteId::new(fid, 1));
+        let cs_id = tracker.get_callstack_id(&cs);
+        tracker.add_allocation(PARENT_PROCESS, 1, 1000, cs_id);
+        let data = tracker.snapshot();
+        let mut sink = MemorySink::default();
+        write_report(
+            &data,
+            &ReportOptions::default(),
+            &mut sink,
+            true,
+            "peak-memory",
+            "Peak",
+            false,
+        );
+        let svg = String::from_utf8(sink.files["peak-memory.svg"].clone()).unwrap();
+        assert!(svg.contains("caf\\xE9.py"));
+        assert!(svg.contains("load\\x01&lt;listcomp&gt;"));
+        let json = serde_json::to_string(&data.to_json()).unwrap();
+        let value = serde_json::from_str(&json).unwrap();
+        assert_eq!(ProfileData::from_json(&value), Ok(data));
+    }
+
+    #[test]
+    fn report_builder_selects_artifacts() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = new_tracker();
+        tracker.add_allocation_with_callstack(1, 3000, &[("app", "main", 1), ("app", "load", 7)]);
+        tracker.add_allocation_with_callstack(2, 1000, &[("app", "main", 2)]);
+        let data = tracker.snapshot();
+        let mut sink = MemorySink::default();
+        Report::new("unused")
+            .with_reversed()
+            .with_json()
+            .with_summary()
+            .named("report", "Peak")
+            .write_to_sink(&data, &mut sink);
+        assert_eq!(
+            sink.files.keys().collect::<Vec<_>>(),
+            vec!["report-reversed.svg", "report-summary.txt", "report.json"]
+        );
+        let summary = String::from_utf8(sink.files["report-summary.txt"].clone()).unwrap();
+        assert!(summary.contains("Peak memory: 0.0 MiB"));
+        assert!(summary.contains("  Unique callstacks: 2\n"));
+        assert!(summary.contains("  Max stack depth: 2\n"));
+        let top: Vec<_> = summary
+            .lines()
+            .skip_while(|l| *l != "Top callstacks:")
+            .collect();
+        assert!(
+            top[1].contains("75.0%") && top[1].ends_with("(load)"),
+            "{}",
+            top[1]
+        );
+        assert!(summary.contains("Top functions, by inclusive and self bytes:"));
+        assert!(!summary.contains("Allocation hotspots"));
+        let json: serde_json::Value = serde_json::from_slice(&sink.files["report.json"]).unwrap();
+        assert_eq!(json["functions"][0]["function"], "main");
+        assert_eq!(json["functions"][0]["inclusive_bytes"], 4000);
+        assert_eq!(json["functions"][0]["self_bytes"], 1000);
+        assert_eq!(json["functions"][1]["self_bytes"], 3000);
+        assert_eq!(json["call_tree"]["bytes"], 4000);
+        assert_eq!(
+            json["call_tree"]["children"][0]["children"][0]["frame"]["function"],
+            "load"
+        );
+        assert_eq!(ProfileData::from_json(&json), Ok(data));
+    }
+
+    #[test]
+    fn summary_lists_allocation_hotspots() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = new_tracker();
+        // A loop allocating and freeing a buffer on every iteration:
+        for _ in 0..500 {
+            tracker.add_allocation_with_callstack(
+                1,
+                100,
+                &[("app", "main", 1), ("app", "loop", 9)],
+            );
+            tracker.free_allocation(PARENT_PROCESS, 1);
+        }
+        tracker.add_allocation_with_callstack(2, 1000, &[("app", "main", 2)]);
+        let data = tracker.snapshot();
+        assert_eq!(data.callstacks.len(), 2);
+        assert_eq!(data.callstacks[0].peak_allocation_rate, 500);
+        let summary = |hot_loop_rate| {
+            let mut sink = MemorySink::default();
+            Report::new("unused")
+                .with_summary()
+                .with_options(ReportOptions {
+                    hot_loop_rate,
+                    ..ReportOptions::default()
+                })
+                .named("report", "Peak")
+                .write_to_sink(&data, &mut sink);
+            String::from_utf8(sink.files["report-summary.txt"].clone()).unwrap()
+        };
+        assert!(!summary(None).contains("Allocation hotspots"));
+        let summary = summary(Some(100));
+        let hotspots: Vec<_> = summary
+            .lines()
+            .skip_while(|l| !l.starts_with("Allocation hotspots"))
+            .collect();
+        assert_eq!(hotspots.len(), 2, "{}", summary);
+        assert!(hotspots[0].contains("at least 100 times a second"));
+        assert!(
+            hotspots[1].trim_start().starts_with("500/s") && hotspots[1].ends_with("(loop)"),
+            "{}",
+            hotspots[1]
+        );
+    }
+
+    #[test]
+    fn heap_peak_gets_its_own_flamegraph() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = new_tracker();
+        tracker.add_allocation_with_callstack(1, 500, &[("app", "main", 1), ("app", "parse", 3)]);
+        tracker.free_allocation(PARENT_PROCESS, 1);
+        let cs_id = tracker.get_callstack_id(&Callstack::new());
+        let cs_id = tracker.with_leaf_frame(cs_id, "big.py", "load");
+        tracker.add_anon_mmap(PARENT_PROCESS, 0x100000, 4096, cs_id);
+        let data = tracker.snapshot();
+        let mut sink = MemorySink::default();
+        Report::new("unused")
+            .with_standard_artifacts()
+            .with_summary()
+            .named("report", "Peak")
+            .write_to_sink(&data, &mut sink);
+        let heap = String::from_utf8(sink.files["report-heap.svg"].clone()).unwrap();
+        assert!(heap.contains("parse") && !heap.contains("big.py"));
+        let summary = String::from_utf8(sink.files["report-summary.txt"].clone()).unwrap();
+        assert!(
+            summary.contains("Peak heap, without mmap(): "),
+            "{}",
+            summary
+        );
+        assert_eq!(data.heap_peak_bytes(), 500);
+
+        // Without mmap()s there's nothing extra:
+        let mut tracker = new_tracker();
+        tracker.add_allocation_with_callstack(1, 500, &[("app", "main", 1)]);
+        let mut sink = MemorySink::default();
+        Report::new("unused")
+            .with_standard_artifacts()
+            .with_summary()
+            .named("report", "Peak")
+            .write_to_sink(&tracker.snapshot(), &mut sink);
+        assert!(!sink.files.contains_key("report-heap.svg"));
+        let summary = String::from_utf8(sink.files["report-summary.txt"].clone()).unwrap();
+        assert!(!summary.contains("Peak heap"));
+    }
+
+    #[test]
+    fn summary_reports_locked_memory() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = new_tracker();
+        tracker.add_allocation_with_callstack(1, 500, &[("app", "main", 1)]);
+        let summary = |tracker: &mut AllocationTracker<VecFunctionLocations>| {
+            let mut sink = MemorySink::default();
+            Report::new("unused")
+                .with_summary()
+                .named("report", "Peak")
+                .write_to_sink(&tracker.snapshot(), &mut sink);
+            String::from_utf8(sink.files["report-summary.txt"].clone()).unwrap()
+        };
+        assert!(!summary(&mut tracker).contains("Locked memory"));
+        tracker.record_lock_event(LockEvent::Lock {
+            address: 0x100000,
+            length: 1 << 20,
+        });
+        tracker.record_lock_event(LockEvent::LockAll {
+            current: false,
+            future: true,
+        });
+        tracker.record_lock_event(LockEvent::HugePages {
+            address: 0x400000,
+            length: 2 << 20,
+        });
+        let summary = summary(&mut tracker);
+        assert!(
+            summary.contains("Locked memory (mlock()): 1.0 MiB, peak 1.0 MiB, RLIMIT_MEMLOCK "),
+            "{}",
+            summary
+        );
+        assert!(summary.contains("mlockall() locked all future mappings\n"));
+        assert!(summary.contains("Advised MADV_HUGEPAGE: 2.0 MiB\n"));
+    }
+
+    #[test]
+    fn output_formats_come_from_one_dump() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = new_tracker();
+        tracker.add_allocation_with_callstack(1, 3000, &[("app", "main", 1), ("app", "load", 7)]);
+        let data = tracker.snapshot();
+        let mut sink = MemorySink::default();
+        let options = ReportOptions {
+            formats: Some(OutputFormats::parse("csv,pprof,speedscope").unwrap()),
+            ..ReportOptions::default()
+        };
+        Report::new("unused")
+            .with_standard_artifacts()
+            .with_options(options)
+            .named("report", "Peak")
+            .write_to_sink(&data, &mut sink);
+        assert_eq!(
+            sink.files.keys().collect::<Vec<_>>(),
+            vec![
+                "report-allocation-sizes.csv",
+                "report-bytes-per-call.csv",
+                "report-callstacks.csv",
+                "report.pb.gz",
+                "report.prof",
+                "report.speedscope.json"
+            ]
+        );
+        let speedscope: serde_json::Value =
+            serde_json::from_slice(&sink.files["report.speedscope.json"]).unwrap();
+        assert_eq!(speedscope["profiles"][0]["weights"][0], 3000);
+        assert_eq!(speedscope["shared"]["frames"][1]["name"], "load");
+        let callstacks = std::str::from_utf8(&sink.files["report-callstacks.csv"]).unwrap();
+        assert_eq!(
+            callstacks,
+            format!(
+                "fingerprint,id,bytes,callstack\n{},{},3000,app:1 (main);app:7 (load)\n",
+                data.callstacks[0].fingerprint(),
+                data.callstacks[0].stable_id()
+            )
+        );
+    }
+
+    #[test]
+    fn units_apply_to_titles_summaries_and_tooltips() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = new_tracker();
+        tracker.add_allocation_with_callstack(1, 3072, &[("app", "main", 1), ("app", "load", 7)]);
+        let data = tracker.snapshot();
+        let mut sink = MemorySink::default();
+        let options = ReportOptions {
+            units: Units::parse_bytes("KiB"),
+            ..ReportOptions::default()
+        };
+        Report::new("unused")
+            .with_flamegraph()
+            .with_summary()
+            .with_options(options)
+            .named("report", "Peak")
+            .write_to_sink(&data, &mut sink);
+        let summary = String::from_utf8(sink.files["report-summary.txt"].clone()).unwrap();
+        assert!(summary.starts_with("Peak (3.0 KiB)\n"));
+        assert!(summary.contains("Current memory: 3.0 KiB\n"));
+        let svg = String::from_utf8(sink.files["report.svg"].clone()).unwrap();
+        assert!(svg.contains("(3.0 KiB, 100.00%)"));
+        assert!(!svg.contains("3,072 bytes"));
+    }
+
+    struct FileDescriptors;
+
+    impl Metric for FileDescriptors {
+        const RESOURCE: &'static str = "file-descriptors";
+        fn units() -> Option<Units> {
+            Some(Units::raw("file descriptors"))
+        }
+    }
+
+    #[test]
+    fn other_metrics_name_their_reports() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker: AllocationTracker<VecFunctionLocations, FileDescriptors> =
+            AllocationTracker::with_metric(".".to_string(), VecFunctionLocations::new());
+        tracker.add_allocation_with_callstack(3, 1, &[("app", "main", 1), ("app", "open", 7)]);
+        tracker.add_allocation_with_callstack(4, 1, &[("app", "main", 1), ("app", "open", 7)]);
+        let data = tracker.snapshot();
+        assert_eq!(data.resource, "file-descriptors");
+        let mut sink = MemorySink::default();
+        tracker
+            .report("unused")
+            .with_prof()
+            .with_flamegraph()
+            .with_summary()
+            .write_to_sink(&data, &mut sink);
+        let summary =
+            String::from_utf8(sink.files["peak-file-descriptors-summary.txt"].clone()).unwrap();
+        assert!(summary.starts_with("Peak Tracked Usage of file-descriptors (2 file descriptors)"));
+        assert!(summary.contains("Current file-descriptors: 2 file descriptors\n"));
+        let prof = String::from_utf8(sink.files["peak-file-descriptors.prof"].clone()).unwrap();
+        assert!(prof
+            .lines()
+            .next()
+            .unwrap()
+            .contains(" units=file_descriptors "));
+        let svg = String::from_utf8(sink.files["peak-file-descriptors.svg"].clone()).unwrap();
+        assert!(svg.contains("(2 file descriptors, 100.00%)"));
+        // The resource survives a round trip through JSON:
+        assert_eq!(ProfileData::from_json(&data.to_json()), Ok(data));
+    }
+
+    #[test]
+    fn dump_current_svgs_shows_live_allocations() {
+        pyo3::prepare_freethreaded_python();
+        let directory = std::env::temp_dir().join(format!("fil-current-{}", std::process::id()));
+        let mut tracker = new_tracker();
+        tracker.add_allocation_with_callstack(1, 3000, &[("app", "main", 1), ("app", "peak", 7)]);
+        tracker.free_allocation(PARENT_PROCESS, 1);
+        tracker.add_allocation_with_callstack(2, 1000, &[("app", "main", 1), ("app", "now", 9)]);
+        tracker.add_timeline_marker("loaded");
+        tracker.dump_current_svgs(directory.to_str().unwrap());
+        let svg = std::fs::read_to_string(directory.join("current-memory.svg")).unwrap();
+        let timeline =
+            std::fs::read_to_string(directory.join("current-memory-timeline.svg")).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        // The timeline has the peak even though it's gone now:
+        assert!(timeline.contains("Peak: 0.0 MiB"));
+        assert!(timeline.contains(">loaded<"));
+        assert!(svg.contains("Current Tracked Memory Usage"));
+        assert!(svg.contains("now"));
+        assert!(!svg.contains("peak (app"));
+    }
+
+    #[test]
+    fn exception_dump_is_tagged() {
+        pyo3::prepare_freethreaded_python();
+        let directory = std::env::temp_dir().join(format!("fil-exception-{}", std::process::id()));
+        let mut tracker = new_tracker();
+        tracker.add_allocation_with_callstack(1, 3000, &[("app", "main", 1), ("app", "load", 7)]);
+        tracker.exception_dump(Some(directory.to_str().unwrap()), "MemoryError");
+        let svg = std::fs::read_to_string(directory.join("exception.svg")).unwrap();
+        let timeline = std::fs::read_to_string(directory.join("exception-timeline.svg")).unwrap();
+        let json = std::fs::read_to_string(directory.join("exception.json")).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert!(svg.contains("Current allocations when MemoryError was raised"));
+        assert!(timeline.contains(">MemoryError<"));
+        assert!(json.contains("load"));
+    }
+
+    #[test]
+    fn bytes_per_call_is_inclusive() {
+        let mut tracker = new_tracker();
+        let main = tracker
+            .functions
+            .add_function("a.py".to_string(), "main".to_string());
+        let small = tracker
+            .functions
+            .add_function("a.py".to_string(), "small".to_string());
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(main, 1));
+        let mut cs2 = cs1.clone();
+        cs2.start_call(0, CallSiteId::new(small, 2));
+        // Recursion shouldn't double count:
+        cs2.start_call(0, CallSiteId::new(small, 2));
+        tracker.call_counts.record(small);
+        tracker.call_counts.record(small);
+        tracker.call_counts.record(small);
+        tracker.call_counts.record(small);
+        let cs1_id = tracker.get_callstack_id(&cs1);
+        let cs2_id = tracker.get_callstack_id(&cs2);
+        tracker.add_allocation(PARENT_PROCESS, 1, 1000, cs1_id);
+        tracker.add_allocation(PARENT_PROCESS, 2, 100, cs2_id);
+        let rows = bytes_per_call(&tracker.snapshot(), true);
+        assert_eq!(
+            rows.iter()
+                .map(|row| (
+                    row.function.as_str(),
+                    row.calls,
+                    row.bytes,
+                    row.self_bytes,
+                    row.average()
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                ("main", 0, 1100, 1000, None),
+                ("small", 4, 100, 100, Some(25.0))
+            ]
+        );
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("main"), "main");
+    }
+
+    #[test]
+    fn frames_json_has_every_line() {
+        pyo3::prepare_freethreaded_python();
+        let mut tracker = new_tracker();
+        tracker.add_allocation_with_callstack(1, 1000, &[("app", "main", 1), ("app", "load", 7)]);
+        // Recursion shouldn't double count:
+        tracker.add_allocation_with_callstack(
+            2,
+            100,
+            &[("app", "main", 1), ("app", "walk", 3), ("app", "walk", 3)],
+        );
+        let data = tracker.snapshot();
+        let display = FilenameDisplay::new(FrameDisplay::AbsolutePath);
+        let rows = bytes_per_frame(&data, true, &display);
+        assert_eq!(
+            rows.iter()
+                .map(|row| (
+                    row.function.as_str(),
+                    row.line_number,
+                    row.bytes,
+                    row.self_bytes
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                ("main", 1, 1100, 0),
+                ("walk", 3, 100, 100),
+                ("load", 7, 1000, 1000)
+            ]
+        );
+        assert!(rows[0].label.ends_with(":1 (main)"));
+
+        let mut sink = MemorySink::default();
+        write_report(
+            &data,
+            &ReportOptions::default(),
+            &mut sink,
+            true,
+            "peak-memory",
+            "Peak",
+            false,
+        );
+        let json: serde_json::Value =
+            serde_json::from_slice(&sink.files["peak-memory-frames.json"]).unwrap();
+        assert_eq!(json["total_bytes"], 1100);
+        assert_eq!(json["frames"][2]["function"], "load");
+        assert_eq!(json["frames"][2]["line"], 7);
+        assert_eq!(json["frames"][2]["bytes"], 1000);
+    }
+}
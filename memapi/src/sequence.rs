@@ -0,0 +1,165 @@
+// Putting allocation events back in order when they arrive out of order.
+//
+// Fil's own hooks record a free() before the memory is actually freed, and an
+// allocation after it's made, so events for the same address always reach
+// the tracker in the order they happened. Hook layers that buffer events and
+// pass them in batches don't have that guarantee: thread A frees an address
+// and buffers the event, thread B gets the same address from malloc() and
+// records it straight away, and then A's buffered free arrives and removes
+// B's allocation. Or an allocation is buffered, the memory is handed to
+// another thread that frees it, and the allocation arrives after its free,
+// and looks live forever.
+//
+// To sort this out, buffered events carry a sequence number, taken from
+// next_sequence() when the malloc() or free() actually happened. When the
+// tracker notices something suspicious, an allocation replacing one that was
+// never freed, or a free of an unknown address, it remembers the address and
+// the current sequence number. A late event whose sequence number is older
+// than that is then recognized as stale and reconciled, rather than applied
+// to the wrong allocation. Until a hook layer asks for a sequence number,
+// nothing can arrive out of order, so nothing is remembered.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::hashing::HashState;
+
+use crate::util::new_hashmap;
+
+/// Events without a sequence number, which are assumed to be in order.
+pub const UNKNOWN_SEQUENCE: u64 = 0;
+
+/// Only so many suspicious addresses are remembered before starting over.
+const MAX_REMEMBERED: usize = 10_000;
+
+static SEQUENCE: AtomicU64 = AtomicU64::new(1);
+static SEQUENCES_USED: AtomicBool = AtomicBool::new(false);
+
+/// A sequence number for an event happening now, for hook layers that buffer
+/// events. Never UNKNOWN_SEQUENCE.
+pub fn next_sequence() -> u64 {
+    SEQUENCES_USED.store(true, Ordering::Relaxed);
+    current_sequence()
+}
+
+fn current_sequence() -> u64 {
+    SEQUENCE.fetch_add(1, Ordering::Relaxed)
+}
+
+fn sequences_used() -> bool {
+    SEQUENCES_USED.load(Ordering::Relaxed)
+}
+
+/// Addresses where events may have arrived out of order.
+#[derive(Clone, Debug)]
+pub struct Reconciler {
+    // Address -> (sequence number when a new allocation replaced one that
+    // hadn't been freed, the replaced allocation's size):
+    replaced: HashMap<usize, (u64, usize), HashState>,
+    // Address -> sequence number when an unknown address was freed:
+    unmatched_frees: HashMap<usize, u64, HashState>,
+}
+
+impl Default for Reconciler {
+    fn default() -> Self {
+        Self {
+            replaced: new_hashmap(),
+            unmatched_frees: new_hashmap(),
+        }
+    }
+}
+
+impl Reconciler {
+    /// An allocation replaced one of the given size that was never freed;
+    /// maybe its free() is still on the way.
+    pub fn replaced(&mut self, address: usize, size: usize) {
+        if !sequences_used() {
+            return;
+        }
+        if self.replaced.len() >= MAX_REMEMBERED {
+            self.replaced.clear();
+        }
+        self.replaced.insert(address, (current_sequence(), size));
+    }
+
+    /// An unknown address was freed; maybe its allocation is still on the
+    /// way.
+    pub fn unmatched_free(&mut self, address: usize) {
+        if !sequences_used() {
+            return;
+        }
+        if self.unmatched_frees.len() >= MAX_REMEMBERED {
+            self.unmatched_frees.clear();
+        }
+        self.unmatched_frees.insert(address, current_sequence());
+    }
+
+    /// If a free() with this sequence number happened before the address was
+    /// reused, it's for the replaced allocation: returns that allocation's
+    /// size, and it shouldn't touch the current one.
+    pub fn stale_free(&mut self, address: usize, sequence: u64) -> Option<usize> {
+        if sequence == UNKNOWN_SEQUENCE || self.replaced.is_empty() {
+            return None;
+        }
+        match self.replaced.get(&address) {
+            Some((replaced_at, size)) if sequence < *replaced_at => {
+                let size = *size;
+                self.replaced.remove(&address);
+                Some(size)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether an allocation with this sequence number was already freed,
+    /// i.e. the free arrived first, so it shouldn't be recorded.
+    pub fn stale_allocation(&mut self, address: usize, sequence: u64) -> bool {
+        if sequence == UNKNOWN_SEQUENCE || self.unmatched_frees.is_empty() {
+            return false;
+        }
+        match self.unmatched_frees.get(&address) {
+            Some(freed_at) if sequence < *freed_at => {
+                self.unmatched_frees.remove(&address);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.replaced.clear();
+        self.unmatched_frees.clear();
+    }
+
+    /// Roughly how much memory this uses.
+    pub fn memory_usage(&self) -> usize {
+        crate::util::hashmap_bytes(&self.replaced)
+            + crate::util::hashmap_bytes(&self.unmatched_frees)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{next_sequence, Reconciler, UNKNOWN_SEQUENCE};
+
+    #[test]
+    fn only_older_events_are_stale() {
+        let mut reconciler = Reconciler::default();
+        let before = next_sequence();
+        reconciler.replaced(0x1000, 100);
+        let after = next_sequence();
+        assert!(before < after);
+        assert_eq!(reconciler.stale_free(0x1000, UNKNOWN_SEQUENCE), None);
+        assert_eq!(reconciler.stale_free(0x1000, after), None);
+        assert_eq!(reconciler.stale_free(0x2000, before), None);
+        assert_eq!(reconciler.stale_free(0x1000, before), Some(100));
+        // Only reconciled once:
+        assert_eq!(reconciler.stale_free(0x1000, before), None);
+
+        let before = next_sequence();
+        reconciler.unmatched_free(0x3000);
+        assert!(!reconciler.stale_allocation(0x3000, UNKNOWN_SEQUENCE));
+        assert!(!reconciler.stale_allocation(0x3000, next_sequence()));
+        assert!(reconciler.stale_allocation(0x3000, before));
+        assert!(!reconciler.stale_allocation(0x3000, before));
+    }
+}
@@ -0,0 +1,179 @@
+// Where report files go. Reports are written as a set of named files (e.g.
+// "peak-memory.svg"); a sink decides what to do with each one, so embedders
+// aren't limited to a local directory.
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+use crate::paths::{create_directory, expand_path};
+
+/// Destination for report files.
+pub trait OutputSink {
+    /// Store a complete file.
+    fn write_file(&mut self, name: &str, contents: &[u8]) -> std::io::Result<()>;
+
+    /// Where a file ended up, for messages to the user.
+    fn describe(&self, name: &str) -> String;
+}
+
+/// Writes files into a directory on disk, creating it if necessary.
+pub struct DirectorySink {
+    directory: PathBuf,
+}
+
+impl DirectorySink {
+    /// `~` and environment variables in the path are expanded, see
+    /// `paths::expand_path()`.
+    pub fn new<P: AsRef<Path>>(directory: P) -> std::io::Result<Self> {
+        let directory = match directory.as_ref().to_str() {
+            Some(path) => expand_path(path)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?,
+            None => directory.as_ref().to_path_buf(),
+        };
+        create_directory(&directory)?;
+        Ok(Self { directory })
+    }
+
+    /// The directory, after expansion.
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.directory.join(name)
+    }
+}
+
+impl OutputSink for DirectorySink {
+    fn write_file(&mut self, name: &str, contents: &[u8]) -> std::io::Result<()> {
+        std::fs::write(self.path(name), contents)
+    }
+
+    fn describe(&self, name: &str) -> String {
+        format!("{:?}", self.path(name))
+    }
+}
+
+/// Keeps files in memory, e.g. for embedders that want to post-process them.
+#[derive(Default)]
+pub struct MemorySink {
+    pub files: BTreeMap<String, Vec<u8>>,
+}
+
+impl OutputSink for MemorySink {
+    fn write_file(&mut self, name: &str, contents: &[u8]) -> std::io::Result<()> {
+        self.files.insert(name.to_string(), contents.to_vec());
+        Ok(())
+    }
+
+    fn describe(&self, name: &str) -> String {
+        format!("memory ({})", name)
+    }
+}
+
+/// Sends files over a Unix domain socket. Each file is a header line of
+/// `<name> <length>\n` followed by exactly `length` bytes of content.
+pub struct SocketSink {
+    stream: UnixStream,
+    description: String,
+}
+
+impl SocketSink {
+    pub fn connect<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        Ok(Self {
+            stream: UnixStream::connect(path.as_ref())?,
+            description: format!("{:?}", path.as_ref()),
+        })
+    }
+}
+
+impl OutputSink for SocketSink {
+    fn write_file(&mut self, name: &str, contents: &[u8]) -> std::io::Result<()> {
+        writeln!(self.stream, "{} {}", name, contents.len())?;
+        self.stream.write_all(contents)?;
+        self.stream.flush()
+    }
+
+    fn describe(&self, name: &str) -> String {
+        format!("socket {} ({})", self.description, name)
+    }
+}
+
+/// Hands each file to a callback, e.g. one uploading to object storage.
+pub struct CallbackSink<F: FnMut(&str, &[u8]) -> std::io::Result<()>> {
+    callback: F,
+}
+
+impl<F: FnMut(&str, &[u8]) -> std::io::Result<()>> CallbackSink<F> {
+    pub fn new(callback: F) -> Self {
+        Self { callback }
+    }
+}
+
+impl<F: FnMut(&str, &[u8]) -> std::io::Result<()>> OutputSink for CallbackSink<F> {
+    fn write_file(&mut self, name: &str, contents: &[u8]) -> std::io::Result<()> {
+        (self.callback)(name, contents)
+    }
+
+    fn describe(&self, name: &str) -> String {
+        name.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CallbackSink, DirectorySink, MemorySink, OutputSink, SocketSink};
+    use std::io::Read;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn directory_sink_creates_directory() {
+        let directory = std::env::temp_dir().join(format!("fil-sink-{}", std::process::id()));
+        let mut sink = DirectorySink::new(directory.join("nested")).unwrap();
+        sink.write_file("a.txt", b"hello").unwrap();
+        assert_eq!(
+            std::fs::read(directory.join("nested").join("a.txt")).unwrap(),
+            b"hello"
+        );
+        assert!(DirectorySink::new(directory.join("nested").join("a.txt")).is_err());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn memory_and_callback_sinks() {
+        let mut sink = MemorySink::default();
+        sink.write_file("a.svg", b"<svg>").unwrap();
+        assert_eq!(sink.files["a.svg"], b"<svg>");
+
+        let mut names = vec![];
+        let mut sink = CallbackSink::new(|name: &str, _: &[u8]| {
+            names.push(name.to_string());
+            Ok(())
+        });
+        sink.write_file("a.prof", b"").unwrap();
+        sink.write_file("b.prof", b"").unwrap();
+        drop(sink);
+        assert_eq!(names, vec!["a.prof", "b.prof"]);
+    }
+
+    #[test]
+    fn socket_sink_frames_files() {
+        let path = std::env::temp_dir().join(format!("fil-sink-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let mut sink = SocketSink::connect(&path).unwrap();
+        sink.write_file("a.txt", b"hi\n").unwrap();
+        sink.write_file("b.txt", b"").unwrap();
+        drop(sink);
+        let mut received = String::new();
+        listener
+            .accept()
+            .unwrap()
+            .0
+            .read_to_string(&mut received)
+            .unwrap();
+        assert_eq!(received, "a.txt 3\nhi\nb.txt 0\n");
+        std::fs::remove_file(path).unwrap();
+    }
+}
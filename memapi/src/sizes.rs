@@ -0,0 +1,147 @@
+// Sizes of the allocations each callstack made over the whole run: how many,
+// the smallest, the largest and the average. Totals can hide outliers, e.g. a
+// callsite that usually allocates a few KiB but occasionally asks for a
+// gigabyte, or one making millions of tiny allocations.
+use serde_json::{json, Value};
+
+use crate::snapshot::{get_usize, Frame, ProfileData};
+
+/// Every allocation a callstack made, freed or not.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AllocationSizes {
+    pub count: usize,
+    pub total_bytes: usize,
+    pub min_bytes: usize,
+    pub max_bytes: usize,
+}
+
+impl AllocationSizes {
+    #[inline]
+    pub fn record(&mut self, size: usize) {
+        self.min_bytes = if self.count == 0 {
+            size
+        } else {
+            self.min_bytes.min(size)
+        };
+        self.max_bytes = self.max_bytes.max(size);
+        self.total_bytes = self.total_bytes.saturating_add(size);
+        self.count += 1;
+    }
+
+    /// Combine with another callstack's sizes.
+    pub fn merge(&mut self, other: &AllocationSizes) {
+        if other.count == 0 {
+            return;
+        }
+        self.min_bytes = if self.count == 0 {
+            other.min_bytes
+        } else {
+            self.min_bytes.min(other.min_bytes)
+        };
+        self.max_bytes = self.max_bytes.max(other.max_bytes);
+        self.total_bytes = self.total_bytes.saturating_add(other.total_bytes);
+        self.count += other.count;
+    }
+
+    /// None if nothing was allocated.
+    pub fn average(&self) -> Option<f64> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.total_bytes as f64 / self.count as f64)
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "count": self.count,
+            "total_bytes": self.total_bytes,
+            "min_bytes": self.min_bytes,
+            "max_bytes": self.max_bytes,
+            "average_bytes": self.average(),
+        })
+    }
+
+    pub fn from_json(value: &Value) -> Result<Self, String> {
+        Ok(Self {
+            count: get_usize(value, "count")?,
+            total_bytes: get_usize(value, "total_bytes")?,
+            min_bytes: get_usize(value, "min_bytes")?,
+            max_bytes: get_usize(value, "max_bytes")?,
+        })
+    }
+}
+
+/// Allocation sizes per callsite, i.e. per innermost frame, largest maximum
+/// first. Callstacks without frames are left out.
+pub fn sizes_per_callsite(data: &ProfileData) -> Vec<(&Frame, AllocationSizes)> {
+    let mut result: Vec<(&Frame, AllocationSizes)> = vec![];
+    let mut callstacks: Vec<_> = data
+        .callstacks
+        .iter()
+        .filter(|usage| usage.sizes.count > 0)
+        .filter_map(|usage| Some((usage.frames.last()?, &usage.sizes)))
+        .collect();
+    callstacks.sort_by(|a, b| a.0.cmp(b.0));
+    for (frame, sizes) in callstacks {
+        match result.last_mut() {
+            Some((previous, total)) if *previous == frame => total.merge(sizes),
+            _ => result.push((frame, *sizes)),
+        }
+    }
+    // Ties broken by frame, which they're already sorted by:
+    result.sort_by_key(|(_, sizes)| std::cmp::Reverse(sizes.max_bytes));
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sizes_per_callsite, AllocationSizes};
+    use crate::folded::parse_folded_str;
+
+    #[test]
+    fn min_average_max() {
+        let mut sizes = AllocationSizes::default();
+        assert_eq!(sizes.average(), None);
+        sizes.record(100);
+        sizes.record(10);
+        sizes.record(1000);
+        assert_eq!(
+            sizes,
+            AllocationSizes {
+                count: 3,
+                total_bytes: 1110,
+                min_bytes: 10,
+                max_bytes: 1000,
+            }
+        );
+        assert_eq!(sizes.average(), Some(370.0));
+        let mut other = AllocationSizes::default();
+        other.record(5);
+        sizes.merge(&other);
+        sizes.merge(&AllocationSizes::default());
+        assert_eq!(sizes.min_bytes, 5);
+        assert_eq!(sizes.count, 4);
+        assert_eq!(AllocationSizes::from_json(&sizes.to_json()).unwrap(), sizes);
+    }
+
+    #[test]
+    fn grouped_by_innermost_frame() {
+        let mut data =
+            parse_folded_str("a.py:1 (main);b.py:2 (f) 1\nc.py:1 (g);b.py:2 (f) 1\na.py:3 (h) 1\n")
+                .unwrap();
+        for usage in data.callstacks.iter_mut() {
+            usage.sizes.record(match usage.frames[0].function.as_str() {
+                "main" => 10,
+                "g" => 20,
+                _ => 1000,
+            });
+        }
+        let callsites = sizes_per_callsite(&data);
+        let summary: Vec<_> = callsites
+            .iter()
+            .map(|(frame, sizes)| (frame.function.as_str(), sizes.count, sizes.max_bytes))
+            .collect();
+        assert_eq!(summary, vec![("h", 1, 1000), ("f", 2, 20)]);
+    }
+}
@@ -0,0 +1,637 @@
+// A plain-data snapshot of what the tracker knows. This is all that reporting
+// needs, so reports can be rendered without access to the tracker, e.g. by
+// offline tools loading a snapshot written out earlier.
+use serde_json::{json, Value};
+
+use crate::addressspace::AddressSpaceStats;
+use crate::ages::{AgeHistogram, AGE_BUCKETS};
+use crate::locked::LockedMemoryStats;
+use crate::memorytracking::LineNumber;
+use crate::metadata::ProcessMetadata;
+use crate::metric::MEMORY;
+use crate::numa::NumaStats;
+use crate::sizes::AllocationSizes;
+use crate::stableid::{callsite_id, callstack_fingerprint, callstack_id, StableId};
+use crate::timeline::TimelineData;
+use crate::window::WindowPeak;
+
+/// A frame in a callstack, with everything resolved to strings.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Frame {
+    pub function: String,
+    pub filename: String,
+    pub line_number: LineNumber,
+    /// Dotted module name, if it was registered.
+    pub module: Option<String>,
+}
+
+/// Memory used by a single callstack.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CallstackUsage {
+    /// Outermost frame first.
+    pub frames: Vec<Frame>,
+    /// Bytes allocated by this callstack at the time of peak memory usage.
+    pub peak_bytes: usize,
+    /// Bytes allocated by this callstack when the snapshot was taken.
+    pub current_bytes: usize,
+    /// Live allocations from this callstack when the most allocations were
+    /// live at once.
+    pub peak_allocations: usize,
+    /// Live allocations from this callstack at the time of peak memory
+    /// usage.
+    pub allocations_at_peak: usize,
+    /// Sizes of every allocation this callstack made.
+    pub sizes: AllocationSizes,
+    /// The most allocations per second this callstack made; see
+    /// hotloops.rs.
+    pub peak_allocation_rate: u64,
+    /// malloc()-style bytes, i.e. not mmap(), allocated by this callstack at
+    /// the time the heap alone peaked, which needn't be the same moment as
+    /// peak_bytes.
+    pub heap_peak_bytes: usize,
+}
+
+impl Frame {
+    /// The same in every run; see stableid.rs.
+    pub fn stable_id(&self) -> StableId {
+        callsite_id(
+            self.module.as_deref(),
+            &self.filename,
+            &self.function,
+            self.line_number,
+        )
+    }
+}
+
+impl CallstackUsage {
+    /// The same in every run; see stableid.rs.
+    pub fn stable_id(&self) -> StableId {
+        callstack_id(self.frames.iter().map(|frame| frame.stable_id()))
+    }
+
+    /// Like stable_id(), but ignoring line numbers, so it stays the same
+    /// when edits elsewhere in a file move the code around; see stableid.rs.
+    pub fn fingerprint(&self) -> StableId {
+        callstack_fingerprint(self.frames.iter().map(|frame| {
+            (
+                frame.module.as_deref(),
+                frame.filename.as_str(),
+                frame.function.as_str(),
+            )
+        }))
+    }
+}
+
+/// How many times a function was called.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FunctionCalls {
+    pub function: String,
+    pub filename: String,
+    pub calls: u64,
+}
+
+/// Allocations from one callstack that were freed by another.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LifetimePair {
+    pub allocated_by: Vec<Frame>,
+    pub freed_by: Vec<Frame>,
+    pub allocations: usize,
+    pub bytes: usize,
+    /// Summed over all the allocations, in seconds.
+    pub total_lifetime: f64,
+    /// In seconds.
+    pub max_lifetime: f64,
+}
+
+impl LifetimePair {
+    /// Average lifetime in seconds.
+    pub fn average_lifetime(&self) -> f64 {
+        self.total_lifetime / self.allocations.max(1) as f64
+    }
+}
+
+/// Memory used by a CPython subinterpreter.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InterpreterStats {
+    pub interpreter: u64,
+    pub current_bytes: usize,
+    /// This interpreter's own peak, not its usage at the overall peak.
+    pub peak_bytes: usize,
+}
+
+/// How old one callstack's live allocations were, in bytes per bucket of
+/// AGE_BUCKETS.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CallstackAges {
+    pub frames: Vec<Frame>,
+    /// Ages at the time of peak memory usage.
+    pub peak_bytes: AgeHistogram,
+    /// Ages when the snapshot was taken.
+    pub current_bytes: AgeHistogram,
+}
+
+/// Rough memory usage of the profiler's own data structures, which isn't
+/// included in any of the tracked numbers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProfilerMemory {
+    /// Function names, filenames and callstacks.
+    pub callsites: usize,
+    /// Live allocations and mmap()s.
+    pub allocations: usize,
+    /// Current and peak usage per callstack.
+    pub usage: usize,
+}
+
+impl ProfilerMemory {
+    pub fn total(&self) -> usize {
+        self.callsites + self.allocations + self.usage
+    }
+}
+
+/// Everything needed to write a report.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProfileData {
+    /// What was tracked, e.g. "memory"; see metric.rs.
+    pub resource: String,
+    /// Only callstacks that use some memory, sorted by frames.
+    pub callstacks: Vec<CallstackUsage>,
+    pub peak_bytes: usize,
+    pub current_bytes: usize,
+    pub peak_allocations: usize,
+    /// Allocations that disappeared without being freed.
+    pub missing_bytes: usize,
+    /// Frees of allocations that weren't tracked.
+    pub failed_deallocations: usize,
+    /// Impossible events, e.g. absurd sizes or freeing more than was
+    /// allocated, which were ignored or clamped rather than trusted.
+    pub accounting_errors: usize,
+    pub metadata: ProcessMetadata,
+    /// Per-NUMA-node totals, if enabled, including live allocations.
+    pub numa: Option<NumaStats>,
+    /// Functions that were called at least once, sorted by filename and
+    /// function.
+    pub function_calls: Vec<FunctionCalls>,
+    /// Who freed what, if enabled, sorted by callstacks.
+    pub lifetime_pairs: Vec<LifetimePair>,
+    /// Subinterpreters that allocated memory, by interpreter ID.
+    pub interpreters: Vec<InterpreterStats>,
+    /// Fil's own overhead, not included in any of the above.
+    pub profiler_memory: ProfilerMemory,
+    /// Virtual address space, with its own peak.
+    pub address_space: AddressSpaceStats,
+    /// mlock()ed memory and huge pages; see locked.rs.
+    pub locked_memory: LockedMemoryStats,
+    /// Ages of live allocations, if enabled, sorted by frames.
+    pub allocation_ages: Vec<CallstackAges>,
+    /// Peak usage over a recent time window, if enabled.
+    pub window_peak: Option<WindowPeak>,
+    /// Total usage over time.
+    pub timeline: TimelineData,
+    /// Whether the heap alone, without mmap(), peaked at a different moment
+    /// than peak_bytes.
+    pub heap_peaked_separately: bool,
+}
+
+impl ProfileData {
+    /// The heap's own peak, malloc() and friends without mmap().
+    pub fn heap_peak_bytes(&self) -> usize {
+        self.callstacks
+            .iter()
+            .map(|usage| usage.heap_peak_bytes)
+            .sum()
+    }
+
+    /// The same data but with the heap-only peak as the peak, if the heap
+    /// peaked at a different moment than the combined peak. Otherwise the
+    /// combined peak already shows the heap at its peak.
+    pub fn heap_peak(&self) -> Option<ProfileData> {
+        if !self.heap_peaked_separately {
+            return None;
+        }
+        let callstacks = self
+            .callstacks
+            .iter()
+            .filter(|usage| usage.heap_peak_bytes > 0)
+            .map(|usage| CallstackUsage {
+                peak_bytes: usage.heap_peak_bytes,
+                ..usage.clone()
+            })
+            .collect();
+        Some(ProfileData {
+            callstacks,
+            peak_bytes: self.heap_peak_bytes(),
+            ..self.clone()
+        })
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "resource": self.resource,
+            "callstacks": self.callstacks.iter().map(|usage| json!({
+                "id": usage.stable_id().to_string(),
+                "fingerprint": usage.fingerprint().to_string(),
+                "frames": frames_to_json(&usage.frames),
+                "peak_bytes": usage.peak_bytes,
+                "current_bytes": usage.current_bytes,
+                "peak_allocations": usage.peak_allocations,
+                "allocations_at_peak": usage.allocations_at_peak,
+                "allocation_sizes": usage.sizes.to_json(),
+                "peak_allocation_rate": usage.peak_allocation_rate,
+                "heap_peak_bytes": usage.heap_peak_bytes,
+            })).collect::<Vec<_>>(),
+            "peak_bytes": self.peak_bytes,
+            "current_bytes": self.current_bytes,
+            "peak_allocations": self.peak_allocations,
+            "missing_bytes": self.missing_bytes,
+            "failed_deallocations": self.failed_deallocations,
+            "accounting_errors": self.accounting_errors,
+            "metadata": self.metadata.to_json(),
+            "numa": self.numa.as_ref().map(|numa| numa.to_json()),
+            "function_calls": self.function_calls.iter().map(|calls| json!({
+                "function": calls.function,
+                "filename": calls.filename,
+                "calls": calls.calls,
+            })).collect::<Vec<_>>(),
+            "lifetime_pairs": self.lifetime_pairs.iter().map(|pair| json!({
+                "allocated_by": frames_to_json(&pair.allocated_by),
+                "freed_by": frames_to_json(&pair.freed_by),
+                "allocations": pair.allocations,
+                "bytes": pair.bytes,
+                "total_lifetime": pair.total_lifetime,
+                "max_lifetime": pair.max_lifetime,
+            })).collect::<Vec<_>>(),
+            "interpreters": self.interpreters.iter().map(|stats| json!({
+                "interpreter": stats.interpreter,
+                "current_bytes": stats.current_bytes,
+                "peak_bytes": stats.peak_bytes,
+            })).collect::<Vec<_>>(),
+            "profiler_memory": {
+                "callsites": self.profiler_memory.callsites,
+                "allocations": self.profiler_memory.allocations,
+                "usage": self.profiler_memory.usage,
+            },
+            "address_space": self.address_space.to_json(),
+            "locked_memory": self.locked_memory.to_json(),
+            "allocation_ages": ages_to_json(&self.allocation_ages),
+            "window_peak": self.window_peak.as_ref().map(|window_peak| window_peak.to_json()),
+            "timeline": self.timeline.to_json(),
+            "heap_peaked_separately": self.heap_peaked_separately,
+        })
+    }
+
+    pub fn from_json(value: &Value) -> Result<Self, String> {
+        let callstacks = value["callstacks"]
+            .as_array()
+            .ok_or("missing callstacks")?
+            .iter()
+            .map(|usage| {
+                Ok(CallstackUsage {
+                    frames: frames_from_json(&usage["frames"])?,
+                    peak_bytes: get_usize(usage, "peak_bytes")?,
+                    current_bytes: get_usize(usage, "current_bytes")?,
+                    peak_allocations: get_usize(usage, "peak_allocations")?,
+                    // Older snapshots don't have this:
+                    allocations_at_peak: match &usage["allocations_at_peak"] {
+                        Value::Null => 0,
+                        _ => get_usize(usage, "allocations_at_peak")?,
+                    },
+                    // Or allocation sizes:
+                    sizes: match &usage["allocation_sizes"] {
+                        Value::Null => AllocationSizes::default(),
+                        sizes => AllocationSizes::from_json(sizes)?,
+                    },
+                    // Or allocation rates:
+                    peak_allocation_rate: match &usage["peak_allocation_rate"] {
+                        Value::Null => 0,
+                        _ => get_usize(usage, "peak_allocation_rate")? as u64,
+                    },
+                    // Or a separate heap peak, in which case there's no
+                    // telling mmap() apart:
+                    heap_peak_bytes: match &usage["heap_peak_bytes"] {
+                        Value::Null => get_usize(usage, "peak_bytes")?,
+                        _ => get_usize(usage, "heap_peak_bytes")?,
+                    },
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        // Older snapshots don't have call counts:
+        let function_calls = match value["function_calls"].as_array() {
+            None => vec![],
+            Some(function_calls) => function_calls
+                .iter()
+                .map(|calls| {
+                    Ok(FunctionCalls {
+                        function: get_string(calls, "function")?,
+                        filename: get_string(calls, "filename")?,
+                        calls: get_usize(calls, "calls")? as u64,
+                    })
+                })
+                .collect::<Result<_, String>>()?,
+        };
+        // Nor do they have lifetime pairs:
+        let lifetime_pairs = match value["lifetime_pairs"].as_array() {
+            None => vec![],
+            Some(pairs) => pairs
+                .iter()
+                .map(|pair| {
+                    Ok(LifetimePair {
+                        allocated_by: frames_from_json(&pair["allocated_by"])?,
+                        freed_by: frames_from_json(&pair["freed_by"])?,
+                        allocations: get_usize(pair, "allocations")?,
+                        bytes: get_usize(pair, "bytes")?,
+                        total_lifetime: get_f64(pair, "total_lifetime")?,
+                        max_lifetime: get_f64(pair, "max_lifetime")?,
+                    })
+                })
+                .collect::<Result<_, String>>()?,
+        };
+        // Or subinterpreters:
+        let interpreters = match value["interpreters"].as_array() {
+            None => vec![],
+            Some(interpreters) => interpreters
+                .iter()
+                .map(|stats| {
+                    Ok(InterpreterStats {
+                        interpreter: get_usize(stats, "interpreter")? as u64,
+                        current_bytes: get_usize(stats, "current_bytes")?,
+                        peak_bytes: get_usize(stats, "peak_bytes")?,
+                    })
+                })
+                .collect::<Result<_, String>>()?,
+        };
+        // Or the profiler's own memory:
+        let profiler_memory = match &value["profiler_memory"] {
+            Value::Null => ProfilerMemory::default(),
+            memory => ProfilerMemory {
+                callsites: get_usize(memory, "callsites")?,
+                allocations: get_usize(memory, "allocations")?,
+                usage: get_usize(memory, "usage")?,
+            },
+        };
+        // Or accounting errors:
+        let accounting_errors = match &value["accounting_errors"] {
+            Value::Null => 0,
+            _ => get_usize(value, "accounting_errors")?,
+        };
+        // Or allocation ages:
+        let allocation_ages = match value["allocation_ages"].as_array() {
+            None => vec![],
+            Some(ages) => ages
+                .iter()
+                .map(|ages| {
+                    Ok(CallstackAges {
+                        frames: frames_from_json(&ages["frames"])?,
+                        peak_bytes: histogram_from_json(&ages["peak_bytes"])?,
+                        current_bytes: histogram_from_json(&ages["current_bytes"])?,
+                    })
+                })
+                .collect::<Result<_, String>>()?,
+        };
+        Ok(Self {
+            // Older snapshots are all of memory:
+            resource: match &value["resource"] {
+                Value::Null => MEMORY.to_string(),
+                _ => get_string(value, "resource")?,
+            },
+            callstacks,
+            peak_bytes: get_usize(value, "peak_bytes")?,
+            current_bytes: get_usize(value, "current_bytes")?,
+            peak_allocations: get_usize(value, "peak_allocations")?,
+            missing_bytes: get_usize(value, "missing_bytes")?,
+            failed_deallocations: get_usize(value, "failed_deallocations")?,
+            accounting_errors,
+            metadata: ProcessMetadata::from_json(&value["metadata"])?,
+            numa: match &value["numa"] {
+                Value::Null => None,
+                numa => Some(NumaStats::from_json(numa)?),
+            },
+            function_calls,
+            lifetime_pairs,
+            interpreters,
+            profiler_memory,
+            // Or address space:
+            address_space: match &value["address_space"] {
+                Value::Null => AddressSpaceStats::default(),
+                address_space => AddressSpaceStats::from_json(address_space)?,
+            },
+            // Or locked memory:
+            locked_memory: match &value["locked_memory"] {
+                Value::Null => LockedMemoryStats::default(),
+                locked_memory => LockedMemoryStats::from_json(locked_memory)?,
+            },
+            allocation_ages,
+            // Or a windowed peak:
+            window_peak: match &value["window_peak"] {
+                Value::Null => None,
+                window_peak => Some(WindowPeak::from_json(window_peak)?),
+            },
+            // Or a timeline:
+            timeline: match &value["timeline"] {
+                Value::Null => TimelineData::default(),
+                timeline => TimelineData::from_json(timeline)?,
+            },
+            heap_peaked_separately: value["heap_peaked_separately"].as_bool().unwrap_or(false),
+        })
+    }
+}
+
+/// The allocation ages, with each histogram as an object keyed by bucket
+/// label.
+pub fn ages_to_json(ages: &[CallstackAges]) -> Value {
+    let histogram = |histogram: &AgeHistogram| {
+        AGE_BUCKETS
+            .iter()
+            .zip(histogram.iter())
+            .map(|((label, _), bytes)| (label.to_string(), json!(bytes)))
+            .collect::<serde_json::Map<_, _>>()
+    };
+    ages.iter()
+        .map(|ages| {
+            json!({
+                "frames": frames_to_json(&ages.frames),
+                "peak_bytes": histogram(&ages.peak_bytes),
+                "current_bytes": histogram(&ages.current_bytes),
+            })
+        })
+        .collect()
+}
+
+fn histogram_from_json(value: &Value) -> Result<AgeHistogram, String> {
+    let mut histogram = AgeHistogram::default();
+    for ((label, _), bytes) in AGE_BUCKETS.iter().zip(histogram.iter_mut()) {
+        *bytes = get_usize(value, label)?;
+    }
+    Ok(histogram)
+}
+
+pub(crate) fn frame_to_json(frame: &Frame) -> Value {
+    json!({
+        "id": frame.stable_id().to_string(),
+        "function": frame.function,
+        "filename": frame.filename,
+        "line_number": frame.line_number,
+        "module": frame.module,
+    })
+}
+
+fn frames_to_json(frames: &[Frame]) -> Value {
+    frames.iter().map(frame_to_json).collect()
+}
+
+fn frames_from_json(value: &Value) -> Result<Vec<Frame>, String> {
+    value
+        .as_array()
+        .ok_or("missing frames")?
+        .iter()
+        .map(|frame| {
+            Ok(Frame {
+                function: get_string(frame, "function")?,
+                filename: get_string(frame, "filename")?,
+                line_number: get_usize(frame, "line_number")? as LineNumber,
+                module: frame["module"].as_str().map(|m| m.to_string()),
+            })
+        })
+        .collect()
+}
+
+fn get_f64(value: &Value, key: &str) -> Result<f64, String> {
+    value[key]
+        .as_f64()
+        .ok_or_else(|| format!("missing or invalid {}", key))
+}
+
+pub(crate) fn get_string(value: &Value, key: &str) -> Result<String, String> {
+    value[key]
+        .as_str()
+        .map(|s| s.to_string())
+        .ok_or_else(|| format!("missing or invalid {}", key))
+}
+
+pub(crate) fn get_usize(value: &Value, key: &str) -> Result<usize, String> {
+    value[key]
+        .as_u64()
+        .map(|n| n as usize)
+        .ok_or_else(|| format!("missing or invalid {}", key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        CallstackAges, CallstackUsage, Frame, FunctionCalls, InterpreterStats, LifetimePair,
+        ProfileData, ProfilerMemory,
+    };
+    use crate::addressspace::AddressSpaceStats;
+    use crate::locked::LockedMemoryStats;
+    use crate::metadata::ProcessMetadata;
+    use crate::numa::NumaStats;
+    use crate::sizes::AllocationSizes;
+    use crate::timeline::{TimelineData, TimelineMarker, TimelineSample};
+    use crate::window::WindowPeak;
+
+    #[test]
+    fn json_roundtrip() {
+        let data = ProfileData {
+            resource: "gpu-memory".to_string(),
+            callstacks: vec![CallstackUsage {
+                frames: vec![
+                    Frame {
+                        function: "main".to_string(),
+                        filename: "/src/main.py".to_string(),
+                        line_number: 3,
+                        module: None,
+                    },
+                    Frame {
+                        function: "Loader.load".to_string(),
+                        filename: "/src/pkg/data.py".to_string(),
+                        line_number: 70000,
+                        module: Some("pkg.data".to_string()),
+                    },
+                ],
+                peak_bytes: 1000,
+                current_bytes: 10,
+                peak_allocations: 2,
+                allocations_at_peak: 1,
+                sizes: AllocationSizes {
+                    count: 3,
+                    total_bytes: 1500,
+                    min_bytes: 100,
+                    max_bytes: 1000,
+                },
+                peak_allocation_rate: 25_000,
+                heap_peak_bytes: 600,
+            }],
+            peak_bytes: 1000,
+            current_bytes: 10,
+            peak_allocations: 2,
+            missing_bytes: 0,
+            failed_deallocations: 7,
+            accounting_errors: 2,
+            metadata: ProcessMetadata::collect(),
+            numa: Some(NumaStats::new(1024)),
+            function_calls: vec![FunctionCalls {
+                function: "main".to_string(),
+                filename: "/src/main.py".to_string(),
+                calls: 1,
+            }],
+            lifetime_pairs: vec![LifetimePair {
+                allocated_by: vec![],
+                freed_by: vec![Frame {
+                    function: "main".to_string(),
+                    filename: "/src/main.py".to_string(),
+                    line_number: 4,
+                    module: None,
+                }],
+                allocations: 2,
+                bytes: 100,
+                total_lifetime: 0.5,
+                max_lifetime: 0.25,
+            }],
+            interpreters: vec![InterpreterStats {
+                interpreter: 1,
+                current_bytes: 5,
+                peak_bytes: 10,
+            }],
+            profiler_memory: ProfilerMemory {
+                callsites: 1,
+                allocations: 2,
+                usage: 3,
+            },
+            address_space: AddressSpaceStats {
+                current_bytes: 4096,
+                peak_bytes: 8192,
+            },
+            locked_memory: LockedMemoryStats {
+                current_bytes: 4096,
+                peak_bytes: 8192,
+                huge_page_bytes: 2 << 20,
+                all_current: false,
+                all_future: true,
+                limit: Some(65536),
+            },
+            allocation_ages: vec![CallstackAges {
+                frames: vec![],
+                peak_bytes: [1, 2, 3, 4, 5],
+                current_bytes: [0, 0, 0, 0, 10],
+            }],
+            window_peak: Some(WindowPeak {
+                window_seconds: 600,
+                peak_bytes: 1234,
+            }),
+            timeline: TimelineData {
+                samples: vec![TimelineSample {
+                    elapsed_ms: 10,
+                    bytes: 100,
+                }],
+                markers: vec![TimelineMarker {
+                    elapsed_ms: 5,
+                    label: "start".to_string(),
+                }],
+            },
+            heap_peaked_separately: true,
+        };
+        let serialized = serde_json::to_string(&data.to_json()).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(ProfileData::from_json(&value), Ok(data));
+        assert!(ProfileData::from_json(&serde_json::json!({})).is_err());
+    }
+}
@@ -0,0 +1,132 @@
+// IDs for callsites and callstacks that are the same in every run, so raw
+// outputs from different runs can be merged or diffed by ID, and tools can
+// cache whatever they've worked out about a callsite.
+//
+// The tracker's own FunctionIds and CallstackIds are handed out in order of
+// first use, since they index vectors on the hot path; these are derived from
+// the names instead, only when writing output. A callsite is identified by
+// its module, function and line; the filename stands in for the module when
+// that isn't known, in which case the ID is only stable as long as the code
+// lives at the same path.
+//
+// Editing a file shifts the line numbers of everything below the edit, which
+// changes the IDs even though the allocation sites are the same. A callstack's
+// fingerprint leaves the line numbers out, so it survives such edits, at the
+// cost of merging callstacks that differ only in which line of a function
+// they went through.
+use crate::memorytracking::LineNumber;
+
+/// A 64-bit ID, written as 16 hex digits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StableId(pub u64);
+
+impl std::fmt::Display for StableId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// FNV-1a: simple, and unlike the hashers used for the tracker's hashmaps,
+/// not randomly seeded, nor liable to change across Rust versions. Also used
+/// for redaction's opaque names.
+pub(crate) struct Fnv(pub u64);
+
+impl Fnv {
+    pub fn new() -> Self {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    /// A field, followed by a byte that can't appear in UTF-8, so ("ab", "c")
+    /// and ("a", "bc") differ.
+    fn field(&mut self, bytes: &[u8]) {
+        self.write(bytes);
+        self.write(&[0xff]);
+    }
+}
+
+/// The ID of a callsite.
+pub fn callsite_id(
+    module: Option<&str>,
+    filename: &str,
+    function: &str,
+    line: LineNumber,
+) -> StableId {
+    let mut hash = Fnv::new();
+    hash.field(module.unwrap_or(filename).as_bytes());
+    hash.field(function.as_bytes());
+    hash.field(&line.to_le_bytes());
+    StableId(hash.0)
+}
+
+/// The ID of a callstack, given its callsites' IDs, outermost first.
+pub fn callstack_id(callsites: impl IntoIterator<Item = StableId>) -> StableId {
+    let mut hash = Fnv::new();
+    for callsite in callsites {
+        hash.write(&callsite.0.to_le_bytes());
+    }
+    StableId(hash.0)
+}
+
+/// The fingerprint of a callstack, given each frame's module, filename and
+/// function, outermost first: its ID as if every line number were 0.
+pub fn callstack_fingerprint<'a>(
+    frames: impl IntoIterator<Item = (Option<&'a str>, &'a str, &'a str)>,
+) -> StableId {
+    callstack_id(
+        frames
+            .into_iter()
+            .map(|(module, filename, function)| callsite_id(module, filename, function, 0)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{callsite_id, callstack_fingerprint, callstack_id};
+
+    #[test]
+    fn ids_depend_only_on_names() {
+        let load = callsite_id(Some("app.data"), "/srv/app/data.py", "load", 7);
+        // Fixed, so it's the same in every run and every version:
+        assert_eq!(load.to_string(), "7cf5e5455fc5e30c");
+        assert_eq!(
+            load,
+            callsite_id(Some("app.data"), "/home/me/app/data.py", "load", 7)
+        );
+        assert_ne!(load, callsite_id(Some("app.data"), "", "load", 8));
+        assert_ne!(load, callsite_id(Some("app.dat"), "", "aload", 7));
+        // Without a module, the filename is used:
+        assert_eq!(
+            callsite_id(None, "data.py", "load", 7),
+            callsite_id(Some("data.py"), "elsewhere.py", "load", 7)
+        );
+        let main = callsite_id(Some("app"), "", "main", 1);
+        assert_ne!(callstack_id([main, load]), callstack_id([load, main]));
+        assert_eq!(callstack_id([main, load]), callstack_id(vec![main, load]));
+    }
+    #[test]
+    fn fingerprints_ignore_line_numbers() {
+        let fingerprint = callstack_fingerprint([
+            (Some("app"), "/srv/app/__init__.py", "main"),
+            (None, "data.py", "load"),
+        ]);
+        assert_eq!(fingerprint.to_string(), "37aa82cf2a50db8f");
+        assert_eq!(
+            fingerprint,
+            callstack_id([
+                callsite_id(Some("app"), "", "main", 0),
+                callsite_id(None, "data.py", "load", 0)
+            ])
+        );
+        assert_ne!(
+            fingerprint,
+            callstack_fingerprint([(Some("app"), "", "main"), (None, "data.py", "save")])
+        );
+    }
+}
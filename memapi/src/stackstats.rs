@@ -0,0 +1,63 @@
+// Statistics about the shape of a profile rather than its memory usage: how
+// many callstacks and callsites it has, and how deep the callstacks go. A
+// profile with millions of unique callstacks, or callstacks thousands of
+// frames deep from runaway recursion, makes for huge, slow flamegraphs, and
+// it's useful to know that before trying to open one.
+use std::collections::HashSet;
+
+use crate::snapshot::ProfileData;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StackStats {
+    /// Distinct callstacks.
+    pub callstacks: usize,
+    /// Distinct frames, i.e. (function, file, line) combinations.
+    pub callsites: usize,
+    /// Frames in the deepest callstack.
+    pub max_depth: usize,
+    /// Average frames per callstack, None if there are no callstacks.
+    pub average_depth: Option<f64>,
+}
+
+impl StackStats {
+    pub fn new(data: &ProfileData) -> Self {
+        let callsites: HashSet<_> = data
+            .callstacks
+            .iter()
+            .flat_map(|usage| usage.frames.iter())
+            .collect();
+        let depths = data.callstacks.iter().map(|usage| usage.frames.len());
+        let callstacks = data.callstacks.len();
+        Self {
+            callstacks,
+            callsites: callsites.len(),
+            max_depth: depths.clone().max().unwrap_or(0),
+            average_depth: if callstacks == 0 {
+                None
+            } else {
+                Some(depths.sum::<usize>() as f64 / callstacks as f64)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StackStats;
+    use crate::folded::parse_folded_str;
+
+    #[test]
+    fn depth_and_breadth() {
+        let data = parse_folded_str(
+            "a.py:1 (main);b.py:2 (f) 10\na.py:1 (main);b.py:2 (f);b.py:9 (g) 5\na.py:3 (h) 1\n",
+        )
+        .unwrap();
+        let stats = StackStats::new(&data);
+        assert_eq!(stats.callstacks, 3);
+        assert_eq!(stats.callsites, 4);
+        assert_eq!(stats.max_depth, 3);
+        assert_eq!(stats.average_depth, Some(2.0));
+        let empty = parse_folded_str("").unwrap();
+        assert_eq!(StackStats::new(&empty).average_depth, None);
+    }
+}
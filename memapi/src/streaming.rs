@@ -0,0 +1,236 @@
+// Live event streaming: send allocation events over a Unix domain socket, so
+// an external viewer can show memory usage as the program runs.
+//
+// The protocol is line-based text, one event per line:
+//
+//     c <callstack id> <callstack>     A new callstack, frames separated by ;
+//     + <callstack id> <bytes>         Memory was allocated
+//     - <callstack id> <bytes>         Memory was freed
+//     s <current> <peak> <dropped>     Periodic summary, in bytes; dropped is
+//                                      the number of events lost so far
+//     r                                Tracking was reset: forget all
+//                                      callstacks and allocations
+//
+// Callstack ids are only meaningful until the next reset.
+use std::io::{BufWriter, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::memorytracking::{Callstack, CallstackId, FunctionLocations};
+
+/// How many events can be queued before we start dropping them. We never
+/// block the allocating thread on a slow reader.
+const QUEUE_SIZE: usize = 64 * 1024;
+
+/// How often summary events are sent.
+const SUMMARY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How many allocations and frees between looks at the clock.
+const CHECK_EVERY: u32 = 1024;
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    Callstack(CallstackId, String),
+    Allocated(CallstackId, usize),
+    Freed(CallstackId, usize),
+    Summary(usize, usize),
+    Reset,
+}
+
+impl Event {
+    fn write(&self, out: &mut impl Write, dropped: usize) -> std::io::Result<()> {
+        match self {
+            Event::Callstack(id, callstack) => writeln!(out, "c {} {}", id, callstack),
+            Event::Allocated(id, bytes) => writeln!(out, "+ {} {}", id, bytes),
+            Event::Freed(id, bytes) => writeln!(out, "- {} {}", id, bytes),
+            Event::Summary(current, peak) => writeln!(out, "s {} {} {}", current, peak, dropped),
+            Event::Reset => writeln!(out, "r"),
+        }
+    }
+}
+
+/// Sends events to a background thread, which writes them to the socket.
+pub struct EventStream {
+    sender: Option<SyncSender<Event>>,
+    dropped: Arc<AtomicUsize>,
+    last_summary: Instant,
+    since_check: u32,
+}
+
+impl EventStream {
+    /// Connect to the socket given by FIL_STREAM_SOCKET, if set.
+    /// `thread_init` is run on the writer thread before anything else, e.g.
+    /// to stop its own allocations from being tracked.
+    pub fn from_env(thread_init: fn()) -> Option<Self> {
+        let path = std::env::var_os("FIL_STREAM_SOCKET")?;
+        match UnixStream::connect(&path) {
+            Ok(socket) => Some(Self::new(socket, thread_init)),
+            Err(e) => {
+                eprintln!(
+                    "=fil-profile= Couldn't connect to stream socket {:?}: {}",
+                    path, e
+                );
+                None
+            }
+        }
+    }
+
+    fn new(socket: impl Write + Send + 'static, thread_init: fn()) -> Self {
+        let (sender, receiver) = sync_channel(QUEUE_SIZE);
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let thread_dropped = dropped.clone();
+        std::thread::Builder::new()
+            .name("fil-stream".to_string())
+            .spawn(move || {
+                thread_init();
+                write_events(receiver, socket, &thread_dropped);
+            })
+            .expect("Couldn't start streaming thread");
+        Self {
+            sender: Some(sender),
+            dropped,
+            last_summary: Instant::now(),
+            since_check: 0,
+        }
+    }
+
+    fn send(&mut self, event: Event) {
+        if let Some(sender) = &self.sender {
+            match sender.try_send(event) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    // The reader went away, stop bothering:
+                    self.sender = None;
+                }
+            }
+        }
+    }
+
+    /// A callstack was seen for the first time.
+    pub fn new_callstack(
+        &mut self,
+        callstack_id: CallstackId,
+        callstack: &Callstack,
+        functions: &dyn FunctionLocations,
+    ) {
+        if self.sender.is_some() {
+            self.send(Event::Callstack(
+                callstack_id,
+                callstack.as_plain_string(functions),
+            ));
+        }
+    }
+
+    pub fn allocated(&mut self, callstack_id: CallstackId, bytes: usize) {
+        self.send(Event::Allocated(callstack_id, bytes));
+    }
+
+    pub fn freed(&mut self, callstack_id: CallstackId, bytes: usize) {
+        self.send(Event::Freed(callstack_id, bytes));
+    }
+
+    /// All previous callstacks and allocations are now meaningless.
+    pub fn reset(&mut self) {
+        self.send(Event::Reset);
+    }
+
+    /// Send a summary if it's been a while since the last one. This is called
+    /// on every allocation and free, so like hotloops.rs the clock is only
+    /// read every CHECK_EVERY calls.
+    pub fn maybe_summarize(&mut self, current: usize, peak: usize) {
+        self.since_check += 1;
+        if self.since_check < CHECK_EVERY {
+            return;
+        }
+        self.since_check = 0;
+        if self.last_summary.elapsed() >= SUMMARY_INTERVAL {
+            self.last_summary = Instant::now();
+            self.send(Event::Summary(current, peak));
+        }
+    }
+}
+
+fn write_events(receiver: Receiver<Event>, socket: impl Write, dropped: &AtomicUsize) {
+    let mut out = BufWriter::new(socket);
+    // Block for the next event, then write out everything else that's queued
+    // before flushing:
+    while let Ok(event) = receiver.recv() {
+        let mut result = event.write(&mut out, dropped.load(Ordering::Relaxed));
+        while let (Ok(()), Ok(event)) = (&result, receiver.try_recv()) {
+            result = event.write(&mut out, dropped.load(Ordering::Relaxed));
+        }
+        if result.and_then(|_| out.flush()).is_err() {
+            // Reader disconnected; dropping the receiver tells the senders.
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Event, EventStream, CHECK_EVERY, SUMMARY_INTERVAL};
+    use crate::memorytracking::{CallSiteId, Callstack, VecFunctionLocations};
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::net::UnixStream;
+    use std::time::Instant;
+
+    #[test]
+    fn events_are_written_as_lines() {
+        let mut out = vec![];
+        Event::Callstack(1, "a.py:2 (f)".to_string())
+            .write(&mut out, 0)
+            .unwrap();
+        Event::Allocated(1, 100).write(&mut out, 0).unwrap();
+        Event::Freed(1, 50).write(&mut out, 0).unwrap();
+        Event::Summary(50, 100).write(&mut out, 3).unwrap();
+        Event::Reset.write(&mut out, 3).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "c 1 a.py:2 (f)\n+ 1 100\n- 1 50\ns 50 100 3\nr\n"
+        );
+    }
+
+    #[test]
+    fn events_arrive_over_socket() {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let mut stream = EventStream::new(ours, || {});
+        let mut functions = VecFunctionLocations::new();
+        let f = functions.add_function("a.py".to_string(), "f".to_string());
+        let mut callstack = Callstack::new();
+        callstack.start_call(0, CallSiteId::new(f, 2));
+        stream.new_callstack(0, &callstack, &functions);
+        stream.allocated(0, 123);
+        stream.freed(0, 123);
+        let lines: Vec<String> = BufReader::new(theirs)
+            .lines()
+            .take(3)
+            .map(|l| l.unwrap())
+            .collect();
+        assert_eq!(lines, vec!["c 0 a.py:2 (f)", "+ 0 123", "- 0 123"]);
+    }
+
+    #[test]
+    fn clock_is_read_every_so_often() {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let mut stream = EventStream::new(ours, || {});
+        stream.last_summary = Instant::now() - SUMMARY_INTERVAL;
+        for _ in 1..CHECK_EVERY {
+            stream.maybe_summarize(100, 200);
+        }
+        // Overdue, but the clock wasn't read until now:
+        stream.allocated(0, 100);
+        stream.maybe_summarize(100, 200);
+        let lines: Vec<String> = BufReader::new(theirs)
+            .lines()
+            .take(2)
+            .map(|l| l.unwrap())
+            .collect();
+        assert_eq!(lines, vec!["+ 0 100", "s 100 200 0"]);
+    }
+}
@@ -0,0 +1,143 @@
+// Turning native (C/C++/Rust) instruction addresses into readable names.
+//
+// Capturing a native frame only means storing its address, which is cheap.
+// Resolving that address to function/file/line means reading debug info,
+// which is slow, so it's only done at dump time, and only once per address.
+use std::collections::HashMap;
+
+use crate::hashing::HashState;
+
+use crate::util::new_hashmap;
+
+/// What we know about a native instruction address.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NativeSymbol {
+    /// Demangled function name, or the hex address if it's unknown.
+    pub function: String,
+    pub filename: Option<String>,
+    pub line_number: Option<u32>,
+}
+
+impl NativeSymbol {
+    /// Format as a flamegraph frame, e.g. "malloc (libc.so.6)" or
+    /// "compute (src/lib.rs:12)".
+    pub fn to_frame_string(&self) -> String {
+        match (&self.filename, self.line_number) {
+            (Some(filename), Some(line)) => format!("{} ({}:{})", self.function, filename, line),
+            (Some(filename), None) => format!("{} ({})", self.function, filename),
+            _ => self.function.clone(),
+        }
+    }
+}
+
+/// Resolves addresses lazily, caching the results.
+pub struct Symbolicator {
+    cache: HashMap<usize, NativeSymbol, HashState>,
+}
+
+impl Symbolicator {
+    pub fn new() -> Self {
+        Self {
+            cache: new_hashmap(),
+        }
+    }
+
+    /// Get the symbol for an instruction address.
+    pub fn resolve(&mut self, address: usize) -> &NativeSymbol {
+        self.cache
+            .entry(address)
+            .or_insert_with(|| resolve_uncached(address))
+    }
+
+    /// Format a native callstack, outermost frame first.
+    pub fn callstack_as_string(&mut self, addresses: &[usize], separator: &str) -> String {
+        addresses
+            .iter()
+            .map(|address| self.resolve(*address).to_frame_string())
+            .collect::<Vec<_>>()
+            .join(separator)
+    }
+
+    /// How many addresses have been resolved so far.
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+}
+
+impl Default for Symbolicator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn resolve_uncached(address: usize) -> NativeSymbol {
+    let mut result = None;
+    backtrace::resolve(address as *mut std::ffi::c_void, |symbol| {
+        // Inlined functions result in multiple symbols for the same address;
+        // the first one is the innermost, which is what we want.
+        if result.is_some() {
+            return;
+        }
+        result = Some(NativeSymbol {
+            function: symbol
+                .name()
+                .map(|name| name.to_string())
+                .unwrap_or_else(|| format!("{:#x}", address)),
+            filename: symbol
+                .filename()
+                .map(|path| path.to_string_lossy().into_owned()),
+            line_number: symbol.lineno(),
+        });
+    });
+    result.unwrap_or_else(|| NativeSymbol {
+        function: format!("{:#x}", address),
+        filename: None,
+        line_number: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{NativeSymbol, Symbolicator};
+
+    // Captured addresses are return addresses, which get adjusted back by one
+    // byte when resolving, so pretend we're a bit into the function:
+    #[inline(never)]
+    fn some_native_function() -> usize {
+        some_native_function as *const () as usize + 1
+    }
+
+    #[test]
+    fn resolves_and_caches() {
+        let address = some_native_function();
+        let mut symbolicator = Symbolicator::new();
+        let symbol = symbolicator.resolve(address).clone();
+        assert!(symbol.function.contains("some_native_function"));
+        assert_eq!(symbolicator.resolve(address), &symbol);
+        assert_eq!(symbolicator.len(), 1);
+    }
+
+    #[test]
+    fn unknown_addresses_show_address() {
+        let mut symbolicator = Symbolicator::new();
+        assert_eq!(symbolicator.resolve(0x10).function, "0x10");
+    }
+
+    #[test]
+    fn frame_strings() {
+        let mut symbol = NativeSymbol {
+            function: "compute".to_string(),
+            filename: Some("src/lib.rs".to_string()),
+            line_number: Some(12),
+        };
+        assert_eq!(symbol.to_frame_string(), "compute (src/lib.rs:12)");
+        symbol.line_number = None;
+        assert_eq!(symbol.to_frame_string(), "compute (src/lib.rs)");
+        symbol.filename = None;
+        assert_eq!(symbol.to_frame_string(), "compute");
+    }
+}
@@ -0,0 +1,399 @@
+// Synchronization primitives for state shared between threads: allocation
+// hooks, report dumping and reset() all run concurrently. When compiled with
+// `--cfg loom` these are loom's instrumented versions, so the interleavings
+// can be model-checked:
+//
+//     RUSTFLAGS="--cfg loom" cargo test --release --lib loom
+//
+// Checking every interleaving takes a long time; LOOM_MAX_PREEMPTIONS=4 gives
+// a quicker, bounded check.
+//
+// Code shared between threads should use these rather than std::sync or
+// parking_lot directly.
+use std::cell::Cell;
+use std::ops::{Deref, DerefMut};
+use std::time::Duration;
+
+#[cfg(not(loom))]
+use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+#[cfg(not(loom))]
+pub use std::sync::atomic::{AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+#[cfg(not(loom))]
+pub use std::sync::Arc;
+
+#[cfg(loom)]
+pub use loom::sync::atomic::{AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+#[cfg(loom)]
+pub use loom::sync::Arc;
+#[cfg(loom)]
+use loom::sync::{RwLockReadGuard, RwLockWriteGuard};
+
+/// loom's RwLock, with the subset of parking_lot's API we use.
+#[cfg(loom)]
+struct RwLock<T>(loom::sync::RwLock<T>);
+
+#[cfg(loom)]
+impl<T> RwLock<T> {
+    fn new(value: T) -> Self {
+        Self(loom::sync::RwLock::new(value))
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.0.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, T> {
+        self.0.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // loom's own try_write() panics instead of failing when the lock is
+    // held, so always fail; callers then wait with write(), or give up,
+    // which are possible outcomes anyway.
+    fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        None
+    }
+
+    // There's no time in loom's model either.
+    fn try_write_for(&self, _timeout: Duration) -> Option<RwLockWriteGuard<'_, T>> {
+        None
+    }
+}
+
+// The address of a thread-local is a cheap, non-zero identifier for the
+// current thread. A const thread-local with no destructor stays accessible
+// during thread shutdown, when allocations still happen.
+#[cfg(not(loom))]
+std::thread_local!(static THREAD_MARKER: u8 = const { 0 });
+#[cfg(loom)]
+loom::thread_local!(static THREAD_MARKER: u8 = 0);
+
+// How many read guards the current thread holds, on any OwnedRwLock. Only
+// one is used in practice, the global tracker, so there's no need to track
+// which.
+#[cfg(not(loom))]
+std::thread_local!(static READS_HELD: Cell<usize> = const { Cell::new(0) });
+#[cfg(loom)]
+loom::thread_local!(static READS_HELD: Cell<usize> = Cell::new(0));
+
+fn current_thread() -> usize {
+    THREAD_MARKER.with(|marker| marker as *const u8 as usize)
+}
+
+/// A read-write lock that knows which thread holds it for writing.
+///
+/// Code running while the lock is held can end up back in code that wants
+/// the lock, e.g. dumping a report calls into Python, which registers new
+/// functions. Blocking there would deadlock, but a plain try_lock() also
+/// fails whenever some *other* thread holds the lock, which is normal.
+///
+/// Allocation tracking needs exclusive access, via lock(). Consumers that
+/// only look, e.g. the dashboard or top callstack queries, can use read()
+/// instead, so they don't hold each other up. Readers still exclude writers:
+/// allocation hooks on every thread wait while any read guard is alive, so
+/// readers should do as little as possible while holding one.
+pub struct OwnedRwLock<T> {
+    lock: RwLock<T>,
+    owner: AtomicUsize,
+    // How often lock() was called, and how often it had to wait:
+    acquisitions: AtomicU64,
+    contended: AtomicU64,
+}
+
+/// How contended an OwnedRwLock is.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LockContention {
+    /// Times the lock was taken by lock() or lock_unless_reentrant().
+    pub acquisitions: u64,
+    /// How many of those had to wait for another thread.
+    pub contended: u64,
+}
+
+/// Exclusive access to an OwnedRwLock's contents.
+pub struct OwnedWriteGuard<'a, T> {
+    guard: RwLockWriteGuard<'a, T>,
+    owner: &'a AtomicUsize,
+}
+
+/// Shared, read-only access to an OwnedRwLock's contents.
+pub struct ReadGuard<'a, T> {
+    guard: RwLockReadGuard<'a, T>,
+}
+
+impl<T> OwnedRwLock<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            lock: RwLock::new(value),
+            owner: AtomicUsize::new(0),
+            acquisitions: AtomicU64::new(0),
+            contended: AtomicU64::new(0),
+        }
+    }
+
+    fn owned<'a>(&'a self, guard: RwLockWriteGuard<'a, T>) -> OwnedWriteGuard<'a, T> {
+        self.owner.store(current_thread(), Ordering::Release);
+        OwnedWriteGuard {
+            guard,
+            owner: &self.owner,
+        }
+    }
+
+    /// Block until the lock is acquired for writing.
+    pub fn lock(&self) -> OwnedWriteGuard<'_, T> {
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        let guard = match self.lock.try_write() {
+            Some(guard) => guard,
+            None => {
+                self.contended.fetch_add(1, Ordering::Relaxed);
+                self.lock.write()
+            }
+        };
+        self.owned(guard)
+    }
+
+    /// Block until the lock is acquired for reading, which only waits for
+    /// writers. While the guard is alive, lock_unless_reentrant() on this
+    /// thread returns None, since waiting for a write lock while holding a
+    /// read lock would deadlock.
+    pub fn read(&self) -> ReadGuard<'_, T> {
+        let guard = self.lock.read();
+        READS_HELD.with(|reads| reads.set(reads.get() + 1));
+        ReadGuard { guard }
+    }
+
+    /// How often the lock was taken, and how often that meant waiting.
+    pub fn contention(&self) -> LockContention {
+        LockContention {
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            contended: self.contended.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Block until the lock is acquired for writing, unless the current
+    /// thread already holds it, in which case return None.
+    pub fn lock_unless_reentrant(&self) -> Option<OwnedWriteGuard<'_, T>> {
+        // Only this thread ever stores its own id, so if it's not there now
+        // it won't show up while we wait:
+        if self.owner.load(Ordering::Acquire) == current_thread()
+            || READS_HELD.with(|reads| reads.get()) > 0
+        {
+            return None;
+        }
+        Some(self.lock())
+    }
+
+    /// Try to acquire the lock for writing, giving up after a timeout.
+    pub fn try_lock_for(&self, timeout: Duration) -> Option<OwnedWriteGuard<'_, T>> {
+        self.lock
+            .try_write_for(timeout)
+            .map(|guard| self.owned(guard))
+    }
+}
+
+impl<'a, T> Deref for OwnedWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T> DerefMut for OwnedWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T> Drop for OwnedWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        // Runs before the inner guard is dropped, i.e. while still locked:
+        self.owner.store(0, Ordering::Release);
+    }
+}
+
+impl<'a, T> Deref for ReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T> Drop for ReadGuard<'a, T> {
+    fn drop(&mut self) {
+        READS_HELD.with(|reads| reads.set(reads.get() - 1));
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::{LockContention, OwnedRwLock};
+    use std::sync::Arc;
+
+    #[test]
+    fn reentrancy_is_per_thread() {
+        let mutex = Arc::new(OwnedRwLock::new(0));
+        let mut guard = mutex.lock();
+        *guard += 1;
+        assert!(mutex.lock_unless_reentrant().is_none());
+        let other = {
+            let mutex = mutex.clone();
+            std::thread::spawn(move || {
+                // Blocks until the main thread lets go:
+                *mutex.lock_unless_reentrant().unwrap() += 1;
+            })
+        };
+        drop(guard);
+        other.join().unwrap();
+        assert_eq!(*mutex.lock_unless_reentrant().unwrap(), 2);
+    }
+
+    #[test]
+    fn readers_share() {
+        let lock = Arc::new(OwnedRwLock::new(1));
+        let reading = lock.read();
+        // Other threads can read at the same time:
+        let other = {
+            let lock = lock.clone();
+            std::thread::spawn(move || *lock.read())
+        };
+        assert_eq!(other.join().unwrap(), 1);
+        // Waiting to write while this thread is reading would deadlock:
+        assert!(lock.lock_unless_reentrant().is_none());
+        drop(reading);
+        *lock.lock_unless_reentrant().unwrap() += 1;
+        assert_eq!(*lock.read(), 2);
+    }
+
+    #[test]
+    fn contention_is_counted() {
+        let mutex = Arc::new(OwnedRwLock::new(0));
+        drop(mutex.lock());
+        let guard = mutex.lock();
+        assert_eq!(
+            mutex.contention(),
+            LockContention {
+                acquisitions: 2,
+                contended: 0,
+            }
+        );
+        let other = {
+            let mutex = mutex.clone();
+            std::thread::spawn(move || drop(mutex.lock()))
+        };
+        // The other thread can't get the lock until it's released:
+        while mutex.contention().contended == 0 {
+            std::thread::yield_now();
+        }
+        drop(guard);
+        other.join().unwrap();
+        assert_eq!(mutex.contention().acquisitions, 3);
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::{Arc, OwnedRwLock};
+    use crate::callcounts::CallCounts;
+    use crate::memorytracking::{
+        AllocationTracker, FunctionId, VecFunctionLocations, PARENT_PROCESS,
+    };
+    use loom::thread;
+
+    #[test]
+    fn loom_call_counts_chunk_race() {
+        loom::model(|| {
+            let counts = Arc::new(CallCounts::default());
+            let threads: Vec<_> = (0..2)
+                .map(|_| {
+                    let counts = counts.clone();
+                    thread::spawn(move || counts.record(FunctionId::new(1)))
+                })
+                .collect();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            assert_eq!(counts.get(FunctionId::new(1)), 2);
+        });
+    }
+
+    #[test]
+    fn loom_lock_unless_reentrant() {
+        loom::model(|| {
+            let mutex = Arc::new(OwnedRwLock::new(0));
+            let other = {
+                let mutex = mutex.clone();
+                thread::spawn(move || {
+                    let mut guard = mutex.lock();
+                    *guard += 1;
+                    assert!(mutex.lock_unless_reentrant().is_none());
+                })
+            };
+            // Whatever the other thread is doing, this thread gets the lock:
+            *mutex.lock_unless_reentrant().unwrap() += 1;
+            other.join().unwrap();
+            assert_eq!(*mutex.lock(), 2);
+        });
+    }
+
+    #[test]
+    fn loom_readers_and_writers() {
+        loom::model(|| {
+            let lock = Arc::new(OwnedRwLock::new(0));
+            let writer = {
+                let lock = lock.clone();
+                thread::spawn(move || *lock.lock_unless_reentrant().unwrap() += 1)
+            };
+            let reader = {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    let value = *lock.read();
+                    assert!(value == 0 || value == 1);
+                })
+            };
+            {
+                let reading = lock.read();
+                assert!(lock.lock_unless_reentrant().is_none());
+                drop(reading);
+            }
+            writer.join().unwrap();
+            reader.join().unwrap();
+            assert_eq!(*lock.read(), 1);
+        });
+    }
+
+    /// Allocation hooks, dumping and reset() racing each other.
+    #[test]
+    fn loom_hooks_dump_reset() {
+        loom::model(|| {
+            let tracker = Arc::new(OwnedRwLock::new(AllocationTracker::new(
+                "/tmp".to_string(),
+                VecFunctionLocations::new(),
+            )));
+            let hooks = {
+                let tracker = tracker.clone();
+                thread::spawn(move || {
+                    let call_counts = tracker.lock().call_counts.clone();
+                    call_counts.record(FunctionId::new(0));
+                    tracker
+                        .lock()
+                        .add_allocation_with_callstack(1, 100, &[("m", "f", 1)]);
+                    tracker.lock().free_allocation(PARENT_PROCESS, 1);
+                })
+            };
+            let reset = {
+                let tracker = tracker.clone();
+                thread::spawn(move || tracker.lock().reset("/tmp".to_string()))
+            };
+            {
+                let mut tracker = tracker.lock();
+                tracker.check_if_new_peak();
+                assert!(
+                    tracker.get_peak_allocated_bytes() >= tracker.get_current_allocated_bytes()
+                );
+            }
+            hooks.join().unwrap();
+            reset.join().unwrap();
+            assert_eq!(tracker.lock().get_current_allocated_bytes(), 0);
+        });
+    }
+}
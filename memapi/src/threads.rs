@@ -0,0 +1,92 @@
+// Per-thread attribution, for heavily threaded programs whose combined
+// flamegraphs are too dense to read. As with subinterpreters, callstacks start
+// with a synthetic frame naming the thread, so each thread gets its own
+// section of the combined flamegraphs; each thread's callstacks can then also
+// be split out into their own report.
+use std::collections::BTreeMap;
+
+use crate::snapshot::{CallstackUsage, ProfileData};
+
+/// The native thread ID, as returned by Python's threading.get_native_id()
+/// where supported.
+pub type ThreadId = u64;
+
+/// Filename of the synthetic root frame of per-thread callstacks.
+pub const THREAD_FILENAME: &str = "<thread>";
+
+/// Function name of the synthetic root frame for a thread.
+pub fn root_function_name(thread: ThreadId) -> String {
+    format!("thread {}", thread)
+}
+
+/// Which thread a synthetic root frame is for, if it is one.
+pub fn thread_for_root(filename: &str, function: &str) -> Option<ThreadId> {
+    if filename != THREAD_FILENAME {
+        return None;
+    }
+    function.strip_prefix("thread ")?.parse().ok()
+}
+
+/// Split a snapshot into one per thread, without the thread frames.
+/// Callstacks that weren't attributed to a thread, e.g. from before tracking
+/// started, aren't in any of them.
+pub fn split_by_thread(data: &ProfileData) -> BTreeMap<ThreadId, ProfileData> {
+    let mut callstacks: BTreeMap<ThreadId, Vec<CallstackUsage>> = BTreeMap::new();
+    for usage in &data.callstacks {
+        let found = usage.frames.iter().enumerate().find_map(|(index, frame)| {
+            thread_for_root(&frame.filename, &frame.function).map(|thread| (index, thread))
+        });
+        if let Some((index, thread)) = found {
+            let mut usage = usage.clone();
+            usage.frames.remove(index);
+            callstacks.entry(thread).or_default().push(usage);
+        }
+    }
+    callstacks
+        .into_iter()
+        .map(|(thread, mut callstacks)| {
+            callstacks.sort_by(|a, b| a.frames.cmp(&b.frames));
+            let thread_data = ProfileData {
+                peak_bytes: callstacks.iter().map(|usage| usage.peak_bytes).sum(),
+                current_bytes: callstacks.iter().map(|usage| usage.current_bytes).sum(),
+                peak_allocations: callstacks.iter().map(|usage| usage.peak_allocations).sum(),
+                callstacks,
+                ..data.clone()
+            };
+            (thread, thread_data)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{root_function_name, split_by_thread, thread_for_root, THREAD_FILENAME};
+    use crate::folded::parse_folded_str;
+
+    #[test]
+    fn root_frames_roundtrip() {
+        assert_eq!(
+            thread_for_root(THREAD_FILENAME, &root_function_name(1234)),
+            Some(1234)
+        );
+        assert_eq!(thread_for_root("/app/main.py", "thread 1234"), None);
+        assert_eq!(thread_for_root(THREAD_FILENAME, "main"), None);
+    }
+
+    #[test]
+    fn split() {
+        let data = parse_folded_str(
+            "<thread>:0 (thread 7);a.py:1 (main) 100\n\
+             <thread>:0 (thread 7);a.py:1 (main);b.py:2 (load) 50\n\
+             <thread>:0 (thread 9);c.py:3 (worker) 10\n\
+             a.py:1 (main) 1\n",
+        )
+        .unwrap();
+        let threads = split_by_thread(&data);
+        assert_eq!(threads.keys().copied().collect::<Vec<_>>(), vec![7, 9]);
+        assert_eq!(threads[&7].peak_bytes, 150);
+        assert_eq!(threads[&7].callstacks[0].frames[0].function, "main");
+        assert_eq!(threads[&9].callstacks.len(), 1);
+        assert_eq!(threads[&9].current_bytes, 10);
+    }
+}
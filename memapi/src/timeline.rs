@@ -0,0 +1,407 @@
+// Total tracked memory over time, for the classic "memory vs. time" chart.
+//
+// Usage is recorded in intervals, keeping the highest usage seen during each
+// one, so short spikes still show up. Once there are too many samples,
+// neighbouring pairs are merged and the interval doubles, so memory use stays
+// bounded however long the program runs. Users can also add markers, e.g.
+// "finished loading", which are drawn on the chart.
+//
+// Updates happen on every allocation and free, so like hotloops.rs the clock
+// is only read every CHECK_EVERY updates, with the highest usage in between
+// recorded then. A spike may therefore be drawn a little late, but it's never
+// lost.
+use std::time::Instant;
+
+use serde_json::{json, Value};
+
+use crate::snapshot::{get_string, get_usize};
+
+/// Samples kept before halving the resolution.
+const MAX_SAMPLES: usize = 2048;
+
+/// Markers beyond this many are ignored.
+const MAX_MARKERS: usize = 1000;
+
+/// The initial sampling interval.
+const INITIAL_INTERVAL_MS: u64 = 10;
+
+/// How many updates between looks at the clock.
+const CHECK_EVERY: u32 = 1024;
+
+/// The highest usage during the interval starting at `elapsed_ms`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimelineSample {
+    pub elapsed_ms: u64,
+    pub bytes: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TimelineMarker {
+    pub elapsed_ms: u64,
+    pub label: String,
+}
+
+/// The timeline as of a snapshot.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TimelineData {
+    pub samples: Vec<TimelineSample>,
+    pub markers: Vec<TimelineMarker>,
+}
+
+impl TimelineData {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "samples": self.samples.iter().map(|sample| json!([sample.elapsed_ms, sample.bytes])).collect::<Vec<_>>(),
+            "markers": self.markers.iter().map(|marker| json!({
+                "elapsed_ms": marker.elapsed_ms,
+                "label": marker.label,
+            })).collect::<Vec<_>>(),
+        })
+    }
+
+    pub fn from_json(value: &Value) -> Result<Self, String> {
+        let samples = value["samples"]
+            .as_array()
+            .ok_or("missing timeline samples")?
+            .iter()
+            .map(|sample| match (sample[0].as_u64(), sample[1].as_u64()) {
+                (Some(elapsed_ms), Some(bytes)) => Ok(TimelineSample {
+                    elapsed_ms,
+                    bytes: bytes as usize,
+                }),
+                _ => Err(format!("invalid timeline sample: {}", sample)),
+            })
+            .collect::<Result<_, String>>()?;
+        let markers = value["markers"]
+            .as_array()
+            .ok_or("missing timeline markers")?
+            .iter()
+            .map(|marker| {
+                Ok(TimelineMarker {
+                    elapsed_ms: get_usize(marker, "elapsed_ms")? as u64,
+                    label: get_string(marker, "label")?,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self { samples, markers })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Timeline {
+    start: Instant,
+    since_check: u32,
+    // Highest usage since the clock was last read:
+    pending_bytes: usize,
+    interval_ms: u64,
+    samples: Vec<TimelineSample>,
+    markers: Vec<TimelineMarker>,
+}
+
+impl Default for Timeline {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            since_check: 0,
+            pending_bytes: 0,
+            interval_ms: INITIAL_INTERVAL_MS,
+            samples: vec![],
+            markers: vec![],
+        }
+    }
+}
+
+impl Timeline {
+    /// Call whenever usage changes.
+    #[inline]
+    pub fn update(&mut self, current_bytes: usize) {
+        self.pending_bytes = self.pending_bytes.max(current_bytes);
+        self.since_check += 1;
+        if self.since_check >= CHECK_EVERY {
+            let now = self.elapsed_ms(Instant::now());
+            let pending_bytes = std::mem::take(&mut self.pending_bytes);
+            self.since_check = 0;
+            self.update_at(now, pending_bytes);
+        }
+    }
+
+    fn update_at(&mut self, elapsed_ms: u64, current_bytes: usize) {
+        match self.samples.last_mut() {
+            Some(last) if elapsed_ms < last.elapsed_ms + self.interval_ms => {
+                last.bytes = last.bytes.max(current_bytes);
+            }
+            _ => {
+                self.samples.push(TimelineSample {
+                    elapsed_ms,
+                    bytes: current_bytes,
+                });
+                if self.samples.len() > MAX_SAMPLES {
+                    self.halve_resolution();
+                }
+            }
+        }
+    }
+
+    /// Merge neighbouring samples, keeping the higher usage.
+    fn halve_resolution(&mut self) {
+        self.samples = self
+            .samples
+            .chunks(2)
+            .map(|pair| TimelineSample {
+                elapsed_ms: pair[0].elapsed_ms,
+                bytes: pair.iter().map(|sample| sample.bytes).max().unwrap_or(0),
+            })
+            .collect();
+        self.interval_ms *= 2;
+    }
+
+    /// Mark the current moment, e.g. the end of a phase of the program.
+    pub fn add_marker(&mut self, label: &str) {
+        if self.markers.len() < MAX_MARKERS {
+            self.markers.push(TimelineMarker {
+                elapsed_ms: self.elapsed_ms(Instant::now()),
+                label: label.to_string(),
+            });
+        }
+    }
+
+    /// The timeline so far, ending with the current usage.
+    pub fn data(&self, current_bytes: usize) -> TimelineData {
+        let now = self.elapsed_ms(Instant::now());
+        let mut samples = self.samples.clone();
+        // Usage since the clock was last read happened by now at the latest:
+        if self.since_check > 0 {
+            samples.push(TimelineSample {
+                elapsed_ms: now,
+                bytes: self.pending_bytes,
+            });
+        }
+        samples.push(TimelineSample {
+            elapsed_ms: now,
+            bytes: current_bytes,
+        });
+        TimelineData {
+            samples,
+            markers: self.markers.clone(),
+        }
+    }
+
+    fn elapsed_ms(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.start).as_millis() as u64
+    }
+
+    /// Roughly how much memory this uses.
+    pub fn memory_usage(&self) -> usize {
+        self.samples.capacity() * std::mem::size_of::<TimelineSample>()
+            + self
+                .markers
+                .iter()
+                .map(|marker| std::mem::size_of::<TimelineMarker>() + marker.label.len())
+                .sum::<usize>()
+    }
+}
+
+const WIDTH: f64 = 1200.0;
+const HEIGHT: f64 = 500.0;
+const LEFT: f64 = 90.0;
+const RIGHT: f64 = 30.0;
+const TOP: f64 = 60.0;
+const BOTTOM: f64 = 50.0;
+const TICKS: usize = 5;
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn mib(bytes: usize) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0)
+}
+
+/// A standalone SVG line chart of memory usage over time, with the peak and
+/// any markers annotated.
+pub fn render_svg(data: &TimelineData, title: &str) -> String {
+    let max_ms = data
+        .samples
+        .iter()
+        .map(|sample| sample.elapsed_ms)
+        .chain(data.markers.iter().map(|marker| marker.elapsed_ms))
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let peak = data
+        .samples
+        .iter()
+        .max_by_key(|sample| sample.bytes)
+        .copied()
+        .unwrap_or(TimelineSample {
+            elapsed_ms: 0,
+            bytes: 0,
+        });
+    let max_bytes = peak.bytes.max(1);
+    let x = |elapsed_ms: u64| LEFT + (WIDTH - LEFT - RIGHT) * elapsed_ms as f64 / max_ms as f64;
+    let y =
+        |bytes: usize| HEIGHT - BOTTOM - (HEIGHT - TOP - BOTTOM) * bytes as f64 / max_bytes as f64;
+
+    let mut svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}" font-family="Verdana, sans-serif" font-size="12">
+<rect width="100%" height="100%" fill="white"/>
+<text x="{cx}" y="24" font-size="17" text-anchor="middle">{title}</text>
+"#,
+        w = WIDTH,
+        h = HEIGHT,
+        cx = WIDTH / 2.0,
+        title = escape(title),
+    );
+    // Axes and ticks:
+    svg.push_str(&format!(
+        "<path d=\"M{l} {t} V{b} H{r}\" stroke=\"black\" fill=\"none\"/>\n",
+        l = LEFT,
+        t = TOP,
+        b = HEIGHT - BOTTOM,
+        r = WIDTH - RIGHT,
+    ));
+    for tick in 0..=TICKS {
+        let bytes = max_bytes * tick / TICKS;
+        let elapsed_ms = max_ms * tick as u64 / TICKS as u64;
+        svg.push_str(&format!(
+            "<line x1=\"{l}\" x2=\"{r}\" y1=\"{y:.1}\" y2=\"{y:.1}\" stroke=\"#ddd\"/>\n<text x=\"{lx}\" y=\"{ty:.1}\" text-anchor=\"end\">{mib:.1} MiB</text>\n",
+            l = LEFT,
+            r = WIDTH - RIGHT,
+            y = y(bytes),
+            lx = LEFT - 6.0,
+            ty = y(bytes) + 4.0,
+            mib = mib(bytes),
+        ));
+        svg.push_str(&format!(
+            "<text x=\"{x:.1}\" y=\"{ty}\" text-anchor=\"middle\">{s:.1}s</text>\n",
+            x = x(elapsed_ms),
+            ty = HEIGHT - BOTTOM + 18.0,
+            s = elapsed_ms as f64 / 1000.0,
+        ));
+    }
+    // Markers:
+    for marker in &data.markers {
+        svg.push_str(&format!(
+            "<line x1=\"{x:.1}\" x2=\"{x:.1}\" y1=\"{t}\" y2=\"{b}\" stroke=\"#888\" stroke-dasharray=\"4 3\"/>\n<text x=\"{tx:.1}\" y=\"{ty}\" fill=\"#555\" transform=\"rotate(-90 {tx:.1} {ty})\" text-anchor=\"end\">{label}</text>\n",
+            x = x(marker.elapsed_ms),
+            t = TOP,
+            b = HEIGHT - BOTTOM,
+            tx = x(marker.elapsed_ms) - 4.0,
+            ty = TOP + 4.0,
+            label = escape(&marker.label),
+        ));
+    }
+    // The usage itself, as steps since each sample is the highest usage
+    // until the next one:
+    let mut points = vec![];
+    for (i, sample) in data.samples.iter().enumerate() {
+        let end = data
+            .samples
+            .get(i + 1)
+            .map(|next| next.elapsed_ms)
+            .unwrap_or(sample.elapsed_ms);
+        points.push(format!(
+            "{:.1},{:.1}",
+            x(sample.elapsed_ms),
+            y(sample.bytes)
+        ));
+        points.push(format!("{:.1},{:.1}", x(end), y(sample.bytes)));
+    }
+    svg.push_str(&format!(
+        "<polyline points=\"{}\" fill=\"none\" stroke=\"#c0392b\" stroke-width=\"1.5\"/>\n",
+        points.join(" ")
+    ));
+    // The peak:
+    svg.push_str(&format!(
+        "<circle cx=\"{x:.1}\" cy=\"{y:.1}\" r=\"4\" fill=\"#c0392b\"/>\n<text x=\"{tx:.1}\" y=\"{ty:.1}\">Peak: {mib:.1} MiB at {s:.1}s</text>\n",
+        x = x(peak.elapsed_ms),
+        y = y(peak.bytes),
+        tx = (x(peak.elapsed_ms) + 8.0).min(WIDTH - RIGHT - 200.0),
+        ty = y(peak.bytes) - 8.0,
+        mib = mib(peak.bytes),
+        s = peak.elapsed_ms as f64 / 1000.0,
+    ));
+    svg.push_str("</svg>\n");
+    svg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render_svg, Timeline, TimelineData, TimelineSample, CHECK_EVERY, MAX_SAMPLES};
+
+    #[test]
+    fn clock_is_read_every_so_often() {
+        let mut timeline = Timeline::default();
+        timeline.update(5000);
+        for _ in 1..CHECK_EVERY {
+            timeline.update(100);
+        }
+        // The spike is kept even though the clock wasn't read at the time:
+        assert_eq!(timeline.samples.len(), 1);
+        assert_eq!(timeline.samples[0].bytes, 5000);
+        timeline.update(7000);
+        assert_eq!(timeline.samples.len(), 1);
+        let samples = timeline.data(200).samples;
+        assert_eq!(
+            samples
+                .iter()
+                .map(|sample| sample.bytes)
+                .collect::<Vec<_>>(),
+            vec![5000, 7000, 200]
+        );
+    }
+
+    #[test]
+    fn spikes_survive_downsampling() {
+        let mut timeline = Timeline::default();
+        timeline.update_at(0, 100);
+        // Same interval, so merged, keeping the highest:
+        timeline.update_at(5, 5000);
+        timeline.update_at(6, 200);
+        timeline.update_at(20, 300);
+        assert_eq!(
+            timeline.samples,
+            vec![
+                TimelineSample {
+                    elapsed_ms: 0,
+                    bytes: 5000
+                },
+                TimelineSample {
+                    elapsed_ms: 20,
+                    bytes: 300
+                }
+            ]
+        );
+        for i in 0..(MAX_SAMPLES as u64 * 4) {
+            timeline.update_at(100 + i * 10, 1000 + (i as usize % 7));
+        }
+        assert!(timeline.samples.len() <= MAX_SAMPLES);
+        assert!(timeline.interval_ms > 10);
+        assert_eq!(timeline.samples[0].bytes, 5000);
+        assert_eq!(timeline.data(42).samples.last().unwrap().bytes, 42);
+    }
+
+    #[test]
+    fn chart_has_peak_and_markers() {
+        let mut timeline = Timeline::default();
+        timeline.update_at(0, 1024 * 1024);
+        timeline.update_at(1000, 3 * 1024 * 1024);
+        timeline.update_at(2000, 1024 * 1024);
+        timeline.add_marker("loaded <data>");
+        let data = timeline.data(0);
+        let json = serde_json::to_string(&data.to_json()).unwrap();
+        assert_eq!(
+            TimelineData::from_json(&serde_json::from_str(&json).unwrap()),
+            Ok(data.clone())
+        );
+        let svg = render_svg(&data, "Memory & time");
+        assert!(svg.contains("Peak: 3.0 MiB at 1.0s"));
+        assert!(svg.contains("loaded &lt;data&gt;"));
+        assert!(svg.contains("Memory &amp; time"));
+        // Empty timelines still render:
+        assert!(render_svg(&TimelineData::default(), "").contains("Peak: 0.0 MiB"));
+    }
+}
@@ -0,0 +1,127 @@
+// A quick way for free() to tell that an address definitely isn't tracked,
+// without taking the tracker lock. Plenty of frees are of memory Fil never
+// recorded: allocated before tracking started, by threads that aren't
+// tracked, or while the profiler itself was allocating. Taking the lock and
+// probing the allocation map for each of those is wasted contention.
+//
+// This is a counting filter: a fixed table of atomic counters, where each
+// tracked address increments the counter it hashes to. A zero counter means
+// no tracked address hashes there, so the free can be skipped; a non-zero one
+// might be a collision, so the free goes through the lock as usual. Counters
+// are only modified by the tracker, under its lock, and an allocation is
+// recorded before malloc() returns it, so by the time another thread can free
+// an address its counter has already been incremented.
+use crate::sync::{AtomicU32, Ordering};
+
+/// 256K counters, 1 MiB. With up to tens of thousands of live tracked
+/// allocations, most untracked frees find an empty slot.
+#[cfg(not(loom))]
+const SLOTS_LOG2: u32 = 18;
+// Every atomic is tracked by loom, so keep the model small:
+#[cfg(loom)]
+const SLOTS_LOG2: u32 = 2;
+
+pub struct TrackedAddresses {
+    counters: Box<[AtomicU32]>,
+}
+
+impl Default for TrackedAddresses {
+    #[cfg(not(loom))]
+    fn default() -> Self {
+        // Zeroed memory straight from the allocator, rather than initializing
+        // each counter, since every tracker has one:
+        let zeroed: Box<[u32]> = vec![0u32; 1 << SLOTS_LOG2].into_boxed_slice();
+        // AtomicU32 has the same in-memory representation as u32:
+        let counters = unsafe { Box::from_raw(Box::into_raw(zeroed) as *mut [AtomicU32]) };
+        Self { counters }
+    }
+
+    #[cfg(loom)]
+    fn default() -> Self {
+        Self {
+            counters: (0..(1usize << SLOTS_LOG2))
+                .map(|_| AtomicU32::new(0))
+                .collect(),
+        }
+    }
+}
+
+impl TrackedAddresses {
+    #[inline]
+    fn slot(&self, address: usize) -> &AtomicU32 {
+        // Allocations are at least 16-byte aligned, so the low bits carry no
+        // information; Fibonacci hashing spreads the rest:
+        let hash = ((address >> 4) as u64).wrapping_mul(0x9E3779B97F4A7C15);
+        &self.counters[(hash >> (64 - SLOTS_LOG2)) as usize]
+    }
+
+    /// Record a newly tracked address. Only call with the tracker locked.
+    #[inline]
+    pub fn insert(&self, address: usize) {
+        let slot = self.slot(address);
+        // Saturate rather than wrap around to zero, which would make tracked
+        // addresses look untracked; a saturated slot just stays non-zero:
+        let count = slot.load(Ordering::Relaxed);
+        if count != u32::MAX {
+            slot.store(count + 1, Ordering::Relaxed);
+        }
+    }
+
+    /// An address is no longer tracked. Only call with the tracker locked.
+    #[inline]
+    pub fn remove(&self, address: usize) {
+        let slot = self.slot(address);
+        let count = slot.load(Ordering::Relaxed);
+        if count != 0 && count != u32::MAX {
+            slot.store(count - 1, Ordering::Relaxed);
+        }
+    }
+
+    /// False if the address is definitely not tracked. Safe to call without
+    /// the lock.
+    #[inline]
+    pub fn may_contain(&self, address: usize) -> bool {
+        self.slot(address).load(Ordering::Relaxed) != 0
+    }
+
+    /// Nothing is tracked anymore. Only call with the tracker locked.
+    pub fn clear(&self) {
+        for counter in self.counters.iter() {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// How much memory the counters use.
+    pub fn memory_usage(&self) -> usize {
+        self.counters.len() * std::mem::size_of::<AtomicU32>()
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::TrackedAddresses;
+
+    #[test]
+    fn untracked_addresses_are_ruled_out() {
+        let tracked = TrackedAddresses::default();
+        assert!(!tracked.may_contain(0x1000));
+        tracked.insert(0x1000);
+        tracked.insert(0x1000);
+        assert!(tracked.may_contain(0x1000));
+        // Neighbouring allocations land in different slots:
+        let false_positives = (1..1000)
+            .filter(|i| tracked.may_contain(0x1000 + i * 16))
+            .count();
+        assert_eq!(false_positives, 0);
+        tracked.remove(0x1000);
+        assert!(tracked.may_contain(0x1000));
+        tracked.remove(0x1000);
+        assert!(!tracked.may_contain(0x1000));
+        // Removing too much doesn't wrap around:
+        tracked.remove(0x1000);
+        tracked.insert(0x1000);
+        assert!(tracked.may_contain(0x1000));
+        tracked.clear();
+        assert!(!tracked.may_contain(0x1000));
+    }
+}
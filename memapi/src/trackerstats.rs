@@ -0,0 +1,45 @@
+// Numbers about the profiler itself rather than the profiled program: how
+// much it's tracking, how big its tables have grown, and how contended its
+// lock is. Useful for figuring out why profiling is slow or memory hungry in
+// some particular program.
+use serde_json::{json, Value};
+
+use crate::snapshot::ProfilerMemory;
+use crate::sync::LockContention;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TrackerStats {
+    /// Live malloc()-style allocations.
+    pub live_allocations: usize,
+    /// Live mmap()ed ranges.
+    pub live_mmaps: usize,
+    /// Interned functions, i.e. distinct callsites' functions.
+    pub functions: usize,
+    /// Interned callstacks.
+    pub callstacks: usize,
+    /// Bytes used by the copies of per-callstack usage made at each new
+    /// peak.
+    pub peak_snapshot_bytes: usize,
+    /// See ProfilerMemory.
+    pub profiler_memory: ProfilerMemory,
+    /// Bumped on every reset().
+    pub generation: u64,
+    /// Filled in by whoever owns the lock around the tracker.
+    pub lock: LockContention,
+}
+
+impl TrackerStats {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "live_allocations": self.live_allocations,
+            "live_mmaps": self.live_mmaps,
+            "functions": self.functions,
+            "callstacks": self.callstacks,
+            "peak_snapshot_bytes": self.peak_snapshot_bytes,
+            "profiler_memory_bytes": self.profiler_memory.total(),
+            "generation": self.generation,
+            "lock_acquisitions": self.lock.acquisitions,
+            "lock_contended": self.lock.contended,
+        })
+    }
+}
@@ -0,0 +1,174 @@
+// How quantities are shown in reports. Fil counts bytes, and by default shows
+// MiB in titles and summaries and raw byte counts in flamegraph tooltips;
+// reports can instead use one unit everywhere, or pick KiB, MiB or GiB to suit
+// each number. Embedders counting something other than bytes can name what
+// they count, and how to scale it.
+use regex::{Captures, Regex};
+
+const KIB: usize = 1024;
+const MIB: usize = 1024 * 1024;
+const GIB: usize = 1024 * 1024 * 1024;
+
+/// How numbers are scaled for display.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Scale {
+    /// As they are, with thousands separators, e.g. "1,234 bytes".
+    Raw,
+    /// Divided by a fixed amount and given that unit's name, e.g. 1048576
+    /// and "MiB".
+    Fixed { divisor: usize, name: String },
+    /// Whichever of KiB, MiB or GiB keeps the number at least 1; smaller
+    /// numbers are shown as they are.
+    Binary,
+}
+
+/// What a report's numbers count, and how they're shown.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Units {
+    /// What the raw numbers count, e.g. "bytes" or "file descriptors".
+    pub count_name: String,
+    pub scale: Scale,
+}
+
+impl Default for Units {
+    /// What Fil's titles and summaries have always used.
+    fn default() -> Self {
+        Self::fixed("bytes", MIB, "MiB")
+    }
+}
+
+impl Units {
+    /// Raw counts of something, e.g. `Units::raw("allocations")`.
+    pub fn raw(count_name: &str) -> Self {
+        Self {
+            count_name: count_name.to_string(),
+            scale: Scale::Raw,
+        }
+    }
+
+    /// Counts divided by `divisor` and shown as `name`.
+    pub fn fixed(count_name: &str, divisor: usize, name: &str) -> Self {
+        Self {
+            count_name: count_name.to_string(),
+            scale: Scale::Fixed {
+                divisor: divisor.max(1),
+                name: name.to_string(),
+            },
+        }
+    }
+
+    /// Parse the units for bytes: "bytes", "KiB", "MiB", "GiB", or "auto"
+    /// for whichever suits each number.
+    pub fn parse_bytes(value: &str) -> Option<Self> {
+        match value {
+            "bytes" => Some(Self::raw("bytes")),
+            "KiB" => Some(Self::fixed("bytes", KIB, "KiB")),
+            "MiB" => Some(Self::fixed("bytes", MIB, "MiB")),
+            "GiB" => Some(Self::fixed("bytes", GIB, "GiB")),
+            "auto" => Some(Self {
+                count_name: "bytes".to_string(),
+                scale: Scale::Binary,
+            }),
+            _ => None,
+        }
+    }
+
+    /// From FIL_UNITS; None means Fil's usual display.
+    pub fn from_env() -> Option<Self> {
+        let value = std::env::var("FIL_UNITS").ok()?;
+        let units = Self::parse_bytes(&value);
+        if units.is_none() {
+            eprintln!("=fil-profile= Ignoring unknown FIL_UNITS {:?}", value);
+        }
+        units
+    }
+
+    /// Format a number, e.g. "1.5 MiB". Scaled numbers always have one
+    /// decimal place, so columns of them line up.
+    pub fn format(&self, value: usize) -> String {
+        let (divisor, name) = match &self.scale {
+            Scale::Raw => return format!("{} {}", with_separators(value), self.count_name),
+            Scale::Fixed { divisor, name } => (*divisor, name.as_str()),
+            Scale::Binary => match value {
+                v if v >= GIB => (GIB, "GiB"),
+                v if v >= MIB => (MIB, "MiB"),
+                v if v >= KIB => (KIB, "KiB"),
+                _ => return format!("{} {}", value, self.count_name),
+            },
+        };
+        format!("{:.1} {}", value as f64 / divisor as f64, name)
+    }
+
+    /// Rewrite the "(1,234,567 bytes, 12.00%)" in a flamegraph's tooltips
+    /// into these units. Raw units are left alone, since that's what inferno
+    /// writes in the first place.
+    pub fn rewrite_tooltips(&self, svg: &str) -> String {
+        if self.scale == Scale::Raw {
+            return svg.to_string();
+        }
+        let pattern = Regex::new(&format!(
+            r"\(([0-9][0-9,]*) {}, ",
+            regex::escape(&self.count_name)
+        ))
+        .expect("escaped regex is valid");
+        pattern
+            .replace_all(svg, |captures: &Captures| {
+                match captures[1].replace(',', "").parse::<usize>() {
+                    Ok(value) => format!("({}, ", self.format(value)),
+                    Err(_) => captures[0].to_string(),
+                }
+            })
+            .into_owned()
+    }
+}
+
+/// 1234567 -> "1,234,567".
+fn with_separators(value: usize) -> String {
+    let digits = value.to_string();
+    let mut result = String::with_capacity(digits.len() + digits.len() / 3);
+    // Separators go every three digits counting from the end:
+    let offset = digits.len() % 3;
+    for (index, digit) in digits.chars().enumerate() {
+        if index > 0 && index % 3 == offset {
+            result.push(',');
+        }
+        result.push(digit);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Units;
+
+    #[test]
+    fn formatting() {
+        assert_eq!(Units::default().format(3 * 1024 * 1024 / 2), "1.5 MiB");
+        assert_eq!(Units::default().format(0), "0.0 MiB");
+        assert_eq!(Units::raw("bytes").format(1234567), "1,234,567 bytes");
+        assert_eq!(Units::raw("handles").format(12), "12 handles");
+        let auto = Units::parse_bytes("auto").unwrap();
+        assert_eq!(auto.format(512), "512 bytes");
+        assert_eq!(auto.format(2048), "2.0 KiB");
+        assert_eq!(auto.format(5 << 30), "5.0 GiB");
+        assert_eq!(
+            Units::fixed("pages", 1000, "k pages").format(2500),
+            "2.5 k pages"
+        );
+        assert_eq!(Units::parse_bytes("TiB"), None);
+    }
+
+    #[test]
+    fn tooltips() {
+        let svg =
+            "<title>all (2,097,152 bytes, 100%)</title><title>f (1,048,576 bytes, 50.00%)</title>";
+        assert_eq!(
+            Units::parse_bytes("MiB").unwrap().rewrite_tooltips(svg),
+            "<title>all (2.0 MiB, 100%)</title><title>f (1.0 MiB, 50.00%)</title>"
+        );
+        assert_eq!(Units::raw("bytes").rewrite_tooltips(svg), svg);
+        // Other counts aren't touched:
+        let counts = "<title>f (1,024 allocations, 50.00%)</title>";
+        assert_eq!(Units::default().rewrite_tooltips(counts), counts);
+    }
+}
@@ -1,22 +1,6 @@
-use ahash::RandomState as ARandomState;
+use crate::hashing::HashState;
 use std::collections::HashMap;
 
-lazy_static! {
-    // If the PYTHONHASHSEED environment variable is set, we will use it as seed
-    // for Rust hashmaps as well, to reduce randomness when benchmarking.
-    static ref HASH_SEED: Option<u64> = match std::env::var("PYTHONHASHSEED") {
-        Ok(value) => {
-            if value == "random" {
-                None
-            } else {
-                let seed = value.parse::<i64>().unwrap();
-                Some(seed as u64)
-            }
-        }
-        _ => None,
-    };
-}
-
 lazy_static! {
     pub static ref DEBUG_MODE: bool = match std::env::var("FIL_DEBUG") {
         Ok(value) => {
@@ -30,12 +14,68 @@ lazy_static! {
     };
 }
 
-/// Create a new hashmap with an optional fixed seed.
-pub fn new_hashmap<K, V>() -> HashMap<K, V, ARandomState> {
-    match *HASH_SEED {
-        Some(seed) => {
-            HashMap::with_hasher(ARandomState::with_seeds(seed, seed + 1, seed + 2, seed + 3))
+/// Create a new hashmap, using the hash function chosen with FIL_HASHER and
+/// an optional fixed seed; see hashing.rs.
+pub fn new_hashmap<K, V>() -> HashMap<K, V, HashState> {
+    HashMap::default()
+}
+
+/// Roughly how much memory a hashmap's table uses, not including anything
+/// the keys and values point to.
+pub fn hashmap_bytes<K, V, S>(map: &HashMap<K, V, S>) -> usize {
+    // Plus a control byte per bucket:
+    map.capacity() * (std::mem::size_of::<(K, V)>() + 1)
+}
+
+/// Turn a function, file or module name into a String. Names from C
+/// extensions or non-UTF-8 filesystems may not be valid UTF-8, and control
+/// characters aren't allowed in SVGs, so invalid bytes and control characters
+/// are written as `\xNN` escapes. The same bytes always give the same name.
+pub fn name_from_bytes(bytes: &[u8]) -> String {
+    let mut result = String::with_capacity(bytes.len());
+    let push_str = |result: &mut String, text: &str| {
+        for c in text.chars() {
+            if c.is_control() {
+                // Control characters are all below U+0100:
+                result.push_str(&format!("\\x{:02X}", c as u32));
+            } else {
+                result.push(c);
+            }
         }
-        None => HashMap::default(),
+    };
+    let mut rest = bytes;
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(valid) => {
+                push_str(&mut result, valid);
+                return result;
+            }
+            Err(error) => {
+                let (valid, after) = rest.split_at(error.valid_up_to());
+                // Safe to unwrap, this part was validated:
+                push_str(&mut result, std::str::from_utf8(valid).unwrap());
+                let invalid_length = error.error_len().unwrap_or(after.len());
+                for byte in &after[..invalid_length] {
+                    result.push_str(&format!("\\x{:02X}", byte));
+                }
+                rest = &after[invalid_length..];
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::name_from_bytes;
+
+    #[test]
+    fn names_from_bytes() {
+        assert_eq!(name_from_bytes(b"<listcomp>"), "<listcomp>");
+        assert_eq!(name_from_bytes("caf\u{e9}".as_bytes()), "caf\u{e9}");
+        assert_eq!(name_from_bytes(b"caf\xe9.py"), "caf\\xE9.py");
+        assert_eq!(name_from_bytes(b"a\xff\xfeb\x07"), "a\\xFF\\xFEb\\x07");
+        // Truncated multi-byte sequence at the end:
+        assert_eq!(name_from_bytes(b"x\xe2\x82"), "x\\xE2\\x82");
+        assert_eq!(name_from_bytes(b"tab\there"), "tab\\x09here");
     }
 }
@@ -0,0 +1,228 @@
+// Watchpoints: log every allocation made by a specific callsite, for digging
+// into one suspicious allocation site in detail.
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+
+use crate::compression::{self, open_log};
+use crate::memorytracking::{Callstack, CallstackId, FunctionLocations, LineNumber};
+
+/// Name of the log file, written in the output directory.
+pub const WATCHPOINT_LOG_FILENAME: &str = "watchpoints.jsonl";
+
+/// A callsite to watch: allocations whose innermost Python frame matches get
+/// logged.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    /// Dotted module name, filename, or the end of a filename, e.g.
+    /// "mypackage.data", "/src/mypackage/data.py" or "data.py".
+    pub location: String,
+    /// Qualified function name, e.g. "Loader.load".
+    pub function: String,
+    /// Only match this line, if set.
+    pub line_number: Option<LineNumber>,
+}
+
+impl Watchpoint {
+    fn matches(&self, callstack: &Callstack, functions: &dyn FunctionLocations) -> bool {
+        let call = match callstack.calls().last() {
+            Some(call) => call,
+            None => return false,
+        };
+        let (function, filename) = functions.get_function_and_filename(call.function());
+        if function != self.function {
+            return false;
+        }
+        if let Some(line_number) = self.line_number {
+            if call.line_number() != line_number {
+                return false;
+            }
+        }
+        functions.get_module(call.function()) == Some(self.location.as_str())
+            || filename == self.location
+            || filename.ends_with(&format!("/{}", self.location))
+    }
+}
+
+/// The registered watchpoints, and the log they write to.
+#[derive(Default)]
+pub struct Watchpoints {
+    watchpoints: Vec<Watchpoint>,
+    // Callstacks that match a watchpoint, with their human-readable form:
+    watched_callstacks: HashMap<CallstackId, String>,
+    log: Option<Box<dyn Write + Send + Sync>>,
+}
+
+impl Watchpoints {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    /// Forget about callstacks and close the log, e.g. because the tracker
+    /// was reset. The watchpoints themselves are kept.
+    pub fn clear(&mut self) {
+        self.watched_callstacks.clear();
+        self.flush();
+        self.log = None;
+    }
+
+    /// Check whether a callstack matches any watchpoint; call this for every
+    /// new callstack.
+    pub fn check_callstack(
+        &mut self,
+        callstack_id: CallstackId,
+        callstack: &Callstack,
+        functions: &dyn FunctionLocations,
+    ) {
+        if self
+            .watchpoints
+            .iter()
+            .any(|watchpoint| watchpoint.matches(callstack, functions))
+        {
+            self.watched_callstacks
+                .insert(callstack_id, callstack.as_plain_string(functions));
+        }
+    }
+
+    /// Log an allocation if its callstack is being watched.
+    pub fn log_allocation(
+        &mut self,
+        callstack_id: CallstackId,
+        address: usize,
+        size: usize,
+        directory: &str,
+    ) {
+        if self.watched_callstacks.is_empty() {
+            return;
+        }
+        let stack = match self.watched_callstacks.get(&callstack_id) {
+            Some(stack) => stack,
+            None => return,
+        };
+        if self.log.is_none() {
+            match open_log(
+                Path::new(directory),
+                WATCHPOINT_LOG_FILENAME,
+                compression::enabled_from_env(),
+            ) {
+                Ok(log) => self.log = Some(log),
+                Err(e) => {
+                    eprintln!("=fil-profile= Error opening watchpoint log: {}", e);
+                    // Don't keep trying:
+                    self.watched_callstacks.clear();
+                    return;
+                }
+            }
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or(0.0);
+        let entry = json!({
+            "timestamp": timestamp,
+            "address": address,
+            "size": size,
+            "stack": stack,
+        });
+        if let Some(log) = &mut self.log {
+            if let Err(e) = writeln!(log, "{}", entry) {
+                eprintln!("=fil-profile= Error writing watchpoint log: {}", e);
+            }
+        }
+    }
+
+    pub fn flush(&mut self) {
+        if let Some(log) = &mut self.log {
+            let _ = log.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Watchpoint, Watchpoints, WATCHPOINT_LOG_FILENAME};
+    use crate::memorytracking::{CallSiteId, Callstack, VecFunctionLocations};
+
+    #[test]
+    fn matching_callstacks_are_logged() {
+        let mut functions = VecFunctionLocations::new();
+        let outer = functions.add_function("/src/main.py".to_string(), "main".to_string());
+        let load = functions.add_function_with_module(
+            "/src/pkg/data.py".to_string(),
+            "Loader.load".to_string(),
+            "pkg.data".to_string(),
+        );
+        let mut watched = Callstack::new();
+        watched.start_call(0, CallSiteId::new(outer, 3));
+        watched.start_call(0, CallSiteId::new(load, 10));
+        let mut other_line = Callstack::new();
+        other_line.start_call(0, CallSiteId::new(load, 11));
+        let mut not_innermost = Callstack::new();
+        not_innermost.start_call(0, CallSiteId::new(load, 10));
+        not_innermost.start_call(0, CallSiteId::new(outer, 5));
+
+        let mut watchpoints = Watchpoints::new();
+        watchpoints.add(Watchpoint {
+            location: "pkg.data".to_string(),
+            function: "Loader.load".to_string(),
+            line_number: Some(10),
+        });
+        watchpoints.check_callstack(0, &watched, &functions);
+        watchpoints.check_callstack(1, &other_line, &functions);
+        watchpoints.check_callstack(2, &not_innermost, &functions);
+        assert_eq!(
+            watchpoints.watched_callstacks.keys().collect::<Vec<_>>(),
+            vec![&0]
+        );
+
+        let directory = std::env::temp_dir().join(format!("fil-watch-{}", std::process::id()));
+        let directory = directory.to_str().unwrap();
+        watchpoints.log_allocation(0, 1234, 100, directory);
+        watchpoints.log_allocation(1, 5678, 200, directory);
+        watchpoints.flush();
+        let log =
+            std::fs::read_to_string(std::path::Path::new(directory).join(WATCHPOINT_LOG_FILENAME))
+                .unwrap();
+        let lines: Vec<serde_json::Value> = log
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["size"], 100);
+        assert_eq!(lines[0]["address"], 1234);
+        assert_eq!(
+            lines[0]["stack"],
+            "/src/main.py:3 (main);/src/pkg/data.py:10 (Loader.load)"
+        );
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn locations_match_files_and_modules() {
+        let mut functions = VecFunctionLocations::new();
+        let f = functions.add_function("/src/pkg/data.py".to_string(), "load".to_string());
+        let mut callstack = Callstack::new();
+        callstack.start_call(0, CallSiteId::new(f, 10));
+        for (location, expected) in [
+            ("data.py", true),
+            ("pkg/data.py", true),
+            ("/src/pkg/data.py", true),
+            ("ta.py", false),
+            ("pkg.data", false), // No module was registered
+        ] {
+            let watchpoint = Watchpoint {
+                location: location.to_string(),
+                function: "load".to_string(),
+                line_number: None,
+            };
+            assert_eq!(watchpoint.matches(&callstack, &functions), expected);
+        }
+    }
+}
@@ -0,0 +1,196 @@
+// Peak memory over a sliding time window, e.g. the last 10 minutes. Services
+// often have a huge spike at startup, which then dominates the all-time peak
+// forever; the peak over a recent window is what matters for capacity
+// planning in steady state.
+//
+// Usage is bucketed by second, keeping the highest usage seen in each second
+// of the window, so the cost is a bounded amount of memory rather than a record
+// of every change. Like hotloops.rs, the clock is only read every CHECK_EVERY
+// updates, with the highest usage in between recorded then.
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use crate::snapshot::get_usize;
+
+/// How many updates between looks at the clock.
+const CHECK_EVERY: u32 = 1024;
+
+#[derive(Clone, Debug)]
+pub struct WindowedPeak {
+    window: Duration,
+    start: Instant,
+    since_check: u32,
+    // Highest usage since the clock was last read:
+    pending_bytes: usize,
+    // (seconds since start, highest usage during that second), oldest
+    // first:
+    buckets: VecDeque<(u64, usize)>,
+}
+
+/// The peak over the window, as of when the snapshot was taken.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WindowPeak {
+    pub window_seconds: u64,
+    pub peak_bytes: usize,
+}
+
+impl WindowPeak {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "window_seconds": self.window_seconds,
+            "peak_bytes": self.peak_bytes,
+        })
+    }
+
+    pub fn from_json(value: &Value) -> Result<Self, String> {
+        Ok(Self {
+            window_seconds: get_usize(value, "window_seconds")? as u64,
+            peak_bytes: get_usize(value, "peak_bytes")?,
+        })
+    }
+}
+
+impl WindowedPeak {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            start: Instant::now(),
+            since_check: 0,
+            pending_bytes: 0,
+            buckets: VecDeque::new(),
+        }
+    }
+
+    /// Enabled by setting FIL_PEAK_WINDOW_SECONDS to the window's length.
+    pub fn from_env() -> Option<Self> {
+        let seconds = std::env::var("FIL_PEAK_WINDOW_SECONDS").ok()?;
+        match seconds.trim().parse() {
+            Ok(seconds) if seconds > 0 => Some(Self::new(Duration::from_secs(seconds))),
+            _ => {
+                eprintln!(
+                    "=fil-profile= Ignoring invalid FIL_PEAK_WINDOW_SECONDS: {:?}",
+                    seconds
+                );
+                None
+            }
+        }
+    }
+
+    /// Same window, no data.
+    pub fn cleared(&self) -> Self {
+        Self::new(self.window)
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Call whenever usage changes.
+    #[inline]
+    pub fn update(&mut self, current_bytes: usize) {
+        self.pending_bytes = self.pending_bytes.max(current_bytes);
+        self.since_check += 1;
+        if self.since_check >= CHECK_EVERY {
+            let pending_bytes = std::mem::take(&mut self.pending_bytes);
+            self.since_check = 0;
+            self.update_at(Instant::now(), pending_bytes);
+        }
+    }
+
+    fn update_at(&mut self, now: Instant, current_bytes: usize) {
+        let second = self.second(now);
+        match self.buckets.back_mut() {
+            Some((last, bytes)) if *last == second => *bytes = (*bytes).max(current_bytes),
+            _ => {
+                self.buckets.push_back((second, current_bytes));
+                self.expire(second);
+            }
+        }
+    }
+
+    /// The highest usage within the window.
+    pub fn peak(&mut self, current_bytes: usize) -> usize {
+        self.peak_at(Instant::now(), current_bytes)
+    }
+
+    fn peak_at(&mut self, now: Instant, current_bytes: usize) -> usize {
+        self.expire(self.second(now));
+        // Usage since the clock was last read happened by now at the latest:
+        self.buckets
+            .iter()
+            .map(|(_, bytes)| *bytes)
+            .fold(current_bytes.max(self.pending_bytes), usize::max)
+    }
+
+    /// The peak, for a snapshot.
+    pub fn stats(&mut self, current_bytes: usize) -> WindowPeak {
+        WindowPeak {
+            window_seconds: self.window.as_secs(),
+            peak_bytes: self.peak(current_bytes),
+        }
+    }
+
+    fn second(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.start).as_secs()
+    }
+
+    /// Drop buckets that are entirely outside the window.
+    fn expire(&mut self, second: u64) {
+        while let Some((oldest, _)) = self.buckets.front() {
+            if oldest + self.window.as_secs() < second {
+                self.buckets.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Roughly how much memory this uses.
+    pub fn memory_usage(&self) -> usize {
+        self.buckets.capacity() * std::mem::size_of::<(u64, usize)>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{WindowedPeak, CHECK_EVERY};
+    use std::time::Duration;
+
+    #[test]
+    fn clock_is_read_every_so_often() {
+        let mut window = WindowedPeak::new(Duration::from_secs(10));
+        window.update(5000);
+        for _ in 1..CHECK_EVERY {
+            window.update(100);
+        }
+        // The spike is kept even though the clock wasn't read at the time:
+        assert_eq!(window.buckets.len(), 1);
+        assert_eq!(window.buckets[0].1, 5000);
+        window.update(7000);
+        assert_eq!(window.pending_bytes, 7000);
+        assert_eq!(window.peak(200), 7000);
+    }
+
+    #[test]
+    fn old_peaks_expire() {
+        let mut window = WindowedPeak::new(Duration::from_secs(10));
+        let start = window.start;
+        let at = |seconds| start + Duration::from_secs(seconds);
+        // Startup spike:
+        window.update_at(at(0), 1000);
+        window.update_at(at(1), 100);
+        window.update_at(at(5), 300);
+        window.update_at(at(5), 200);
+        assert_eq!(window.peak_at(at(5), 200), 1000);
+        assert_eq!(window.peak_at(at(10), 200), 1000);
+        // The spike is now out of the window:
+        assert_eq!(window.peak_at(at(11), 200), 300);
+        assert_eq!(window.peak_at(at(16), 200), 200);
+        // Current usage always counts:
+        window.update_at(at(20), 50);
+        assert_eq!(window.peak_at(at(20), 400), 400);
+        assert_eq!(window.cleared().peak(0), 0);
+    }
+}
@@ -1,3 +1,4 @@
+import os
 from os.path import join
 from glob import glob
 
@@ -17,7 +18,7 @@ setup(
         "console_scripts": ["fil-profile=filprofiler._script:stage_1"],
     },
     package_data={
-        "filprofiler": ["licenses.txt"],
+        "filprofiler": ["licenses.txt", "_childsite/sitecustomize.py"],
     },
     data_files=[
         (
@@ -31,6 +32,8 @@ setup(
             path="filpreload/Cargo.toml",
             debug=False,
             binding=Binding.PyO3,
+            # Set FIL_BUILD_DASHBOARD=1 to include the live dashboard:
+            features=["dashboard"] if os.environ.get("FIL_BUILD_DASHBOARD") else [],
         )
     ],
     use_scm_version=True,
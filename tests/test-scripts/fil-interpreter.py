@@ -29,7 +29,7 @@ from filprofiler._tracer import (
 )
 from filprofiler._testing import get_allocations, big, as_mb
 from filprofiler._ipython import run_with_profile
-from filprofiler.api import profile
+from filprofiler.api import profile, start_recording, stop_recording
 from pymalloc import pymalloc
 import fil_api
 
@@ -62,6 +62,44 @@ def test_temporary_profiling(tmpdir):
     test_no_profiling()
 
 
+def test_recording_windows(tmpdir):
+    """Each recording window gets its own report, with its own peak."""
+
+    def allocate(megabytes):
+        arr = np.ones((1024, 1024, megabytes // 8), dtype=np.uint64)
+        del arr
+
+    start_recording("small", tmpdir)
+    allocate(16)
+    stop_recording()
+    # Not recorded:
+    allocate(64)
+    start_recording("big", tmpdir)
+    allocate(32)
+    index_path = stop_recording()
+    # Same label again gets a new directory:
+    start_recording("big", tmpdir)
+    stop_recording()
+
+    assert index_path == str(tmpdir / "big" / "index.html")
+    assert sorted(os.listdir(tmpdir)) == ["big", "big-2", "small"]
+    for label, expected_mb in [("small", 16), ("big", 32)]:
+        allocations = get_allocations(tmpdir / label / "peak-memory.prof", direct=True)
+        path = (
+            (__file__, "allocate", ANY),
+            (numpy.core.numeric.__file__, "ones", ANY),
+        )
+        assert match(allocations, {path: big}, as_mb) == pytest.approx(
+            expected_mb, 0.1
+        )
+
+    with pytest.raises(RuntimeError):
+        stop_recording()
+
+    # Profiling stopped:
+    test_no_profiling()
+
+
 def run_in_ipython_shell(code_cells):
     """Run a list of strings in IPython.
 